@@ -0,0 +1,44 @@
+//! Minimal druid app with accord chat next to its own widgets.
+use std::sync::Arc;
+
+use accord_gui::{chat_widget, config::Config, AppState};
+use druid::{
+    widget::{Button, Flex, Label},
+    AppLauncher, Data, Lens, WidgetExt, WindowDesc,
+};
+
+#[derive(Clone, Data, Lens)]
+struct HostState {
+    clicks: u32,
+    chat: AppState,
+}
+
+fn main() {
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let config = Config::default();
+    let theme = Arc::new(config.theme.clone().unwrap_or_default());
+
+    let sidebar = Flex::column()
+        .with_child(Label::dynamic(|data: &HostState, _env| {
+            format!("Clicked {} times", data.clicks)
+        }))
+        .with_child(
+            Button::new("Click").on_click(|_ctx, data: &mut HostState, _env| data.clicks += 1),
+        )
+        .padding(10.0);
+    let chat =
+        chat_widget(theme, config.image_cache_size, rt.handle().clone()).lens(HostState::chat);
+    let root = Flex::row().with_child(sidebar).with_flex_child(chat, 1.0);
+
+    let launcher = AppLauncher::with_window(WindowDesc::new(root).title("Host app"));
+    let tx = accord_gui::spawn_connection_handler(
+        config.connection.clone(),
+        rt.handle().clone(),
+        launcher.get_external_handle(),
+    );
+    let data = HostState {
+        clicks: 0,
+        chat: AppState::new(&config, tx),
+    };
+    launcher.launch(data).unwrap();
+}
@@ -1,7 +1,48 @@
+use std::ffi::OsString;
 use std::path::PathBuf;
+use std::sync::OnceLock;
 
+use accord::client_config::ClientConfig;
+use druid::{Data, Rect};
 use serde::{Deserialize, Serialize};
 
+/// Server saved for quick connecting
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, Data)]
+pub struct SavedServer {
+    pub address: String,
+    pub username: String,
+}
+
+/// Size and position of the main window, restored on launch
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Data)]
+pub struct WindowGeometry {
+    pub x: f64,
+    pub y: f64,
+    pub width: f64,
+    pub height: f64,
+}
+
+impl WindowGeometry {
+    /// How much of the window has to be on a screen to be grabbed
+    const MIN_VISIBLE: f64 = 64.0;
+
+    /// Whether the window would be reachable on one of the `screens`
+    /// (e.g. not left on a monitor that was since disconnected).
+    pub fn fits_on(&self, screens: &[Rect]) -> bool {
+        if self.width <= 0.0 || self.height <= 0.0 {
+            return false;
+        }
+        let window = Rect::new(self.x, self.y, self.x + self.width, self.y + self.height);
+        screens.iter().any(|screen| {
+            let visible = screen.intersect(window);
+            // Top edge (title bar) has to be on the screen too
+            (screen.y0..screen.y1).contains(&self.y)
+                && visible.width() >= Self::MIN_VISIBLE.min(self.width)
+                && visible.height() >= Self::MIN_VISIBLE.min(self.height)
+        })
+    }
+}
+
 /// Represents config file loaded into memory
 #[derive(Serialize, Deserialize)]
 pub struct Config {
@@ -9,7 +50,32 @@ pub struct Config {
     pub username: String,
     pub remember_login: bool,
     pub images_from_links: bool,
+    #[serde(default)]
+    pub open_links: bool,
+    #[serde(default)]
+    pub servers: Vec<SavedServer>,
+    /// Whether message times are shown relative to now ("5m ago")
+    #[serde(default)]
+    pub relative_times: bool,
+    /// Show only hour and minute once per message group, exact time on hover
+    #[serde(default)]
+    pub compact_times: bool,
+    /// Max number of images kept in memory
+    #[serde(default = "default_image_cache_size")]
+    pub image_cache_size: usize,
+    /// Last geometry of the main window
+    #[serde(default)]
+    pub window: Option<WindowGeometry>,
+    /// Multiplier of font sizes (zoom)
+    #[serde(default = "default_font_scale")]
+    pub font_scale: f64,
+    /// Use high contrast theme instead of [`Config::theme`]
+    #[serde(default)]
+    pub high_contrast: bool,
     pub theme: Option<crate::Theme>,
+    /// How servers are connected to (timeouts, pinging, fetched history)
+    #[serde(default)]
+    pub connection: ClientConfig,
 }
 
 impl Default for Config {
@@ -19,17 +85,57 @@ impl Default for Config {
             username: Default::default(),
             remember_login: true,
             images_from_links: false,
+            open_links: false,
+            servers: Default::default(),
+            relative_times: false,
+            compact_times: false,
+            image_cache_size: default_image_cache_size(),
+            window: None,
+            font_scale: default_font_scale(),
+            high_contrast: false,
             theme: Some(Default::default()),
+            connection: Default::default(),
         }
     }
 }
 
+fn default_image_cache_size() -> usize {
+    64
+}
+
+fn default_font_scale() -> f64 {
+    1.0
+}
+
 const CONFIG_FILE: &str = "config.toml";
 
+/// Environment variable with path to the config file, `--config` flag takes precedence over it
+pub const CONFIG_PATH_VAR: &str = "ACCORD_GUI_CONFIG";
+
+/// Path set with [`set_config_path`]
+static CONFIG_PATH: OnceLock<PathBuf> = OnceLock::new();
+
+/// Makes [`load_config`] and [`save_config`] use `path` instead of the default location.
+///
+/// Has to be called before the config is first loaded, later calls are ignored.
+pub fn set_config_path(path: PathBuf) {
+    if CONFIG_PATH.set(path).is_err() {
+        log::warn!("Config path already set, ignoring.");
+    }
+}
+
+/// Path set with [`set_config_path`], then [`CONFIG_PATH_VAR`], then the default location
 fn config_path() -> PathBuf {
-    let mut path = config_path_dir();
-    path.push(CONFIG_FILE);
-    path
+    resolve_config_path(
+        CONFIG_PATH.get().cloned(),
+        std::env::var_os(CONFIG_PATH_VAR),
+    )
+}
+
+fn resolve_config_path(set_path: Option<PathBuf>, env_path: Option<OsString>) -> PathBuf {
+    set_path
+        .or_else(|| env_path.filter(|p| !p.is_empty()).map(PathBuf::from))
+        .unwrap_or_else(|| config_path_dir().join(CONFIG_FILE))
 }
 
 #[cfg(unix)]
@@ -40,10 +146,27 @@ fn config_path_dir() -> PathBuf {
 
 #[cfg(windows)]
 fn config_path_dir() -> PathBuf {
-    let local_app_data = std::env::var("LOCALAPPDATA").unwrap();
-    let mut path = PathBuf::from(local_app_data);
-    path.push("accord-gui");
-    path
+    windows_config_dir(std::env::var_os("LOCALAPPDATA"))
+}
+
+/// `%LOCALAPPDATA%\accord-gui`, or directory of the executable if the variable isn't set
+/// (e.g. when running as a service)
+#[cfg(any(windows, test))]
+fn windows_config_dir(local_app_data: Option<OsString>) -> PathBuf {
+    match local_app_data.filter(|dir| !dir.is_empty()) {
+        Some(dir) => PathBuf::from(dir).join("accord-gui"),
+        None => {
+            let dir = std::env::current_exe()
+                .ok()
+                .and_then(|exe| exe.parent().map(PathBuf::from))
+                .unwrap_or_else(|| PathBuf::from("."));
+            log::warn!(
+                "LOCALAPPDATA is not set, looking for config in {:?}. Set the path with `--config` or `ACCORD_GUI_CONFIG`.",
+                dir
+            );
+            dir
+        }
+    }
 }
 
 /// Saves config.
@@ -51,7 +174,9 @@ fn config_path_dir() -> PathBuf {
 pub fn save_config(mut config: Config) -> std::io::Result<()> {
     log::info!("Saving config.");
     let config_path = config_path();
-    std::fs::create_dir_all(config_path_dir()).unwrap();
+    if let Some(dir) = config_path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
 
     if config.theme.is_none() {
         // This _shouldn't_ create an infinite loop, because if `load_config` doesn't load a theme,
@@ -86,3 +211,88 @@ pub fn load_config() -> Config {
     }
     config
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn window_geometry_roundtrip() {
+        let geometry = WindowGeometry {
+            x: 120.0,
+            y: -30.5,
+            width: 800.0,
+            height: 600.0,
+        };
+        let config = Config {
+            window: Some(geometry),
+            ..Default::default()
+        };
+        let toml = toml::to_string(&config).unwrap();
+        let loaded: Config = toml::from_str(&toml).unwrap();
+        assert_eq!(loaded.window, Some(geometry));
+
+        // Configs from before this field load without it
+        let toml = toml.replace("[window]", "[old_window]");
+        let loaded: Config = toml::from_str(&toml).unwrap();
+        assert_eq!(loaded.window, None);
+    }
+
+    #[test]
+    fn window_geometry_fits_on() {
+        let screens = [
+            Rect::new(0.0, 0.0, 1920.0, 1080.0),
+            Rect::new(1920.0, 0.0, 3840.0, 1080.0),
+        ];
+        let at = |x, y| WindowGeometry {
+            x,
+            y,
+            width: 800.0,
+            height: 600.0,
+        };
+        assert!(at(100.0, 100.0).fits_on(&screens));
+        // Spanning two monitors
+        assert!(at(1500.0, 100.0).fits_on(&screens));
+        assert!(at(3000.0, 100.0).fits_on(&screens));
+        // Monitor on the right disconnected
+        assert!(!at(3000.0, 100.0).fits_on(&screens[..1]));
+        // Barely visible or title bar off screen
+        assert!(!at(-780.0, 100.0).fits_on(&screens));
+        assert!(!at(100.0, -200.0).fits_on(&screens));
+        assert!(!at(100.0, 100.0).fits_on(&[]));
+        let empty = WindowGeometry {
+            width: 0.0,
+            ..at(100.0, 100.0)
+        };
+        assert!(!empty.fits_on(&screens));
+    }
+
+    #[test]
+    fn config_path_override() {
+        let set = PathBuf::from("/etc/accord/set.toml");
+        let env = PathBuf::from("/etc/accord/env.toml");
+        assert_eq!(
+            resolve_config_path(Some(set.clone()), Some(env.clone().into())),
+            set
+        );
+        assert_eq!(resolve_config_path(None, Some(env.clone().into())), env);
+        assert_eq!(
+            resolve_config_path(None, Some("".into())),
+            resolve_config_path(None, None)
+        );
+    }
+
+    #[test]
+    fn windows_config_dir_fallback() {
+        assert_eq!(
+            windows_config_dir(Some("C:\\Users\\me\\AppData\\Local".into())),
+            PathBuf::from("C:\\Users\\me\\AppData\\Local").join("accord-gui")
+        );
+        let exe_dir = std::env::current_exe()
+            .unwrap()
+            .parent()
+            .unwrap()
+            .to_path_buf();
+        assert_eq!(windows_config_dir(None), exe_dir);
+    }
+}
@@ -2,32 +2,39 @@ use chrono::TimeZone;
 use druid::ExtEventSink;
 
 use tokio::{
-    net::TcpStream,
-    runtime,
+    runtime::Handle,
     sync::{mpsc, oneshot},
-    time::timeout,
 };
 
-use accord::{connection::*, packets::*, ENC_TOK_LEN, SECRET_LEN};
+use accord::{
+    client_config::{establish_encryption, ClientConfig, InitError},
+    connection::*,
+    packets::*,
+};
 
-use std::sync::Arc;
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Arc,
+};
 
-use rand::{rngs::OsRng, Rng, SeedableRng};
 use rand_chacha::ChaCha20Rng;
 
-use rsa::{PaddingScheme, PublicKey};
-
 use crate::Message as GMessage;
 
-use log::{error, info};
+use log::{error, info, warn};
 
 /// Commands sent to GUI
+///
+/// Commands coming from a connection are submitted with [`SERVER_COMMAND`](crate::SERVER_COMMAND),
+/// together with key of the server they come from.
 #[derive(Debug)]
 pub enum GuiCommand {
     /// Add message to message list
     AddMessage(GMessage),
     /// Connected to server
     Connected,
+    /// Logged in as user with this id and username (sent after [`GuiCommand::Connected`])
+    LoggedIn { user_id: i64, username: String },
     /// Connection ended with reason as `String`
     ConnectionEnded(String),
     /// Send image stored in bytes
@@ -38,6 +45,17 @@ pub enum GuiCommand {
     StoreImage(String, Arc<Vec<u8>>),
     /// Set the list of connected users
     UpdateUserList(Vec<String>),
+    /// Server advertised its `(name, description)`
+    ServerInfo(String, String),
+    /// User was renamed `(old, new)`
+    UserRenamed(String, String),
+    /// Server didn't accept a message from us, with the reason
+    MessageRejected(String),
+    /// Not connected, message will be sent after connecting again
+    /// (with number of messages waiting)
+    MessageQueued(usize),
+    /// Not connected and too many messages are waiting, so the message was dropped
+    QueueFull,
 }
 
 /// Commands sent to ConnectionHandler (from GUI)
@@ -45,32 +63,185 @@ pub enum GuiCommand {
 pub enum ConnectionHandlerCommand {
     /// Connects with `(address, username, password)`
     Connect(String, String, String),
-    /// Sends this packet to server
-    Write(accord::packets::ServerboundPacket),
+    /// Sends this packet to server identified by the key (see [`server_key`])
+    Write(String, accord::packets::ServerboundPacket),
+}
+
+/// Key identifying a connection to a server as a given user
+pub fn server_key(addr: &str, username: &str) -> String {
+    format!("{}@{}", username, addr)
+}
+
+/// Max number of messages waiting for a server while not connected to it
+pub const MAX_QUEUED: usize = 16;
+
+/// Messages written while not connected, by server key (see [`server_key`]).
+///
+/// They are sent, oldest first, when connecting to that server again.
+#[derive(Default)]
+struct OutgoingQueue {
+    queued: HashMap<String, VecDeque<ServerboundPacket>>,
+}
+
+impl OutgoingQueue {
+    /// Only messages are kept, other requests make no sense for a new connection
+    fn should_queue(p: &ServerboundPacket) -> bool {
+        matches!(
+            p,
+            ServerboundPacket::Message(_) | ServerboundPacket::ImageMessage(_)
+        )
+    }
+
+    /// Queues the packet, returns number of packets waiting for `server`,
+    /// or the packet back if there are already [`MAX_QUEUED`]
+    fn push(&mut self, server: &str, p: ServerboundPacket) -> Result<usize, ServerboundPacket> {
+        let queued = self.queued.entry(server.to_string()).or_default();
+        if queued.len() >= MAX_QUEUED {
+            return Err(p);
+        }
+        queued.push_back(p);
+        Ok(queued.len())
+    }
+
+    /// Takes packets waiting for `server`, oldest first
+    fn drain(&mut self, server: &str) -> VecDeque<ServerboundPacket> {
+        self.queued.remove(server).unwrap_or_default()
+    }
+
+    /// Makes channel for a new connection to `server`, with packets waiting for it going first
+    fn open(
+        &mut self,
+        server: &str,
+    ) -> (
+        mpsc::Sender<ServerboundPacket>,
+        mpsc::Receiver<ServerboundPacket>,
+    ) {
+        let (tx, rx) = mpsc::channel(16 + MAX_QUEUED);
+        for p in self.drain(server) {
+            tx.try_send(p)
+                .expect("Channel should fit all queued messages");
+        }
+        (tx, rx)
+    }
+
+    /// Takes back packets from channel of connection to `server` that ended before logging in
+    /// (see [`OutgoingQueue::open`]), so they are sent after connecting again.
+    ///
+    /// Returns number of packets waiting, or `Err` with it if some didn't fit.
+    fn requeue(
+        &mut self,
+        server: &str,
+        gui_rx: &mut mpsc::Receiver<ServerboundPacket>,
+    ) -> Result<usize, usize> {
+        gui_rx.close();
+        let mut full = false;
+        while let Ok(p) = gui_rx.try_recv() {
+            if Self::should_queue(&p) {
+                full |= self.push(server, p).is_err();
+            }
+        }
+        let pending = self.queued.get(server).map_or(0, VecDeque::len);
+        if full {
+            Err(pending)
+        } else {
+            Ok(pending)
+        }
+    }
 }
 
-/// Handles connection to the server.
+/// Handles connections to the servers.
 /// Communicates with GUI with [`GuiCommand`]s and [`ConnectionHandlerCommand`]s.
-pub struct ConnectionHandler;
+pub struct ConnectionHandler {
+    /// Connection behavior shared by all connections
+    pub config: ClientConfig,
+}
 
 impl ConnectionHandler {
-    /// Awaits [`ConnectionHandlerCommand::Connect`] from GUI
-    /// and then connects to the server.
+    /// Awaits [`ConnectionHandlerCommand`]s from GUI,
+    /// spawning new connections (on runtime of `rt`) and routing packets to them.
+    ///
+    /// Blocks the current thread, which must not be a runtime's thread.
     pub fn main_loop(
         self,
+        rt: Handle,
         mut rx: mpsc::Receiver<ConnectionHandlerCommand>,
         event_sink: ExtEventSink,
     ) {
-        let rt = runtime::Runtime::new().unwrap();
         rt.block_on(async move {
+            // Senders to writing loops of connections, by server key
+            let mut connections: HashMap<String, mpsc::Sender<ServerboundPacket>> = HashMap::new();
+            let mut queue = OutgoingQueue::default();
+            // Channels of connections that ended before logging in
+            let (requeue_tx, mut requeue_rx) = mpsc::unbounded_channel();
             loop {
-                match rx.recv().await {
-                    Some(ConnectionHandlerCommand::Connect(addr, username, password)) => {
-                        self.connect(&mut rx, addr, username, password, &event_sink)
-                            .await;
+                let command = tokio::select! {
+                    command = rx.recv() => match command {
+                        Some(command) => command,
+                        None => break,
+                    },
+                    Some((server, mut gui_rx)) = requeue_rx.recv() => {
+                        let command = match queue.requeue(&server, &mut gui_rx) {
+                            Ok(0) => continue,
+                            Ok(pending) => GuiCommand::MessageQueued(pending),
+                            Err(_) => {
+                                log::warn!("Not connected to {}, queue is full", server);
+                                GuiCommand::QueueFull
+                            }
+                        };
+                        submit_command(&event_sink, &server, command);
+                        continue;
                     }
-                    c => {
-                        panic!("Expected ConnectionHandlerCommand::Connect, got {:?}", c);
+                };
+                match command {
+                    ConnectionHandlerCommand::Connect(addr, username, password) => {
+                        let server = server_key(&addr, &username);
+                        if connections.get(&server).map_or(false, |tx| !tx.is_closed()) {
+                            // Already connected, just switch to it
+                            submit_command(&event_sink, &server, GuiCommand::Connected);
+                            continue;
+                        }
+                        // Messages written while disconnected go first
+                        let (tx, rx) = queue.open(&server);
+                        connections.insert(server.clone(), tx);
+                        let connecting = Self::connect(
+                            rx,
+                            self.config.clone(),
+                            server.clone(),
+                            addr,
+                            username,
+                            password,
+                            event_sink.clone(),
+                        );
+                        let requeue_tx = requeue_tx.clone();
+                        tokio::spawn(async move {
+                            if let Err(gui_rx) = connecting.await {
+                                requeue_tx.send((server, gui_rx)).ok();
+                            }
+                        });
+                    }
+                    ConnectionHandlerCommand::Write(server, p) => {
+                        let p = match connections.get(&server) {
+                            Some(tx) => match tx.send(p).await {
+                                Ok(()) => continue,
+                                Err(mpsc::error::SendError(p)) => {
+                                    connections.remove(&server);
+                                    p
+                                }
+                            },
+                            None => p,
+                        };
+                        if !OutgoingQueue::should_queue(&p) {
+                            log::warn!("Not connected to {}", server);
+                            continue;
+                        }
+                        let command = match queue.push(&server, p) {
+                            Ok(pending) => GuiCommand::MessageQueued(pending),
+                            Err(_) => {
+                                log::warn!("Not connected to {}, queue is full", server);
+                                GuiCommand::QueueFull
+                            }
+                        };
+                        submit_command(&event_sink, &server, command);
                     }
                 }
             }
@@ -79,28 +250,33 @@ impl ConnectionHandler {
 
     /// Connects to the server, establishes encryption, logs in
     /// and spawns reading and writing loops.
+    ///
+    /// Gives `gui_rx` back if it fails before logging in, so messages in it aren't lost.
     pub async fn connect(
-        &self,
-        gui_rx: &mut mpsc::Receiver<ConnectionHandlerCommand>,
+        gui_rx: mpsc::Receiver<ServerboundPacket>,
+        config: ClientConfig,
+        server: String,
         addr: String,
         username: String,
         password: String,
-        event_sink: &ExtEventSink,
-    ) {
+        event_sink: ExtEventSink,
+    ) -> Result<(), mpsc::Receiver<ServerboundPacket>> {
+        let event_sink = &event_sink;
         //==================================
         //      Connect
         //==================================
         info!("Connecting to: {}", addr);
-        let socket = if let Ok(Ok(socket)) =
-            timeout(std::time::Duration::from_secs(5), TcpStream::connect(addr)).await
-        {
-            socket
-        } else {
-            submit_command(
-                event_sink,
-                GuiCommand::ConnectionEnded("Failed to connect!".to_string()),
-            );
-            return;
+        let socket = match config.connect(addr).await {
+            Ok(socket) => socket,
+            Err(e) => {
+                let e = InitError::ConnectFailed(e);
+                submit_command(
+                    event_sink,
+                    &server,
+                    GuiCommand::ConnectionEnded(e.to_string()),
+                );
+                return Err(gui_rx);
+            }
         };
 
         info!("Connected!");
@@ -111,89 +287,20 @@ impl ConnectionHandler {
         //      Encryption
         //==================================
         info!("Establishing encryption...");
-        let secret = None;
-        let mut nonce_generator_write = None;
-        let mut nonce_generator_read = None;
-
-        // Request encryption
-        writer
-            .write_packet(
-                ServerboundPacket::EncryptionRequest,
-                &secret,
-                nonce_generator_write.as_mut(),
-            )
-            .await
-            .unwrap();
-
-        // Handle encryption response
-        let pub_key: rsa::RsaPublicKey;
-        let token = if let Ok(Some(p)) = reader
-            .read_packet(&secret, nonce_generator_read.as_mut())
-            .await
-        {
-            match p {
-                ClientboundPacket::EncryptionResponse(pub_key_der, token_) => {
-                    info!("Encryption step 1 successful");
-                    pub_key = rsa::pkcs8::FromPublicKey::from_public_key_der(&pub_key_der).unwrap();
-                    assert_eq!(ENC_TOK_LEN, token_.len());
-                    token_
-                }
-                _ => {
-                    error!("Encryption failed. Server response: {:?}", p);
-                    std::process::exit(1)
+        let (secret, mut nonce_generator_write, mut nonce_generator_read) =
+            match establish_encryption(&mut reader, &mut writer).await {
+                Ok((secret, write, read)) => (Some(secret), Some(write), Some(read)),
+                Err(e) => {
+                    error!("{}", e);
+                    submit_command(
+                        event_sink,
+                        &server,
+                        GuiCommand::ConnectionEnded(e.to_string()),
+                    );
+                    return Err(gui_rx);
                 }
-            }
-        } else {
-            error!("Failed to establish encryption");
-            std::process::exit(1)
-        };
-
-        // Generate secret
-        let mut secret = [0u8; SECRET_LEN];
-        OsRng.fill(&mut secret);
-
-        // Encrypt and send
-        let padding = PaddingScheme::new_pkcs1v15_encrypt();
-        let enc_secret = pub_key
-            .encrypt(&mut OsRng, padding, &secret[..])
-            .expect("failed to encrypt");
-        let padding = PaddingScheme::new_pkcs1v15_encrypt();
-        let enc_token = pub_key
-            .encrypt(&mut OsRng, padding, &token[..])
-            .expect("failed to encrypt");
-        writer
-            .write_packet(
-                ServerboundPacket::EncryptionConfirm(enc_secret, enc_token),
-                &None,
-                nonce_generator_write.as_mut(),
-            )
-            .await
-            .unwrap();
-
-        // From this point onward we assume everything is encrypted
-        let secret = Some(secret.to_vec());
-        let mut seed = [0u8; accord::SECRET_LEN];
-        seed.copy_from_slice(&secret.as_ref().unwrap()[..]);
-        nonce_generator_write = Some(ChaCha20Rng::from_seed(seed));
-        nonce_generator_read = Some(ChaCha20Rng::from_seed(seed));
-
-        // Expect EncryptionAck (should be encrypted)
-        let p = reader
-            .read_packet(&secret, nonce_generator_read.as_mut())
-            .await;
-        match p {
-            Ok(Some(ClientboundPacket::EncryptionAck)) => {
-                info!("Encryption handshake successful!");
-            }
-            Ok(_) => {
-                error!("Failed encryption step 2. Server response: {:?}", p);
-                std::process::exit(1);
-            }
-            Err(e) => {
-                error!("{}", e);
-                std::process::exit(1);
-            }
-        }
+            };
+        info!("Encryption handshake successful!");
 
         //==================================
         //      Login
@@ -209,42 +316,52 @@ impl ConnectionHandler {
             .unwrap();
 
         // Next packet must be login related
-        if let Ok(Some(p)) = reader
+        let logged_in = if let Ok(Some(p)) = reader
             .read_packet(&secret, nonce_generator_read.as_mut())
             .await
         {
             match p {
-                ClientboundPacket::LoginAck => {
-                    info!("Login successful");
+                ClientboundPacket::LoginAck { user_id, username } => {
+                    info!("Logged in as {}", username);
+                    GuiCommand::LoggedIn { user_id, username }
                 }
                 ClientboundPacket::LoginFailed(m) => {
-                    submit_command(event_sink, GuiCommand::ConnectionEnded(m));
-                    return;
+                    submit_command(event_sink, &server, GuiCommand::ConnectionEnded(m));
+                    return Err(gui_rx);
+                }
+                ClientboundPacket::Disconnect(reason) => {
+                    let m = reason.to_string();
+                    submit_command(event_sink, &server, GuiCommand::ConnectionEnded(m));
+                    return Err(gui_rx);
                 }
                 p => {
                     let m = format!("Login failed. Server response: {:?}", p);
-                    submit_command(event_sink, GuiCommand::ConnectionEnded(m));
-                    return;
+                    submit_command(event_sink, &server, GuiCommand::ConnectionEnded(m));
+                    return Err(gui_rx);
                 }
             }
         } else {
             submit_command(
                 event_sink,
+                &server,
                 GuiCommand::ConnectionEnded("Login failed ;/".to_string()),
             );
-            return;
-        }
-        submit_command(event_sink, GuiCommand::Connected);
+            return Err(gui_rx);
+        };
+        submit_command(event_sink, &server, GuiCommand::Connected);
+        submit_command(event_sink, &server, logged_in);
 
-        // Get last 50 messages
-        writer
-            .write_packet(
-                ServerboundPacket::FetchMessages(0, 50),
-                &secret,
-                nonce_generator_write.as_mut(),
-            )
-            .await
-            .unwrap();
+        // Get last messages, in chunks so they are shown gradually
+        for (offset, count) in config.history_chunks() {
+            writer
+                .write_packet(
+                    ServerboundPacket::FetchMessages(offset, count),
+                    &secret,
+                    nonce_generator_write.as_mut(),
+                )
+                .await
+                .unwrap();
+        }
 
         // Get player list on join
         writer
@@ -260,9 +377,24 @@ impl ConnectionHandler {
         let (tx, rx) = oneshot::channel::<()>();
 
         tokio::join!(
-            Self::reading_loop(reader, tx, secret.clone(), nonce_generator_read, event_sink),
-            Self::writing_loop(writer, rx, secret.clone(), nonce_generator_write, gui_rx)
+            Self::reading_loop(
+                reader,
+                tx,
+                secret.clone(),
+                nonce_generator_read,
+                &server,
+                event_sink
+            ),
+            Self::writing_loop(
+                writer,
+                rx,
+                secret.clone(),
+                nonce_generator_write,
+                gui_rx,
+                config.ping_interval()
+            )
         );
+        Ok(())
     }
 
     /// Reads incoming packets, processes them and sends commands to GUI
@@ -271,69 +403,169 @@ impl ConnectionHandler {
         close_sender: oneshot::Sender<()>,
         secret: Option<Vec<u8>>,
         mut nonce_generator: Option<ChaCha20Rng>,
+        server: &str,
         event_sink: &ExtEventSink,
     ) {
         let mut user_list = vec![];
+        // Packets unpacked from a batch, handled before reading more
+        let mut pending = VecDeque::new();
+        let mut warned_about_clock = false;
+        // Warns once about messages dated in the future, i.e. server's clock is off
+        let mut check_time = |time: i64| {
+            let now = chrono::Utc::now().timestamp_millis();
+            if !warned_about_clock && accord::utils::is_from_future(time, now) {
+                warn!(
+                    "{}: messages are dated in the future, server's clock may be wrong.",
+                    server
+                );
+                warned_about_clock = true;
+            }
+        };
         'l: loop {
-            match reader.read_packet(&secret, nonce_generator.as_mut()).await {
-                Ok(Some(ClientboundPacket::Message(Message {
-                    text,
-                    sender_id,
-                    sender,
-                    time,
-                }))) => {
-                    let time = chrono::Local.timestamp(time as i64, 0);
+            let packet = match pending.pop_front() {
+                Some(p) => Ok(Some(p)),
+                None => reader.read_packet(&secret, nonce_generator.as_mut()).await,
+            };
+            match packet {
+                Ok(Some(
+                    ClientboundPacket::Message(Message {
+                        text,
+                        sender_id,
+                        sender,
+                        time,
+                        sender_color,
+                    })
+                    | ClientboundPacket::CommandResponse(Message {
+                        text,
+                        sender_id,
+                        sender,
+                        time,
+                        sender_color,
+                    }),
+                )) => {
+                    check_time(time);
                     submit_command(
                         event_sink,
+                        server,
                         GuiCommand::AddMessage(GMessage {
                             sender_id,
                             sender,
-                            date: time.format("(%H:%M %d-%m)").to_string(),
+                            sender_color,
+                            date: message_date(&chrono::Local, time),
+                            time,
                             content: text,
                             is_image: false,
+                            highlighted: false,
+                            selected: false,
+                            grouped: false,
+                            day_separator: None,
+                            spoilers_revealed: false,
+                            rich_text: None,
+                            hovered: false,
+                            own: false,
                         }),
                     );
                 }
                 Ok(Some(ClientboundPacket::UserJoined(username))) => {
                     user_list.push(username);
-                    submit_command(event_sink, GuiCommand::UpdateUserList(user_list.clone()));
+                    submit_command(
+                        event_sink,
+                        server,
+                        GuiCommand::UpdateUserList(user_list.clone()),
+                    );
                 }
                 Ok(Some(ClientboundPacket::UserLeft(username))) => {
                     user_list
                         .iter()
                         .position(|u| *u == username)
                         .map(|p| user_list.remove(p));
-                    submit_command(event_sink, GuiCommand::UpdateUserList(user_list.clone()));
+                    submit_command(
+                        event_sink,
+                        server,
+                        GuiCommand::UpdateUserList(user_list.clone()),
+                    );
+                }
+                Ok(Some(ClientboundPacket::UserRenamed { old, new })) => {
+                    for u in user_list.iter_mut().filter(|u| **u == old) {
+                        *u = new.clone();
+                    }
+                    submit_command(
+                        event_sink,
+                        server,
+                        GuiCommand::UpdateUserList(user_list.clone()),
+                    );
+                    submit_command(event_sink, server, GuiCommand::UserRenamed(old, new));
                 }
                 Ok(Some(ClientboundPacket::UsersOnline(usernames))) => {
                     user_list = usernames;
-                    submit_command(event_sink, GuiCommand::UpdateUserList(user_list.clone()));
-                }
-                Ok(Some(ClientboundPacket::ImageMessage(im))) => {
-                    use sha2::{Digest, Sha256};
-                    let mut hasher = Sha256::new();
-                    hasher.update(&im.image_bytes);
-
-                    // Hash to string
-                    let hash = hasher.finalize()[..16]
-                        .iter()
-                        .fold("".to_string(), |accum, item| {
-                            accum + &format!("{:02x}", item)
-                        });
-
-                    let time = chrono::Local.timestamp(im.time as i64, 0);
                     submit_command(
                         event_sink,
-                        GuiCommand::StoreImage(hash.clone(), Arc::new(im.image_bytes)),
+                        server,
+                        GuiCommand::UpdateUserList(user_list.clone()),
                     );
+                }
+                Ok(Some(ClientboundPacket::ImageMessage(im))) => {
+                    check_time(im.time);
+                    let hash = im.hash;
+                    // Images from history come without bytes, those are fetched when shown
+                    if let Some(image_bytes) = im.image_bytes {
+                        submit_command(
+                            event_sink,
+                            server,
+                            GuiCommand::StoreImage(hash.clone(), Arc::new(image_bytes)),
+                        );
+                    }
                     let m = GMessage {
                         content: hash,
                         sender_id: im.sender_id,
                         sender: im.sender,
-                        date: time.format("(%H:%M %d-%m)").to_string(),
+                        sender_color: im.sender_color,
+                        date: message_date(&chrono::Local, im.time),
+                        time: im.time,
                         is_image: true,
+                        highlighted: false,
+                        selected: false,
+                        grouped: false,
+                        day_separator: None,
+                        spoilers_revealed: false,
+                        rich_text: None,
+                        hovered: false,
+                        own: false,
                     };
-                    submit_command(event_sink, GuiCommand::AddMessage(m));
+                    submit_command(event_sink, server, GuiCommand::AddMessage(m));
+                }
+                Ok(Some(ClientboundPacket::ServerInfo { name, description })) => {
+                    submit_command(
+                        event_sink,
+                        server,
+                        GuiCommand::ServerInfo(name, description),
+                    );
+                }
+                Ok(Some(ClientboundPacket::ImageData { hash, bytes })) => match bytes {
+                    Some(bytes) => submit_command(
+                        event_sink,
+                        server,
+                        GuiCommand::StoreImage(hash, Arc::new(bytes)),
+                    ),
+                    None => log::warn!("Server doesn't have image {}", hash),
+                },
+                Ok(Some(ClientboundPacket::YourPermissions(permissions))) => {
+                    // Nothing in the GUI is operator-only yet
+                    info!("Permissions on {}: {:?}", server, permissions);
+                }
+                Ok(Some(ClientboundPacket::MessageRejected { reason })) => {
+                    submit_command(event_sink, server, GuiCommand::MessageRejected(reason))
+                }
+                Ok(Some(ClientboundPacket::MessageBatch(packets))) => pending.extend(packets),
+                Ok(Some(ClientboundPacket::Pong)) => (),
+                Ok(Some(ClientboundPacket::Disconnect(reason))) => {
+                    submit_command(
+                        event_sink,
+                        server,
+                        GuiCommand::ConnectionEnded(reason.to_string()),
+                    );
+                    close_sender.send(()).unwrap();
+                    break 'l;
                 }
                 Ok(Some(p)) => {
                     error!("!!Unhandled packet: {:?}", p);
@@ -341,6 +573,7 @@ impl ConnectionHandler {
                 _ => {
                     submit_command(
                         event_sink,
+                        server,
                         GuiCommand::ConnectionEnded("Connection closed.".to_string()),
                     );
                     close_sender.send(()).unwrap();
@@ -356,22 +589,25 @@ impl ConnectionHandler {
         mut close_receiver: oneshot::Receiver<()>,
         secret: Option<Vec<u8>>,
         mut nonce_generator: Option<ChaCha20Rng>,
-        gui_rx: &mut mpsc::Receiver<ConnectionHandlerCommand>,
+        mut gui_rx: mpsc::Receiver<ServerboundPacket>,
+        ping_interval: Option<std::time::Duration>,
     ) {
+        let mut ping = ping_interval
+            .map(|period| tokio::time::interval_at(tokio::time::Instant::now() + period, period));
         loop {
             tokio::select!(
                 r = gui_rx.recv() => {
-                    if let Some(c) = r {
-                        match c {
-                            ConnectionHandlerCommand::Write(p) => {
-                                writer.write_packet(p, &secret, nonce_generator.as_mut()).await.unwrap();
-                            },
-                            c => {
-                                panic!("Got unexpected {:?}", c);
-                            }
-                        }
+                    match r {
+                        Some(p) => {
+                            writer.write_packet(p, &secret, nonce_generator.as_mut()).await.unwrap();
+                        },
+                        None => break,
                     }
                 },
+                _ = async { ping.as_mut().unwrap().tick().await }, if ping.is_some() => {
+                    // Keeps the connection alive
+                    writer.write_packet(ServerboundPacket::Ping, &secret, nonce_generator.as_mut()).await.unwrap();
+                },
                 _ = &mut close_receiver => {
                     break;
                 }
@@ -380,9 +616,96 @@ impl ConnectionHandler {
     }
 }
 
-/// Helper function to submit a GUI command
-fn submit_command(event_sink: &ExtEventSink, info: GuiCommand) {
+/// Time and date of message (e.g. "(14:03 26-04)"), empty if `time` is out of range
+fn message_date<Tz: TimeZone>(tz: &Tz, time: i64) -> String
+where
+    Tz::Offset: std::fmt::Display,
+{
+    tz.timestamp_millis_opt(time)
+        .single()
+        .map(|t| t.format("(%H:%M %d-%m)").to_string())
+        .unwrap_or_default()
+}
+
+/// Helper function to submit a GUI command coming from connection to `server`
+fn submit_command(event_sink: &ExtEventSink, server: &str, info: GuiCommand) {
     event_sink
-        .submit_command(crate::GUI_COMMAND, info, druid::Target::Global)
+        .submit_command(
+            crate::SERVER_COMMAND,
+            (server.to_string(), info),
+            druid::Target::Global,
+        )
         .unwrap();
 }
+
+#[cfg(test)]
+mod test {
+    use super::{message_date, OutgoingQueue, MAX_QUEUED};
+    use accord::packets::ServerboundPacket;
+
+    fn message(i: usize) -> ServerboundPacket {
+        ServerboundPacket::Message(i.to_string())
+    }
+
+    #[test]
+    fn queue_while_disconnected() {
+        let mut queue = OutgoingQueue::default();
+        assert!(!OutgoingQueue::should_queue(
+            &ServerboundPacket::FetchMessages(0, 10)
+        ));
+        assert!(OutgoingQueue::should_queue(&message(0)));
+
+        for i in 0..MAX_QUEUED {
+            assert_eq!(queue.push("a@server", message(i)), Ok(i + 1));
+        }
+        assert_eq!(queue.push("a@server", message(99)), Err(message(99)));
+        // Other servers have their own queue
+        assert_eq!(queue.push("b@server", message(0)), Ok(1));
+
+        // Drained on reconnect, in order they were written
+        let drained: Vec<_> = queue.drain("a@server").into_iter().collect();
+        assert_eq!(drained, (0..MAX_QUEUED).map(message).collect::<Vec<_>>());
+        assert!(queue.drain("a@server").is_empty());
+        assert_eq!(queue.push("a@server", message(0)), Ok(1));
+        assert_eq!(queue.drain("b@server").len(), 1);
+    }
+
+    #[test]
+    fn failed_reconnect_keeps_queue() {
+        let mut queue = OutgoingQueue::default();
+        for i in 0..3 {
+            queue.push("a@server", message(i)).unwrap();
+        }
+        let (tx, mut rx) = queue.open("a@server");
+        assert!(queue.drain("a@server").is_empty());
+        // Written while connecting
+        tx.try_send(message(3)).unwrap();
+        tx.try_send(ServerboundPacket::FetchMessages(0, 10))
+            .unwrap();
+
+        // Connection ends before logging in, messages wait for the next one
+        assert_eq!(queue.requeue("a@server", &mut rx), Ok(4));
+        assert!(tx.is_closed());
+        let (_, mut rx) = queue.open("a@server");
+        let mut reopened = vec![];
+        while let Ok(p) = rx.try_recv() {
+            reopened.push(p);
+        }
+        assert_eq!(reopened, (0..4).map(message).collect::<Vec<_>>());
+
+        // Too many to keep
+        let (tx, mut rx) = queue.open("a@server");
+        for i in 0..=MAX_QUEUED {
+            tx.try_send(message(i)).unwrap();
+        }
+        assert_eq!(queue.requeue("a@server", &mut rx), Err(MAX_QUEUED));
+    }
+
+    #[test]
+    fn message_date_test() {
+        // 2022-04-26 19:06:40 UTC
+        assert_eq!(message_date(&chrono::Utc, 1651000000000), "(19:06 26-04)");
+        // Bogus time from the server doesn't panic
+        assert_eq!(message_date(&chrono::Utc, i64::MAX), "");
+    }
+}
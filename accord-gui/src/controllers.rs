@@ -1,37 +1,51 @@
-use crate::{GuiCommand, Message, GUI_COMMAND};
+use crate::{
+    config::WindowGeometry, image_cache::ImageCache, AppState, GuiCommand, Message, FIND_OPENED,
+    GUI_COMMAND, SERVER_COMMAND,
+};
 use druid::{
     im::Vector,
     widget::{Controller, Image},
-    Env, Event, EventCtx, ImageBuf, Insets, Selector, Size, Widget, WidgetExt, WidgetPod,
-};
-use std::{
-    collections::HashMap,
-    sync::{Arc, Mutex},
+    Data, Env, Event, EventCtx, Insets, Rect, Selector, Size, UpdateCtx, Widget, WidgetExt,
+    WidgetPod,
 };
+use std::sync::{Arc, Mutex};
 
 const LIST_CHANGED: Selector<Size> = Selector::new("list-changed");
 
 pub const SCROLL: Selector<f64> = Selector::new("scroll");
 
+/// Image with given key was evicted from cache
+pub const IMAGE_EVICTED: Selector<String> = Selector::new("image_evicted");
+
+/// Image with given key (and whether it's from an image message) should be loaded again
+pub const IMAGE_NEEDED: Selector<(String, bool)> = Selector::new("image_needed");
+
+/// Visible part of message list changed (in window coordinates)
+const VIEWPORT_CHANGED: Selector<Rect> = Selector::new("viewport_changed");
+
 /// Widget that contains a dynamically loaded image
 ///
 /// "Heavily inspired" by RemoteImage from jpochyla's psst ;]
 pub struct ImageMessage {
-    pub dled_images: Arc<Mutex<HashMap<String, ImageBuf>>>,
+    pub dled_images: Arc<Mutex<ImageCache>>,
     placeholder: WidgetPod<Message, Box<dyn Widget<Message>>>,
     image: Option<WidgetPod<Message, Box<dyn Widget<Message>>>>,
+    /// Image should be shown, but isn't in cache
+    /// (it got evicted or is from history and wasn't fetched yet)
+    missing_image: bool,
 }
 
 impl ImageMessage {
     /// Creates new `ImageMessage`
     pub fn new(
         placeholder: impl Widget<Message> + 'static,
-        dled_images: Arc<Mutex<HashMap<String, ImageBuf>>>,
+        dled_images: Arc<Mutex<ImageCache>>,
     ) -> Self {
         Self {
             placeholder: WidgetPod::new(placeholder).boxed(),
             dled_images,
             image: None,
+            missing_image: false,
         }
     }
 
@@ -40,7 +54,7 @@ impl ImageMessage {
         if let Some(ib) = self.dled_images.lock().unwrap().get(id) {
             self.image.replace(
                 WidgetPod::new(
-                    Image::new(ib.clone())
+                    Image::new(ib)
                         .fill_mode(druid::widget::FillStrat::Contain)
                         .interpolation_mode(druid::piet::InterpolationMode::Bilinear)
                         .fix_width(400.0)
@@ -62,10 +76,34 @@ impl Widget<Message> for ImageMessage {
             if let Some(link_c) = cmd.get(Selector::<String>::new("image_downloaded")) {
                 let link = &data.content;
                 if link == link_c && self.try_get_image(link) {
+                    self.missing_image = false;
                     ctx.children_changed();
                 }
                 return;
             }
+            // Let go of the image, so it can be freed
+            if let Some(key) = cmd.get(IMAGE_EVICTED) {
+                if *key == data.content && self.image.take().is_some() {
+                    self.missing_image = true;
+                    ctx.children_changed();
+                }
+                return;
+            }
+            // Load missing image when it's visible
+            if let Some(viewport) = cmd.get(VIEWPORT_CHANGED) {
+                let rect = Rect::from_origin_size(ctx.window_origin(), ctx.size());
+                if self.missing_image && rect.intersect(*viewport).area() > 0.0 {
+                    self.missing_image = false;
+                    if self.try_get_image(&data.content) {
+                        ctx.children_changed();
+                    } else {
+                        ctx.submit_command(
+                            IMAGE_NEEDED.with((data.content.clone(), data.is_image)),
+                        );
+                    }
+                }
+                return;
+            }
         }
 
         if let Some(image) = self.image.as_mut() {
@@ -85,6 +123,8 @@ impl Widget<Message> for ImageMessage {
         if let druid::LifeCycle::WidgetAdded = event {
             if self.try_get_image(&data.content) {
                 ctx.children_changed();
+            } else if data.is_image {
+                self.missing_image = true;
             }
         }
         if let Some(image) = self.image.as_mut() {
@@ -163,6 +203,7 @@ where
         data: &mut Vector<Message>,
         env: &Env,
     ) {
+        let old_offset = child.offset();
         if let Event::Command(cmd) = event {
             if let Some(size) = cmd.get(LIST_CHANGED) {
                 let mut should_scroll = true;
@@ -190,7 +231,14 @@ where
             }
         }
 
-        child.event(ctx, event, data, env)
+        let list_changed = matches!(event, Event::Command(cmd) if cmd.is(LIST_CHANGED));
+
+        child.event(ctx, event, data, env);
+
+        if list_changed || child.offset() != old_offset {
+            let viewport = Rect::from_origin_size(ctx.window_origin(), ctx.size());
+            ctx.submit_command(VIEWPORT_CHANGED.with(viewport));
+        }
     }
 
     fn lifecycle(
@@ -237,7 +285,7 @@ impl<T, W: Widget<T>> Controller<T, W> for TakeFocusConnect {
         if let Event::WindowConnected = event {
             ctx.request_focus();
         } else if let Event::Command(command) = event {
-            if let Some(GuiCommand::ConnectionEnded(_)) = command.get(GUI_COMMAND) {
+            if let Some((_, GuiCommand::ConnectionEnded(_))) = command.get(SERVER_COMMAND) {
                 ctx.request_focus();
             }
         }
@@ -251,7 +299,7 @@ pub struct TakeFocusMain;
 impl<T, W: Widget<T>> Controller<T, W> for TakeFocusMain {
     fn event(&mut self, child: &mut W, ctx: &mut EventCtx, event: &Event, data: &mut T, env: &Env) {
         if let Event::Command(command) = event {
-            if let Some(GuiCommand::Connected) = command.get(GUI_COMMAND) {
+            if let Some((_, GuiCommand::Connected)) = command.get(SERVER_COMMAND) {
                 ctx.request_focus();
             }
         }
@@ -259,6 +307,120 @@ impl<T, W: Widget<T>> Controller<T, W> for TakeFocusMain {
     }
 }
 
+/// Controller for find bar TextBox.
+/// Updates matches when query changes and takes focus when find bar is opened.
+pub struct FindController;
+
+impl<W: Widget<AppState>> Controller<AppState, W> for FindController {
+    fn event(
+        &mut self,
+        child: &mut W,
+        ctx: &mut EventCtx,
+        event: &Event,
+        data: &mut AppState,
+        env: &Env,
+    ) {
+        if let Event::Command(command) = event {
+            if command.is(FIND_OPENED) {
+                ctx.request_focus();
+            }
+        }
+        let old_query = data.find_query.clone();
+        child.event(ctx, event, data, env);
+        if !old_query.same(&data.find_query) {
+            crate::update_find(data, None);
+        }
+    }
+}
+
+/// Repaints message when its find state changes and scrolls to it when it gets selected.
+pub struct FindMatchController;
+
+impl<W: Widget<Message>> Controller<Message, W> for FindMatchController {
+    fn update(
+        &mut self,
+        child: &mut W,
+        ctx: &mut UpdateCtx,
+        old_data: &Message,
+        data: &Message,
+        env: &Env,
+    ) {
+        if old_data.highlighted != data.highlighted || old_data.selected != data.selected {
+            ctx.request_paint();
+        }
+        if data.selected && !old_data.selected {
+            ctx.scroll_to_view();
+        }
+        child.update(ctx, old_data, data, env)
+    }
+}
+
+/// Reveals spoilers of a message when it's clicked.
+pub struct SpoilerController;
+
+impl<W: Widget<Message>> Controller<Message, W> for SpoilerController {
+    fn event(
+        &mut self,
+        child: &mut W,
+        ctx: &mut EventCtx,
+        event: &Event,
+        data: &mut Message,
+        env: &Env,
+    ) {
+        if let Event::MouseUp(_) = event {
+            let has_spoilers = accord::utils::split_spoilers(&data.content)
+                .iter()
+                .any(|(_, is_spoiler)| *is_spoiler);
+            if has_spoilers && !data.spoilers_revealed {
+                data.spoilers_revealed = true;
+                data.rich_text = Some(crate::message_rich_text(&data.content, true));
+            }
+        }
+        child.event(ctx, event, data, env)
+    }
+}
+
+/// Hover state of the widget changed, sent by [`HoverController`] to itself
+const HOVER_CHANGED: Selector<bool> = Selector::new("hover_changed");
+
+/// Keeps [`Message::hovered`] in sync with mouse being over the widget.
+pub struct HoverController;
+
+impl<W: Widget<Message>> Controller<Message, W> for HoverController {
+    fn event(
+        &mut self,
+        child: &mut W,
+        ctx: &mut EventCtx,
+        event: &Event,
+        data: &mut Message,
+        env: &Env,
+    ) {
+        if let Event::Command(cmd) = event {
+            if let Some(hovered) = cmd.get(HOVER_CHANGED) {
+                data.hovered = *hovered;
+                ctx.set_handled();
+                return;
+            }
+        }
+        child.event(ctx, event, data, env)
+    }
+
+    fn lifecycle(
+        &mut self,
+        child: &mut W,
+        ctx: &mut druid::LifeCycleCtx,
+        event: &druid::LifeCycle,
+        data: &Message,
+        env: &Env,
+    ) {
+        // Data can't be changed here, so it's done in `event`
+        if let druid::LifeCycle::HotChanged(hot) = event {
+            ctx.submit_command(HOVER_CHANGED.with(*hot).to(ctx.widget_id()));
+        }
+        child.lifecycle(ctx, event, data, env)
+    }
+}
+
 /// Controller for message TextBox.
 /// Handles pasting.
 pub struct MessageTextBoxController;
@@ -279,3 +441,83 @@ impl<T, W: Widget<T>> Controller<T, W> for MessageTextBoxController {
         child.event(ctx, event, data, env)
     }
 }
+
+/// Updates [`AppState::now`] periodically, so relative message times stay current.
+#[derive(Default)]
+pub struct ClockController {
+    timer: Option<druid::TimerToken>,
+}
+
+impl ClockController {
+    const INTERVAL: std::time::Duration = std::time::Duration::from_secs(30);
+}
+
+impl<W: Widget<AppState>> Controller<AppState, W> for ClockController {
+    fn event(
+        &mut self,
+        child: &mut W,
+        ctx: &mut EventCtx,
+        event: &Event,
+        data: &mut AppState,
+        env: &Env,
+    ) {
+        match event {
+            Event::WindowConnected => {
+                self.timer = Some(ctx.request_timer(Self::INTERVAL));
+            }
+            Event::Timer(token) if Some(*token) == self.timer => {
+                data.now = chrono::Utc::now().timestamp_millis();
+                self.timer = Some(ctx.request_timer(Self::INTERVAL));
+                return;
+            }
+            _ => (),
+        }
+        child.event(ctx, event, data, env)
+    }
+}
+
+/// Restores the main window geometry from [`AppState::window`] and saves it on close.
+pub struct WindowGeometryController;
+
+impl<W: Widget<AppState>> Controller<AppState, W> for WindowGeometryController {
+    fn event(
+        &mut self,
+        child: &mut W,
+        ctx: &mut EventCtx,
+        event: &Event,
+        data: &mut AppState,
+        env: &Env,
+    ) {
+        match event {
+            Event::WindowConnected => {
+                let screens: Vec<Rect> = druid::Screen::get_monitors()
+                    .iter()
+                    .map(|m| m.virtual_work_rect())
+                    .collect();
+                match data.window {
+                    Some(g) if g.fits_on(&screens) => {
+                        ctx.window().set_size((g.width, g.height));
+                        ctx.window().set_position((g.x, g.y));
+                    }
+                    Some(g) => log::info!("Saved window geometry {:?} is off screen.", g),
+                    None => (),
+                }
+            }
+            Event::WindowCloseRequested => {
+                let position = ctx.window().get_position();
+                let size = ctx.window().get_size();
+                data.window = Some(WindowGeometry {
+                    x: position.x,
+                    y: position.y,
+                    width: size.width,
+                    height: size.height,
+                });
+                if let Err(e) = crate::config::save_config(crate::config_from_appstate(data)) {
+                    log::error!("Failed to save config: {}", e);
+                }
+            }
+            _ => (),
+        }
+        child.event(ctx, event, data, env)
+    }
+}
@@ -0,0 +1,200 @@
+use std::collections::HashMap;
+
+use std::sync::Arc;
+
+use druid::ImageBuf;
+
+/// Image stored in [`ImageCache`]
+struct CachedImage {
+    image: ImageBuf,
+    /// Encoded image, as it was inserted (e.g. for copying)
+    bytes: Arc<[u8]>,
+    /// Value of [`ImageCache::clock`] at last use
+    last_used: u64,
+}
+
+/// Cache of downloaded images, bounded to `capacity` images.
+///
+/// Images are stored once per content hash, so the same image
+/// (e.g. from a link and from an image message) is decoded and kept only once.
+/// Images from links can also be looked up by their link.
+///
+/// When full, least recently used images are evicted.
+pub struct ImageCache {
+    /// Images by hash
+    images: HashMap<String, CachedImage>,
+    /// Hashes of images by link they were downloaded from
+    links: HashMap<String, String>,
+    capacity: usize,
+    /// Incremented on every use of the cache
+    clock: u64,
+    /// Keys (hashes and links) of images evicted since last [`take_evicted`](Self::take_evicted)
+    evicted: Vec<String>,
+}
+
+impl ImageCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            images: HashMap::new(),
+            links: HashMap::new(),
+            capacity: capacity.max(1),
+            clock: 0,
+            evicted: Vec::new(),
+        }
+    }
+
+    /// Gets image by its hash or by link it was downloaded from
+    pub fn get(&mut self, key: &str) -> Option<ImageBuf> {
+        let hash = self.links.get(key).map_or(key, String::as_str);
+        let cached = self.images.get_mut(hash)?;
+        self.clock += 1;
+        cached.last_used = self.clock;
+        Some(cached.image.clone())
+    }
+
+    /// Gets encoded image (as it was inserted) by its hash or by link it was downloaded from
+    pub fn get_bytes(&mut self, key: &str) -> Option<Arc<[u8]>> {
+        let hash = self.links.get(key).map_or(key, String::as_str);
+        let cached = self.images.get_mut(hash)?;
+        self.clock += 1;
+        cached.last_used = self.clock;
+        Some(Arc::clone(&cached.bytes))
+    }
+
+    pub fn contains(&self, key: &str) -> bool {
+        let hash = self.links.get(key).map_or(key, String::as_str);
+        self.images.contains_key(hash)
+    }
+
+    /// Stores image from bytes, decoding it only if it's not cached yet.
+    ///
+    /// Returns hash of the image.
+    pub fn insert(
+        &mut self,
+        image_bytes: &[u8],
+    ) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+        let hash = accord::utils::image_hash(image_bytes);
+        self.clock += 1;
+        if let Some(cached) = self.images.get_mut(&hash) {
+            cached.last_used = self.clock;
+        } else {
+            let image = ImageBuf::from_data(image_bytes)?;
+            self.images.insert(
+                hash.clone(),
+                CachedImage {
+                    image,
+                    bytes: image_bytes.into(),
+                    last_used: self.clock,
+                },
+            );
+            self.evict();
+        }
+        Ok(hash)
+    }
+
+    /// Like [`insert`](Self::insert), but the image can be also looked up by `link`.
+    pub fn insert_from_link(
+        &mut self,
+        link: String,
+        image_bytes: &[u8],
+    ) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+        let hash = self.insert(image_bytes)?;
+        self.links.insert(link, hash.clone());
+        Ok(hash)
+    }
+
+    /// Returns keys of images evicted since last call, so widgets showing them can let go.
+    pub fn take_evicted(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.evicted)
+    }
+
+    /// Evicts least recently used images until within capacity
+    fn evict(&mut self) {
+        while self.images.len() > self.capacity {
+            let lru = self
+                .images
+                .iter()
+                .min_by_key(|(_, cached)| cached.last_used)
+                .map(|(hash, _)| hash.clone())
+                .expect("Cache can't be empty here");
+            self.images.remove(&lru);
+            let links: Vec<_> = self
+                .links
+                .iter()
+                .filter(|(_, hash)| **hash == lru)
+                .map(|(link, _)| link.clone())
+                .collect();
+            for link in links {
+                self.links.remove(&link);
+                self.evicted.push(link);
+            }
+            self.evicted.push(lru);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::ImageCache;
+
+    const BLACK: &[u8] = include_bytes!("resources/test-pixel-black.png");
+    const WHITE: &[u8] = include_bytes!("resources/test-pixel-white.png");
+    const GRAY: &[u8] = include_bytes!("resources/test-pixel-gray.png");
+
+    #[test]
+    fn encoded_image_is_kept() {
+        let mut cache = ImageCache::new(10);
+        let hash = cache.insert(BLACK).unwrap();
+        assert_eq!(cache.get_bytes(&hash).as_deref(), Some(BLACK));
+        assert_eq!(cache.get_bytes("missing"), None);
+    }
+
+    #[test]
+    fn same_image_is_stored_once() {
+        let mut cache = ImageCache::new(10);
+        let hash = cache.insert(BLACK).unwrap();
+        let link = "https://example.com/pixel.png".to_string();
+        let link_hash = cache.insert_from_link(link.clone(), BLACK).unwrap();
+
+        assert_eq!(hash, link_hash);
+        assert_eq!(cache.images.len(), 1);
+        assert!(cache.contains(&hash));
+        assert!(cache.contains(&link));
+        assert!(!cache.contains("https://example.com/other.png"));
+    }
+
+    #[test]
+    fn invalid_image_is_not_stored() {
+        let mut cache = ImageCache::new(10);
+        assert!(cache.insert(b"not an image").is_err());
+        assert!(cache.images.is_empty());
+    }
+
+    #[test]
+    fn least_recently_used_is_evicted() {
+        let mut cache = ImageCache::new(2);
+        let black = cache.insert(BLACK).unwrap();
+        let link = "https://example.com/white.png".to_string();
+        let white = cache.insert_from_link(link.clone(), WHITE).unwrap();
+        assert!(cache.take_evicted().is_empty());
+
+        // Use black, so white is the least recently used
+        assert!(cache.get(&black).is_some());
+        let gray = cache.insert(GRAY).unwrap();
+        assert_eq!(cache.images.len(), 2);
+        assert!(!cache.contains(&white));
+        assert!(!cache.contains(&link));
+        let mut evicted = cache.take_evicted();
+        evicted.sort();
+        let mut expected = vec![white.clone(), link];
+        expected.sort();
+        assert_eq!(evicted, expected);
+
+        // Repopulating evicts the next least recently used
+        cache.insert(WHITE).unwrap();
+        assert!(cache.contains(&white));
+        assert!(cache.contains(&gray));
+        assert!(!cache.contains(&black));
+        assert_eq!(cache.take_evicted(), vec![black]);
+    }
+}
@@ -0,0 +1,2051 @@
+//! GUI client for accord.
+//!
+//! Besides running as a standalone app ([`run`]), the chat can be embedded in another druid app:
+//! 1. Get a [`Handle`](tokio::runtime::Handle) of a tokio runtime in your app,
+//!    it's shared by all connections and image downloads.
+//! 2. Put [`chat_widget`] somewhere in your layout, lensed to an [`AppState`] in your app's data.
+//! 3. Start the connections with [`spawn_connection_handler`]
+//!    and create the state with [`AppState::new`] from the returned sender.
+//!
+//! The chat doesn't need anything else from your app's data,
+//! everything it shows and edits is in [`AppState`].
+//!
+//! Connections and the chat's widgets communicate with commands,
+//! which are handled by the controller of [`chat_widget`], so your app's
+//! [`AppDelegate`](druid::AppDelegate) must let them through to the window with the chat:
+//! - [`SERVER_COMMAND`]: [`GuiCommand`] from a connection, with key of its server,
+//! - [`GUI_COMMAND`]: [`GuiCommand`] from widgets (e.g. pasted image to send),
+//! - [`OPEN_LINK`], [`MENTION_USER`] and [`FIND_OPENED`]: clicks and shortcuts in the chat.
+//!
+//! Keyboard shortcuts (Enter to send, Ctrl+F to find, etc.) work while the chat has focus.
+//!
+//! See `examples/embedded.rs` for a minimal host app.
+use std::sync::{Arc, Mutex};
+
+use accord::client_config::ClientConfig;
+use accord::packets::ServerboundPacket;
+use config::{Config, SavedServer, WindowGeometry};
+use tokio::sync::mpsc;
+
+use druid::{
+    im::{OrdMap, Vector},
+    kurbo::Insets,
+    lens,
+    text::{RichText, RichTextBuilder},
+    widget::Controller,
+    widget::{Button, Checkbox, Either, Flex, Label, List, Svg, SvgData, TextBox, ViewSwitcher},
+    AppLauncher, Color, Data, Env, Event, EventCtx, FontDescriptor, FontFamily, Lens, LensExt,
+    UnitPoint, Widget, WidgetExt, WindowDesc,
+};
+
+use serde::{Deserialize, Serialize};
+
+use flexi_logger::Logger;
+
+mod controllers;
+use controllers::*;
+
+mod connection_handler;
+use connection_handler::*;
+pub use connection_handler::{ConnectionHandlerCommand, GuiCommand};
+
+pub mod config;
+
+mod image_cache;
+use image_cache::ImageCache;
+
+mod widgets;
+use widgets::*;
+
+//TODO: Loading up past messages
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Theme {
+    pub background1: String,
+    pub background2: String,
+    pub text_color1: String,
+    pub color1: String,
+    pub highlight: String,
+    pub border: f64,
+}
+
+impl Theme {
+    /// Contrast ratio recommended for text (WCAG AA)
+    const MIN_CONTRAST: f64 = 4.5;
+
+    /// Black and white preset with strong borders
+    pub fn high_contrast() -> Self {
+        Self {
+            background1: "#000000".to_string(),
+            background2: "#000000".to_string(),
+            text_color1: "#ffffff".to_string(),
+            color1: "#000000".to_string(),
+            highlight: "#ffff00".to_string(),
+            border: 3.0,
+        }
+    }
+
+    /// Describes the problem if text is hard to read on the backgrounds of this theme.
+    pub fn contrast_warning(&self) -> Option<String> {
+        let lowest = [&self.background1, &self.background2]
+            .iter()
+            .filter_map(|bg| contrast_ratio(&self.text_color1, bg))
+            .fold(f64::INFINITY, f64::min);
+        (lowest < Self::MIN_CONTRAST).then(|| {
+            format!(
+                "Low contrast theme ({:.1}:1, at least {}:1 recommended)",
+                lowest,
+                Self::MIN_CONTRAST
+            )
+        })
+    }
+}
+
+/// WCAG contrast ratio (1 to 21) of two colors given in hex. Alpha is ignored.
+///
+/// `None` if either color is invalid.
+fn contrast_ratio(a: &str, b: &str) -> Option<f64> {
+    let luminance = |hex: &str| -> Option<f64> {
+        let (r, g, b, _) = Color::from_hex_str(hex).ok()?.as_rgba();
+        let linear = |c: f64| {
+            if c <= 0.03928 {
+                c / 12.92
+            } else {
+                ((c + 0.055) / 1.055).powf(2.4)
+            }
+        };
+        Some(0.2126 * linear(r) + 0.7152 * linear(g) + 0.0722 * linear(b))
+    };
+    let (a, b) = (luminance(a)?, luminance(b)?);
+    Some((a.max(b) + 0.05) / (a.min(b) + 0.05))
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        Self {
+            background1: "#200730".to_string(),
+            background2: "#030009".to_string(),
+            text_color1: "#6ef3e7".to_string(),
+            color1: "#7521ee29".to_string(),
+            highlight: "#77777777".to_string(),
+            border: 4.5,
+        }
+    }
+}
+
+/// Represents a message on the server
+#[derive(Debug, Data, Lens, Clone)]
+pub struct Message {
+    pub sender_id: i64,
+    pub sender: String,
+    /// Name color picked by the sender (`#rrggbb`), theme's text color if `None`
+    pub sender_color: Option<String>,
+    pub date: String,
+    /// Milliseconds since unix epoch
+    pub time: i64,
+    pub content: String,
+    pub is_image: bool,
+    /// Matches current find query
+    pub highlighted: bool,
+    /// Is the currently selected find match
+    pub selected: bool,
+    /// Continues group of previous message (see [`accord::utils::continues_group`]),
+    /// so sender and time aren't shown
+    pub grouped: bool,
+    /// Shown above the message if it was sent on a different day than previous message
+    pub day_separator: Option<String>,
+    /// Spoilers in the content were clicked and are shown
+    pub spoilers_revealed: bool,
+    /// Content as shown (see [`message_rich_text`]), built once when the message is added
+    /// and again when spoilers are revealed, so it isn't parsed and laid out on every update
+    pub rich_text: Option<RichText>,
+    /// Mouse is over the message (shows copy button)
+    pub hovered: bool,
+    /// Sent by the logged in user (see [`accord::utils::is_own_message`]), shown on the right
+    pub own: bool,
+}
+
+/// Views in accord-gui application
+#[derive(Debug, Data, Clone, Copy, PartialEq, Eq)]
+enum Views {
+    /// Starting view. Login prompt
+    Connect,
+    /// Main view, with messages etc.
+    Main,
+}
+
+/// State of the connection to a single server
+#[derive(Debug, Lens, Data, Clone, Default)]
+struct ServerState {
+    /// List of connected users
+    user_list: Vector<String>,
+    /// Cached messages
+    messages: Vector<Message>,
+    /// Name advertised by the server (empty if not received yet)
+    server_name: Arc<String>,
+    /// Id of the logged in user (`None` if not received yet)
+    user_id: Option<i64>,
+    /// Name of the logged in user, as the server has it (empty if not received yet)
+    username: Arc<String>,
+}
+
+/// State of the chat, see [`chat_widget`]
+#[derive(Debug, Lens, Data, Clone)]
+pub struct AppState {
+    current_view: Views,
+    info_label_text: Arc<String>,
+    input_text1: Arc<String>,
+    input_text2: Arc<String>,
+    input_text3: Arc<String>,
+    remember_login: bool,
+    input_text4: Arc<String>,
+    /// For sending commands to [`ConnectionHandler`]
+    connection_handler_tx: Arc<mpsc::Sender<ConnectionHandlerCommand>>,
+    /// Active connections, by server key (see [`server_key`])
+    servers: OrdMap<String, ServerState>,
+    /// Key of the server that is currently shown
+    active_server: Arc<String>,
+    /// Servers saved in config
+    saved_servers: Vector<SavedServer>,
+    images_from_links: bool,
+    /// Whether clicking on links should offer to open them in browser
+    open_links: bool,
+    /// Whether the find bar is shown
+    find_open: bool,
+    find_query: Arc<String>,
+    /// Index of selected match (among all matches)
+    find_current: Option<usize>,
+    /// Date to jump to in history
+    goto_date: Arc<String>,
+    /// Max number of images kept in memory
+    image_cache_size: usize,
+    /// How servers are connected to, from config
+    connection: Arc<ClientConfig>,
+    /// Whether message times are shown relative to [`AppState::now`]
+    relative_times: bool,
+    /// Only hour and minute in message headers, exact time when message is hovered
+    compact_times: bool,
+    /// Current time (milliseconds since unix epoch), updated periodically
+    now: i64,
+    /// Geometry of the main window, restored on launch and saved on close
+    window: Option<WindowGeometry>,
+    /// Multiplier of font sizes (zoom), see [`zoom`]
+    font_scale: f64,
+    /// Use [`Theme::high_contrast`] instead of theme from config
+    high_contrast: bool,
+}
+
+impl AppState {
+    /// Initial state (connect view) with settings from `config`.
+    ///
+    /// `connection_handler_tx` is the sender returned by [`spawn_connection_handler`].
+    pub fn new(
+        config: &Config,
+        connection_handler_tx: mpsc::Sender<ConnectionHandlerCommand>,
+    ) -> Self {
+        AppState {
+            current_view: Views::Connect,
+            info_label_text: Arc::new(String::new()),
+            input_text1: Arc::new(config.address.clone()),
+            input_text2: Arc::new(config.username.clone()),
+            input_text3: Arc::new("".to_string()),
+            remember_login: config.remember_login,
+            input_text4: Arc::new("".to_string()),
+            connection_handler_tx: Arc::new(connection_handler_tx),
+            servers: OrdMap::new(),
+            active_server: Arc::new(String::new()),
+            saved_servers: config.servers.iter().cloned().collect(),
+            images_from_links: config.images_from_links,
+            open_links: config.open_links,
+            find_open: false,
+            find_query: Arc::new(String::new()),
+            find_current: None,
+            goto_date: Arc::new(String::new()),
+            image_cache_size: config.image_cache_size,
+            connection: Arc::new(config.connection.clone()),
+            relative_times: config.relative_times,
+            compact_times: config.compact_times,
+            now: chrono::Utc::now().timestamp_millis(),
+            window: config.window,
+            font_scale: clamp_font_scale(config.font_scale),
+            high_contrast: config.high_contrast,
+        }
+    }
+}
+
+/// Lens to the state of the currently shown server.
+///
+/// If there is no such server, it lenses to an empty state and discards changes.
+struct ActiveServer;
+
+impl Lens<AppState, ServerState> for ActiveServer {
+    fn with<V, F: FnOnce(&ServerState) -> V>(&self, data: &AppState, f: F) -> V {
+        match data.servers.get(data.active_server.as_str()) {
+            Some(server) => f(server),
+            None => f(&ServerState::default()),
+        }
+    }
+
+    fn with_mut<V, F: FnOnce(&mut ServerState) -> V>(&self, data: &mut AppState, f: F) -> V {
+        match data.servers.get_mut(data.active_server.as_str()) {
+            Some(server) => f(server),
+            None => f(&mut ServerState::default()),
+        }
+    }
+}
+
+fn init_logger() {
+    Logger::try_with_env_or_str("warn")
+        .unwrap()
+        .start()
+        .unwrap();
+}
+
+pub const GUI_COMMAND: druid::Selector<GuiCommand> = druid::Selector::new("gui_command");
+
+/// Link clicked in a message
+pub const OPEN_LINK: druid::Selector<String> = druid::Selector::new("open_link");
+
+/// Find bar was opened
+pub const FIND_OPENED: druid::Selector = druid::Selector::new("find_opened");
+
+/// Border color of a message (changes when message matches find query)
+const MESSAGE_BORDER: druid::Key<Color> = druid::Key::new("accord.message-border");
+
+/// Color of sender's name in message header
+const SENDER_COLOR: druid::Key<Color> = druid::Key::new("accord.sender-color");
+
+/// Whether message times are shown relative to [`CURRENT_TIME`]
+const RELATIVE_TIMES: druid::Key<bool> = druid::Key::new("accord.relative-times");
+
+/// Whether message headers only show [`coarse_time`], with [`exact_time`] on hover
+const COMPACT_TIMES: druid::Key<bool> = druid::Key::new("accord.compact-times");
+
+/// Current time in milliseconds since unix epoch, for relative message times
+const CURRENT_TIME: druid::Key<f64> = druid::Key::new("accord.current-time");
+
+/// Font of the connect view, scaled by [`AppState::font_scale`]
+const CONNECT_FONT: druid::Key<FontDescriptor> = druid::Key::new("accord.connect-font");
+
+/// Font of message content, scaled by [`AppState::font_scale`]
+const MESSAGE_FONT: druid::Key<FontDescriptor> = druid::Key::new("accord.message-font");
+
+/// Font of message sender and time, scaled by [`AppState::font_scale`]
+const MESSAGE_HEADER_FONT: druid::Key<FontDescriptor> =
+    druid::Key::new("accord.message-header-font");
+
+/// Font of the user list, scaled by [`AppState::font_scale`]
+const USER_LIST_FONT: druid::Key<FontDescriptor> = druid::Key::new("accord.user-list-font");
+
+/// Bounds of [`AppState::font_scale`]
+const FONT_SCALE_RANGE: (f64, f64) = (0.5, 3.0);
+
+/// [`AppState::font_scale`] of the "Large text" option
+const LARGE_FONT_SCALE: f64 = 1.5;
+
+/// How much a single zoom step changes [`AppState::font_scale`]
+const FONT_SCALE_STEP: f64 = 0.1;
+
+/// Text color of code blocks in messages
+const CODE_BLOCK_COLOR: Color = Color::rgb8(0xd0, 0xd0, 0xd0);
+
+/// Username clicked in a message
+pub const MENTION_USER: druid::Selector<String> = druid::Selector::new("mention_user");
+
+/// [`GuiCommand`] coming from the connection to the server with given key
+pub const SERVER_COMMAND: druid::Selector<(String, GuiCommand)> =
+    druid::Selector::new("server_command");
+
+/// Runs accord-gui as a standalone app.
+pub fn run() {
+    init_logger();
+    // The only runtime of the app, shared by connections and image downloads.
+    let rt = tokio::runtime::Runtime::new().unwrap();
+
+    let mut args: Vec<String> = std::env::args().collect();
+    if let Some(path) = accord::utils::take_flag(&mut args, "--config") {
+        config::set_config_path(path.into());
+    }
+    let config = config::load_config();
+
+    let theme = Arc::new(
+        config
+            .theme
+            .clone()
+            .expect("Theme should be loaded from config!"),
+    );
+    let contrast_warning = theme.contrast_warning();
+    if let Some(warning) = &contrast_warning {
+        log::warn!("{}", warning);
+    }
+
+    let root = chat_widget(theme, config.image_cache_size, rt.handle().clone())
+        .controller(WindowGeometryController);
+    let main_window = WindowDesc::new(root).title(|data: &AppState, _env: &Env| {
+        ActiveServer.with(data, |server| window_title(&server.server_name))
+    });
+
+    let launcher = AppLauncher::with_window(main_window);
+
+    let tx = spawn_connection_handler(
+        config.connection.clone(),
+        rt.handle().clone(),
+        launcher.get_external_handle(),
+    );
+
+    let mut data = AppState::new(&config, tx);
+    data.info_label_text = Arc::new(contrast_warning.unwrap_or_default());
+
+    launcher.launch(data).unwrap();
+}
+
+/// Starts [`ConnectionHandler`] on its own thread, with connections on `rt`.
+///
+/// Returns sender for [`AppState::new`].
+pub fn spawn_connection_handler(
+    config: ClientConfig,
+    rt: tokio::runtime::Handle,
+    event_sink: druid::ExtEventSink,
+) -> mpsc::Sender<ConnectionHandlerCommand> {
+    let connection_handler = ConnectionHandler { config };
+    let (tx, rx) = mpsc::channel(16);
+    std::thread::spawn(move || {
+        connection_handler.main_loop(rt, rx, event_sink);
+    });
+    tx
+}
+
+/// Title of the window, showing the server name if connected
+fn window_title(server_name: &str) -> String {
+    if server_name.is_empty() {
+        "accord".to_string()
+    } else {
+        format!("{} - accord", server_name)
+    }
+}
+
+/// Connect to server using data from input textboxes
+fn connect_click(data: &mut AppState) {
+    let addr = try_parse_addr(&data.input_text1);
+    // Server checks username against its own policy and tells us why it's invalid,
+    // so only obviously invalid ones are rejected here
+    if let Err(e) = accord::utils::validate_message(&*data.input_text2) {
+        log::warn!("Invalid username: {}", e);
+        data.info_label_text = Arc::new(format!("Invalid username: {}", e));
+    } else {
+        data.info_label_text = Arc::new("Connecting...".to_string());
+        if data.remember_login {
+            let saved = SavedServer {
+                address: data.input_text1.to_string(),
+                username: data.input_text2.to_string(),
+            };
+            if !data.saved_servers.contains(&saved) {
+                data.saved_servers.push_back(saved);
+            }
+        }
+        data.connection_handler_tx
+            .blocking_send(ConnectionHandlerCommand::Connect(
+                addr,
+                data.input_text2.to_string(),
+                data.input_text3.to_string(),
+            ))
+            .unwrap();
+        config::save_config(config_from_appstate(data)).unwrap();
+    };
+}
+
+/// Send message to server
+fn send_message_click(data: &mut AppState) {
+    let s = accord::utils::trim_line_ending(&data.input_text4).to_string();
+    if let Err(e) = accord::utils::validate_message(&s) {
+        data.info_label_text = Arc::new(format!("Invalid message: {}", e));
+    } else {
+        // "/color #rrggbb" sets color of user's name, without argument resets it
+        let color = s
+            .strip_prefix("/color")
+            .filter(|rest| rest.is_empty() || rest.starts_with(' '))
+            .map(str::trim);
+        let p = if let Some(color) = color {
+            if !color.is_empty() && accord::utils::hex_color_rgb(color).is_none() {
+                data.info_label_text = Arc::new("Invalid color, expected #rrggbb.".to_string());
+                return;
+            }
+            ServerboundPacket::SetColor(color.to_string())
+        } else if let Some(command) = s.strip_prefix('/') {
+            ServerboundPacket::Command(command.to_string())
+        } else {
+            ServerboundPacket::Message(accord::utils::expand_shortcodes(&s))
+        };
+        data.connection_handler_tx
+            .blocking_send(ConnectionHandlerCommand::Write(
+                data.active_server.to_string(),
+                p,
+            ))
+            .unwrap();
+        data.input_text4 = Arc::new(String::new());
+    };
+}
+
+/// Jump to messages around the date from input
+fn goto_date_click(data: &mut AppState) {
+    use chrono::TimeZone;
+    let time = chrono::NaiveDate::parse_from_str(data.goto_date.trim(), "%Y-%m-%d")
+        .ok()
+        .and_then(|d| d.and_hms_opt(0, 0, 0))
+        .and_then(|d| chrono::Local.from_local_datetime(&d).earliest());
+    if let Some(time) = time {
+        // Replace loaded messages with ones around that date
+        ActiveServer.with_mut(data, |server| server.messages.clear());
+        let p = ServerboundPacket::FetchMessagesAround {
+            time: time.timestamp_millis(),
+            count: 50,
+        };
+        data.connection_handler_tx
+            .blocking_send(ConnectionHandlerCommand::Write(
+                data.active_server.to_string(),
+                p,
+            ))
+            .unwrap();
+    } else {
+        data.info_label_text = Arc::new("Invalid date. Expected YYYY-MM-DD".to_string());
+    }
+}
+
+// Less typing
+fn unwrap_from_hex(s: &str) -> Color {
+    Color::from_hex_str(s).unwrap()
+}
+
+/// Builds UI of connect view
+fn connect_view(theme: &Theme) -> impl Widget<AppState> {
+    let font = CONNECT_FONT;
+
+    let input_label_c = |s: &str| -> druid::widget::Align<AppState> {
+        Label::new(s)
+            .with_font(font)
+            .with_text_color(unwrap_from_hex(&theme.text_color1))
+            .padding(7.0)
+            .center()
+    };
+    let input_box_c = || -> TextBox<Arc<String>> {
+        TextBox::new()
+            .with_font(font)
+            .with_text_color(unwrap_from_hex(&theme.text_color1))
+    };
+
+    let info_label = Label::dynamic(|data, _env| format!("{}", data))
+        .with_text_color(Color::YELLOW)
+        .with_font(font)
+        .padding(5.0)
+        .lens(AppState::info_label_text);
+    let label1 = input_label_c("Address:");
+    let label2 = input_label_c("Username:");
+    let label3 = input_label_c("Password:");
+    let button = Button::new("Connect")
+        .on_click(|_, data, _| connect_click(data))
+        .padding(5.0);
+    let input1 = input_box_c().lens(AppState::input_text1).expand_width();
+    let input2 = input_box_c().lens(AppState::input_text2).expand_width();
+    let input3 = input_box_c()
+        .lens(AppState::input_text3)
+        .expand_width()
+        .controller(TakeFocusConnect);
+    let checkbox = Checkbox::new("Remember login").lens(AppState::remember_login);
+
+    let checkbox2 = Checkbox::new("Images from links").lens(AppState::images_from_links);
+    let checkbox3 = Checkbox::new("Open links").lens(AppState::open_links);
+    let checkbox4 = Checkbox::new("Relative times").lens(AppState::relative_times);
+    let checkbox5 = Checkbox::new("High contrast").lens(AppState::high_contrast);
+    let checkbox7 = Checkbox::new("Compact times").lens(AppState::compact_times);
+    let checkbox6 = Checkbox::new("Large text").lens(lens::Map::new(
+        |data: &AppState| data.font_scale >= LARGE_FONT_SCALE,
+        |data: &mut AppState, large| data.font_scale = if large { LARGE_FONT_SCALE } else { 1.0 },
+    ));
+
+    // Clicking on a saved server fills in the address and username
+    let saved_servers = List::new(|| {
+        Button::dynamic(|(_, saved): &(_, SavedServer), _env| {
+            server_key(&saved.address, &saved.username)
+        })
+        .on_click(|_ctx, ((address, username), saved), _env| {
+            *address = Arc::new(saved.address.clone());
+            *username = Arc::new(saved.username.clone());
+        })
+        .padding(2.0)
+    })
+    .lens(lens::Map::new(
+        |data: &AppState| {
+            (
+                (data.input_text1.clone(), data.input_text2.clone()),
+                data.saved_servers.clone(),
+            )
+        },
+        |data: &mut AppState, ((address, username), saved_servers)| {
+            data.input_text1 = address;
+            data.input_text2 = username;
+            data.saved_servers = saved_servers;
+        },
+    ));
+
+    // Go back to already connected servers
+    let back_button = Either::new(
+        |data: &AppState, _env| data.servers.is_empty(),
+        Label::new(""),
+        Button::new("Back")
+            .on_click(|_ctx, data: &mut AppState, _env| {
+                data.info_label_text = Arc::new(String::new());
+                data.current_view = Views::Main;
+            })
+            .padding(5.0),
+    );
+
+    let accord_logo_data = match include_str!("resources/accord-logo.svg").parse::<SvgData>() {
+        Ok(svg) => svg,
+        Err(err) => {
+            log::error!("{}", err);
+            log::error!("Using an empty SVG instead.");
+            SvgData::default()
+        }
+    };
+    let accord_logo = Svg::new(accord_logo_data).fill_mode(druid::widget::FillStrat::ScaleDown);
+
+    Flex::column()
+        .with_child(
+            accord_logo
+                .fix_width(300.0)
+                .align_vertical(UnitPoint::BOTTOM),
+        )
+        .with_child(info_label)
+        .with_child(
+            Flex::column()
+                .with_child(
+                    Flex::row()
+                        .with_child(label1)
+                        .with_flex_child(input1, 1.0)
+                        .fix_width(250.0),
+                )
+                .with_child(
+                    Flex::row()
+                        .with_child(label2)
+                        .with_flex_child(input2, 1.0)
+                        .fix_width(250.0),
+                )
+                .with_child(
+                    Flex::row()
+                        .with_child(label3)
+                        .with_flex_child(input3, 1.0)
+                        .fix_width(250.0),
+                )
+                .with_child(checkbox)
+                .with_child(button)
+                .with_child(checkbox2)
+                .with_child(checkbox3)
+                .with_child(checkbox4)
+                .with_child(checkbox7)
+                .with_child(checkbox5)
+                .with_child(checkbox6)
+                .with_child(saved_servers)
+                .with_child(back_button)
+                .padding(10.0)
+                .fix_width(350.0)
+                .padding((-30.0, 5.0, -20.0, 5.0))
+                .cut_corners(0.0, 20.0, 20.0, 0.0)
+                .with_border(unwrap_from_hex(&theme.highlight), theme.border)
+                .with_background(unwrap_from_hex(&theme.color1)),
+        )
+        .align_vertical(UnitPoint::new(0.0, 0.25))
+}
+
+/// Builds a [`Widget`] showing a message, own messages on the right
+fn message(theme: &Theme, dled_images: Arc<Mutex<ImageCache>>) -> impl Widget<Message> {
+    let day_separator = Either::new(
+        |data: &Message, _env| data.day_separator.is_some(),
+        Label::dynamic(|data: &Message, _env| data.day_separator.clone().unwrap_or_default())
+            .with_text_color(unwrap_from_hex(&theme.text_color1))
+            .padding(Insets::uniform_xy(0.0, 5.0))
+            .center(),
+        druid::widget::SizedBox::empty(),
+    );
+    let theme = theme.clone();
+    let bubble = ViewSwitcher::new(
+        |data: &Message, _env| data.own,
+        move |own, _data, _env| {
+            let bubble = message_bubble(&theme, Arc::clone(&dled_images));
+            if *own {
+                Box::new(
+                    Flex::row()
+                        .with_flex_spacer(1.0)
+                        .with_flex_child(bubble, 3.0),
+                )
+            } else {
+                Box::new(bubble)
+            }
+        },
+    );
+    Flex::column()
+        .cross_axis_alignment(druid::widget::CrossAxisAlignment::Start)
+        .with_child(day_separator)
+        .with_child(bubble)
+}
+
+/// Builds the message itself (with sender, time and copy button)
+fn message_bubble(theme: &Theme, dled_images: Arc<Mutex<ImageCache>>) -> impl Widget<Message> {
+    let highlight = unwrap_from_hex(&theme.highlight);
+    let default_sender_color = unwrap_from_hex(&theme.text_color1);
+    let content_label = Label::raw()
+        .with_font(MESSAGE_FONT)
+        .with_text_color(unwrap_from_hex(&theme.text_color1))
+        .with_line_break_mode(druid::widget::LineBreaking::WordWrap)
+        .lens(lens::Map::new(
+            |data: &Message| {
+                data.rich_text
+                    .clone()
+                    .unwrap_or_else(|| message_rich_text(&data.content, data.spoilers_revealed))
+            },
+            |_data: &mut Message, _text| (),
+        ))
+        .controller(SpoilerController);
+    let copy_images = Arc::clone(&dled_images);
+    let image_from_link = ImageMessage::new(content_label, dled_images);
+    // Hovering shows exact time (and copy button), like a tooltip
+    let exact_time_label = Either::new(
+        |data: &Message, env| data.hovered && env.get(COMPACT_TIMES),
+        Label::dynamic(|data: &Message, _env| exact_time(&chrono::Local, data.time))
+            .with_text_color(unwrap_from_hex(&theme.text_color1))
+            .padding(Insets::uniform_xy(5.0, 0.0)),
+        druid::widget::SizedBox::empty(),
+    );
+    let copy_button = Either::new(
+        |data: &Message, _env| data.hovered,
+        Button::new("Copy")
+            .on_click(move |_ctx, data: &mut Message, _env| copy_message(data, &copy_images)),
+        druid::widget::SizedBox::empty(),
+    );
+    Flex::row()
+        .cross_axis_alignment(druid::widget::CrossAxisAlignment::Start)
+        .with_child(
+            Label::dynamic(|data: &Message, env| {
+                let sender = if data.sender_id == accord::packets::SERVER_SENDER_ID {
+                    "Server"
+                } else {
+                    data.sender.as_str()
+                };
+                if data.sender.is_empty() || data.grouped {
+                    "".to_string()
+                } else if env.get(RELATIVE_TIMES) {
+                    let now = env.get(CURRENT_TIME) as i64;
+                    let date = accord::utils::format_relative(data.time, now);
+                    format!("{} ({}):", sender, date)
+                } else if env.get(COMPACT_TIMES) {
+                    format!("{} {}:", sender, coarse_time(&chrono::Local, data.time))
+                } else {
+                    format!("{} {}:", sender, data.date)
+                }
+            })
+            .with_text_color(SENDER_COLOR)
+            .with_font(MESSAGE_HEADER_FONT)
+            .on_click(|ctx, data: &mut Message, _env| {
+                // Server can't be mentioned
+                if !data.sender.is_empty() && data.sender_id != accord::packets::SERVER_SENDER_ID {
+                    ctx.submit_command(MENTION_USER.with(data.sender.clone()));
+                }
+            }),
+        )
+        .with_default_spacer()
+        .with_flex_child(Flex::column().with_child(image_from_link), 1.0)
+        .with_child(exact_time_label)
+        .with_child(copy_button)
+        .controller(HoverController)
+        .padding(Insets::uniform_xy(5.0, 5.0))
+        .cut_corners_sym(10.0)
+        .with_background(unwrap_from_hex(&theme.color1))
+        .with_border(MESSAGE_BORDER, theme.border)
+        .env_scope(move |env, data: &Message| {
+            let color = if data.selected {
+                Color::rgb8(255, 140, 0)
+            } else if data.highlighted {
+                Color::YELLOW
+            } else {
+                highlight
+            };
+            env.set(MESSAGE_BORDER, color);
+            let sender_color = data
+                .sender_color
+                .as_deref()
+                .and_then(|c| Color::from_hex_str(c).ok())
+                .unwrap_or(default_sender_color);
+            env.set(SENDER_COLOR, sender_color);
+        })
+        .controller(FindMatchController)
+        .padding(Insets::uniform_xy(0.0, 1.0))
+}
+
+/// Sets fonts and sizes of widgets (ours and druid's) in `env` scaled by `scale`,
+/// so bigger text also gets bigger buttons etc.
+fn set_scale(env: &mut Env, scale: f64) {
+    let font = |size: f64| FontDescriptor::new(FontFamily::SYSTEM_UI).with_size(size * scale);
+    env.set(CONNECT_FONT, font(20.0));
+    env.set(MESSAGE_FONT, font(17.0));
+    env.set(
+        MESSAGE_HEADER_FONT,
+        font(17.0).with_weight(druid::FontWeight::BOLD),
+    );
+    env.set(
+        USER_LIST_FONT,
+        font(15.0).with_weight(druid::FontWeight::BOLD),
+    );
+    // Buttons, textboxes etc.
+    let ui_size = env.get(druid::theme::TEXT_SIZE_NORMAL);
+    env.set(druid::theme::UI_FONT, font(ui_size));
+    env.set(druid::theme::TEXT_SIZE_NORMAL, ui_size * scale);
+    for key in [
+        druid::theme::BASIC_WIDGET_HEIGHT,
+        druid::theme::BORDERED_WIDGET_HEIGHT,
+        druid::theme::WIDE_WIDGET_WIDTH,
+        druid::theme::WIDGET_PADDING_HORIZONTAL,
+        druid::theme::WIDGET_PADDING_VERTICAL,
+        druid::theme::WIDGET_CONTROL_COMPONENT_PADDING,
+    ] {
+        let size = env.get(key);
+        env.set(key, size * scale);
+    }
+}
+
+/// Changes font scale by `steps` of [`FONT_SCALE_STEP`], staying within [`FONT_SCALE_RANGE`].
+fn zoom(scale: f64, steps: i32) -> f64 {
+    let scale = scale + steps as f64 * FONT_SCALE_STEP;
+    // Rounded so repeated steps don't accumulate float errors
+    clamp_font_scale((scale / FONT_SCALE_STEP).round() / (1.0 / FONT_SCALE_STEP))
+}
+
+/// Keeps font scale within [`FONT_SCALE_RANGE`], falling back to 1.0 if it's not a number.
+fn clamp_font_scale(scale: f64) -> f64 {
+    if scale.is_nan() {
+        1.0
+    } else {
+        scale.clamp(FONT_SCALE_RANGE.0, FONT_SCALE_RANGE.1)
+    }
+}
+
+/// Hour and minute of `time` (e.g. "14:03"), shown once per message group with compact times
+fn coarse_time<Tz: chrono::TimeZone>(tz: &Tz, time: i64) -> String
+where
+    Tz::Offset: std::fmt::Display,
+{
+    tz.timestamp_millis_opt(time)
+        .single()
+        .map(|t| t.format("%H:%M").to_string())
+        .unwrap_or_default()
+}
+
+/// Full date and time to the second (e.g. "2022-04-26 19:06:40"), shown on hover with compact times
+fn exact_time<Tz: chrono::TimeZone>(tz: &Tz, time: i64) -> String
+where
+    Tz::Offset: std::fmt::Display,
+{
+    tz.timestamp_millis_opt(time)
+        .single()
+        .map(|t| t.format("%Y-%m-%d %H:%M:%S").to_string())
+        .unwrap_or_default()
+}
+
+/// Returns separator (e.g. "—— March 3, 2022 ——") to show above message sent at `time`,
+/// if it was sent on a different day (in given timezone) than previous message.
+fn day_separator<Tz: chrono::TimeZone>(tz: &Tz, prev_time: Option<i64>, time: i64) -> Option<String>
+where
+    Tz::Offset: std::fmt::Display,
+{
+    let date = tz.timestamp_millis_opt(time).single()?.date_naive();
+    let prev_date = prev_time.and_then(|t| tz.timestamp_millis_opt(t).single());
+    if prev_date.map(|d| d.date_naive()) == Some(date) {
+        None
+    } else {
+        Some(format!("—— {} ——", date.format("%B %-d, %Y")))
+    }
+}
+
+/// Builds [`RichText`] from message content, with markdown styling
+/// (see [`accord::markdown`]), links made clickable
+/// and spoilers blacked out (unless `spoilers_revealed`).
+fn message_rich_text(content: &str, spoilers_revealed: bool) -> RichText {
+    use accord::markdown::Block;
+    let mut builder = RichTextBuilder::new();
+    // Code blocks go on their own lines
+    let mut line_start = true;
+    let mut after_code = false;
+    for block in accord::markdown::split_code_blocks(content) {
+        let text = match block {
+            Block::Text(text) => text,
+            Block::Code { code, .. } => {
+                if !line_start {
+                    builder.push("\n");
+                }
+                builder
+                    .push(code)
+                    .font_family(FontFamily::MONOSPACE)
+                    .text_color(CODE_BLOCK_COLOR);
+                line_start = false;
+                after_code = true;
+                continue;
+            }
+        };
+        if after_code && !text.starts_with('\n') {
+            builder.push("\n");
+        }
+        after_code = false;
+        line_start = text.ends_with('\n');
+        for (part, is_spoiler) in accord::utils::split_spoilers(text) {
+            if is_spoiler && !spoilers_revealed {
+                builder.push(&"█".repeat(part.chars().count()));
+                continue;
+            }
+            for span in accord::markdown::parse_inline(part) {
+                push_span(&mut builder, &span);
+            }
+        }
+    }
+    builder.build()
+}
+
+/// Pushes styled span to `builder`, making links (outside of code) clickable.
+fn push_span(builder: &mut RichTextBuilder, span: &accord::markdown::Span) {
+    if span.code {
+        push_styled(builder, span.text, span);
+        return;
+    }
+    let text = span.text;
+    let mut last = 0;
+    for (start, url) in accord::utils::extract_urls(text) {
+        push_styled(builder, &text[last..start], span);
+        push_styled(builder, url, span)
+            .underline(true)
+            .link(OPEN_LINK.with(url.to_string()));
+        last = start + url.len();
+    }
+    push_styled(builder, &text[last..], span);
+}
+
+/// Pushes `text` to `builder` with style of `span`.
+fn push_styled<'a>(
+    builder: &'a mut RichTextBuilder,
+    text: &str,
+    span: &accord::markdown::Span,
+) -> druid::text::AttributesAdder<'a> {
+    let mut attrs = builder.push(text);
+    if span.bold {
+        attrs.weight(druid::FontWeight::BOLD);
+    }
+    if span.italic {
+        attrs.style(druid::FontStyle::Italic);
+    }
+    if span.code {
+        attrs.font_family(FontFamily::MONOSPACE);
+    }
+    attrs
+}
+
+/// What copying a message puts in the clipboard
+#[derive(Debug, PartialEq, Eq)]
+enum CopyPayload {
+    Text(String),
+    /// Image with this key in [`ImageCache`]
+    Image(String),
+}
+
+/// Picks what to copy from a message.
+fn copy_payload(message: &Message) -> CopyPayload {
+    if message.is_image {
+        CopyPayload::Image(message.content.clone())
+    } else {
+        CopyPayload::Text(message.content.clone())
+    }
+}
+
+/// Copies message text or image to the clipboard.
+fn copy_message(message: &Message, dled_images: &Mutex<ImageCache>) {
+    let mut clipboard = druid::Application::global().clipboard();
+    match copy_payload(message) {
+        CopyPayload::Text(text) => clipboard.put_string(text),
+        CopyPayload::Image(key) => {
+            let bytes = dled_images.lock().unwrap().get_bytes(&key);
+            match bytes.as_deref().and_then(|b| Some((image_mime(b)?, b))) {
+                Some((mime, bytes)) => {
+                    clipboard.put_formats(&[druid::ClipboardFormat::new(mime, bytes.to_vec())])
+                }
+                None => log::warn!("Can't copy image {}: not loaded or unknown format", key),
+            }
+        }
+    }
+}
+
+/// MIME type of encoded image, if it's one we can paste (see [`MessageTextBoxController`])
+fn image_mime(bytes: &[u8]) -> Option<&'static str> {
+    if bytes.starts_with(b"\x89PNG") {
+        Some("image/png")
+    } else if bytes.starts_with(&[0xff, 0xd8, 0xff]) {
+        Some("image/jpeg")
+    } else {
+        None
+    }
+}
+
+/// Appends mention of `username` to message input.
+fn mention(input: &str, username: &str) -> String {
+    if input.is_empty() || input.ends_with(char::is_whitespace) {
+        format!("{}@{} ", input, username)
+    } else {
+        format!("{} @{} ", input, username)
+    }
+}
+
+/// Builds dialog asking whether to open the link in browser
+fn open_link_dialog(theme: &Theme, link: String) -> impl Widget<()> {
+    let label = Label::new(format!("Open {} in browser?", link))
+        .with_text_color(unwrap_from_hex(&theme.text_color1))
+        .with_line_break_mode(druid::widget::LineBreaking::WordWrap);
+    let open_button = Button::new("Open").on_click(move |ctx, _data: &mut (), _env| {
+        if let Err(e) = open::that(&link) {
+            log::warn!("Failed to open link: {}", e);
+        }
+        ctx.window().close();
+    });
+    let cancel_button =
+        Button::new("Cancel").on_click(|ctx, _data: &mut (), _env| ctx.window().close());
+    Flex::column()
+        .with_child(label)
+        .with_default_spacer()
+        .with_child(
+            Flex::row()
+                .with_child(open_button)
+                .with_default_spacer()
+                .with_child(cancel_button),
+        )
+        .padding(10.0)
+        .background(unwrap_from_hex(&theme.background1))
+}
+
+/// Parses address from string.
+/// If string contains `':'`, it assumes it's "ADDRESS:PORT",
+/// else it assumes it's just the address.
+fn try_parse_addr(s: &str) -> String {
+    if s.contains(':') {
+        s.to_owned()
+    } else {
+        format!("{}:{}", s, accord::DEFAULT_PORT)
+    }
+}
+
+/// Builds UI of main view
+fn main_view(theme: Arc<Theme>, dled_images: Arc<Mutex<ImageCache>>) -> impl Widget<AppState> {
+    let info_label = Label::dynamic(|data, _env| format!("{}", data))
+        .with_text_color(Color::YELLOW)
+        .lens(AppState::info_label_text);
+
+    let accord_logo_data = match include_str!("resources/accord-logo.svg").parse::<SvgData>() {
+        Ok(svg) => svg,
+        Err(err) => {
+            log::error!("{}", err);
+            log::error!("Using an empty SVG instead.");
+            SvgData::default()
+        }
+    };
+    let accord_logo = Svg::new(accord_logo_data).fill_mode(druid::widget::FillStrat::ScaleDown);
+
+    // Switching between connected servers
+    let server_list_widget = List::new(|| {
+        Button::dynamic(|(active, server): &(Arc<String>, String), _env| {
+            if **active == *server {
+                format!("> {}", server)
+            } else {
+                server.clone()
+            }
+        })
+        .on_click(|_ctx, (active, server), _env| *active = Arc::new(server.clone()))
+        .padding(2.0)
+    })
+    .lens(lens::Map::new(
+        |data: &AppState| {
+            (
+                data.active_server.clone(),
+                data.servers.keys().cloned().collect::<Vector<_>>(),
+            )
+        },
+        |data: &mut AppState, (active_server, _)| data.active_server = active_server,
+    ));
+
+    let add_server_button = Button::new("+")
+        .on_click(|_ctx, data: &mut AppState, _env| data.current_view = Views::Connect)
+        .padding(2.0);
+
+    let goto_date_widget = Flex::row()
+        .with_child(
+            TextBox::new()
+                .with_placeholder("YYYY-MM-DD")
+                .lens(AppState::goto_date)
+                .fix_width(100.0),
+        )
+        .with_child(
+            Button::new("Go").on_click(|_ctx, data: &mut AppState, _env| goto_date_click(data)),
+        );
+
+    let user_list_widget = Flex::column()
+        .cross_axis_alignment(druid::widget::CrossAxisAlignment::Start)
+        .with_child(server_list_widget)
+        .with_child(add_server_button)
+        .with_child(goto_date_widget)
+        .with_default_spacer()
+        .with_flex_child(
+            List::new(|| Label::raw().with_font(USER_LIST_FONT))
+                .lens(ActiveServer.then(ServerState::user_list)),
+            1.0,
+        )
+        .with_child(Label::new("").fix_width(100.0))
+        .expand_height()
+        .padding((10.0, 5.0, 5.0, 5.0))
+        .cut_corners(10.0, 0.0, 0.0, 10.0)
+        .with_border(unwrap_from_hex(&theme.highlight), theme.border)
+        .with_background(unwrap_from_hex(&theme.color1))
+        .padding((0.0, 0.0, 5.0, 0.0));
+
+    let messages_list_widget = List::new(move || message(&theme, Arc::clone(&dled_images)))
+        .controller(ListController)
+        .scroll()
+        .vertical()
+        .controller(ScrollController::new())
+        .expand_height()
+        .lens(ActiveServer.then(ServerState::messages));
+
+    let input_text_box = TextBox::multiline()
+        .lens(AppState::input_text4)
+        .expand_width()
+        .controller(TakeFocusMain)
+        .controller(MessageTextBoxController);
+
+    let send_button =
+        Button::new("Send").on_click(|_ctx, data: &mut AppState, _env| send_message_click(data));
+
+    let find_bar = Flex::row()
+        .with_flex_child(
+            TextBox::new()
+                .with_placeholder("Find")
+                .lens(AppState::find_query)
+                .expand_width()
+                .controller(FindController),
+            1.0,
+        )
+        .with_default_spacer()
+        .with_child(Label::dynamic(|data: &AppState, _env| {
+            let count = ActiveServer.with(data, |server| {
+                server.messages.iter().filter(|m| m.highlighted).count()
+            });
+            match data.find_current {
+                Some(i) if count > 0 => format!("{}/{}", i + 1, count),
+                _ => "No matches".to_string(),
+            }
+        }))
+        .with_default_spacer()
+        .with_child(
+            Button::new("Previous")
+                .on_click(|_ctx, data: &mut AppState, _env| update_find(data, Some(false))),
+        )
+        .with_child(
+            Button::new("Next")
+                .on_click(|_ctx, data: &mut AppState, _env| update_find(data, Some(true))),
+        )
+        .with_child(
+            Button::new("Close").on_click(|_ctx, data: &mut AppState, _env| {
+                data.find_open = false;
+                update_find(data, None);
+            }),
+        );
+    let find_bar = Either::new(
+        |data: &AppState, _env| data.find_open,
+        find_bar.padding((0.0, 0.0, 0.0, 5.0)),
+        Label::new(""),
+    );
+
+    Flex::column()
+        .cross_axis_alignment(druid::widget::CrossAxisAlignment::Start)
+        .with_child(accord_logo.fix_height(80.0).center())
+        .with_child(info_label)
+        .with_child(find_bar)
+        .with_flex_child(
+            Flex::row()
+                .cross_axis_alignment(druid::widget::CrossAxisAlignment::Start)
+                .with_child(user_list_widget)
+                .with_flex_child(messages_list_widget, 1.0),
+            1.0,
+        )
+        .with_default_spacer()
+        .with_child(
+            Flex::row()
+                .with_flex_child(input_text_box, 1.0)
+                .with_default_spacer()
+                .with_child(send_button),
+        )
+        .padding(20.0)
+}
+
+/// Builds the whole chat (connect and main views), using runtime of `rt`
+/// and keeping up to `image_cache_size` images in memory.
+///
+/// Can be embedded in other apps, see [crate docs](crate).
+pub fn chat_widget(
+    theme: Arc<Theme>,
+    image_cache_size: usize,
+    rt: tokio::runtime::Handle,
+) -> impl Widget<AppState> {
+    let dled_images = Arc::new(Mutex::new(ImageCache::new(image_cache_size)));
+    let controller = ChatController {
+        theme: Arc::clone(&theme),
+        dled_images: Arc::clone(&dled_images),
+        rt,
+    };
+    Flex::column()
+        .with_flex_child(
+            // Rebuilt when theme changes
+            ViewSwitcher::new(
+                |data: &AppState, _env| (data.current_view, data.high_contrast),
+                move |(view, high_contrast), _data, _env| {
+                    let theme = if *high_contrast {
+                        Arc::new(Theme::high_contrast())
+                    } else {
+                        Arc::clone(&theme)
+                    };
+                    let background = druid::LinearGradient::new(
+                        UnitPoint::BOTTOM,
+                        UnitPoint::TOP,
+                        (
+                            unwrap_from_hex(&theme.background2),
+                            unwrap_from_hex(&theme.background1),
+                        ),
+                    );
+                    match view {
+                        Views::Connect => {
+                            Box::new(connect_view(&theme).expand().background(background))
+                        }
+                        Views::Main => Box::new(
+                            main_view(Arc::clone(&theme), Arc::clone(&dled_images))
+                                .expand()
+                                .background(background),
+                        ),
+                    }
+                },
+            ),
+            1.0,
+        )
+        .env_scope(|env, data: &AppState| {
+            env.set(RELATIVE_TIMES, data.relative_times);
+            env.set(COMPACT_TIMES, data.compact_times);
+            env.set(CURRENT_TIME, data.now as f64);
+            set_scale(env, data.font_scale);
+        })
+        .controller(ClockController::default())
+        .controller(controller)
+}
+
+/// Handles keyboard shortcuts and commands of [`chat_widget`]
+struct ChatController {
+    theme: Arc<Theme>,
+    dled_images: Arc<Mutex<ImageCache>>,
+    /// Runtime shared with [`ConnectionHandler`]
+    rt: tokio::runtime::Handle,
+}
+
+/// Construct [`Config`] from [`AppState`]
+fn config_from_appstate(data: &AppState) -> Config {
+    let (address, username) = if data.remember_login {
+        (data.input_text1.to_string(), data.input_text2.to_string())
+    } else {
+        Default::default()
+    };
+    Config {
+        address,
+        username,
+        remember_login: data.remember_login,
+        images_from_links: data.images_from_links,
+        open_links: data.open_links,
+        servers: data.saved_servers.iter().cloned().collect(),
+        relative_times: data.relative_times,
+        compact_times: data.compact_times,
+        image_cache_size: data.image_cache_size,
+        connection: data.connection.as_ref().clone(),
+        window: data.window,
+        font_scale: data.font_scale,
+        high_contrast: data.high_contrast,
+        theme: None,
+    }
+}
+
+impl<W: Widget<AppState>> Controller<AppState, W> for ChatController {
+    fn event(
+        &mut self,
+        child: &mut W,
+        ctx: &mut EventCtx,
+        event: &Event,
+        data: &mut AppState,
+        env: &Env,
+    ) {
+        let handled = match event {
+            Event::KeyDown(kevent) => self.key_down(ctx, kevent, data),
+            Event::Command(cmd) => self.command(ctx, cmd, data, env),
+            _ => false,
+        };
+        if !handled {
+            child.event(ctx, event, data, env)
+        }
+    }
+}
+
+impl ChatController {
+    /// Returns whether the key was handled
+    fn key_down(
+        &mut self,
+        ctx: &mut EventCtx,
+        kevent: &druid::KeyEvent,
+        data: &mut AppState,
+    ) -> bool {
+        use druid::keyboard_types::Key;
+        match kevent.key {
+            // Shift+Enter inserts a new line instead (e.g. for code blocks)
+            Key::Enter if !kevent.mods.shift() => {
+                match data.current_view {
+                    Views::Connect => connect_click(data),
+                    Views::Main => send_message_click(data),
+                }
+                true
+            }
+            Key::PageUp => {
+                ctx.submit_command(controllers::SCROLL.with(-1.0));
+                true
+            }
+            Key::PageDown => {
+                ctx.submit_command(controllers::SCROLL.with(1.0));
+                true
+            }
+            Key::Character(ref c)
+                if c == "f" && kevent.mods.ctrl() && data.current_view == Views::Main =>
+            {
+                data.find_open = !data.find_open;
+                update_find(data, None);
+                if data.find_open {
+                    ctx.submit_command(FIND_OPENED);
+                }
+                true
+            }
+            Key::Character(ref c)
+                if c == "t" && kevent.mods.ctrl() && data.current_view == Views::Main =>
+            {
+                data.relative_times = !data.relative_times;
+                true
+            }
+            // "=" for "+" without shift
+            Key::Character(ref c) if (c == "+" || c == "=") && kevent.mods.ctrl() => {
+                data.font_scale = zoom(data.font_scale, 1);
+                true
+            }
+            Key::Character(ref c) if c == "-" && kevent.mods.ctrl() => {
+                data.font_scale = zoom(data.font_scale, -1);
+                true
+            }
+            Key::Character(ref c) if c == "0" && kevent.mods.ctrl() => {
+                data.font_scale = 1.0;
+                true
+            }
+            Key::F3 if data.find_open => {
+                update_find(data, Some(!kevent.mods.shift()));
+                true
+            }
+            Key::Escape if data.find_open => {
+                data.find_open = false;
+                update_find(data, None);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Returns whether the command was handled
+    fn command(
+        &mut self,
+        ctx: &mut EventCtx,
+        cmd: &druid::Command,
+        data: &mut AppState,
+        env: &Env,
+    ) -> bool {
+        if let Some((server, command)) = cmd.get(SERVER_COMMAND) {
+            route_server_command(data, server, command);
+            match command {
+                GuiCommand::AddMessage(m) => {
+                    // Try to get image from message link
+                    if data.images_from_links {
+                        let dled_images = Arc::clone(&self.dled_images);
+                        let link = m.content.clone();
+                        let event_sink = ctx.get_external_handle();
+                        self.rt.spawn(async move {
+                            try_get_image_from_link(&link, dled_images, event_sink).await;
+                        });
+                    }
+                }
+                GuiCommand::StoreImage(hash, img_bytes) => {
+                    let mut dled_images = self.dled_images.lock().unwrap();
+                    match dled_images.insert(img_bytes) {
+                        Ok(stored_hash) => {
+                            if stored_hash != *hash {
+                                log::warn!("Image hash mismatch: {} != {}", stored_hash, hash);
+                            }
+                            ctx.submit_command(
+                                druid::Selector::<String>::new("image_downloaded")
+                                    .with(hash.to_string()),
+                            );
+                        }
+                        Err(e) => log::warn!("Failed to load image: {}", e),
+                    }
+                    for key in dled_images.take_evicted() {
+                        ctx.submit_command(IMAGE_EVICTED.with(key));
+                    }
+                }
+                _ => (),
+            };
+        };
+        if let Some(link) = cmd.get(OPEN_LINK) {
+            if data.open_links {
+                let theme = if data.high_contrast {
+                    Theme::high_contrast()
+                } else {
+                    Theme::clone(&self.theme)
+                };
+                ctx.new_sub_window(
+                    druid::WindowConfig::default().window_size((400.0, 150.0)),
+                    open_link_dialog(&theme, link.clone()),
+                    (),
+                    env.clone(),
+                );
+            } else {
+                data.info_label_text = Arc::new("Opening links is disabled".to_string());
+            }
+            return true;
+        }
+        if let Some((key, is_image)) = cmd.get(IMAGE_NEEDED) {
+            if *is_image {
+                let p = ServerboundPacket::FetchImage(key.clone());
+                data.connection_handler_tx
+                    .blocking_send(ConnectionHandlerCommand::Write(
+                        data.active_server.to_string(),
+                        p,
+                    ))
+                    .unwrap();
+            } else if data.images_from_links {
+                let dled_images = Arc::clone(&self.dled_images);
+                let link = key.clone();
+                let event_sink = ctx.get_external_handle();
+                self.rt.spawn(async move {
+                    try_get_image_from_link(&link, dled_images, event_sink).await;
+                });
+            }
+            return true;
+        }
+        if let Some(username) = cmd.get(MENTION_USER) {
+            data.input_text4 = Arc::new(mention(&data.input_text4, username));
+            return true;
+        }
+        if let Some(GuiCommand::SendImage(image_bytes)) = cmd.get(GUI_COMMAND) {
+            let v = image_bytes.to_vec();
+            let p = ServerboundPacket::ImageMessage(v);
+            data.connection_handler_tx
+                .blocking_send(ConnectionHandlerCommand::Write(
+                    data.active_server.to_string(),
+                    p,
+                ))
+                .unwrap();
+        };
+        false
+    }
+}
+
+/// Indices of messages with content containing `query` (ignoring case).
+///
+/// Empty query matches nothing.
+fn find_matches(messages: &Vector<Message>, query: &str) -> Vec<usize> {
+    if query.is_empty() {
+        return vec![];
+    }
+    let query = query.to_lowercase();
+    messages
+        .iter()
+        .enumerate()
+        .filter(|(_, m)| !m.is_image && m.content.to_lowercase().contains(&query))
+        .map(|(i, _)| i)
+        .collect()
+}
+
+/// Moves selection among `count` matches, wrapping around.
+///
+/// Without previous selection, the newest (last) match is selected.
+fn step_match(current: Option<usize>, count: usize, forward: bool) -> Option<usize> {
+    if count == 0 {
+        return None;
+    }
+    match current {
+        Some(i) if i < count => {
+            if forward {
+                Some((i + 1) % count)
+            } else {
+                Some((i + count - 1) % count)
+            }
+        }
+        _ => Some(count - 1),
+    }
+}
+
+/// Updates find matches of the active server's messages.
+///
+/// With `step`, moves selection forward (`Some(true)`) or backward (`Some(false)`),
+/// otherwise the selection is reset.
+fn update_find(data: &mut AppState, step: Option<bool>) {
+    let query = if data.find_open {
+        data.find_query.to_string()
+    } else {
+        String::new()
+    };
+    let current = data.find_current;
+    data.find_current = ActiveServer.with_mut(data, |server| {
+        let matches = find_matches(&server.messages, &query);
+        let current = match step {
+            Some(forward) => step_match(current, matches.len(), forward),
+            None => step_match(None, matches.len(), true),
+        };
+        let selected = current.map(|i| matches[i]);
+        for (i, m) in server.messages.iter_mut().enumerate() {
+            let highlighted = matches.binary_search(&i).is_ok();
+            let is_selected = selected == Some(i);
+            if m.highlighted != highlighted || m.selected != is_selected {
+                m.highlighted = highlighted;
+                m.selected = is_selected;
+            }
+        }
+        current
+    });
+}
+
+/// Updates state of the `server` according to `command` coming from its connection.
+fn route_server_command(data: &mut AppState, server: &str, command: &GuiCommand) {
+    match command {
+        GuiCommand::Connected => {
+            if !data.servers.contains_key(server) {
+                data.servers
+                    .insert(server.to_string(), ServerState::default());
+            }
+            data.active_server = Arc::new(server.to_string());
+            data.info_label_text = Arc::new(String::new());
+            data.current_view = Views::Main;
+        }
+        GuiCommand::ConnectionEnded(m) => {
+            data.servers.remove(server);
+            if *data.active_server == server {
+                // Switch to any other connected server
+                let next = data.servers.keys().next().cloned().unwrap_or_default();
+                data.active_server = Arc::new(next);
+            }
+            if data.servers.is_empty() {
+                data.info_label_text = Arc::new(m.to_string());
+                data.current_view = Views::Connect;
+            } else {
+                data.info_label_text = Arc::new(format!("{}: {}", server, m));
+            }
+        }
+        GuiCommand::AddMessage(m) => {
+            if let Some(state) = data.servers.get_mut(server) {
+                let mut m = m.clone();
+                let prev_time = state.messages.last().map(|prev| prev.time);
+                m.day_separator = day_separator(&chrono::Local, prev_time, m.time);
+                m.grouped = !m.sender.is_empty()
+                    && m.day_separator.is_none()
+                    && state.messages.last().is_some_and(|prev| {
+                        accord::utils::continues_group(
+                            prev.sender_id,
+                            prev.time,
+                            m.sender_id,
+                            m.time,
+                        )
+                    });
+                m.own = accord::utils::is_own_message(m.sender_id, state.user_id);
+                m.rich_text = Some(message_rich_text(&m.content, m.spoilers_revealed));
+                state.messages.push_back(m);
+            }
+        }
+        GuiCommand::LoggedIn { user_id, username } => {
+            if let Some(state) = data.servers.get_mut(server) {
+                state.user_id = Some(*user_id);
+                state.username = Arc::new(username.clone());
+            }
+        }
+        GuiCommand::UpdateUserList(user_list) => {
+            if let Some(state) = data.servers.get_mut(server) {
+                state.user_list = user_list.clone().into();
+            }
+        }
+        GuiCommand::ServerInfo(name, description) => {
+            if let Some(state) = data.servers.get_mut(server) {
+                state.server_name = Arc::new(name.clone());
+            }
+            if *data.active_server == server {
+                data.info_label_text = Arc::new(description.clone());
+            }
+        }
+        GuiCommand::UserRenamed(old, new) => {
+            // Past messages are attributed to the new name, like on the server
+            if let Some(state) = data.servers.get_mut(server) {
+                for m in state.messages.iter_mut().filter(|m| m.sender == *old) {
+                    m.sender = new.clone();
+                }
+            }
+        }
+        GuiCommand::MessageRejected(reason) => {
+            if *data.active_server == server {
+                data.info_label_text = Arc::new(format!("Message not sent: {}", reason));
+            } else {
+                data.info_label_text =
+                    Arc::new(format!("{}: message not sent: {}", server, reason));
+            }
+        }
+        GuiCommand::MessageQueued(pending) => {
+            data.info_label_text = Arc::new(format!(
+                "Not connected to {}, {} message(s) will be sent after reconnecting.",
+                server, pending
+            ));
+        }
+        GuiCommand::QueueFull => {
+            data.info_label_text = Arc::new(format!(
+                "Not connected to {}, too many messages waiting. Message not sent.",
+                server
+            ));
+        }
+        GuiCommand::SendImage(_) | GuiCommand::StoreImage(..) => (),
+    }
+}
+
+/// Tries to download and image from the link and stores it in `dled_images` cache.
+///
+/// Returns `true` on success.
+async fn try_get_image_from_link(
+    link: &str,
+    dled_images: Arc<Mutex<ImageCache>>,
+    event_sink: druid::ExtEventSink,
+) -> bool {
+    if !dled_images.lock().unwrap().contains(link) {
+        let client = reqwest::ClientBuilder::new()
+            .timeout(std::time::Duration::from_secs(10))
+            .build()
+            .unwrap();
+
+        // We get just head first to see if it's an image
+        let req = client.head(link).build();
+        let resp = match req {
+            Ok(req) => client.execute(req).await,
+            Err(_) => return false,
+        };
+        match resp {
+            Ok(resp) => {
+                if resp.status() == reqwest::StatusCode::OK
+                    && resp.headers().get("content-type").map_or(false, |v| {
+                        v.to_str().map_or(false, |s| s.starts_with("image/"))
+                    })
+                    && resp.headers().get("content-length").map_or(false, |v| {
+                        v.to_str().map_or(false, |s| {
+                            s.parse::<u32>().map_or(false, |l| {
+                                l < 31457280 // 30 MB
+                            })
+                        })
+                    })
+                {
+                    let req = client.get(link).build().unwrap();
+
+                    let resp = match client.execute(req).await {
+                        Ok(resp) => resp,
+                        Err(_) => return false,
+                    };
+
+                    let img_bytes = resp.bytes().await.unwrap();
+
+                    let mut dled_images = dled_images.lock().unwrap();
+                    if let Err(e) = dled_images.insert_from_link(link.to_string(), &img_bytes) {
+                        log::warn!("Failed to load image from {}: {}", link, e);
+                        return false;
+                    }
+                    for key in dled_images.take_evicted() {
+                        event_sink
+                            .submit_command(IMAGE_EVICTED, key, druid::Target::Auto)
+                            .unwrap();
+                    }
+                    event_sink
+                        .submit_command(
+                            druid::Selector::<String>::new("image_downloaded"),
+                            link.to_string(),
+                            druid::Target::Auto,
+                        )
+                        .unwrap();
+                }
+            }
+            Err(e) => {
+                log::warn!("Error when getting image: {}", e);
+                return false;
+            }
+        };
+    };
+
+    true
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn test_state() -> AppState {
+        let (tx, _rx) = mpsc::channel(1);
+        AppState {
+            current_view: Views::Connect,
+            info_label_text: Arc::new(String::new()),
+            input_text1: Arc::new(String::new()),
+            input_text2: Arc::new(String::new()),
+            input_text3: Arc::new(String::new()),
+            remember_login: false,
+            input_text4: Arc::new(String::new()),
+            connection_handler_tx: Arc::new(tx),
+            servers: OrdMap::new(),
+            active_server: Arc::new(String::new()),
+            saved_servers: Vector::new(),
+            images_from_links: false,
+            open_links: false,
+            find_open: false,
+            find_query: Arc::new(String::new()),
+            find_current: None,
+            goto_date: Arc::new(String::new()),
+            image_cache_size: 64,
+            connection: Default::default(),
+            relative_times: false,
+            compact_times: false,
+            now: 0,
+            window: None,
+            font_scale: 1.0,
+            high_contrast: false,
+        }
+    }
+
+    fn test_message(content: &str) -> Message {
+        Message {
+            sender_id: 1,
+            sender: "user".to_string(),
+            sender_color: None,
+            date: String::new(),
+            time: 0,
+            content: content.to_string(),
+            is_image: false,
+            highlighted: false,
+            selected: false,
+            grouped: false,
+            day_separator: None,
+            spoilers_revealed: false,
+            rich_text: None,
+            hovered: false,
+            own: false,
+        }
+    }
+
+    #[test]
+    fn find_matches_test() {
+        let messages: Vector<Message> = ["Hello", "world", "HELLO there", "", "say hello"]
+            .iter()
+            .map(|c| test_message(c))
+            .collect();
+        assert_eq!(find_matches(&messages, "hello"), vec![0, 2, 4]);
+        assert_eq!(find_matches(&messages, "WORLD"), vec![1]);
+        assert!(find_matches(&messages, "").is_empty());
+        assert!(find_matches(&messages, "nope").is_empty());
+    }
+
+    #[test]
+    fn step_match_test() {
+        assert_eq!(step_match(None, 0, true), None);
+        assert_eq!(step_match(Some(1), 0, false), None);
+        // Start from the newest match
+        assert_eq!(step_match(None, 3, true), Some(2));
+        assert_eq!(step_match(None, 3, false), Some(2));
+        // Wrap around
+        assert_eq!(step_match(Some(2), 3, true), Some(0));
+        assert_eq!(step_match(Some(0), 3, false), Some(2));
+        assert_eq!(step_match(Some(1), 3, false), Some(0));
+        // Selection out of range (matches changed)
+        assert_eq!(step_match(Some(5), 3, true), Some(2));
+    }
+
+    #[test]
+    fn update_find_test() {
+        let mut data = test_state();
+        route_server_command(&mut data, "a@first", &GuiCommand::Connected);
+        for content in ["foo", "bar", "foo bar"] {
+            route_server_command(
+                &mut data,
+                "a@first",
+                &GuiCommand::AddMessage(test_message(content)),
+            );
+        }
+        data.find_open = true;
+        data.find_query = Arc::new("FOO".to_string());
+        update_find(&mut data, None);
+        assert_eq!(data.find_current, Some(1));
+        let flags = |data: &AppState| {
+            ActiveServer.with(data, |s| {
+                s.messages
+                    .iter()
+                    .map(|m| (m.highlighted, m.selected))
+                    .collect::<Vec<_>>()
+            })
+        };
+        assert_eq!(
+            flags(&data),
+            vec![(true, false), (false, false), (true, true)]
+        );
+
+        update_find(&mut data, Some(true));
+        assert_eq!(data.find_current, Some(0));
+        assert_eq!(
+            flags(&data),
+            vec![(true, true), (false, false), (true, false)]
+        );
+
+        // Closing clears highlights
+        data.find_open = false;
+        update_find(&mut data, None);
+        assert_eq!(data.find_current, None);
+        assert_eq!(flags(&data), vec![(false, false); 3]);
+    }
+
+    #[test]
+    fn mention_test() {
+        assert_eq!(mention("", "user"), "@user ");
+        assert_eq!(mention("hi", "user"), "hi @user ");
+        assert_eq!(mention("hi ", "user"), "hi @user ");
+    }
+
+    #[test]
+    fn day_separator_test() {
+        use chrono::Utc;
+        let day = 24 * 60 * 60 * 1000;
+        // 2022-04-26 19:06 UTC
+        let time = 1_651_000_000_000;
+        let midnight = time - time % day + day;
+        assert_eq!(
+            day_separator(&Utc, None, time).as_deref(),
+            Some("—— April 26, 2022 ——")
+        );
+        assert_eq!(day_separator(&Utc, Some(time), midnight - 1), None);
+        assert_eq!(
+            day_separator(&Utc, Some(midnight - 1), midnight).as_deref(),
+            Some("—— April 27, 2022 ——")
+        );
+    }
+
+    #[test]
+    fn compact_time_formats() {
+        use chrono::Utc;
+        // 2022-04-26 19:06:40 UTC
+        let time = 1_651_000_000_000;
+        assert_eq!(coarse_time(&Utc, time), "19:06");
+        assert_eq!(exact_time(&Utc, time), "2022-04-26 19:06:40");
+        // Milliseconds are dropped, not rounded
+        assert_eq!(exact_time(&Utc, time + 999), "2022-04-26 19:06:40");
+        let offset = chrono::FixedOffset::east_opt(2 * 60 * 60).unwrap();
+        assert_eq!(coarse_time(&offset, time), "21:06");
+    }
+
+    #[test]
+    fn added_messages_are_grouped() {
+        let mut data = test_state();
+        route_server_command(&mut data, "a@first", &GuiCommand::Connected);
+        let mut other_sender = test_message("hey");
+        other_sender.sender_id = 2;
+        for m in [
+            test_message("hi"),
+            test_message("how are you"),
+            other_sender,
+            test_message("?"),
+        ] {
+            route_server_command(&mut data, "a@first", &GuiCommand::AddMessage(m));
+        }
+        let grouped: Vec<_> = data.servers["a@first"]
+            .messages
+            .iter()
+            .map(|m| m.grouped)
+            .collect();
+        assert_eq!(grouped, [false, true, false, false]);
+    }
+
+    #[test]
+    fn own_messages_are_marked() {
+        let mut data = test_state();
+        route_server_command(&mut data, "a@first", &GuiCommand::Connected);
+        let logged_in = GuiCommand::LoggedIn {
+            user_id: 2,
+            username: "a".to_string(),
+        };
+        route_server_command(&mut data, "a@first", &logged_in);
+        let mut own = test_message("mine");
+        own.sender_id = 2;
+        for m in [test_message("not mine"), own] {
+            route_server_command(&mut data, "a@first", &GuiCommand::AddMessage(m));
+        }
+        let own: Vec<_> = data.servers["a@first"]
+            .messages
+            .iter()
+            .map(|m| m.own)
+            .collect();
+        assert_eq!(own, [false, true]);
+    }
+
+    #[test]
+    fn contrast_ratio_test() {
+        let ratio = |a, b| contrast_ratio(a, b).unwrap();
+        assert!((ratio("#000000", "#ffffff") - 21.0).abs() < 1e-9);
+        assert!((ratio("#ffffff", "#000000") - 21.0).abs() < 1e-9);
+        assert!((ratio("#123456", "#123456") - 1.0).abs() < 1e-9);
+        // Known value: #777777 on white is 4.48:1, just below AA
+        assert!((ratio("#777777", "#ffffff") - 4.48).abs() < 0.01);
+        // Alpha is ignored
+        assert_eq!(ratio("#ff000080", "#ffffff"), ratio("#ff0000", "#ffffff"));
+        assert_eq!(contrast_ratio("not a color", "#ffffff"), None);
+
+        assert_eq!(Theme::high_contrast().contrast_warning(), None);
+        assert_eq!(Theme::default().contrast_warning(), None);
+        let low = Theme {
+            text_color1: "#333333".to_string(),
+            ..Theme::default()
+        };
+        assert!(low.contrast_warning().is_some());
+    }
+
+    #[test]
+    fn copy_payload_test() {
+        let text = test_message("hello");
+        assert_eq!(copy_payload(&text), CopyPayload::Text("hello".to_string()));
+        let image = Message {
+            is_image: true,
+            ..test_message("abc123")
+        };
+        assert_eq!(
+            copy_payload(&image),
+            CopyPayload::Image("abc123".to_string())
+        );
+        // Links are copied as text, even if shown as images
+        let link = test_message("https://example.com/a.png");
+        assert_eq!(
+            copy_payload(&link),
+            CopyPayload::Text("https://example.com/a.png".to_string())
+        );
+
+        assert_eq!(
+            image_mime(include_bytes!("resources/test-pixel-black.png")),
+            Some("image/png")
+        );
+        assert_eq!(image_mime(b"not an image"), None);
+    }
+
+    #[test]
+    fn zoom_test() {
+        assert_eq!(zoom(1.0, 1), 1.1);
+        assert_eq!(zoom(1.0, -3), 0.7);
+        // Repeated steps land on the same value
+        assert_eq!((0..5).fold(1.0, |s, _| zoom(s, 1)), 1.5);
+        assert_eq!((0..5).fold(1.5, |s, _| zoom(s, -1)), 1.0);
+        // Clamped to the range
+        assert_eq!(zoom(FONT_SCALE_RANGE.1, 1), FONT_SCALE_RANGE.1);
+        assert_eq!(zoom(FONT_SCALE_RANGE.0, -1), FONT_SCALE_RANGE.0);
+        assert_eq!(zoom(1.0, 100), FONT_SCALE_RANGE.1);
+        // Broken values from config
+        assert_eq!(clamp_font_scale(f64::NAN), 1.0);
+        assert_eq!(clamp_font_scale(0.0), FONT_SCALE_RANGE.0);
+        assert_eq!(clamp_font_scale(f64::INFINITY), FONT_SCALE_RANGE.1);
+    }
+
+    #[test]
+    fn rename_updates_history() {
+        let mut data = test_state();
+        route_server_command(&mut data, "a@first", &GuiCommand::Connected);
+        let mut other = test_message("hi");
+        other.sender = "other".to_string();
+        for m in [test_message("hello"), other] {
+            route_server_command(&mut data, "a@first", &GuiCommand::AddMessage(m));
+        }
+        route_server_command(
+            &mut data,
+            "a@first",
+            &GuiCommand::UserRenamed("user".to_string(), "newname".to_string()),
+        );
+        let senders: Vec<_> = data.servers["a@first"]
+            .messages
+            .iter()
+            .map(|m| m.sender.clone())
+            .collect();
+        assert_eq!(senders, ["newname", "other"]);
+    }
+
+    #[test]
+    fn route_server_command_test() {
+        let mut data = test_state();
+        route_server_command(&mut data, "a@first", &GuiCommand::Connected);
+        route_server_command(&mut data, "b@second", &GuiCommand::Connected);
+        assert_eq!(*data.active_server, "b@second");
+        assert_eq!(data.current_view, Views::Main);
+
+        // Messages go only to their server's buffer
+        route_server_command(
+            &mut data,
+            "a@first",
+            &GuiCommand::AddMessage(test_message("hi")),
+        );
+        assert_eq!(data.servers["a@first"].messages.len(), 1);
+        assert!(data.servers["a@first"].messages[0].rich_text.is_some());
+        assert!(data.servers["b@second"].messages.is_empty());
+        assert!(ActiveServer.with(&data, |s| s.messages.is_empty()));
+
+        // Ending active connection switches to the other one
+        route_server_command(
+            &mut data,
+            "b@second",
+            &GuiCommand::ConnectionEnded("bye".to_string()),
+        );
+        assert_eq!(*data.active_server, "a@first");
+        assert_eq!(data.current_view, Views::Main);
+        assert_eq!(ActiveServer.with(&data, |s| s.messages.len()), 1);
+
+        // Ending the last one goes back to connect view
+        route_server_command(
+            &mut data,
+            "a@first",
+            &GuiCommand::ConnectionEnded("bye".to_string()),
+        );
+        assert!(data.servers.is_empty());
+        assert_eq!(data.current_view, Views::Connect);
+    }
+
+    #[test]
+    fn window_title_test() {
+        assert_eq!(window_title(""), "accord");
+        assert_eq!(window_title("My server"), "My server - accord");
+    }
+
+    #[test]
+    fn state_from_config() {
+        let (tx, _rx) = mpsc::channel(1);
+        let config = Config {
+            address: "example.com".to_string(),
+            font_scale: 10.0,
+            ..Default::default()
+        };
+        let data = AppState::new(&config, tx);
+        assert_eq!(data.current_view, Views::Connect);
+        assert_eq!(*data.input_text1, "example.com");
+        assert_eq!(data.font_scale, FONT_SCALE_RANGE.1);
+        assert!(data.servers.is_empty());
+    }
+}
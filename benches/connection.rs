@@ -0,0 +1,160 @@
+use accord::connection::Connection;
+use accord::packets::*;
+use bytes::{Buf, BytesMut};
+use chacha20poly1305::aead::{Aead, NewAead};
+use chacha20poly1305::XChaCha20Poly1305;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use rand::SeedableRng;
+use rand_chacha::ChaCha20Rng;
+use tokio::net::{TcpListener, TcpStream};
+
+const PACKETS: usize = 1000;
+
+fn message() -> ServerboundPacket {
+    ServerboundPacket::Message("Lorem ipsum dolor sit amet, consectetur adipiscing elit".repeat(4))
+}
+
+/// Old way of moving past a read packet (copies leftover bytes into a new buffer)
+/// compared with advancing the buffer in place.
+fn buffer_handling(c: &mut Criterion) {
+    let mut data = BytesMut::new();
+    for _ in 0..PACKETS {
+        data.extend_from_slice(&message().serialized());
+    }
+    let mut group = c.benchmark_group("buffer_handling");
+    group.throughput(Throughput::Bytes(data.len() as u64));
+    group.bench_function("reassign", |b| {
+        b.iter(|| {
+            let mut buffer = data.clone();
+            while let Ok((_, rest)) = ServerboundPacket::deserialized(&buffer) {
+                buffer = BytesMut::from(rest);
+            }
+        })
+    });
+    group.bench_function("advance", |b| {
+        b.iter(|| {
+            let mut buffer = data.clone();
+            while let Ok((_, rest)) = ServerboundPacket::deserialized(&buffer) {
+                let consumed = buffer.len() - rest.len();
+                buffer.advance(consumed);
+            }
+        })
+    });
+    group.finish();
+}
+
+/// Constructing the cipher for every frame compared with reusing one per connection.
+fn cipher_per_frame(c: &mut Criterion) {
+    let key = [1u8; accord::SECRET_LEN];
+    let nonce = [2u8; accord::NONCE_LEN];
+    let packet = message().serialized();
+    let mut group = c.benchmark_group("encrypt_frame");
+    group.bench_function("new_cipher", |b| {
+        b.iter(|| {
+            let cipher = XChaCha20Poly1305::new(&key.into());
+            cipher.encrypt(&nonce.into(), &packet[..]).unwrap()
+        })
+    });
+    let cipher = XChaCha20Poly1305::new(&key.into());
+    group.bench_function("cached_cipher", |b| {
+        b.iter(|| cipher.encrypt(&nonce.into(), &packet[..]).unwrap())
+    });
+    group.finish();
+}
+
+/// Sending 50 history messages, flushing after each one compared with flushing once.
+fn history_replay(c: &mut Criterion) {
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let (mut writer, _reader) = rt.block_on(async {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = TcpStream::connect(addr).await.unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        let (_, writer) = Connection::<ServerboundPacket, ServerboundPacket>::new(client).split();
+        // Drain everything written, so the writer never blocks
+        let reader = tokio::spawn(async move {
+            let (mut reader, _) =
+                Connection::<ServerboundPacket, ServerboundPacket>::new(server).split();
+            while reader.read_packet(&None, None).await.is_ok() {}
+        });
+        (writer, reader)
+    });
+    let history: Vec<_> = (0..50).map(|_| message()).collect();
+
+    let mut group = c.benchmark_group("history_50");
+    group.bench_function("flush_per_packet", |b| {
+        b.iter(|| {
+            rt.block_on(async {
+                for p in history.clone() {
+                    writer.write_packet(p, &None, None).await.unwrap();
+                }
+            })
+        })
+    });
+    group.bench_function("batched", |b| {
+        b.iter(|| {
+            rt.block_on(writer.write_packets(history.clone(), &None, None))
+                .unwrap()
+        })
+    });
+    group.finish();
+}
+
+/// Writes `PACKETS` packets over loopback and reads them back with [`ConnectionReader`].
+async fn read_packets(encrypted: bool) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let secret = if encrypted {
+        Some(vec![1u8; accord::SECRET_LEN])
+    } else {
+        None
+    };
+
+    let writer_secret = secret.clone();
+    let writer = tokio::spawn(async move {
+        let (socket, _) = listener.accept().await.unwrap();
+        let (_, mut writer) =
+            Connection::<ServerboundPacket, ServerboundPacket>::new(socket).split();
+        let mut nonces = ChaCha20Rng::from_seed([1u8; accord::SECRET_LEN]);
+        for _ in 0..PACKETS {
+            let nonces = writer_secret.as_ref().map(|_| &mut nonces);
+            writer
+                .write_packet(message(), &writer_secret, nonces)
+                .await
+                .unwrap();
+        }
+    });
+
+    let socket = TcpStream::connect(addr).await.unwrap();
+    let (mut reader, _) = Connection::<ServerboundPacket, ServerboundPacket>::new(socket).split();
+    let mut nonces = ChaCha20Rng::from_seed([1u8; accord::SECRET_LEN]);
+    for _ in 0..PACKETS {
+        let nonces = secret.as_ref().map(|_| &mut nonces);
+        reader.read_packet(&secret, nonces).await.unwrap().unwrap();
+    }
+    writer.await.unwrap();
+}
+
+fn read_throughput(c: &mut Criterion) {
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let bytes = (message().serialized().len() * PACKETS) as u64;
+    let mut group = c.benchmark_group("read_packet");
+    group.throughput(Throughput::Bytes(bytes));
+    for encrypted in [false, true] {
+        group.bench_with_input(
+            BenchmarkId::from_parameter(if encrypted { "encrypted" } else { "plaintext" }),
+            &encrypted,
+            |b, &encrypted| b.iter(|| rt.block_on(read_packets(encrypted))),
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    buffer_handling,
+    cipher_per_frame,
+    history_replay,
+    read_throughput
+);
+criterion_main!(benches);
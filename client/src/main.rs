@@ -1,40 +1,64 @@
-use chrono::TimeZone;
+use std::cell::Cell;
+use std::collections::VecDeque;
 use std::str::FromStr;
 use tokio::io::AsyncReadExt;
-use tokio::net::TcpStream;
 
 use accord::connection::*;
 
 use accord::packets::*;
 
-use accord::{ENC_TOK_LEN, SECRET_LEN};
+use accord::client_config::{establish_encryption, ClientConfig, InitError};
+use accord::utils::MAX_FETCH_COUNT;
 
 use std::net::SocketAddr;
 
 use tokio::sync::oneshot;
 
-use rand::{rngs::OsRng, Rng, SeedableRng};
 use rand_chacha::ChaCha20Rng;
 
-use rsa::PaddingScheme;
-use rsa::PublicKey;
-
 // TODO: config file?
 
+/// Environment variable with path to [`ClientConfig`] file (TOML), `--config` flag takes precedence over it
+const CLIENT_CONFIG_VAR: &str = "ACCORD_CLIENT_CONFIG";
+/// Environment variable with max number of messages fetched at once, overrides config
+const HISTORY_CHUNK_VAR: &str = "ACCORD_HISTORY_CHUNK";
+
 #[tokio::main(flavor = "current_thread")]
 async fn main() {
     //==================================
     //      Parse args
     //==================================
-    let mut args = std::env::args();
+    let mut args: Vec<String> = std::env::args().collect();
+    let config_path = accord::utils::take_flag(&mut args, "--config")
+        .or_else(|| std::env::var(CLIENT_CONFIG_VAR).ok());
     let addr = SocketAddr::from_str(&format!(
         "{}:{}",
-        args.nth(1).unwrap_or_else(|| "127.0.0.1".to_string()),
+        args.get(1).map_or("127.0.0.1", |s| s.as_str()),
         accord::DEFAULT_PORT
     ))
     .unwrap();
+    let mut config = match config_path {
+        Some(path) => ClientConfig::from_file(&path).unwrap_or_else(|e| {
+            println!("Failed to load client config from {}: {}", path, e);
+            std::process::exit(1)
+        }),
+        None => ClientConfig::default(),
+    };
+    if let Some(chunk_size) = std::env::var(HISTORY_CHUNK_VAR)
+        .ok()
+        .and_then(|s| s.parse().ok())
+    {
+        config.history_chunk_size = chunk_size;
+    }
+
     println!("Connecting to: {}", addr);
-    let socket = TcpStream::connect(addr).await.unwrap();
+    let socket = match config.connect(addr).await {
+        Ok(socket) => socket,
+        Err(e) => {
+            println!("{}", InitError::ConnectFailed(e));
+            std::process::exit(1)
+        }
+    };
 
     println!("Connected!");
     let connection = Connection::<ClientboundPacket, ServerboundPacket>::new(socket);
@@ -44,89 +68,15 @@ async fn main() {
     //      Encryption
     //==================================
     println!("Establishing encryption...");
-    let secret = None;
-    let mut nonce_generator_write = None;
-    let mut nonce_generator_read = None;
-
-    // Request encryption
-    writer
-        .write_packet(
-            ServerboundPacket::EncryptionRequest,
-            &secret,
-            nonce_generator_write.as_mut(),
-        )
-        .await
-        .unwrap();
-
-    // Handle encryption response
-    let pub_key: rsa::RsaPublicKey;
-    let token = if let Ok(Some(p)) = reader
-        .read_packet(&secret, nonce_generator_read.as_mut())
-        .await
-    {
-        match p {
-            ClientboundPacket::EncryptionResponse(pub_key_der, token_) => {
-                println!("Encryption step 1 successful");
-                pub_key = rsa::pkcs8::FromPublicKey::from_public_key_der(&pub_key_der).unwrap();
-                assert_eq!(ENC_TOK_LEN, token_.len());
-                token_
-            }
-            _ => {
-                println!("Encryption failed. Server response: {:?}", p);
+    let (secret, mut nonce_generator_write, mut nonce_generator_read) =
+        match establish_encryption(&mut reader, &mut writer).await {
+            Ok((secret, write, read)) => (Some(secret), Some(write), Some(read)),
+            Err(e) => {
+                println!("{}", e);
                 std::process::exit(1)
             }
-        }
-    } else {
-        println!("Failed to establish encryption");
-        std::process::exit(1)
-    };
-
-    // Generate secret
-    let mut secret = [0u8; SECRET_LEN];
-    OsRng.fill(&mut secret);
-
-    // Encrypt and send
-    let padding = PaddingScheme::new_pkcs1v15_encrypt();
-    let enc_secret = pub_key
-        .encrypt(&mut OsRng, padding, &secret[..])
-        .expect("failed to encrypt");
-    let padding = PaddingScheme::new_pkcs1v15_encrypt();
-    let enc_token = pub_key
-        .encrypt(&mut OsRng, padding, &token[..])
-        .expect("failed to encrypt");
-    writer
-        .write_packet(
-            ServerboundPacket::EncryptionConfirm(enc_secret, enc_token),
-            &None,
-            nonce_generator_write.as_mut(),
-        )
-        .await
-        .unwrap();
-
-    // From this point onward we assume everything is encrypted
-    let secret = Some(secret.to_vec());
-    let mut seed = [0u8; accord::SECRET_LEN];
-    seed.copy_from_slice(&secret.as_ref().unwrap()[..]);
-    nonce_generator_write = Some(ChaCha20Rng::from_seed(seed));
-    nonce_generator_read = Some(ChaCha20Rng::from_seed(seed));
-
-    // Expect EncryptionAck (should be encrypted)
-    let p = reader
-        .read_packet(&secret, nonce_generator_read.as_mut())
-        .await;
-    match p {
-        Ok(Some(ClientboundPacket::EncryptionAck)) => {
-            println!("Encryption handshake successful!");
-        }
-        Ok(_) => {
-            println!("Failed encryption step 2. Server response: {:?}", p);
-            std::process::exit(1);
-        }
-        Err(e) => {
-            println!("{}", e);
-            std::process::exit(1);
-        }
-    }
+        };
+    println!("Encryption handshake successful!");
 
     //==================================
     //      Get credentials
@@ -137,16 +87,14 @@ async fn main() {
         let mut buf = bytes::BytesMut::new();
         match stdio.read_buf(&mut buf).await {
             Ok(0 | 1) => println!("Username can't be empty!"),
-            Ok(l) => {
-                if l > 18 {
-                    println!("Username too long. (Max 17 characters)");
-                    continue;
-                }
-                let s = String::from_utf8_lossy(buf.strip_suffix(b"\n").unwrap()).to_string();
-                if s.chars().any(|c| !c.is_alphanumeric()) {
-                    println!("Invalid characters in username.");
-                } else {
-                    break s;
+            Ok(_) => {
+                let s = String::from_utf8_lossy(&buf);
+                let s = accord::utils::trim_line_ending(&s).to_string();
+                // Server checks username against its own policy and tells us why it's invalid,
+                // so only obviously invalid ones are rejected here
+                match accord::utils::validate_message(&s) {
+                    Ok(()) => break s,
+                    Err(e) => println!("Invalid username: {}.", e),
                 }
             }
             Err(e) => println!("Error: {:?}", e),
@@ -158,8 +106,11 @@ async fn main() {
         match stdio.read_buf(&mut buf).await {
             Ok(0 | 1) => println!("Password can't be empty!"),
             Ok(_) => {
-                let s = String::from_utf8_lossy(buf.strip_suffix(b"\n").unwrap()).to_string();
-                if s.chars().any(|c| !c.is_alphanumeric()) {
+                let s = String::from_utf8_lossy(&buf);
+                let s = accord::utils::trim_line_ending(&s).to_string();
+                if s.is_empty() {
+                    println!("Password can't be empty!");
+                } else if s.chars().any(|c| !c.is_alphanumeric()) {
                     println!("Invalid characters in password.");
                 } else {
                     break s;
@@ -183,18 +134,23 @@ async fn main() {
         .unwrap();
 
     // Next packet must be login related
-    if let Ok(Some(p)) = reader
+    let user_id = if let Ok(Some(p)) = reader
         .read_packet(&secret, nonce_generator_read.as_mut())
         .await
     {
         match p {
-            ClientboundPacket::LoginAck => {
-                println!("Login successful");
+            ClientboundPacket::LoginAck { user_id, username } => {
+                println!("Logged in as {}.", username);
+                user_id
             }
             ClientboundPacket::LoginFailed(m) => {
                 println!("{}", m);
                 std::process::exit(1);
             }
+            ClientboundPacket::Disconnect(reason) => {
+                println!("Disconnected: {}", reason);
+                std::process::exit(1);
+            }
             _ => {
                 println!("Login failed. Server response: {:?}", p);
                 std::process::exit(1);
@@ -203,7 +159,7 @@ async fn main() {
     } else {
         println!("Failed to login ;/");
         std::process::exit(1);
-    }
+    };
 
     // Get player list on join
     writer
@@ -215,47 +171,165 @@ async fn main() {
         .await
         .unwrap();
 
-    // Get last 20 messages
-    writer
-        .write_packet(
-            ServerboundPacket::FetchMessages(0, 20),
-            &secret,
-            nonce_generator_write.as_mut(),
-        )
-        .await
-        .unwrap();
+    // Get last messages, in chunks so slow terminals show them gradually
+    for (offset, count) in config.history_chunks() {
+        writer
+            .write_packet(
+                ServerboundPacket::FetchMessages(offset, count),
+                &secret,
+                nonce_generator_write.as_mut(),
+            )
+            .await
+            .unwrap();
+    }
 
     // To send close command when tcpstream is closed
     let (tx, rx) = oneshot::channel::<()>();
 
+    let display_settings = DisplaySettings::default();
+    let session = Session {
+        user_id,
+        ..Default::default()
+    };
+
     tokio::join!(
-        reading_loop(reader, tx, secret.clone(), nonce_generator_read),
-        writing_loop(writer, rx, secret.clone(), nonce_generator_write)
+        reading_loop(
+            reader,
+            tx,
+            secret.clone(),
+            nonce_generator_read,
+            &display_settings,
+            &session
+        ),
+        writing_loop(
+            writer,
+            rx,
+            secret.clone(),
+            nonce_generator_write,
+            config.ping_interval(),
+            &display_settings,
+            &session
+        )
     );
 }
 
+/// Local display settings, toggled with commands
+#[derive(Default)]
+struct DisplaySettings {
+    /// Show message times relative to now ("/reltime")
+    relative_times: Cell<bool>,
+    /// Make links clickable with OSC 8 escape sequences ("/links")
+    hyperlinks: Cell<bool>,
+    /// Show text of spoilers instead of hiding it ("/spoilers")
+    spoilers: Cell<bool>,
+}
+
+/// What the server told us about this session
+#[derive(Default)]
+struct Session {
+    /// Id of the logged in user, to tell apart own messages
+    user_id: i64,
+    /// Operator-only commands aren't sent if we aren't one
+    operator: Cell<bool>,
+    /// Next batch of messages is a response to "/history" and is shown as a block
+    history_requested: Cell<bool>,
+}
+
 async fn reading_loop(
     mut reader: ConnectionReader<ClientboundPacket>,
     close_sender: oneshot::Sender<()>,
     secret: Option<Vec<u8>>,
     mut nonce_generator: Option<ChaCha20Rng>,
+    display_settings: &DisplaySettings,
+    session: &Session,
 ) {
+    // Sender id and time of last printed message, for grouping
+    let mut last_message: Option<(i64, i64)> = None;
+    // Time of last printed message, for day separators
+    let mut last_time: Option<i64> = None;
+    // Packets unpacked from a batch, handled before reading more
+    let mut pending = VecDeque::new();
+    let mut warned_about_clock = false;
+    // Messages of "/history" block left to print
+    let mut history_left: Option<usize> = None;
     'l: loop {
-        match reader.read_packet(&secret, nonce_generator.as_mut()).await {
-            Ok(Some(ClientboundPacket::Message(Message {
-                text,
-                sender_id: _sender_id,
-                sender,
-                time,
-            }))) => {
-                let time = chrono::Local.timestamp(time as i64, 0);
-                println!("{} ({}): {}", sender, time.format("%H:%M %d-%m"), text);
+        if history_left == Some(0) {
+            println!("———————————");
+            history_left = None;
+            last_message = None;
+        }
+        let packet = match pending.pop_front() {
+            Some(p) => {
+                if let Some(left) = history_left.as_mut() {
+                    *left -= 1;
+                }
+                Ok(Some(p))
+            }
+            None => reader.read_packet(&secret, nonce_generator.as_mut()).await,
+        };
+        match packet {
+            Ok(Some(
+                ClientboundPacket::Message(Message {
+                    text,
+                    sender_id,
+                    sender,
+                    time,
+                    sender_color,
+                })
+                | ClientboundPacket::CommandResponse(Message {
+                    text,
+                    sender_id,
+                    sender,
+                    time,
+                    sender_color,
+                }),
+            )) => {
+                warn_if_from_future(time, &mut warned_about_clock);
+                if let Some(separator) = day_separator(&chrono::Local, last_time, time) {
+                    println!("{}", separator);
+                    last_message = None;
+                }
+                last_time = Some(time);
+                let grouped = last_message.is_some_and(|(last_sender_id, last_time)| {
+                    accord::utils::continues_group(last_sender_id, last_time, sender_id, time)
+                });
+                let text = render_text(&text, display_settings);
+                // Own messages stand out
+                let text = if accord::utils::is_own_message(sender_id, Some(session.user_id)) {
+                    format!("\u{1b}[36m{}\u{1b}[39m", text)
+                } else {
+                    text
+                };
+                if sender_id == accord::packets::SERVER_SENDER_ID {
+                    println!(
+                        "* Server ({}): {}",
+                        message_time(time, display_settings.relative_times.get()),
+                        text
+                    );
+                } else if grouped {
+                    // Sender and time are in the line above
+                    println!("  {}", text);
+                } else {
+                    println!(
+                        "{} ({}): {}",
+                        colored_name(&sender, sender_color.as_deref()),
+                        message_time(time, display_settings.relative_times.get()),
+                        text
+                    );
+                }
+                last_message = Some((sender_id, time));
             }
             Ok(Some(ClientboundPacket::UserJoined(username))) => {
                 println!("{} joined the channel", username);
+                last_message = None;
             }
             Ok(Some(ClientboundPacket::UserLeft(username))) => {
                 println!("{} left the channel", username);
+                last_message = None;
+            }
+            Ok(Some(ClientboundPacket::UserRenamed { old, new })) => {
+                println!("{} is now known as {}", old, new);
+                last_message = None;
             }
             Ok(Some(ClientboundPacket::UsersOnline(usernames))) => {
                 println!("-------------");
@@ -265,13 +339,51 @@ async fn reading_loop(
                 }
                 println!("-------------");
             }
+            Ok(Some(ClientboundPacket::ServerInfo { name, description })) => {
+                // Set terminal title
+                print!("\u{1b}]0;{}\u{7}", server_title(&name));
+                if description.is_empty() {
+                    println!("Connected to {}", name);
+                } else {
+                    println!("Connected to {}: {}", name, description);
+                }
+            }
             Ok(Some(ClientboundPacket::ImageMessage(im))) => {
-                let time = chrono::Local.timestamp(im.time as i64, 0);
+                warn_if_from_future(im.time, &mut warned_about_clock);
+                if let Some(separator) = day_separator(&chrono::Local, last_time, im.time) {
+                    println!("{}", separator);
+                }
+                last_time = Some(im.time);
                 println!(
                     "{} sent an image. ({})",
-                    im.sender,
-                    time.format("%H:%M %d-%m")
-                )
+                    colored_name(&im.sender, im.sender_color.as_deref()),
+                    message_time(im.time, display_settings.relative_times.get())
+                );
+                last_message = None;
+            }
+            Ok(Some(ClientboundPacket::YourPermissions(permissions))) => {
+                session.operator.set(permissions.operator);
+                if permissions.operator {
+                    println!("You are an operator.");
+                }
+            }
+            Ok(Some(ClientboundPacket::MessageRejected { reason })) => {
+                println!("Message not sent: {}", reason);
+                last_message = None;
+            }
+            Ok(Some(ClientboundPacket::MessageBatch(packets))) => {
+                if session.history_requested.replace(false) {
+                    println!("——— Last {} message(s) ———", packets.len());
+                    history_left = Some(pending.len() + packets.len());
+                    last_message = None;
+                }
+                pending.extend(packets);
+            }
+            Ok(Some(ClientboundPacket::Pong)) => (),
+            Ok(Some(ClientboundPacket::Disconnect(reason))) => {
+                println!("Disconnected: {}\nPress Enter to exit.", reason);
+                close_sender.send(()).unwrap();
+                break 'l;
             }
             Ok(Some(p)) => {
                 println!("!!Unhandled packet: {:?}", p);
@@ -295,21 +407,50 @@ async fn writing_loop(
     mut close_receiver: oneshot::Receiver<()>,
     secret: Option<Vec<u8>>,
     mut nonce_generator: Option<ChaCha20Rng>,
+    ping_interval: Option<std::time::Duration>,
+    display_settings: &DisplaySettings,
+    session: &Session,
 ) {
     let mut stdio = tokio::io::stdin();
     let mut buf = bytes::BytesMut::new();
+    let mut ping = ping_interval
+        .map(|period| tokio::time::interval_at(tokio::time::Instant::now() + period, period));
+    // Code block being typed, started with "```"
+    let mut code_block: Option<String> = None;
     loop {
         tokio::select!(
             r = stdio.read_buf(&mut buf) => {
                 if r.is_ok() {
                     let s = String::from_utf8_lossy(&buf).to_string();
 
-                    if let Some(s) = s.strip_suffix('\n') {
+                    if s.ends_with('\n') {
+                        let s = accord::utils::trim_line_ending(&s);
                         buf.clear();
+                        // Lines of a code block are collected until the closing fence
+                        let block;
+                        let s = match code_block.take() {
+                            Some(mut lines) => {
+                                lines.push('\n');
+                                lines.push_str(s);
+                                if !s.trim_end().ends_with("```") {
+                                    code_block = Some(lines);
+                                    continue;
+                                }
+                                block = lines;
+                                block.as_str()
+                            }
+                            None if s.starts_with("```") && !s[3..].contains("```") => {
+                                code_block = Some(s.to_string());
+                                continue;
+                            }
+                            None => s,
+                        };
                         // Clear input line
                         print!("\r\u{1b}[A");
-                        if s.chars().any(|c| c.is_control()) {
-                            println!("Invalid message text!");
+                        if let Err(accord::utils::ValidationError::ControlCharacter) =
+                            accord::utils::validate_message(s)
+                        {
+                            println!("Invalid message text: contains control characters.");
                             continue;
                         }
 
@@ -318,18 +459,371 @@ async fn writing_loop(
                             continue;
                         }
 
-                        let p = if let Some(command) = s.strip_prefix('/') {
+                        if s == "/reltime" {
+                            let relative_times = &display_settings.relative_times;
+                            relative_times.set(!relative_times.get());
+                            if relative_times.get() {
+                                println!("Showing relative message times.");
+                            } else {
+                                println!("Showing absolute message times.");
+                            }
+                            continue;
+                        }
+
+                        if s == "/links" {
+                            let hyperlinks = &display_settings.hyperlinks;
+                            hyperlinks.set(!hyperlinks.get());
+                            if hyperlinks.get() {
+                                println!("Links are clickable (if terminal supports it).");
+                            } else {
+                                println!("Links are plain text.");
+                            }
+                            continue;
+                        }
+
+                        if s == "/spoilers" {
+                            let spoilers = &display_settings.spoilers;
+                            spoilers.set(!spoilers.get());
+                            if spoilers.get() {
+                                println!("Showing spoilers.");
+                            } else {
+                                println!("Hiding spoilers.");
+                            }
+                            continue;
+                        }
+
+                        let p = if let Some(date) = s.strip_prefix("/goto ") {
+                            match parse_goto_date(&chrono::Local, date) {
+                                Some(time) => ServerboundPacket::FetchMessagesAround { time, count: 20 },
+                                None => {
+                                    println!("Invalid date. Expected YYYY-MM-DD or \"YYYY-MM-DD HH:MM\".");
+                                    continue;
+                                }
+                            }
+                        } else if s == "/history" || s.starts_with("/history ") {
+                            // Handled here, not a server command
+                            match parse_history_count(s.trim_start_matches("/history")) {
+                                Some(count) => {
+                                    session.history_requested.set(true);
+                                    ServerboundPacket::FetchMessages(0, count)
+                                }
+                                None => {
+                                    println!("Usage: /history <count> (at most {})", MAX_FETCH_COUNT);
+                                    continue;
+                                }
+                            }
+                        } else if s == "/color" || s.starts_with("/color ") {
+                            // Empty resets the color
+                            let color = s.trim_start_matches("/color").trim();
+                            if !color.is_empty() && accord::utils::hex_color_rgb(color).is_none() {
+                                println!("Invalid color. Expected #rrggbb.");
+                                continue;
+                            }
+                            ServerboundPacket::SetColor(color.to_string())
+                        } else if let Some(command) = s.strip_prefix('/') {
+                            let name = command.split(' ').next().unwrap_or_default();
+                            if !session.operator.get() && OPERATOR_COMMANDS.contains(&name) {
+                                println!("Only operators can use /{}.", name);
+                                continue;
+                            }
                             ServerboundPacket::Command(command.to_string())
                         } else {
-                            ServerboundPacket::Message(s.to_string())
+                            ServerboundPacket::Message(accord::utils::expand_shortcodes(s))
                         };
                         writer.write_packet(p, &secret, nonce_generator.as_mut()).await.unwrap();
                     }
                 }
             }
+            _ = async { ping.as_mut().unwrap().tick().await }, if ping.is_some() => {
+                // Keeps the connection alive
+                writer.write_packet(ServerboundPacket::Ping, &secret, nonce_generator.as_mut()).await.unwrap();
+            }
             _ = &mut close_receiver => {
                 break;
             }
         );
     }
 }
+
+/// Warns (once) about message dated in the future, which means server's clock is off
+fn warn_if_from_future(time: i64, warned: &mut bool) {
+    if !*warned && accord::utils::is_from_future(time, chrono::Utc::now().timestamp_millis()) {
+        println!("Warning: messages are dated in the future, server's clock may be wrong.");
+        *warned = true;
+    }
+}
+
+/// Colors `name` with sender's color (24-bit escape sequence), if they picked a valid one
+fn colored_name(name: &str, color: Option<&str>) -> String {
+    match color.and_then(accord::utils::hex_color_rgb) {
+        Some((r, g, b)) => format!("\u{1b}[38;2;{};{};{}m{}\u{1b}[0m", r, g, b, name),
+        None => name.to_string(),
+    }
+}
+
+/// Wraps links in text in OSC 8 escape sequences,
+/// so terminals that support them make links clickable.
+///
+/// Other terminals should ignore these sequences and show plain text.
+fn hyperlink_urls(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut last = 0;
+    for (start, url) in accord::utils::extract_urls(text) {
+        result.push_str(&text[last..start]);
+        result.push_str(&format!(
+            "\u{1b}]8;;{}\u{1b}\\{}\u{1b}]8;;\u{1b}\\",
+            url, url
+        ));
+        last = start + url.len();
+    }
+    result.push_str(&text[last..]);
+    result
+}
+
+/// Renders message text for the terminal.
+///
+/// Code blocks are put on separate lines and colored,
+/// spoilers and links in the rest are shown according to `display_settings`.
+fn render_text(text: &str, display_settings: &DisplaySettings) -> String {
+    use accord::markdown::Block;
+    let mut rendered = String::with_capacity(text.len());
+    // Text after a code block has to start on a new line
+    let mut after_code = false;
+    for block in accord::markdown::split_code_blocks(text) {
+        match block {
+            Block::Text(text) => {
+                if after_code && !text.starts_with('\n') {
+                    rendered.push('\n');
+                }
+                let text = render_spoilers(text, display_settings.spoilers.get());
+                if display_settings.hyperlinks.get() {
+                    rendered.push_str(&hyperlink_urls(&text));
+                } else {
+                    rendered.push_str(&text);
+                }
+                after_code = false;
+            }
+            Block::Code { language, code } => {
+                if !rendered.ends_with('\n') {
+                    rendered.push('\n');
+                }
+                rendered.push_str(&format!("  ┌ {}", language.unwrap_or("code")));
+                for line in code.lines() {
+                    rendered.push_str(&format!("\n  │ \u{1b}[36m{}\u{1b}[39m", line));
+                }
+                after_code = true;
+            }
+        }
+    }
+    rendered
+}
+
+/// Replaces spoilers (see [`accord::utils::split_spoilers`]) with blocks, unless `show`,
+/// in which case they are shown in reverse video.
+fn render_spoilers(text: &str, show: bool) -> String {
+    accord::utils::split_spoilers(text)
+        .into_iter()
+        .map(|(part, is_spoiler)| match (is_spoiler, show) {
+            (false, _) => part.to_string(),
+            (true, false) => "█".repeat(part.chars().count()),
+            (true, true) => format!("\u{1b}[7m{}\u{1b}[27m", part),
+        })
+        .collect()
+}
+
+/// Formats message time (milliseconds since unix epoch) in local timezone,
+/// or relative to now if `relative`
+fn message_time(time: i64, relative: bool) -> String {
+    if relative {
+        accord::utils::format_relative(time, chrono::Utc::now().timestamp_millis())
+    } else {
+        format_time(&chrono::Local, time)
+    }
+}
+
+/// Formats message time (milliseconds since unix epoch) in given timezone
+fn format_time<Tz: chrono::TimeZone>(tz: &Tz, time: i64) -> String
+where
+    Tz::Offset: std::fmt::Display,
+{
+    tz.timestamp_millis_opt(time)
+        .unwrap()
+        .format("%H:%M %d-%m")
+        .to_string()
+}
+
+/// Returns separator (e.g. "—— March 3, 2022 ——") to show before message sent at `time`,
+/// if it was sent on a different day (in given timezone) than previous message.
+fn day_separator<Tz: chrono::TimeZone>(tz: &Tz, prev_time: Option<i64>, time: i64) -> Option<String>
+where
+    Tz::Offset: std::fmt::Display,
+{
+    let date = tz.timestamp_millis_opt(time).unwrap().date_naive();
+    let prev_date = prev_time.map(|t| tz.timestamp_millis_opt(t).unwrap().date_naive());
+    if prev_date == Some(date) {
+        None
+    } else {
+        Some(format!("—— {} ——", date.format("%B %-d, %Y")))
+    }
+}
+
+/// Parses date given to `/goto` ("YYYY-MM-DD" or "YYYY-MM-DD HH:MM") in given timezone.
+///
+/// Returns milliseconds since unix epoch.
+fn parse_goto_date<Tz: chrono::TimeZone>(tz: &Tz, s: &str) -> Option<i64> {
+    let s = s.trim();
+    let date_time = chrono::NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M")
+        .ok()
+        .or_else(|| {
+            chrono::NaiveDate::parse_from_str(s, "%Y-%m-%d")
+                .ok()
+                .and_then(|d| d.and_hms_opt(0, 0, 0))
+        })?;
+    tz.from_local_datetime(&date_time)
+        .earliest()
+        .map(|t| t.timestamp_millis())
+}
+
+/// Parses count of messages given to `/history`, clamped to [`MAX_FETCH_COUNT`].
+///
+/// Returns `None` if it's not a positive number.
+fn parse_history_count(s: &str) -> Option<i64> {
+    match s.trim().parse::<u64>() {
+        Ok(0) => None,
+        Ok(count) => Some(count.min(MAX_FETCH_COUNT as u64) as i64),
+        // Too big for u64 is still a valid (very big) count
+        Err(e) if *e.kind() == std::num::IntErrorKind::PosOverflow => Some(MAX_FETCH_COUNT),
+        Err(_) => None,
+    }
+}
+
+/// Title of the terminal window when connected to server with this name.
+///
+/// Control characters are dropped, so the name can't end the title sequence.
+fn server_title(name: &str) -> String {
+    let name: String = name.chars().filter(|c| !c.is_control()).collect();
+    format!("{} - accord", name)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        day_separator, format_time, hyperlink_urls, parse_goto_date, parse_history_count,
+        render_spoilers, render_text, server_title, DisplaySettings,
+    };
+    use accord::utils::MAX_FETCH_COUNT;
+    use chrono::Utc;
+
+    #[test]
+    fn hyperlink_urls_test() {
+        assert_eq!(hyperlink_urls("no links"), "no links");
+        assert_eq!(
+            hyperlink_urls("see (https://example.com/a)."),
+            "see (\u{1b}]8;;https://example.com/a\u{1b}\\https://example.com/a\u{1b}]8;;\u{1b}\\)."
+        );
+        let text = hyperlink_urls("http://a.org and http://b.org");
+        assert_eq!(text.matches("\u{1b}]8;;\u{1b}\\").count(), 2);
+        assert!(text.starts_with("\u{1b}]8;;http://a.org\u{1b}\\http://a.org"));
+        assert!(text.contains(" and "));
+    }
+
+    #[test]
+    fn render_spoilers_test() {
+        assert_eq!(render_spoilers("it's ||him||!", false), "it's ███!");
+        assert_eq!(
+            render_spoilers("it's ||him||!", true),
+            "it's \u{1b}[7mhim\u{1b}[27m!"
+        );
+        assert_eq!(render_spoilers("a || b", false), "a || b");
+    }
+
+    #[test]
+    fn render_text_test() {
+        let settings = DisplaySettings::default();
+        assert_eq!(render_text("plain", &settings), "plain");
+        assert_eq!(
+            render_text("see:\n```rust\nlet a;\n  a = 1;\n```\ndone", &settings),
+            "see:\n  ┌ rust\n  │ \u{1b}[36mlet a;\u{1b}[39m\n  │ \u{1b}[36m  a = 1;\u{1b}[39m\ndone"
+        );
+        // Block inline with text still gets its own lines
+        assert_eq!(
+            render_text("a ```b``` c", &settings),
+            "a \n  ┌ code\n  │ \u{1b}[36mb\u{1b}[39m\n c"
+        );
+        // Spoilers inside code are not hidden
+        assert!(render_text("```||x||```", &settings).contains("||x||"));
+    }
+
+    #[test]
+    fn day_separator_test() {
+        let day = 24 * 60 * 60 * 1000;
+        // 2022-04-26 19:06 UTC
+        let time = 1_651_000_000_000;
+        assert_eq!(
+            day_separator(&Utc, None, time).as_deref(),
+            Some("—— April 26, 2022 ——")
+        );
+        assert_eq!(day_separator(&Utc, Some(time - 60_000), time), None);
+        // Just before midnight and just after
+        let midnight = time - time % day + day;
+        assert_eq!(day_separator(&Utc, Some(time), midnight - 1), None);
+        assert_eq!(
+            day_separator(&Utc, Some(midnight - 1), midnight).as_deref(),
+            Some("—— April 27, 2022 ——")
+        );
+        // Same UTC day, but different days in UTC+3
+        let tz = chrono::FixedOffset::east_opt(3 * 60 * 60).unwrap();
+        let tz_midnight = midnight - 3 * 60 * 60 * 1000;
+        assert!(day_separator(&tz, Some(tz_midnight - 1), tz_midnight).is_some());
+        assert_eq!(day_separator(&tz, Some(midnight - 1), midnight), None);
+    }
+
+    #[test]
+    fn format_time_test() {
+        assert_eq!(format_time(&Utc, 0), "00:00 01-01");
+        assert_eq!(format_time(&Utc, 1_651_000_000_000), "19:06 26-04");
+        assert_eq!(format_time(&Utc, 1_651_000_059_999), "19:07 26-04");
+        // Before the epoch
+        assert_eq!(format_time(&Utc, -60_000), "23:59 31-12");
+        // Past year 2262
+        assert_eq!(format_time(&Utc, 9_300_000_000_000), "21:20 14-09");
+    }
+
+    #[test]
+    fn parse_goto_date_test() {
+        assert_eq!(
+            parse_goto_date(&Utc, "2022-04-26 19:06"),
+            Some(1_650_999_960_000)
+        );
+        assert_eq!(
+            parse_goto_date(&Utc, " 2022-04-26 "),
+            Some(1_650_931_200_000)
+        );
+        assert_eq!(parse_goto_date(&Utc, "26-04-2022"), None);
+        assert_eq!(parse_goto_date(&Utc, "2022-13-01"), None);
+        assert_eq!(parse_goto_date(&Utc, ""), None);
+    }
+
+    #[test]
+    fn server_title_test() {
+        assert_eq!(server_title("My server"), "My server - accord");
+        assert_eq!(
+            server_title("evil\u{7}\u{1b}]0;pwned\u{9c}\n"),
+            "evil]0;pwned - accord"
+        );
+    }
+
+    #[test]
+    fn history_count_test() {
+        assert_eq!(parse_history_count(" 10"), Some(10));
+        assert_eq!(parse_history_count(" 1000"), Some(MAX_FETCH_COUNT));
+        assert_eq!(
+            parse_history_count(" 99999999999999999999999"),
+            Some(MAX_FETCH_COUNT)
+        );
+        assert_eq!(parse_history_count(""), None);
+        assert_eq!(parse_history_count(" 0"), None);
+        assert_eq!(parse_history_count(" -5"), None);
+        assert_eq!(parse_history_count(" ten"), None);
+    }
+}
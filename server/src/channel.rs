@@ -1,121 +1,198 @@
 use accord::packets::*;
-use accord::utils::verify_username;
 use accord::{ENC_TOK_LEN, RSA_BITS};
 
+use accord::utils::MAX_FETCH_COUNT;
 use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, OnceLock};
 use tokio::sync::mpsc::{Receiver, Sender};
 
-use tokio_postgres::{Client as DBClient, NoTls};
+use tokio_postgres::Client as DBClient;
 
-use crate::config::{save_config, Config};
+use crate::config::{Config, ConfigSaver, WhitelistMode};
+use crate::connection::ConnectionStats;
+use crate::image_quota::ImageQuota;
+use crate::login_throttle::LoginThrottle;
 
 use super::commands::*;
 
 use rand::rngs::OsRng;
 use rand::Rng;
-use rand::RngCore;
 use rand::SeedableRng;
 use rand_chacha::ChaCha20Rng;
 use rsa::{pkcs8::ToPublicKey, PaddingScheme, RsaPrivateKey, RsaPublicKey};
 
 use anyhow::{Context, Result};
+use argon2::password_hash::SaltString;
+
+/// Usernames of logged in users by their address,
+/// with a sorted list of them kept until someone joins or leaves.
+#[derive(Default)]
+struct ConnectedUsers {
+    users: HashMap<SocketAddr, String>,
+    /// `None` after membership changed, rebuilt on next [`ConnectedUsers::list`]
+    list: Option<Arc<Vec<String>>>,
+}
+
+impl ConnectedUsers {
+    fn insert(&mut self, addr: SocketAddr, username: String) -> Option<String> {
+        self.list = None;
+        self.users.insert(addr, username)
+    }
+
+    fn remove(&mut self, addr: &SocketAddr) -> Option<String> {
+        let removed = self.users.remove(addr);
+        if removed.is_some() {
+            self.list = None;
+        }
+        removed
+    }
+
+    fn get(&self, addr: &SocketAddr) -> Option<&String> {
+        self.users.get(addr)
+    }
+
+    fn contains_key(&self, addr: &SocketAddr) -> bool {
+        self.users.contains_key(addr)
+    }
+
+    fn iter(&self) -> impl Iterator<Item = (&SocketAddr, &String)> {
+        self.users.iter()
+    }
+
+    /// Sorted usernames, cheap to call again while nobody joins or leaves
+    fn list(&mut self) -> Arc<Vec<String>> {
+        let users = &self.users;
+        self.list
+            .get_or_insert_with(|| {
+                let mut list: Vec<String> = users.values().cloned().collect();
+                list.sort();
+                Arc::new(list)
+            })
+            .clone()
+    }
+}
+
+/// Notes of users who are away ("/afk"), by address of their connection.
+///
+/// Note is cleared by the user's next message.
+#[derive(Default)]
+struct AfkNotes {
+    notes: HashMap<SocketAddr, String>,
+}
+
+impl AfkNotes {
+    fn set(&mut self, addr: SocketAddr, note: String) {
+        self.notes.insert(addr, note);
+    }
+
+    /// Returns whether `addr` was away
+    fn clear(&mut self, addr: &SocketAddr) -> bool {
+        self.notes.remove(addr).is_some()
+    }
+
+    /// Notes of away users mentioned in `text`, to tell the one who mentioned them
+    fn mentioned(&self, text: &str, users: &ConnectedUsers) -> Vec<String> {
+        let mut names = accord::utils::mentioned_names(text);
+        names.dedup();
+        names
+            .into_iter()
+            .filter_map(|name| {
+                let (addr, _) = users.iter().find(|(_, un)| *un == name)?;
+                Some(afk_text(name, self.notes.get(addr)?))
+            })
+            .collect()
+    }
+}
+
+/// Text letting others know that `username` is away
+fn afk_text(username: &str, note: &str) -> String {
+    if note.is_empty() {
+        format!("{} is AFK.", username)
+    } else {
+        format!("{} is AFK: {}", username, note)
+    }
+}
+
+/// Message from the server to everyone (e.g. that someone is away)
+fn server_message(text: String) -> ClientboundPacket {
+    ClientboundPacket::Message(accord::packets::Message::from_server(
+        text,
+        crate::connection::current_time_as_millis(),
+    ))
+}
 
 /// Channel represents the server that the users connect to and send messages to.
 pub struct AccordChannel {
     receiver: Receiver<ChannelCommand>,
+    /// For finishing logins checked off the loop, see [`ChannelCommand::LoginChecked`]
+    sender: Sender<ChannelCommand>,
     txs: HashMap<std::net::SocketAddr, Sender<ConnectionCommand>>,
-    connected_users: HashMap<std::net::SocketAddr, String>,
+    connected_users: ConnectedUsers,
+    connection_stats: HashMap<SocketAddr, Arc<ConnectionStats>>,
     salt_generator: ChaCha20Rng,
     db_client: DBClient,
     priv_key: RsaPrivateKey,
     pub_key: RsaPublicKey,
     config: Config,
+    /// Saves [`AccordChannel::config`] after changes
+    config_saver: ConfigSaver,
+    login_throttle: LoginThrottle,
+    image_quota: ImageQuota,
+    /// Name colors of users that logged in, by user id
+    user_colors: HashMap<i64, String>,
+    afk_notes: AfkNotes,
 }
 
 impl AccordChannel {
     /// Generates private key, connects to the databse, sets up the database if needed,
     /// and spawns the channel loop.
-    pub async fn spawn(receiver: Receiver<ChannelCommand>, config: Config) -> Result<()> {
+    pub async fn spawn(
+        receiver: Receiver<ChannelCommand>,
+        sender: Sender<ChannelCommand>,
+        config: Config,
+        config_path: PathBuf,
+    ) -> Result<()> {
         // Setup
         let txs: HashMap<std::net::SocketAddr, Sender<ConnectionCommand>> = HashMap::new();
-        let connected_users: HashMap<std::net::SocketAddr, String> = HashMap::new();
+        let connected_users = ConnectedUsers::default();
         let mut rng = OsRng;
         let priv_key =
             RsaPrivateKey::new(&mut rng, RSA_BITS).with_context(|| "Failed to generate a key.")?;
         let pub_key = RsaPublicKey::from(&priv_key);
 
-        let database_config = format!(
-            "host='{}' port='{}' user='{}' password='{}' dbname='{}'",
-            config.db_host, config.db_port, config.db_user, config.db_pass, config.db_dbname,
-        );
+        let database_config = config.database_config().map_err(anyhow::Error::msg)?;
 
-        let (db_client, db_connection) = tokio_postgres::connect(&database_config, NoTls)
+        let tls = match &config.db_tls {
+            Some(db_tls) => Some(db_tls.connector().map_err(anyhow::Error::msg)?),
+            None => None,
+        };
+        // Debug output hides the password
+        let mut db_client = crate::config::connect_database(&database_config, tls)
             .await
-            .with_context(|| format!("Postgres connection ({}) error.", database_config))?;
-
-        tokio::spawn(async move {
-            if let Err(e) = db_connection.await {
-                log::error!("Database connection error: {}.", e);
-            };
-        });
+            .with_context(|| format!("Postgres connection ({:?}) error.", database_config))?;
 
-        // Prepare Database, panic if it fails and gives us the reason. Without this, the server will be useless anyway, so it is ok to panic here.
-        // Friendly reminder @LoipesMas never silence errors, otherwise debugging will be a pain.
         log::info!("Preparing database...");
-
-        // Create accord schema if not exists, handle errors
-        let _ = db_client
-            .execute("CREATE SCHEMA IF NOT EXISTS accord", &[])
-            .await
-            .with_context(|| "Failed to create schema 'accord'.")?;
-
-        // Create account table if not exists
-        let _ = db_client
-            .execute(
-                "CREATE TABLE IF NOT EXISTS accord.accounts (
-                    user_id serial8 NOT null PRIMARY KEY, 
-                    username varchar(255) NOT NULL UNIQUE, 
-                    password varchar(44) NOT NULL, 
-                    salt varchar(88) NOT NULL,
-                    banned bool NOT NULL DEFAULT false,
-                    whitelisted bool NOT NULL DEFAULT false
-                    );",
-                &[],
-            )
-            .await
-            .with_context(|| "Failed to create table 'accounts'.")?;
-
-        // Create images table if not exists
-        let _ = db_client
-            .execute(
-                "CREATE TABLE IF NOT EXISTS accord.images ( image_hash INT PRIMARY KEY, data BYTEA NOT NULL);",
-                &[],
-            )
-            .await
-            .with_context(|| "Failed to create table 'images'.")?;
-
-        // Create messages table if not exists
-        let _ = db_client
-            .execute(
-        "CREATE TABLE IF NOT EXISTS accord.messages ( 
-                        sender_id int8 NOT NULL, sender varchar(255) NOT NULL DEFAULT '*deleted_user*', content varchar(1023), send_time bigint NOT NULL, image_hash INT DEFAULT NULL, 
-                        CONSTRAINT fk_image_hash FOREIGN KEY(image_hash) REFERENCES accord.images(image_hash) ON DELETE SET DEFAULT ON UPDATE CASCADE, 
-                        CONSTRAINT fk_username FOREIGN KEY(sender) REFERENCES accord.accounts(username) ON DELETE SET DEFAULT ON UPDATE CASCADE
-                    );",
-        &[],
-        ).await
-        .with_context(|| "Failed to create table 'messages'.")?;
+        crate::migrations::migrate(&mut db_client).await?;
 
         log::info!("DONE: Preparing database.");
 
         let s = Self {
             receiver,
+            sender,
             txs,
             connected_users,
+            connection_stats: HashMap::new(),
             salt_generator: ChaCha20Rng::from_entropy(),
             db_client,
             priv_key,
             pub_key,
+            config_saver: ConfigSaver::spawn(config_path, config.clone()),
+            login_throttle: LoginThrottle::default(),
+            image_quota: ImageQuota::new(config.image_quota),
+            user_colors: HashMap::new(),
+            afk_notes: AfkNotes::default(),
             config,
         };
         // Launch channel loop
@@ -132,40 +209,60 @@ impl AccordChannel {
                 None => break,
             };
             match p {
-                Close => {
-                    break;
+                Close(otx) => {
+                    self.close().await;
+                    otx.send(()).ok();
+                    return;
                 }
-                Write(p) => {
+                Write(mut p) => {
+                    if let ClientboundPacket::ImageMessage(im) = &p {
+                        let now = std::time::Instant::now();
+                        if let Err(wait) = self.image_quota.try_send(im.sender_id, now) {
+                            log::info!("Image from {} is over quota.", im.sender);
+                            let reason = format!(
+                                "Too many images, try again in {} min.",
+                                wait.as_secs().div_ceil(60)
+                            );
+                            self.reject(&im.sender, reason).await;
+                            continue;
+                        }
+                    }
+                    set_sender_color(&mut p, &self.user_colors);
                     match p {
                         ClientboundPacket::ImageMessage(ref im) => {
                             log::info!("Image from {}.", im.sender);
                         }
+                        ClientboundPacket::Message(ref m) if self.config.ephemeral => {
+                            log::info!("Message from {}.", m.sender);
+                        }
                         _ => log::info!("Message: {:?}.", &p),
                     }
-                    match &p {
-                        ClientboundPacket::Message(message) => {
-                            self.insert_message(message).await;
-                        }
-                        ClientboundPacket::ImageMessage(im) => {
-                            self.insert_image_message(im).await;
+                    if is_persisted(&p, self.config.ephemeral) {
+                        match &p {
+                            ClientboundPacket::Message(message) => {
+                                self.insert_message(message).await;
+                            }
+                            ClientboundPacket::ImageMessage(im) => {
+                                self.insert_image_message(im).await;
+                            }
+                            _ => (),
                         }
-                        _ => (),
                     }
-                    for (addr, tx_) in &self.txs {
-                        // Only send to logged in users
-                        // Maybe there is a prettier way to achieve that? Seems suboptimal
-                        if self.connected_users.contains_key(addr) {
-                            tx_.send(ConnectionCommand::Write(p.clone())).await.ok();
+                    self.broadcast(&p).await;
+                    if let ClientboundPacket::Message(message) = &p {
+                        if !message.is_from_server() {
+                            self.update_afk(message).await;
                         }
                     }
                 }
-                EncryptionRequest(tx, otx) => {
+                EncryptionRequest(tx, suite, otx) => {
                     let mut token = [0u8; ENC_TOK_LEN];
                     OsRng.fill(&mut token);
                     tx.send(ConnectionCommand::Write(
                         ClientboundPacket::EncryptionResponse(
                             self.pub_key.to_public_key_der().unwrap().as_ref().to_vec(),
                             token.to_vec(),
+                            suite,
                         ),
                     ))
                     .await
@@ -181,7 +278,11 @@ impl AccordChannel {
                     };
                     if t != exp_t {
                         log::error!("Encryption handshake failed!");
-                        tx.send(ConnectionCommand::Close).await.ok();
+                        tx.send(ConnectionCommand::Disconnect(
+                            DisconnectReason::ProtocolError,
+                        ))
+                        .await
+                        .ok();
                         otx.send(Err(())).unwrap();
                     } else {
                         let s = {
@@ -202,6 +303,9 @@ impl AccordChannel {
                 LoginAttempt { .. } => {
                     self.handle_login(p).await;
                 }
+                LoginChecked { .. } => {
+                    self.finish_login(p).await;
+                }
                 UserJoined(username) => {
                     for tx_ in self.txs.values() {
                         tx_.send(ConnectionCommand::Write(ClientboundPacket::UserJoined(
@@ -212,73 +316,72 @@ impl AccordChannel {
                     }
                 }
                 UserLeft(addr) => {
-                    self.txs.remove(&addr);
-                    if let Some(username) = self.connected_users.remove(&addr) {
+                    if let Some(username) = self.remove_user(addr).await {
                         log::info!("Connection ended from: {} ({}).", username, addr);
-                        for tx_ in self.txs.values() {
-                            tx_.send(ConnectionCommand::Write(ClientboundPacket::UserLeft(
-                                username.clone(),
-                            )))
-                            .await
-                            .ok();
-                        }
                     } else {
                         log::info!("Connection ended from: {}", addr);
                     }
                 }
                 UsersQueryTUI(otx) => {
-                    if otx
-                        .send(self.connected_users.values().cloned().collect())
-                        .is_err()
-                    {
+                    if otx.send(self.connected_users.list()).is_err() {
                         log::error!("Error while getting user list in TUI");
                     }
                 }
                 UsersQuery(addr) => {
+                    let list = self.connected_users.list().as_ref().clone();
                     let tx = self
                         .txs
                         .get(&addr)
                         .unwrap_or_else(|| panic!("Wrong reply addr: {}.", addr));
                     tx.send(ConnectionCommand::Write(ClientboundPacket::UsersOnline(
-                        self.connected_users.values().cloned().collect(),
+                        list,
                     )))
                     .await
                     .unwrap();
                 }
+                ServerInfoQuery(addr) => {
+                    let tx = self
+                        .txs
+                        .get(&addr)
+                        .unwrap_or_else(|| panic!("Wrong reply addr: {}.", addr));
+                    tx.send(ConnectionCommand::Write(ClientboundPacket::ServerInfo {
+                        name: self.config.server_name.clone(),
+                        description: self.config.server_description.clone(),
+                    }))
+                    .await
+                    .unwrap();
+                }
+                // There is no history in ephemeral mode
+                FetchMessages(_, _, otx) | FetchMessagesAround(_, _, otx)
+                    if self.config.ephemeral =>
+                {
+                    otx.send(vec![]).unwrap();
+                }
                 FetchMessages(o, n, otx) => {
-                    let n = n.min(64); // Clamp so we don't query and send too much
-                    let messages_rows = self.fetch_messages(o, n).await;
-                    let messages = messages_rows.iter().map(|r| async {
-                        if let Some(hash) = r.get::<_, Option<i32>>("image_hash") {
-                            let image_bytes = self.fetch_image(hash).await;
-                            ClientboundPacket::ImageMessage(accord::packets::ImageMessage {
-                                sender_id: r.get("sender_id"),
-                                sender: r.get("sender"),
-                                image_bytes,
-                                time: r.get::<_, i64>("send_time") as u64,
-                            })
-                        } else {
-                            ClientboundPacket::Message(accord::packets::Message {
-                                sender_id: r.get("sender_id"),
-                                sender: r.get("sender"),
-                                text: r.get("content"),
-                                time: r.get::<_, i64>("send_time") as u64,
-                            })
-                        }
-                    });
-                    let messages = futures::future::join_all(messages).await;
+                    let n = n.min(MAX_FETCH_COUNT); // Clamp so we don't query and send too much
+                    let messages_rows = fetch_messages(&self.db_client, o, n).await;
+                    let messages = messages_rows.iter().map(message_packet).collect();
+                    otx.send(messages).unwrap();
+                }
+                FetchMessagesAround(time, n, otx) => {
+                    let n = n.min(MAX_FETCH_COUNT); // Clamp so we don't query and send too much
+                    let messages_rows = self.fetch_messages_around(time, n).await;
+                    let messages = messages_rows.iter().map(message_packet).collect();
                     otx.send(messages).unwrap();
                 }
+                FetchImage(hash, otx) => {
+                    otx.send(self.fetch_image(&hash).await).unwrap();
+                }
                 CheckPermissions(username, otx) => {
                     let perms = self.get_user_perms(&username).await;
                     otx.send(perms).unwrap();
                 }
                 KickUser(username) => {
-                    self.kick_user(&username).await;
+                    self.kick_user(&username, DisconnectReason::Kicked).await;
                 }
                 BanUser(username, switch) => {
                     if switch {
-                        self.kick_user(&username).await;
+                        self.kick_user(&username, DisconnectReason::Banned).await;
                     }
                     self.ban_user(&username, switch).await;
                 }
@@ -288,33 +391,157 @@ impl AccordChannel {
                 SetWhitelist(state) => {
                     self.config.whitelist_on = state;
                     log::info!("Set whitelist: {}", state);
-                    save_config(&self.config).unwrap();
+                    self.config_saver.save(&self.config);
                 }
                 SetAllowNewAccounts(state) => {
                     self.config.allow_new_accounts = state;
                     log::info!("Set allow_new_accounts: {}", state);
-                    save_config(&self.config).unwrap();
+                    self.config_saver.save(&self.config);
+                }
+                RenameUser {
+                    addr,
+                    new_name,
+                    otx,
+                } => {
+                    let res = self.rename_user(addr, new_name).await;
+                    otx.send(res).ok();
+                }
+                HealthCheck(otx) => {
+                    let res = self
+                        .db_client
+                        .simple_query("SELECT 1;")
+                        .await
+                        .map(|_| ())
+                        .map_err(|e| e.to_string());
+                    otx.send(res).ok();
+                }
+                SetColor(user_id, color) => {
+                    log::info!("User {} set color to {:?}", user_id, color);
+                    match &color {
+                        Some(color) => self.user_colors.insert(user_id, color.clone()),
+                        None => self.user_colors.remove(&user_id),
+                    };
+                    self.db_client
+                        .execute(
+                            "UPDATE accord.accounts SET color = $1 WHERE user_id = $2",
+                            &[&color, &user_id],
+                        )
+                        .await
+                        .unwrap();
+                }
+                SetAfk(addr, note) => {
+                    if let Some(username) = self.connected_users.get(&addr) {
+                        let text = afk_text(username, &note);
+                        self.afk_notes.set(addr, note);
+                        self.broadcast(&server_message(text)).await;
+                    }
+                }
+                UserInfo(username, otx) => {
+                    let report = self
+                        .connected_users
+                        .iter()
+                        .find(|(_, un)| **un == username)
+                        .and_then(|(addr, _)| self.connection_stats.get(addr))
+                        .map(|stats| {
+                            stats.report(&username, crate::connection::current_time_as_millis())
+                        });
+                    otx.send(report).ok();
                 }
             };
         }
     }
 
+    /// Sends packet to all logged in users.
+    async fn broadcast(&self, p: &ClientboundPacket) {
+        for (addr, tx_) in &self.txs {
+            // Only send to logged in users
+            // Maybe there is a prettier way to achieve that? Seems suboptimal
+            if self.connected_users.contains_key(addr) {
+                tx_.send(ConnectionCommand::Write(p.clone())).await.ok();
+            }
+        }
+    }
+
+    /// Address of the connection of logged in user
+    fn addr_of(&self, username: &str) -> Option<SocketAddr> {
+        self.connected_users
+            .iter()
+            .find(|(_, un)| *un == username)
+            .map(|(addr, _)| *addr)
+    }
+
+    /// Tells `username` that their message wasn't accepted.
+    async fn reject(&self, username: &str, reason: String) {
+        if let Some(tx) = self.addr_of(username).and_then(|addr| self.txs.get(&addr)) {
+            tx.send(ConnectionCommand::Write(
+                ClientboundPacket::MessageRejected { reason },
+            ))
+            .await
+            .ok();
+        }
+    }
+
+    /// Clears AFK note of the sender of `message`
+    /// and tells them about notes of away users they mentioned.
+    async fn update_afk(&mut self, message: &accord::packets::Message) {
+        let addr = match self.addr_of(&message.sender) {
+            Some(addr) => addr,
+            None => return,
+        };
+        if self.afk_notes.clear(&addr) {
+            let text = format!("{} is back.", message.sender);
+            self.broadcast(&server_message(text)).await;
+        }
+        if let Some(tx) = self.txs.get(&addr) {
+            for note in self
+                .afk_notes
+                .mentioned(&message.text, &self.connected_users)
+            {
+                let p = ClientboundPacket::CommandResponse(accord::packets::Message::from_server(
+                    note,
+                    crate::connection::current_time_as_millis(),
+                ));
+                tx.send(ConnectionCommand::Write(p)).await.ok();
+            }
+        }
+    }
+
+    /// Forgets connection from `addr` and lets others know if it was logged in.
+    ///
+    /// Returns username of that connection.
+    async fn remove_user(&mut self, addr: SocketAddr) -> Option<String> {
+        self.txs.remove(&addr);
+        self.connection_stats.remove(&addr);
+        self.afk_notes.clear(&addr);
+        let username = self.connected_users.remove(&addr)?;
+        for tx_ in self.txs.values() {
+            tx_.send(ConnectionCommand::Write(ClientboundPacket::UserLeft(
+                username.clone(),
+            )))
+            .await
+            .ok();
+        }
+        Some(username)
+    }
+
     /// Disconnects user from the channel.
-    async fn kick_user(&mut self, username: &str) {
+    async fn kick_user(&mut self, username: &str, reason: DisconnectReason) {
         log::info!("Kicked user {}", username);
         for (addr, un) in self.connected_users.iter() {
             if un == username {
                 self.txs
                     .get(addr)
                     .unwrap()
-                    .send(ConnectionCommand::Close)
+                    .send(ConnectionCommand::Disconnect(reason))
                     .await
                     .unwrap();
             }
         }
     }
 
-    /// Handles pretty much entire login process.
+    /// Starts login process: checks what's cheap right away, then checks the password
+    /// (and hashes a new one) on a blocking thread, so Argon2 doesn't hold up the channel.
+    /// Login is finished by [`AccordChannel::finish_login`].
     async fn handle_login(&mut self, p: ChannelCommand) {
         if let ChannelCommand::LoginAttempt {
             username,
@@ -322,27 +549,120 @@ impl AccordChannel {
             addr,
             otx,
             tx,
+            stats,
         } = p
         {
+            let now = std::time::Instant::now();
+            let early = if let Err(wait) = self.login_throttle.check(addr.ip(), &username, now) {
+                Err(format!(
+                    "Too many failed login attempts, try again in {} seconds.",
+                    wait.as_secs() + 1
+                ))
+            } else if let Err(e) = self.config.username_policy.validate(&username) {
+                Err(format!("Invalid username: {}.", e))
+            } else {
+                Ok(())
+            };
+            if let Err(e) = early {
+                log::info!("Failed to log in: {}, reason: {}", username, e);
+                otx.send(Err(e)).ok();
+                return;
+            }
             let perms = self.get_user_perms(&username).await;
-            let res = if !verify_username(&username) {
-                Err("Invalid username!".to_string())
-            } else if perms.banned {
-                Err("User banned.".to_string())
-            } else if self.config.whitelist_on && !perms.whitelisted {
-                Err("User not on whitelist.".to_string())
-            } else if let Some(row) = self.get_user(&username).await {
-                // Account exists
-                let salt_s: String = row.get("salt");
-                let salt = base64::decode(salt_s).unwrap();
-                let pass_hash = hash_password(password, salt);
-                let acc_pass_s: String = row.get("password");
-                let acc_pass = base64::decode(acc_pass_s).unwrap();
-                if pass_hash == acc_pass.as_slice() {
-                    if self.connected_users.values().any(|u| u == &username) {
-                        Err("Already logged in.".to_string())
-                    } else {
+            let account = self.get_user(&username).await;
+            // Used if there's a hash to make
+            let salt = SaltString::generate(&mut self.salt_generator);
+            let config = self.config.clone();
+            let sender = self.sender.clone();
+            tokio::spawn(async move {
+                let stored = account.as_ref().map(|row| {
+                    (
+                        row.get::<_, String>("password"),
+                        row.get::<_, String>("salt"),
+                    )
+                });
+                let check_username = username.clone();
+                let checked = tokio::task::spawn_blocking(move || {
+                    let stored = stored
+                        .as_ref()
+                        .map(|(hash, salt)| (hash.as_str(), salt.as_str()));
+                    check_login(&config, &check_username, &password, stored, perms)?;
+                    // New account, or legacy hash to upgrade
+                    Ok(match stored {
+                        Some((hash, _)) if !is_legacy_hash(hash) => None,
+                        _ => Some(hash_password(&password, &salt)),
+                    })
+                })
+                .await
+                .expect("Checking login panicked.");
+                sender
+                    .send(ChannelCommand::LoginChecked {
+                        username,
+                        addr,
+                        otx,
+                        tx,
+                        stats,
+                        account,
+                        checked,
+                    })
+                    .await
+                    .ok();
+            });
+        } else {
+            panic!("Provided not login packet to handle_login.")
+        }
+    }
+
+    /// Finishes login started by [`AccordChannel::handle_login`].
+    async fn finish_login(&mut self, p: ChannelCommand) {
+        if let ChannelCommand::LoginChecked {
+            username,
+            addr,
+            otx,
+            tx,
+            stats,
+            account,
+            checked,
+        } = p
+        {
+            let res = match (checked, account) {
+                (Err(e), _) => {
+                    if e == BAD_CREDENTIALS {
+                        self.login_throttle
+                            .failed(addr.ip(), &username, std::time::Instant::now());
+                    }
+                    Err(e)
+                }
+                // Account exists and password is right
+                (Ok(new_hash), Some(row)) => match check_duplicate_login(
+                    self.config.login_takeover,
+                    &self.connected_users,
+                    &username,
+                ) {
+                    Err(e) => Err(e),
+                    Ok(old_session) => {
+                        if let Some(old_addr) = old_session {
+                            log::info!(
+                                "{} logged in again, ending session from {}.",
+                                username,
+                                old_addr
+                            );
+                            if let Some(tx) = self.txs.get(&old_addr) {
+                                tx.send(ConnectionCommand::Disconnect(
+                                    DisconnectReason::DuplicateLogin,
+                                ))
+                                .await
+                                .ok();
+                            }
+                            self.remove_user(old_addr).await;
+                        }
                         let user_id: i64 = row.get("user_id");
+                        if let Some(hash) = new_hash {
+                            self.upgrade_password_hash(user_id, &hash).await;
+                        }
+                        if let Some(color) = row.get::<_, Option<String>>("color") {
+                            self.user_colors.insert(user_id, color);
+                        }
                         let username: String = row.get("username");
                         log::info!(
                             "Logged in: {} (user_id: {}) from {}.",
@@ -350,123 +670,164 @@ impl AccordChannel {
                             user_id,
                             addr
                         );
-                        Ok(format!("{}|{}", user_id, username))
+                        Ok((user_id, username))
                     }
-                } else {
-                    Err("Incorrect password.".to_string())
-                }
-            } else {
-                // New account
-                if self.config.allow_new_accounts {
-                    let mut salt = [0; 64];
-                    self.salt_generator.fill_bytes(&mut salt);
-                    let pass_hash = hash_password(password, salt);
-
-                    if let Some(row) = self.insert_user(&username, &pass_hash, &salt).await {
-                        log::info!("New account: {}.", username);
-                        let user_id: i64 = row.get("user_id");
-                        let username: String = row.get("username");
+                },
+                // New account (allowed, otherwise credentials check fails)
+                (Ok(new_hash), None) => {
+                    let pass_hash = new_hash.expect("New account should have a hash.");
+                    create_account(&mut self.db_client, &username, &pass_hash)
+                        .await
+                        .map(|row| {
+                            log::info!("New account: {}.", username);
+                            let user_id: i64 = row.get("user_id");
+                            let username: String = row.get("username");
 
-                        Ok(format!("{}|{}", user_id, username))
-                    } else {
-                        Err("Failed to create account.".to_string())
-                    }
-                } else {
-                    Err("Account creation disabled.".to_string())
+                            (user_id, username)
+                        })
                 }
             };
             if let Err(ref e) = res {
                 log::info!("Failed to log in: {}, reason: {}", username, e);
             } else {
+                self.login_throttle.succeeded(addr.ip(), &username);
                 self.connected_users.insert(addr, username);
                 self.txs.insert(addr, tx);
+                self.connection_stats.insert(addr, stats);
             }
-            otx.send(res).unwrap();
+            otx.send(res).ok();
         } else {
-            panic!("Provided not login packet to handle_login.")
+            panic!("Provided not checked login to finish_login.")
         }
     }
 
-    /// Inserts new user into the database.
-    async fn insert_user(
-        &self,
-        username: &str,
-        pass_hash: &[u8],
-        salt: &[u8],
-    ) -> Option<tokio_postgres::Row> {
-        self.db_client
-            .query_opt(
-                "INSERT INTO accord.accounts(username, password, salt) VALUES ($1, $2, $3) RETURNING *",
-                &[&username, &base64::encode(pass_hash), &base64::encode(salt)],
+    /// Tells connected users that server is shutting down, closes their connections
+    /// and saves config.
+    async fn close(self) {
+        for tx in self.txs.values() {
+            tx.send(ConnectionCommand::Disconnect(
+                DisconnectReason::ServerShutdown,
+            ))
+            .await
+            .ok();
+        }
+        self.config_saver.finish().await;
+    }
+
+    /// Renames user connected from `addr` and lets everyone know.
+    ///
+    /// Messages follow the account thanks to `ON UPDATE CASCADE`.
+    async fn rename_user(&mut self, addr: std::net::SocketAddr, new: String) -> Result<(), String> {
+        let old = match self.connected_users.get(&addr) {
+            Some(old) => old.clone(),
+            None => return Err("Not logged in.".to_string()),
+        };
+        let taken = self.get_user(&new).await.is_some();
+        check_rename(&self.config, &old, &new, taken)?;
+        // Unique constraint still guards against a race with account creation
+        if let Err(e) = self
+            .db_client
+            .execute(
+                "UPDATE accord.accounts SET username = $1 WHERE username = $2",
+                &[&new, &old],
             )
             .await
-            .unwrap()
+        {
+            log::warn!("Failed to rename {} to {}: {}", old, new, e);
+            return Err("Username taken.".to_string());
+        }
+        log::info!("Renamed user {} to {}", old, new);
+        self.connected_users.insert(addr, new.clone());
+        let packet = ClientboundPacket::UserRenamed { old, new };
+        for (addr, tx_) in &self.txs {
+            if self.connected_users.contains_key(addr) {
+                tx_.send(ConnectionCommand::Write(packet.clone()))
+                    .await
+                    .ok();
+            }
+        }
+        Ok(())
     }
 
     /// Gets user from the database by the username.
     async fn get_user(&self, username: &str) -> Option<tokio_postgres::Row> {
         self.db_client
             .query_opt(
-                "SELECT user_id, username, password, salt FROM accord.accounts WHERE username=$1",
+                "SELECT user_id, username, password, salt, color FROM accord.accounts WHERE username=$1",
                 &[&username],
             )
             .await
             .unwrap()
     }
 
+    /// Replaces legacy hash of user's password with an Argon2 `hash`, see [`hash_password`].
+    async fn upgrade_password_hash(&self, user_id: i64, hash: &str) {
+        match self
+            .db_client
+            .execute(
+                "UPDATE accord.accounts SET password = $1, salt = '' WHERE user_id = $2",
+                &[&hash, &user_id],
+            )
+            .await
+        {
+            Ok(_) => log::info!("Upgraded password hash of user_id {}.", user_id),
+            Err(e) => log::error!(
+                "Failed to upgrade password hash of user_id {}: {}",
+                user_id,
+                e
+            ),
+        }
+    }
+
     /// Inserts new text message into the database.
     async fn insert_message(&self, message: &accord::packets::Message) {
-        self.db_client
+        if let Err(e) = self
+            .db_client
             .execute(
                 "INSERT INTO accord.messages(sender_id, sender, content, send_time) VALUES ($1, $2, $3, $4)",
-                &[&message.sender_id, &message.sender, &message.text, &(message.time as i64)],
+                &[&message.sender_id, &message.sender, &message.text, &message.time],
             )
             .await
-            .unwrap();
+        {
+            log::error!("Failed to store message from {}: {}", message.sender, e);
+        }
     }
 
     /// Inserts new image message into the database.
-    async fn insert_image_message(&self, message: &accord::packets::ImageMessage) {
-        use sha2::{Digest, Sha256};
-        use tokio_postgres::types::private::read_be_i32;
-
-        // Get hash of the image as i32
-        let mut hasher = Sha256::new();
-        hasher.update(&message.image_bytes);
-        let hash = read_be_i32(&mut &hasher.finalize()[..4]).unwrap();
-
-        // Insert image into db
-        self.db_client
-            .execute(
-                "INSERT INTO accord.images VALUES ($1, $2) ON CONFLICT DO NOTHING",
-                &[&hash, &message.image_bytes],
-            )
-            .await
-            .unwrap();
+    async fn insert_image_message(&mut self, message: &accord::packets::ImageMessage) {
+        if let Err(e) = store_image_message(&mut self.db_client, message).await {
+            log::error!(
+                "Failed to store image message from {}: {}",
+                message.sender,
+                e
+            );
+        }
+    }
 
-        // Inser message with hash as a foreign key
-        self.db_client
-            .execute(
-                "INSERT INTO accord.messages (sender_id, sender, content, send_time, image_hash) VALUES ($1, $2, '', $3, $4)",
-                &[&message.sender_id, &message.sender, &(message.time as i64), &hash],
+    /// Fetches `count` messages closest to `time`, ordered from newest
+    async fn fetch_messages_around(&self, time: i64, count: i64) -> Vec<tokio_postgres::Row> {
+        let before = self
+            .db_client
+            .query(
+                "SELECT sender_id, sender, content, send_time, image_hash, color FROM accord.messages LEFT JOIN accord.accounts ON user_id = sender_id WHERE send_time <= $1 ORDER BY send_time DESC, message_id DESC FETCH FIRST $2 ROW ONLY;",
+                &[&time, &count],
             )
             .await
             .unwrap();
-    }
-
-    /// Gets a range of messages from the database.
-    async fn fetch_messages(&self, offset: i64, count: i64) -> Vec<tokio_postgres::Row> {
-        self.db_client
+        let after = self
+            .db_client
             .query(
-                "SELECT sender_id, sender, content, send_time, image_hash FROM accord.messages ORDER BY send_time DESC OFFSET $1 ROWS FETCH FIRST $2 ROW ONLY;",
-                &[&offset, &count],
+                "SELECT sender_id, sender, content, send_time, image_hash, color FROM accord.messages LEFT JOIN accord.accounts ON user_id = sender_id WHERE send_time > $1 ORDER BY send_time ASC, message_id ASC FETCH FIRST $2 ROW ONLY;",
+                &[&time, &count],
             )
             .await
-            .unwrap()
+            .unwrap();
+        closest_around(before, after, time, count as usize, |r| r.get("send_time"))
     }
 
     /// Given hash, fetch image bytes from db
-    async fn fetch_image(&self, hash: i32) -> Vec<u8> {
+    /// `None` if there is no such image
+    async fn fetch_image(&self, hash: &str) -> Option<Vec<u8>> {
         let r = self
             .db_client
             .query(
@@ -475,7 +836,7 @@ impl AccordChannel {
             )
             .await
             .unwrap();
-        r.get(0).unwrap().get::<_, Vec<u8>>("data")
+        r.first().map(|r| r.get::<_, Vec<u8>>("data"))
     }
 
     /// Returns permissions of a user
@@ -490,11 +851,14 @@ impl AccordChannel {
             .await
             .unwrap();
 
-        r.get(0)
-            .map(|r| UserPermissions {
-                operator: self.config.operators.contains(username),
-                banned: r.get::<_, bool>("banned"),
-                whitelisted: r.get::<_, bool>("whitelisted"),
+        r.first()
+            .map(|r| {
+                user_permissions(
+                    &self.config,
+                    username,
+                    r.get::<_, bool>("banned"),
+                    r.get::<_, bool>("whitelisted"),
+                )
             })
             .unwrap_or_default()
     }
@@ -516,7 +880,9 @@ impl AccordChannel {
     }
 
     /// Whitelists (or unwhitelists) a user
-    async fn whitelist_user(&self, username: &str, switch: bool) {
+    ///
+    /// Names without an account are kept in config, so they can create one.
+    async fn whitelist_user(&mut self, username: &str, switch: bool) {
         let n = self
             .db_client
             .execute(
@@ -525,8 +891,22 @@ impl AccordChannel {
             )
             .await
             .unwrap();
+        let changed_names = if switch && n == 0 {
+            self.config.whitelisted_names.insert(username.to_string())
+        } else {
+            self.config.whitelisted_names.remove(username)
+        };
+        if changed_names {
+            self.config_saver.save(&self.config);
+        }
         if n == 0 {
-            log::warn!("User {} not in database!", &username);
+            if switch {
+                log::info!("User {} not in database, whitelisted the name.", username);
+            } else if !changed_names {
+                log::warn!("User {} not in database!", &username);
+            } else {
+                log::info!("Unwhitelisted name {}", username);
+            }
         } else if switch {
             log::info!("Whitelisted user {}", username);
         } else {
@@ -535,8 +915,166 @@ impl AccordChannel {
     }
 }
 
-#[inline]
-fn hash_password<P: AsRef<[u8]>, S: AsRef<[u8]>>(pass: P, salt: S) -> [u8; 32] {
+/// Permissions of user with an account, operators come from config
+fn user_permissions(
+    config: &Config,
+    username: &str,
+    banned: bool,
+    whitelisted: bool,
+) -> UserPermissions {
+    UserPermissions {
+        operator: config.operators.contains(username),
+        banned,
+        whitelisted,
+    }
+}
+
+/// Checks if whitelist lets user in. `whitelisted` is the flag of user's account
+/// and `account_exists` tells if there is one.
+fn check_whitelist(
+    config: &Config,
+    username: &str,
+    whitelisted: bool,
+    account_exists: bool,
+) -> Result<(), String> {
+    let whitelisted = whitelisted || config.whitelisted_names.contains(username);
+    match config.whitelist_mode {
+        _ if !config.whitelist_on || whitelisted => Ok(()),
+        WhitelistMode::All => Err("User not on whitelist.".to_string()),
+        WhitelistMode::NewAccounts if account_exists => Ok(()),
+        WhitelistMode::NewAccounts => Err("Name not on whitelist for new accounts.".to_string()),
+    }
+}
+
+/// Reply to failed login, the same whether the account exists or not
+const BAD_CREDENTIALS: &str = "Incorrect username or password.";
+
+/// Checks `password` against `stored` password and salt columns of the account or,
+/// if there is no account, whether a new one can be created.
+///
+/// Failures get the same reply, so it doesn't tell which names have accounts
+/// (actual reason is only logged). Without an account, the password is checked against
+/// [`dummy_hash`] anyway, so it takes as long as with one.
+fn check_credentials(
+    stored: Option<(&str, &str)>,
+    password: &str,
+    allow_new_accounts: bool,
+) -> Result<(), String> {
+    let reason = match stored {
+        Some((hash, salt)) if verify_password(password, hash, salt) => return Ok(()),
+        Some(_) => "incorrect password",
+        None => {
+            verify_password(password, dummy_hash(), "");
+            if allow_new_accounts {
+                return Ok(());
+            }
+            "no account and account creation disabled"
+        }
+    };
+    log::info!("Credentials rejected: {}.", reason);
+    Err(BAD_CREDENTIALS.to_string())
+}
+
+/// Checks if `username` can log in with `password`, given `stored` credentials of the account
+/// (see [`check_credentials`]) and its `perms`.
+///
+/// Credentials go first, so ban and whitelist only tell their reason to someone who knows
+/// the password, and a name without an account gets the same reply as a wrong password
+/// (unless new accounts can be made).
+fn check_login(
+    config: &Config,
+    username: &str,
+    password: &str,
+    stored: Option<(&str, &str)>,
+    perms: UserPermissions,
+) -> Result<(), String> {
+    check_credentials(stored, password, config.allow_new_accounts)?;
+    if perms.banned {
+        return Err("User banned.".to_string());
+    }
+    check_whitelist(config, username, perms.whitelisted, stored.is_some())
+}
+
+/// Checks what to do with `username` logging in while already connected.
+///
+/// Refuses the login, unless `takeover` is on, in which case returns address of the session to end.
+fn check_duplicate_login(
+    takeover: bool,
+    connected_users: &ConnectedUsers,
+    username: &str,
+) -> Result<Option<SocketAddr>, String> {
+    match connected_users.iter().find(|(_, u)| *u == username) {
+        None => Ok(None),
+        Some((addr, _)) if takeover => Ok(Some(*addr)),
+        Some(_) => Err("Already logged in.".to_string()),
+    }
+}
+
+/// Checks if user `old` can become `new`. `taken` tells if there is an account named `new`.
+///
+/// Names of operators and whitelisted names are refused, as their rights go by name.
+fn check_rename(config: &Config, old: &str, new: &str, taken: bool) -> Result<(), String> {
+    if let Err(e) = config.username_policy.validate(new) {
+        Err(format!("Invalid username: {}.", e))
+    } else if old == new {
+        Err("That's already your username.".to_string())
+    } else if taken {
+        Err("Username taken.".to_string())
+    } else if config.operators.contains(new) || config.whitelisted_names.contains(new) {
+        Err("Username reserved.".to_string())
+    } else {
+        Ok(())
+    }
+}
+
+/// Hashes `password` with Argon2id into a PHC string (`$argon2id$...`, salt included)
+/// stored in `accounts.password`
+fn hash_password(password: &str, salt: &SaltString) -> String {
+    use argon2::password_hash::PasswordHasher;
+    argon2::Argon2::default()
+        .hash_password(password.as_bytes(), salt)
+        .expect("failed to hash password")
+        .to_string()
+}
+
+/// Hash of no account's password, checked against when there is no account,
+/// made with the same parameters as [`hash_password`]
+fn dummy_hash() -> &'static str {
+    static DUMMY_HASH: OnceLock<String> = OnceLock::new();
+    DUMMY_HASH.get_or_init(|| {
+        let salt = SaltString::b64_encode(&[0; 16]).expect("failed to encode salt");
+        hash_password("", &salt)
+    })
+}
+
+/// Whether `stored` password is from before Argon2, see [`legacy_hash_password`].
+///
+/// These are upgraded on the next successful login.
+fn is_legacy_hash(stored: &str) -> bool {
+    !stored.starts_with("$argon2")
+}
+
+/// Checks `password` against `stored` password column,
+/// `salt` column is only used by legacy hashes
+fn verify_password(password: &str, stored: &str, salt: &str) -> bool {
+    if is_legacy_hash(stored) {
+        match (base64::decode(stored), base64::decode(salt)) {
+            (Ok(hash), Ok(salt)) => legacy_hash_password(password, salt) == hash[..],
+            _ => false,
+        }
+    } else {
+        use argon2::password_hash::{PasswordHash, PasswordVerifier};
+        PasswordHash::new(stored).is_ok_and(|hash| {
+            argon2::Argon2::default()
+                .verify_password(password.as_bytes(), &hash)
+                .is_ok()
+        })
+    }
+}
+
+/// How passwords were hashed before Argon2: SHA-256 of password and salt,
+/// stored as base64 in `password` and `salt` columns
+fn legacy_hash_password<P: AsRef<[u8]>, S: AsRef<[u8]>>(pass: P, salt: S) -> [u8; 32] {
     use sha2::{Digest, Sha256};
     let mut hasher = Sha256::new();
     hasher.update(pass);
@@ -545,3 +1083,748 @@ fn hash_password<P: AsRef<[u8]>, S: AsRef<[u8]>>(pass: P, salt: S) -> [u8; 32] {
     ret.copy_from_slice(&hasher.finalize()[..32]);
     ret
 }
+
+/// Whether packet written to the channel is stored in the history.
+///
+/// Nothing is stored in `ephemeral` mode (see [`Config::ephemeral`]),
+/// server's own messages never are.
+fn is_persisted(p: &ClientboundPacket, ephemeral: bool) -> bool {
+    match p {
+        _ if ephemeral => false,
+        ClientboundPacket::Message(message) => !message.is_from_server(),
+        ClientboundPacket::ImageMessage(_) => true,
+        _ => false,
+    }
+}
+
+/// Makes a message packet from a row of `accord.messages`
+fn message_packet(r: &tokio_postgres::Row) -> ClientboundPacket {
+    history_packet(
+        r.get("sender_id"),
+        r.get("sender"),
+        r.get("content"),
+        r.get("send_time"),
+        r.get("color"),
+        r.get("image_hash"),
+    )
+}
+
+/// Makes a packet of a message from history.
+///
+/// Image messages carry only the hash, so clients can fetch images when needed.
+fn history_packet(
+    sender_id: i64,
+    sender: String,
+    text: String,
+    time: i64,
+    sender_color: Option<String>,
+    image_hash: Option<String>,
+) -> ClientboundPacket {
+    match image_hash {
+        Some(hash) => ClientboundPacket::ImageMessage(accord::packets::ImageMessage {
+            sender_id,
+            sender,
+            time,
+            image_bytes: None,
+            hash,
+            sender_color,
+        }),
+        None => ClientboundPacket::Message(accord::packets::Message {
+            sender_id,
+            sender,
+            text,
+            time,
+            sender_color,
+        }),
+    }
+}
+
+/// Sets color of the sender (from `user_colors`, by user id) on a message packet
+fn set_sender_color(p: &mut ClientboundPacket, user_colors: &HashMap<i64, String>) {
+    let (sender_id, sender_color) = match p {
+        ClientboundPacket::Message(m) => (m.sender_id, &mut m.sender_color),
+        ClientboundPacket::ImageMessage(im) => (im.sender_id, &mut im.sender_color),
+        _ => return,
+    };
+    *sender_color = user_colors.get(&sender_id).cloned();
+}
+
+/// Picks `count` items closest to `time`, given items before it (ordered from newest)
+/// and after it (ordered from oldest).
+///
+/// Returns picked items ordered from newest.
+fn closest_around<T>(
+    before: Vec<T>,
+    after: Vec<T>,
+    time: i64,
+    count: usize,
+    time_of: impl Fn(&T) -> i64,
+) -> Vec<T> {
+    let mut before = before.into_iter().peekable();
+    let mut after = after.into_iter().peekable();
+    let mut picked_before = Vec::new();
+    let mut picked_after = Vec::new();
+    while picked_before.len() + picked_after.len() < count {
+        let take_before = match (before.peek(), after.peek()) {
+            (Some(b), Some(a)) => time - time_of(b) <= time_of(a) - time,
+            (Some(_), None) => true,
+            (None, Some(_)) => false,
+            (None, None) => break,
+        };
+        if take_before {
+            picked_before.extend(before.next());
+        } else {
+            picked_after.extend(after.next());
+        }
+    }
+    picked_after.reverse();
+    picked_after.extend(picked_before);
+    picked_after
+}
+
+/// Inserts new account, returns `None` if the username is taken.
+async fn insert_user(
+    db_client: &impl tokio_postgres::GenericClient,
+    username: &str,
+    pass_hash: &str,
+) -> Result<Option<tokio_postgres::Row>, tokio_postgres::Error> {
+    // Salt is part of the hash, column is only used by legacy hashes
+    db_client
+        .query_opt(
+            "INSERT INTO accord.accounts(username, password, salt) VALUES ($1, $2, '') ON CONFLICT (username) DO NOTHING RETURNING *",
+            &[&username, &pass_hash],
+        )
+        .await
+}
+
+/// Creates account in a transaction and returns its row.
+///
+/// Nothing is left behind if it fails, the error is meant for the user.
+async fn create_account(
+    db_client: &mut DBClient,
+    username: &str,
+    pass_hash: &str,
+) -> Result<tokio_postgres::Row, String> {
+    let failed = |e: tokio_postgres::Error| {
+        log::error!("Failed to create account {}: {}", username, e);
+        "Failed to create account.".to_string()
+    };
+    let transaction = db_client.transaction().await.map_err(failed)?;
+    match insert_user(&transaction, username, pass_hash).await {
+        Ok(Some(row)) => {
+            transaction.commit().await.map_err(failed)?;
+            Ok(row)
+        }
+        // Someone else took the name in the meantime
+        Ok(None) => Err("Username taken.".to_string()),
+        Err(e) => Err(failed(e)),
+    }
+}
+
+/// Gets a range of messages from the database, newest first
+/// (in order they were stored, if sent in the same millisecond)
+async fn fetch_messages(db_client: &DBClient, offset: i64, count: i64) -> Vec<tokio_postgres::Row> {
+    db_client
+        .query(
+            "SELECT sender_id, sender, content, send_time, image_hash, color FROM accord.messages LEFT JOIN accord.accounts ON user_id = sender_id ORDER BY send_time DESC, message_id DESC OFFSET $1 ROWS FETCH FIRST $2 ROW ONLY;",
+            &[&offset, &count],
+        )
+        .await
+        .unwrap()
+}
+
+/// Inserts image and message referencing it in one transaction,
+/// so there is never an image without its message or the other way around.
+async fn store_image_message(
+    db_client: &mut DBClient,
+    message: &accord::packets::ImageMessage,
+) -> Result<(), tokio_postgres::Error> {
+    let transaction = db_client.transaction().await?;
+    // Insert image into db
+    transaction
+        .execute(
+            "INSERT INTO accord.images VALUES ($1, $2) ON CONFLICT DO NOTHING",
+            &[&message.hash, &message.image_bytes],
+        )
+        .await?;
+
+    // Insert message with hash as a foreign key
+    transaction
+        .execute(
+            "INSERT INTO accord.messages (sender_id, sender, content, send_time, image_hash) VALUES ($1, $2, '', $3, $4)",
+            &[&message.sender_id, &message.sender, &message.time, &message.hash],
+        )
+        .await?;
+    transaction.commit().await
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        check_credentials, check_duplicate_login, check_login, check_rename, check_whitelist,
+        closest_around, dummy_hash, hash_password, history_packet, is_legacy_hash, is_persisted,
+        legacy_hash_password, set_sender_color, user_permissions, verify_password, AfkNotes,
+        ConnectedUsers, UserPermissions, BAD_CREDENTIALS,
+    };
+    use crate::config::{Config, WhitelistMode};
+    use accord::packets::ClientboundPacket;
+    use argon2::password_hash::SaltString;
+    use rand::SeedableRng;
+    use rand_chacha::ChaCha20Rng;
+
+    #[test]
+    fn history_has_no_image_bytes() {
+        let hash = accord::utils::image_hash(b"image");
+        match history_packet(
+            1,
+            "user".to_string(),
+            String::new(),
+            0,
+            None,
+            Some(hash.clone()),
+        ) {
+            ClientboundPacket::ImageMessage(im) => {
+                assert_eq!(im.hash, hash);
+                assert_eq!(im.image_bytes, None);
+            }
+            p => panic!("Expected image message, got {:?}", p),
+        }
+        match history_packet(1, "user".to_string(), "hi".to_string(), 0, None, None) {
+            ClientboundPacket::Message(m) => assert_eq!(m.text, "hi"),
+            p => panic!("Expected message, got {:?}", p),
+        }
+    }
+
+    #[test]
+    fn ephemeral_messages_are_not_stored() {
+        let message = ClientboundPacket::Message(accord::packets::Message {
+            sender_id: 1,
+            sender: "user".to_string(),
+            text: "hi".to_string(),
+            time: 0,
+            sender_color: None,
+        });
+        let image = ClientboundPacket::ImageMessage(accord::packets::ImageMessage::new(
+            1,
+            "user".to_string(),
+            0,
+            vec![1, 2, 3],
+        ));
+        let from_server =
+            ClientboundPacket::Message(accord::packets::Message::from_server("hi".into(), 0));
+
+        assert!(is_persisted(&message, false));
+        assert!(is_persisted(&image, false));
+        assert!(!is_persisted(&from_server, false));
+        assert!(!is_persisted(
+            &ClientboundPacket::UserJoined("user".into()),
+            false
+        ));
+        for p in [message, image, from_server] {
+            assert!(!is_persisted(&p, true));
+        }
+    }
+
+    #[test]
+    fn messages_get_sender_color() {
+        let colors = [(1, "#ff8800".to_string())].into();
+        let message = |sender_id| {
+            ClientboundPacket::Message(accord::packets::Message {
+                sender_id,
+                sender: "user".to_string(),
+                text: "hi".to_string(),
+                time: 0,
+                sender_color: None,
+            })
+        };
+        let color_of = |p: &ClientboundPacket| match p {
+            ClientboundPacket::Message(m) => m.sender_color.clone(),
+            ClientboundPacket::ImageMessage(im) => im.sender_color.clone(),
+            p => panic!("Expected message, got {:?}", p),
+        };
+
+        let mut colored = message(1);
+        set_sender_color(&mut colored, &colors);
+        assert_eq!(color_of(&colored).as_deref(), Some("#ff8800"));
+        let mut image = ClientboundPacket::ImageMessage(accord::packets::ImageMessage::new(
+            1,
+            "user".to_string(),
+            0,
+            vec![1, 2, 3],
+        ));
+        set_sender_color(&mut image, &colors);
+        assert_eq!(color_of(&image).as_deref(), Some("#ff8800"));
+        // No color picked
+        let mut plain = message(2);
+        set_sender_color(&mut plain, &colors);
+        assert_eq!(color_of(&plain), None);
+    }
+
+    #[test]
+    fn closest_around_test() {
+        let time_of = |t: &i64| *t;
+        // Both sides of the target
+        let before = vec![95, 80, 70];
+        let after = vec![101, 125, 140];
+        assert_eq!(
+            closest_around(before.clone(), after.clone(), 100, 4, time_of),
+            vec![125, 101, 95, 80]
+        );
+        // Ties prefer older messages
+        assert_eq!(
+            closest_around(vec![90], vec![110], 100, 1, time_of),
+            vec![90]
+        );
+        // Not enough on one side
+        assert_eq!(
+            closest_around(vec![], after.clone(), 100, 2, time_of),
+            vec![125, 101]
+        );
+        assert_eq!(
+            closest_around(before.clone(), vec![], 100, 2, time_of),
+            vec![95, 80]
+        );
+        // Not enough messages at all
+        assert_eq!(
+            closest_around(before, after, 100, 10, time_of),
+            vec![140, 125, 101, 95, 80, 70]
+        );
+        assert!(closest_around(Vec::<i64>::new(), vec![], 100, 5, time_of).is_empty());
+    }
+
+    #[test]
+    fn rename_check() {
+        let mut config = Config::default();
+        assert_eq!(check_rename(&config, "alice", "alicia", false), Ok(()));
+        // Collision with an existing account
+        assert_eq!(
+            check_rename(&config, "alice", "bob", true),
+            Err("Username taken.".to_string())
+        );
+        assert!(check_rename(&config, "alice", "alice", true).is_err());
+        assert!(check_rename(&config, "alice", "", false)
+            .unwrap_err()
+            .starts_with("Invalid username"));
+
+        // Even without an account, these names come with rights
+        config.operators.insert("admin".to_string());
+        config.whitelisted_names.insert("carol".to_string());
+        for name in ["admin", "carol"] {
+            assert_eq!(
+                check_rename(&config, "alice", name, false),
+                Err("Username reserved.".to_string())
+            );
+        }
+    }
+
+    #[test]
+    fn permissions_packet_test() {
+        use accord::packets::{Packet, UserPermissions};
+        let config = Config {
+            operators: ["admin".to_string()].into(),
+            ..Default::default()
+        };
+        for (username, operator) in [("admin", true), ("user", false)] {
+            let packet = ClientboundPacket::YourPermissions(user_permissions(
+                &config, username, false, true,
+            ));
+            let (received, _) = ClientboundPacket::deserialized(&packet.serialized()).unwrap();
+            assert_eq!(
+                received,
+                ClientboundPacket::YourPermissions(UserPermissions {
+                    operator,
+                    whitelisted: true,
+                    banned: false,
+                }),
+                "{}",
+                username
+            );
+        }
+    }
+
+    #[test]
+    fn whitelist_modes() {
+        let mut config = Config {
+            whitelisted_names: ["newbie".to_string()].into(),
+            ..Default::default()
+        };
+        // (username, whitelisted account, account exists)
+        let users = [
+            ("old_friend", true, true),
+            ("old_stranger", false, true),
+            ("newbie", false, false),
+            ("new_stranger", false, false),
+        ];
+        let allowed = |config: &Config| -> Vec<bool> {
+            users
+                .iter()
+                .map(|&(name, whitelisted, exists)| {
+                    check_whitelist(config, name, whitelisted, exists).is_ok()
+                })
+                .collect()
+        };
+
+        // Whitelist off, mode doesn't matter
+        assert_eq!(allowed(&config), [true, true, true, true]);
+        config.whitelist_mode = WhitelistMode::NewAccounts;
+        assert_eq!(allowed(&config), [true, true, true, true]);
+
+        config.whitelist_on = true;
+        config.whitelist_mode = WhitelistMode::All;
+        assert_eq!(allowed(&config), [true, false, true, false]);
+        config.whitelist_mode = WhitelistMode::NewAccounts;
+        assert_eq!(allowed(&config), [true, true, true, false]);
+    }
+
+    /// Needs a database prepared by the server, pointed to by `DATABASE_URL`.
+    #[test]
+    #[ignore]
+    fn failed_image_message_is_rolled_back() {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let url = std::env::var("DATABASE_URL").expect("DATABASE_URL not set");
+            let (mut db_client, connection) = tokio_postgres::connect(&url, tokio_postgres::NoTls)
+                .await
+                .unwrap();
+            tokio::spawn(connection);
+
+            let image = format!("rollback test {}", std::process::id()).into_bytes();
+            // No such account, so inserting the message fails
+            let message = accord::packets::ImageMessage::new(
+                0,
+                "*no such user in rollback test*".to_string(),
+                0,
+                image,
+            );
+            assert!(super::store_image_message(&mut db_client, &message)
+                .await
+                .is_err());
+            let images = db_client
+                .query(
+                    "SELECT 1 FROM accord.images WHERE image_hash = $1",
+                    &[&message.hash],
+                )
+                .await
+                .unwrap();
+            assert!(images.is_empty());
+        });
+    }
+
+    /// Needs a database prepared by the server, pointed to by `DATABASE_URL`.
+    #[test]
+    #[ignore]
+    fn same_time_messages_keep_order() {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let url = std::env::var("DATABASE_URL").expect("DATABASE_URL not set");
+            let (mut db_client, connection) = tokio_postgres::connect(&url, tokio_postgres::NoTls)
+                .await
+                .unwrap();
+            tokio::spawn(connection);
+            let username = format!("order{}", std::process::id());
+            let user_id: i64 = super::create_account(&mut db_client, &username, "hash")
+                .await
+                .unwrap()
+                .get("user_id");
+
+            // Newer than anything else in the database, sent in the same millisecond
+            let time = i64::MAX / 2;
+            for text in ["first", "second"] {
+                db_client
+                    .execute(
+                        "INSERT INTO accord.messages(sender_id, sender, content, send_time) VALUES ($1, $2, $3, $4)",
+                        &[&user_id, &username, &text, &time],
+                    )
+                    .await
+                    .unwrap();
+            }
+            let texts: Vec<String> = super::fetch_messages(&db_client, 0, 2)
+                .await
+                .iter()
+                .map(|r| r.get("content"))
+                .collect();
+            assert_eq!(texts, ["second", "first"]);
+
+            db_client
+                .execute("DELETE FROM accord.messages WHERE sender_id = $1", &[&user_id])
+                .await
+                .unwrap();
+            db_client
+                .execute(
+                    "DELETE FROM accord.accounts WHERE username = $1",
+                    &[&username],
+                )
+                .await
+                .unwrap();
+        });
+    }
+
+    /// Needs a database prepared by the server, pointed to by `DATABASE_URL`.
+    #[test]
+    #[ignore]
+    fn colliding_image_hashes_are_stored_apart() {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let url = std::env::var("DATABASE_URL").expect("DATABASE_URL not set");
+            let (mut db_client, connection) = tokio_postgres::connect(&url, tokio_postgres::NoTls)
+                .await
+                .unwrap();
+            tokio::spawn(connection);
+            let username = format!("images{}", std::process::id());
+            let user_id: i64 = super::create_account(&mut db_client, &username, "hash")
+                .await
+                .unwrap()
+                .get("user_id");
+
+            // These collide on first 4 bytes of the hash (see `accord::utils::image_hash`)
+            let images = [b"image 9808".to_vec(), b"image 45671".to_vec()];
+            let mut hashes = vec![];
+            for image in &images {
+                let message =
+                    accord::packets::ImageMessage::new(user_id, username.clone(), 0, image.clone());
+                super::store_image_message(&mut db_client, &message)
+                    .await
+                    .unwrap();
+                hashes.push(message.hash);
+            }
+            for (image, hash) in images.iter().zip(&hashes) {
+                let row = db_client
+                    .query_one(
+                        "SELECT data FROM accord.images WHERE image_hash = $1",
+                        &[hash],
+                    )
+                    .await
+                    .unwrap();
+                assert_eq!(&row.get::<_, Vec<u8>>("data"), image);
+            }
+
+            db_client
+                .execute(
+                    "DELETE FROM accord.messages WHERE sender = $1",
+                    &[&username],
+                )
+                .await
+                .unwrap();
+            db_client
+                .execute(
+                    "DELETE FROM accord.images WHERE image_hash = ANY($1)",
+                    &[&hashes],
+                )
+                .await
+                .unwrap();
+            db_client
+                .execute(
+                    "DELETE FROM accord.accounts WHERE username = $1",
+                    &[&username],
+                )
+                .await
+                .unwrap();
+        });
+    }
+
+    /// Needs a database prepared by the server, pointed to by `DATABASE_URL`.
+    #[test]
+    #[ignore]
+    fn account_creation_is_atomic() {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let url = std::env::var("DATABASE_URL").expect("DATABASE_URL not set");
+            let (mut db_client, connection) = tokio_postgres::connect(&url, tokio_postgres::NoTls)
+                .await
+                .unwrap();
+            tokio::spawn(connection);
+            let username = format!("atomic{}", std::process::id());
+
+            // Failure after the insert, before commit
+            {
+                let transaction = db_client.transaction().await.unwrap();
+                super::insert_user(&transaction, &username, "hash")
+                    .await
+                    .unwrap()
+                    .unwrap();
+                // Dropped without commit
+            }
+            let rows = db_client
+                .query(
+                    "SELECT 1 FROM accord.accounts WHERE username = $1",
+                    &[&username],
+                )
+                .await
+                .unwrap();
+            assert!(rows.is_empty());
+
+            let row = super::create_account(&mut db_client, &username, "hash")
+                .await
+                .unwrap();
+            assert_eq!(row.get::<_, String>("username"), username);
+            let taken = super::create_account(&mut db_client, &username, "hash").await;
+            assert_eq!(taken.err(), Some("Username taken.".to_string()));
+
+            db_client
+                .execute(
+                    "DELETE FROM accord.accounts WHERE username = $1",
+                    &[&username],
+                )
+                .await
+                .unwrap();
+        });
+    }
+
+    #[test]
+    fn connected_users_list_test() {
+        let addr = |port| std::net::SocketAddr::from(([127, 0, 0, 1], port));
+        let mut users = ConnectedUsers::default();
+        assert!(users.list().is_empty());
+
+        users.insert(addr(1), "bob".to_string());
+        users.insert(addr(2), "alice".to_string());
+        let list = users.list();
+        assert_eq!(*list, vec!["alice".to_string(), "bob".to_string()]);
+        // Cached while nothing changes
+        assert!(std::sync::Arc::ptr_eq(&list, &users.list()));
+
+        users.remove(&addr(1));
+        assert_eq!(*users.list(), vec!["alice".to_string()]);
+        // Removing someone not connected keeps the cache
+        let list = users.list();
+        users.remove(&addr(3));
+        assert!(std::sync::Arc::ptr_eq(&list, &users.list()));
+
+        // Rename replaces the entry
+        users.insert(addr(2), "carol".to_string());
+        assert_eq!(*users.list(), vec!["carol".to_string()]);
+    }
+
+    #[test]
+    fn afk_set_and_cleared() {
+        let addr = |port| std::net::SocketAddr::from(([127, 0, 0, 1], port));
+        let mut users = ConnectedUsers::default();
+        users.insert(addr(1), "bob".to_string());
+        users.insert(addr(2), "alice".to_string());
+        let mut afk = AfkNotes::default();
+        assert!(afk.mentioned("@bob hi", &users).is_empty());
+
+        afk.set(addr(1), "lunch".to_string());
+        assert_eq!(
+            afk.mentioned("@bob, @bob hi", &users),
+            ["bob is AFK: lunch"]
+        );
+        assert!(afk.mentioned("@alice @carol", &users).is_empty());
+        afk.set(addr(2), String::new());
+        assert_eq!(afk.mentioned("@alice", &users), ["alice is AFK."]);
+
+        // Cleared by next message
+        assert!(afk.clear(&addr(1)));
+        assert!(!afk.clear(&addr(1)));
+        assert!(afk.mentioned("@bob", &users).is_empty());
+    }
+
+    #[test]
+    fn failed_credentials_look_the_same() {
+        let salt = SaltString::generate(&mut ChaCha20Rng::seed_from_u64(0));
+        let hash = hash_password("right", &salt);
+        let account = Some((hash.as_str(), ""));
+
+        assert_eq!(check_credentials(account, "right", false), Ok(()));
+        let wrong_password = check_credentials(account, "wrong", false);
+        let no_account = check_credentials(None, "wrong", false);
+        assert!(wrong_password.is_err());
+        assert_eq!(wrong_password, no_account);
+
+        // Without an account, a new one is made if allowed
+        assert_eq!(check_credentials(None, "anything", true), Ok(()));
+        assert_eq!(check_credentials(account, "wrong", true), wrong_password);
+        // Even the dummy password doesn't get in without an account
+        assert_eq!(check_credentials(None, "", false), wrong_password);
+        assert!(!is_legacy_hash(dummy_hash()));
+
+        // Nor do ban or whitelist tell, without the right password
+        let config = Config {
+            allow_new_accounts: false,
+            whitelist_on: true,
+            whitelist_mode: WhitelistMode::NewAccounts,
+            ..Default::default()
+        };
+        let banned = UserPermissions {
+            banned: true,
+            ..Default::default()
+        };
+        let login = |stored, password, perms| check_login(&config, "bob", password, stored, perms);
+        assert_eq!(login(None, "wrong", Default::default()), wrong_password);
+        assert_eq!(login(account, "wrong", Default::default()), wrong_password);
+        assert_eq!(login(account, "wrong", banned), wrong_password);
+        assert_eq!(login(account, "right", Default::default()), Ok(()));
+        assert_eq!(
+            login(account, "right", banned),
+            Err("User banned.".to_string())
+        );
+        let config = Config {
+            whitelist_mode: WhitelistMode::All,
+            ..config
+        };
+        let login =
+            |stored, password| check_login(&config, "bob", password, stored, Default::default());
+        assert_eq!(login(None, "wrong"), wrong_password);
+        assert_eq!(login(account, "wrong"), wrong_password);
+        assert!(login(account, "right").is_err_and(|e| e != BAD_CREDENTIALS));
+    }
+
+    #[test]
+    fn password_hashing() {
+        let mut rng = ChaCha20Rng::seed_from_u64(0);
+
+        // Fresh account
+        let hash = hash_password("right", &SaltString::generate(&mut rng));
+        assert!(hash.starts_with("$argon2id$"));
+        assert!(hash.len() <= 255);
+        assert!(!is_legacy_hash(&hash));
+        assert!(verify_password("right", &hash, ""));
+        assert!(!verify_password("wrong", &hash, ""));
+        // Salt is random
+        assert_ne!(
+            hash,
+            hash_password("right", &SaltString::generate(&mut rng))
+        );
+
+        // Legacy row, upgraded after login
+        let salt = [1u8; 64];
+        let legacy = base64::encode(legacy_hash_password("right", salt));
+        let salt = base64::encode(salt);
+        assert!(is_legacy_hash(&legacy));
+        assert!(verify_password("right", &legacy, &salt));
+        assert!(!verify_password("wrong", &legacy, &salt));
+        assert!(!verify_password("right", &legacy, ""));
+
+        // Garbage never matches
+        assert!(!verify_password("right", "$argon2id$nonsense", ""));
+        assert!(!verify_password("", "", ""));
+    }
+
+    #[test]
+    fn duplicate_login_refused_or_taken_over() {
+        let addr = std::net::SocketAddr::from(([127, 0, 0, 1], 1));
+        let mut users = ConnectedUsers::default();
+        users.insert(addr, "alice".to_string());
+
+        assert_eq!(
+            check_duplicate_login(false, &users, "alice"),
+            Err("Already logged in.".to_string())
+        );
+        assert_eq!(check_duplicate_login(true, &users, "alice"), Ok(Some(addr)));
+        // Nothing to do for users that aren't connected, either way
+        assert_eq!(check_duplicate_login(false, &users, "bob"), Ok(None));
+        assert_eq!(check_duplicate_login(true, &users, "bob"), Ok(None));
+    }
+}
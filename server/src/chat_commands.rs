@@ -0,0 +1,323 @@
+//! Commands issued by users in chat (i.e. "/kick someone") and by the server operator in TUI
+use std::net::SocketAddr;
+
+use tokio::sync::mpsc::Sender;
+
+use crate::commands::{ChannelCommand, UserPermissions};
+
+/// Who issued the command, which decides what it's allowed to do.
+#[derive(Debug)]
+pub enum CommandContext {
+    /// Server operator using the TUI. Trusted, allowed everything.
+    Local,
+    /// Logged in user with their permissions.
+    User {
+        addr: SocketAddr,
+        permissions: UserPermissions,
+    },
+}
+
+/// What is needed to run a command
+#[derive(Debug, PartialEq, Eq)]
+enum Requirement {
+    None,
+    /// [`UserPermissions::operator`] (or [`CommandContext::Local`])
+    Operator,
+    /// Only [`CommandContext::Local`]
+    Local,
+    /// Only [`CommandContext::User`]
+    User,
+}
+
+impl CommandContext {
+    fn meets(&self, requirement: Requirement) -> bool {
+        match self {
+            CommandContext::Local => requirement != Requirement::User,
+            CommandContext::User { permissions, .. } => match requirement {
+                Requirement::None => true,
+                Requirement::Operator => permissions.operator,
+                Requirement::Local => false,
+                Requirement::User => true,
+            },
+        }
+    }
+}
+
+/// Result of running a command
+#[derive(Debug, PartialEq, Eq)]
+pub enum CommandOutcome {
+    /// Reply for whoever issued the command
+    Reply(String),
+    /// Reply will be sent by the channel (i.e. user list)
+    NoReply,
+    /// Server should exit
+    Exit,
+    /// User is now known under this name
+    Renamed(String),
+}
+
+fn requirement(command: &str) -> Requirement {
+    match command {
+        "list" => Requirement::None,
+        c if accord::packets::OPERATOR_COMMANDS.contains(&c) => Requirement::Operator,
+        "exit" => Requirement::Local,
+        "nick" | "afk" => Requirement::User,
+        // Unknown commands are reported as such
+        _ => Requirement::None,
+    }
+}
+
+/// Parses and runs the command (with or without leading "/"), checking permissions of `context`.
+pub async fn run_command(
+    command: &str,
+    context: &CommandContext,
+    channel_sender: &Sender<ChannelCommand>,
+) -> CommandOutcome {
+    use CommandOutcome::*;
+    let mut split = command.trim_start_matches('/').split(' ');
+    let name = split.next().unwrap_or_default();
+    let arg = split.next();
+    // Everything after the name, for arguments with spaces
+    let rest = command
+        .trim_start_matches('/')
+        .split_once(' ')
+        .map_or("", |(_, rest)| rest.trim());
+    if !context.meets(requirement(name)) {
+        return Reply("Not permitted.".to_string());
+    }
+    let send = |command| async move { channel_sender.send(command).await.unwrap() };
+    match name {
+        "exit" => Exit,
+        "list" => match context {
+            CommandContext::User { addr, .. } => {
+                send(ChannelCommand::UsersQuery(*addr)).await;
+                NoReply
+            }
+            CommandContext::Local => {
+                let (otx, orx) = tokio::sync::oneshot::channel();
+                send(ChannelCommand::UsersQueryTUI(otx)).await;
+                match orx.await {
+                    Ok(list) => Reply(format!("Connected users: {:?}", list)),
+                    Err(e) => Reply(format!("Error while receiving user list: {}", e)),
+                }
+            }
+        },
+        "userinfo" => match arg {
+            Some(target) => {
+                let (otx, orx) = tokio::sync::oneshot::channel();
+                send(ChannelCommand::UserInfo(target.to_owned(), otx)).await;
+                match orx.await {
+                    Ok(Some(report)) => Reply(report),
+                    Ok(None) => Reply(format!("{} is not connected.", target)),
+                    Err(e) => Reply(format!("Error while receiving user info: {}", e)),
+                }
+            }
+            None => Reply("No target provided".to_string()),
+        },
+        "kick" => match arg {
+            Some(target) => {
+                send(ChannelCommand::KickUser(target.to_owned())).await;
+                Reply(format!("{} kicked.", target))
+            }
+            None => Reply("No target provided".to_string()),
+        },
+        "ban" | "unban" => match arg {
+            Some(target) => {
+                let switch = name == "ban";
+                send(ChannelCommand::BanUser(target.to_owned(), switch)).await;
+                Reply(format!(
+                    "{} {}.",
+                    target,
+                    if switch { "banned" } else { "unbanned" }
+                ))
+            }
+            None => Reply("No target provided".to_string()),
+        },
+        "whitelist" | "unwhitelist" => match arg {
+            Some(target) => {
+                let switch = name == "whitelist";
+                send(ChannelCommand::WhitelistUser(target.to_owned(), switch)).await;
+                let what = if switch {
+                    "whitelisted"
+                } else {
+                    "unwhitelisted"
+                };
+                Reply(format!("{} {}.", target, what))
+            }
+            None => Reply("No target provided".to_string()),
+        },
+        "set_whitelist" => match parse_switch(arg) {
+            Ok(state) => {
+                send(ChannelCommand::SetWhitelist(state)).await;
+                Reply(format!("Whitelist {}.", if state { "on" } else { "off" }))
+            }
+            Err(e) => Reply(e),
+        },
+        "set_allow_new_accounts" => match parse_switch(arg) {
+            Ok(state) => {
+                send(ChannelCommand::SetAllowNewAccounts(state)).await;
+                Reply(format!(
+                    "Allow new accounts {}.",
+                    if state { "on" } else { "off" }
+                ))
+            }
+            Err(e) => Reply(e),
+        },
+        "nick" => match (arg, context) {
+            (Some(new_name), CommandContext::User { addr, .. }) => {
+                let (otx, orx) = tokio::sync::oneshot::channel();
+                send(ChannelCommand::RenameUser {
+                    addr: *addr,
+                    new_name: new_name.to_owned(),
+                    otx,
+                })
+                .await;
+                match orx.await {
+                    Ok(Ok(())) => Renamed(new_name.to_owned()),
+                    Ok(Err(e)) => Reply(e),
+                    Err(e) => Reply(format!("Error while renaming: {}", e)),
+                }
+            }
+            _ => Reply("No name provided".to_string()),
+        },
+        "afk" => match context {
+            CommandContext::User { addr, .. } => {
+                // Channel lets everyone know
+                send(ChannelCommand::SetAfk(*addr, rest.to_owned())).await;
+                NoReply
+            }
+            CommandContext::Local => Reply("Not permitted.".to_string()),
+        },
+        c => Reply(format!("Unknown command: {}", c)),
+    }
+}
+
+/// Parses "on"/"off" argument
+fn parse_switch(arg: Option<&str>) -> Result<bool, String> {
+    match arg {
+        Some("on" | "true") => Ok(true),
+        Some("off" | "false") => Ok(false),
+        Some(arg) => Err(format!(
+            "Invalid argument: {}.\nExpected \"on\"/\"off\"",
+            arg
+        )),
+        None => Err("No argument provided".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{run_command, CommandContext, CommandOutcome};
+    use crate::commands::{ChannelCommand, UserPermissions};
+    use tokio::sync::mpsc;
+
+    fn user(operator: bool) -> CommandContext {
+        CommandContext::User {
+            addr: "127.0.0.1:1234".parse().unwrap(),
+            permissions: UserPermissions {
+                operator,
+                ..Default::default()
+            },
+        }
+    }
+
+    /// Runs command and returns its outcome together with commands sent to the channel
+    fn run(command: &str, context: &CommandContext) -> (CommandOutcome, Vec<ChannelCommand>) {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let (tx, mut rx) = mpsc::channel(8);
+        let outcome = rt.block_on(run_command(command, context, &tx));
+        drop(tx);
+        let mut sent = vec![];
+        while let Ok(command) = rx.try_recv() {
+            sent.push(command);
+        }
+        (outcome, sent)
+    }
+
+    #[test]
+    fn operator_commands_need_permission() {
+        for command in ["kick bob", "/ban bob", "whitelist bob", "set_whitelist on"] {
+            let (outcome, sent) = run(command, &CommandContext::Local);
+            assert!(matches!(outcome, CommandOutcome::Reply(_)), "{}", command);
+            assert_eq!(sent.len(), 1, "{}", command);
+
+            let (outcome, sent) = run(command, &user(true));
+            assert!(matches!(outcome, CommandOutcome::Reply(_)), "{}", command);
+            assert_eq!(sent.len(), 1, "{}", command);
+
+            let (outcome, sent) = run(command, &user(false));
+            assert_eq!(outcome, CommandOutcome::Reply("Not permitted.".to_string()));
+            assert!(sent.is_empty(), "{}", command);
+        }
+        let (outcome, sent) = run("userinfo bob", &user(false));
+        assert_eq!(outcome, CommandOutcome::Reply("Not permitted.".to_string()));
+        assert!(sent.is_empty());
+    }
+
+    #[test]
+    fn same_command_in_both_contexts() {
+        let (local, local_sent) = run("kick bob", &CommandContext::Local);
+        let (remote, remote_sent) = run("kick bob", &user(true));
+        assert_eq!(local, remote);
+        assert_eq!(local, CommandOutcome::Reply("bob kicked.".to_string()));
+        for sent in [local_sent, remote_sent] {
+            assert!(matches!(&sent[..], [ChannelCommand::KickUser(target)] if target == "bob"));
+        }
+
+        let (outcome, sent) = run("set_allow_new_accounts maybe", &CommandContext::Local);
+        assert!(matches!(outcome, CommandOutcome::Reply(m) if m.starts_with("Invalid argument")));
+        assert!(sent.is_empty());
+    }
+
+    #[test]
+    fn exit_is_local_only() {
+        assert_eq!(run("exit", &CommandContext::Local).0, CommandOutcome::Exit);
+        assert_eq!(
+            run("exit", &user(true)).0,
+            CommandOutcome::Reply("Not permitted.".to_string())
+        );
+    }
+
+    #[test]
+    fn nick_is_user_only() {
+        assert_eq!(
+            run("nick bob", &CommandContext::Local).0,
+            CommandOutcome::Reply("Not permitted.".to_string())
+        );
+        let (outcome, sent) = run("nick", &user(false));
+        assert_eq!(
+            outcome,
+            CommandOutcome::Reply("No name provided".to_string())
+        );
+        assert!(sent.is_empty());
+    }
+
+    #[test]
+    fn afk_keeps_whole_note() {
+        let (outcome, sent) = run("/afk out for  lunch ", &user(false));
+        assert_eq!(outcome, CommandOutcome::NoReply);
+        assert!(matches!(&sent[..], [ChannelCommand::SetAfk(_, note)] if note == "out for  lunch"));
+
+        let (_, sent) = run("afk", &user(false));
+        assert!(matches!(&sent[..], [ChannelCommand::SetAfk(_, note)] if note.is_empty()));
+        assert_eq!(
+            run("afk", &CommandContext::Local).0,
+            CommandOutcome::Reply("Not permitted.".to_string())
+        );
+    }
+
+    #[test]
+    fn list_for_user_is_answered_by_channel() {
+        let (outcome, sent) = run("list", &user(false));
+        assert_eq!(outcome, CommandOutcome::NoReply);
+        assert!(matches!(&sent[..], [ChannelCommand::UsersQuery(_)]));
+
+        let (outcome, _) = run("dance", &user(false));
+        assert_eq!(
+            outcome,
+            CommandOutcome::Reply("Unknown command: dance".to_string())
+        );
+    }
+}
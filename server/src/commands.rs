@@ -1,31 +1,34 @@
 //! Commands used internally for communication between connections and channel loop
 use accord::packets::*;
+use accord::CryptoSuite;
 use std::net::SocketAddr;
+use std::sync::Arc;
+
+use crate::connection::ConnectionStats;
 
 use tokio::sync::{mpsc::Sender, oneshot::Sender as OSender};
 
 /// Fetched permissions of the user.
-#[derive(Debug, Default)]
-pub struct UserPermissions {
-    pub operator: bool,
-    pub whitelisted: bool,
-    pub banned: bool,
-}
+pub use accord::packets::UserPermissions;
 
 /// Commands sent to client-server connection handlers.
 #[derive(Debug)]
 pub enum ConnectionCommand {
     Write(ClientboundPacket),
     SetSecret(Option<Vec<u8>>),
+    /// Closes the connection without telling the client why (i.e. it's already gone)
     Close,
+    /// Lets the client know why and closes the connection
+    Disconnect(DisconnectReason),
 }
 
 /// Commands sent to [`AccordChannel`](`crate::channel::AccordChannel`)
 #[derive(Debug)]
 pub enum ChannelCommand {
-    Close,
+    /// Lets users know, saves config and stops the channel loop, replies when done
+    Close(OSender<()>),
     Write(ClientboundPacket),
-    EncryptionRequest(Sender<ConnectionCommand>, OSender<Vec<u8>>),
+    EncryptionRequest(Sender<ConnectionCommand>, CryptoSuite, OSender<Vec<u8>>),
     // Maybe this should be a struct?
     EncryptionConfirm(
         Sender<ConnectionCommand>,
@@ -40,18 +43,48 @@ pub enum ChannelCommand {
         addr: SocketAddr,
         otx: OSender<LoginResult>,
         tx: Sender<ConnectionCommand>,
+        stats: Arc<ConnectionStats>,
+    },
+    /// [`ChannelCommand::LoginAttempt`] after its password was checked off the channel loop
+    LoginChecked {
+        username: String,
+        addr: SocketAddr,
+        otx: OSender<LoginResult>,
+        tx: Sender<ConnectionCommand>,
+        stats: Arc<ConnectionStats>,
+        /// Row of the account, `None` for a new one
+        account: Option<tokio_postgres::Row>,
+        /// Hash to store for a new account or in place of a legacy one, if the login can go on
+        checked: Result<Option<String>, String>,
     },
     UserJoined(String),
     UserLeft(SocketAddr),
     UsersQuery(SocketAddr),
-    UsersQueryTUI(OSender<Vec<String>>),
+    UsersQueryTUI(OSender<Arc<Vec<String>>>),
+    ServerInfoQuery(SocketAddr),
     FetchMessages(i64, i64, OSender<Vec<ClientboundPacket>>),
+    FetchMessagesAround(i64, i64, OSender<Vec<ClientboundPacket>>),
+    FetchImage(String, OSender<Option<Vec<u8>>>),
     CheckPermissions(String, OSender<UserPermissions>),
     KickUser(String),
     BanUser(String, bool),
     WhitelistUser(String, bool),
     SetWhitelist(bool),
     SetAllowNewAccounts(bool),
+    /// Renames user connected from `addr` to `new_name`, replies with error on failure
+    RenameUser {
+        addr: SocketAddr,
+        new_name: String,
+        otx: OSender<Result<(), String>>,
+    },
+    /// Checks if database is reachable, replies with error if it isn't
+    HealthCheck(OSender<Result<(), String>>),
+    /// Replies with connection stats of given user, `None` if they aren't connected
+    UserInfo(String, OSender<Option<String>>),
+    /// Sets name color of user with given id, `None` resets it
+    SetColor(i64, Option<String>),
+    /// Marks user connected from `addr` as away with a note (can be empty)
+    SetAfk(SocketAddr, String),
 }
 
-pub type LoginResult = Result<String, String>;
+pub type LoginResult = Result<(i64, String), String>;
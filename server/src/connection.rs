@@ -1,7 +1,11 @@
+use crate::chat_commands::{run_command, CommandContext, CommandOutcome};
 use crate::commands::*;
 use accord::connection::*;
 use accord::packets::*;
-use accord::utils::verify_message;
+use accord::utils::{format_relative, trim_line_ending, validate_command, validate_message};
+use accord::CryptoSuite;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 use tokio::sync::mpsc::{self, Receiver, Sender};
 use tokio::sync::oneshot;
 
@@ -13,22 +17,73 @@ pub struct ConnectionWrapper; // Maybe this shouldn't be a struct?
 
 impl ConnectionWrapper {
     /// Handles incoming connection and spawns reading and writing loops.
+    ///
+    /// Packets over `max_packet_size` bytes end the connection, see [`Config::max_packet_size`](crate::config::Config::max_packet_size).
     pub async fn spawn(
         socket: tokio::net::TcpStream,
         addr: std::net::SocketAddr,
         ctx: Sender<ChannelCommand>,
+        max_packet_size: usize,
     ) {
         let (tx, rx) = mpsc::channel::<ConnectionCommand>(32);
         log::info!("Connection from: {:?}", addr);
         let connection = Connection::<ServerboundPacket, ClientboundPacket>::new(socket);
-        let (reader, writer) = connection.split();
-        let reader_wrapped = ConnectionReaderWrapper::new(reader, addr, tx, ctx);
+        let (mut reader, writer) = connection.split();
+        reader.set_max_packet_size(max_packet_size);
+        let stats = Arc::new(ConnectionStats::new(current_time_as_millis()));
+        let reader_wrapped = ConnectionReaderWrapper::new(reader, addr, tx, ctx, stats.clone());
         tokio::spawn(reader_wrapped.spawn_loop());
-        let writer_wrapped = ConnectionWriterWrapper::new(writer, rx);
+        let writer_wrapped = ConnectionWriterWrapper::new(writer, rx, stats);
         tokio::spawn(writer_wrapped.spawn_loop());
     }
 }
 
+/// Counters of a single connection, updated by its reader and writer.
+#[derive(Debug)]
+pub struct ConnectionStats {
+    /// Milliseconds since unix epoch
+    connected_at: i64,
+    messages_sent: AtomicU64,
+    bytes_received: AtomicU64,
+    bytes_sent: AtomicU64,
+}
+
+impl ConnectionStats {
+    pub fn new(connected_at: i64) -> Self {
+        Self {
+            connected_at,
+            messages_sent: AtomicU64::new(0),
+            bytes_received: AtomicU64::new(0),
+            bytes_sent: AtomicU64::new(0),
+        }
+    }
+
+    /// Counts a message (or an image) accepted from the user
+    fn message_sent(&self) {
+        self.messages_sent.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn set_bytes_received(&self, bytes: u64) {
+        self.bytes_received.store(bytes, Ordering::Relaxed);
+    }
+
+    fn set_bytes_sent(&self, bytes: u64) {
+        self.bytes_sent.store(bytes, Ordering::Relaxed);
+    }
+
+    /// Summary for operators (see `/userinfo`)
+    pub fn report(&self, username: &str, now: i64) -> String {
+        format!(
+            "{}: connected {}, {} messages sent, {} bytes received, {} bytes sent.",
+            username,
+            format_relative(self.connected_at, now),
+            self.messages_sent.load(Ordering::Relaxed),
+            self.bytes_received.load(Ordering::Relaxed),
+            self.bytes_sent.load(Ordering::Relaxed),
+        )
+    }
+}
+
 pub struct ConnectionReaderWrapper {
     reader: ConnectionReader<ServerboundPacket>,
     addr: std::net::SocketAddr,
@@ -38,6 +93,7 @@ pub struct ConnectionReaderWrapper {
     username: Option<String>,
     secret: Option<Vec<u8>>,
     nonce_generator: Option<ChaCha20Rng>,
+    stats: Arc<ConnectionStats>,
 }
 
 impl ConnectionReaderWrapper {
@@ -46,6 +102,7 @@ impl ConnectionReaderWrapper {
         addr: std::net::SocketAddr,
         connection_sender: Sender<ConnectionCommand>,
         channel_sender: Sender<ChannelCommand>,
+        stats: Arc<ConnectionStats>,
     ) -> Self {
         Self {
             reader,
@@ -56,6 +113,7 @@ impl ConnectionReaderWrapper {
             username: None,
             secret: None,
             nonce_generator: None,
+            stats,
         }
     }
 
@@ -68,17 +126,34 @@ impl ConnectionReaderWrapper {
                 addr: self.addr,
                 otx,
                 tx: self.connection_sender.clone(),
+                stats: self.stats.clone(),
             })
             .await
             .unwrap();
         match orx.await.unwrap() {
-            Ok(response) => {
-                let mut response_split = response.split('|');
-                self.user_id = Some(response_split.next().unwrap().parse().unwrap());
-                self.username = Some(response_split.next().unwrap().parse().unwrap());
+            Ok((user_id, username)) => {
+                self.user_id = Some(user_id);
+                self.username = Some(username);
 
                 self.connection_sender
-                    .send(ConnectionCommand::Write(ClientboundPacket::LoginAck))
+                    .send(ConnectionCommand::Write(ClientboundPacket::LoginAck {
+                        user_id: self.user_id.unwrap(),
+                        username: self.username.clone().unwrap(),
+                    }))
+                    .await
+                    .unwrap();
+                match self.get_perms(self.username.clone().unwrap()).await {
+                    Ok(permissions) => self
+                        .connection_sender
+                        .send(ConnectionCommand::Write(
+                            ClientboundPacket::YourPermissions(permissions),
+                        ))
+                        .await
+                        .unwrap(),
+                    Err(e) => log::error!("Error while getting permissions: {}", e),
+                }
+                self.channel_sender
+                    .send(ChannelCommand::ServerInfoQuery(self.addr))
                     .await
                     .unwrap();
                 self.channel_sender
@@ -99,13 +174,27 @@ impl ConnectionReaderWrapper {
         }
     }
 
-    async fn handle_encryption_request(&mut self) {
+    async fn handle_encryption_request(&mut self, offered: Vec<CryptoSuite>) {
         use ServerboundPacket::*;
+        let suite = match CryptoSuite::negotiate(&offered) {
+            Some(suite) => suite,
+            None => {
+                log::warn!("Client offered no supported crypto suite: {:?}.", offered);
+                self.connection_sender
+                    .send(ConnectionCommand::Disconnect(
+                        DisconnectReason::ProtocolError,
+                    ))
+                    .await
+                    .ok(); // it's ok if already closed
+                return;
+            }
+        };
         // To send back the token
         let (otx, orx) = oneshot::channel();
         self.channel_sender
             .send(ChannelCommand::EncryptionRequest(
                 self.connection_sender.clone(),
+                suite,
                 otx,
             ))
             .await
@@ -152,14 +241,16 @@ impl ConnectionReaderWrapper {
             Ok(_) => {
                 log::warn!("Client sent wrong packet during encryption handshake.");
                 self.connection_sender
-                    .send(ConnectionCommand::Close)
+                    .send(ConnectionCommand::Disconnect(
+                        DisconnectReason::ProtocolError,
+                    ))
                     .await
                     .ok(); // it's ok if already closed
             }
-            Err(_) => {
+            Err(e) => {
                 log::warn!("Error during encryption handshake.");
                 self.connection_sender
-                    .send(ConnectionCommand::Close)
+                    .send(read_error_command(&e))
                     .await
                     .ok(); // it's ok if already closed
             }
@@ -187,151 +278,60 @@ impl ConnectionReaderWrapper {
                 }
             }
             // Users requests encryption
-            EncryptionRequest => self.handle_encryption_request().await,
+            EncryptionRequest(offered) => self.handle_encryption_request(offered).await,
             // rest is only for logged in users
             p => {
                 if self.username.is_some() {
                     match p {
-                        // User wants to send a message
-                        Message(m) => {
-                            if verify_message(&m) {
-                                let p = ClientboundPacket::Message(accord::packets::Message {
-                                    sender_id: self.user_id.unwrap(),
-                                    sender: self.username.clone().unwrap(),
-                                    text: m,
-                                    time: current_time_as_sec(),
-                                });
-                                self.channel_sender
-                                    .send(ChannelCommand::Write(p))
-                                    .await
-                                    .unwrap();
-                            } else {
-                                log::info!("Invalid message from {:?}: {}", self.username, m);
+                        // User wants to send a message or an image
+                        p @ (Message(_) | ImageMessage(_)) => {
+                            let p = sent_by_user(
+                                self.user_id.unwrap(),
+                                self.username.as_ref().unwrap(),
+                                current_time_as_millis(),
+                                p,
+                            );
+                            match p {
+                                Ok(p) => {
+                                    self.stats.message_sent();
+                                    self.channel_sender
+                                        .send(ChannelCommand::Write(p))
+                                        .await
+                                        .unwrap()
+                                }
+                                Err(rejection) => {
+                                    log::info!("Invalid message from {:?}", self.username);
+                                    self.connection_sender
+                                        .send(ConnectionCommand::Write(rejection))
+                                        .await
+                                        .unwrap();
+                                }
                             }
                         }
-                        // User sends an image
-                        ImageMessage(im) => {
-                            let p =
-                                ClientboundPacket::ImageMessage(accord::packets::ImageMessage {
-                                    image_bytes: im,
-                                    sender_id: self.user_id.unwrap(),
-                                    sender: self.username.clone().unwrap(),
-                                    time: current_time_as_sec(),
-                                });
-                            self.channel_sender
-                                .send(ChannelCommand::Write(p))
-                                .await
-                                .unwrap();
-                        }
                         // User issued a commend (i.e "/list")
                         Command(command) => {
-                            //TODO: abstract this code more
-                            let mut split = command.as_str().split(' ');
-                            if let Some(command) = split.next() {
-                                match command {
-                                    "list" => {
-                                        self.channel_sender
-                                            .send(ChannelCommand::UsersQuery(self.addr))
-                                            .await
-                                            .unwrap();
-                                    }
-                                    "kick" => {
-                                        let m = if let Some(target) = split.next() {
-                                            let perms = self
-                                                .get_perms(self.username.to_owned().unwrap())
-                                                .await;
-                                            if let Ok(perms) = perms {
-                                                if perms.operator {
-                                                    self.channel_sender
-                                                        .send(ChannelCommand::KickUser(
-                                                            target.to_owned(),
-                                                        ))
-                                                        .await
-                                                        .unwrap();
-                                                    format!("{} kicked.", target)
-                                                } else {
-                                                    "Not permitted.".to_owned()
-                                                }
-                                            } else {
-                                                "Error.".to_owned()
-                                            }
-                                        } else {
-                                            "No target provided".to_owned()
-                                        };
-                                        self.respond(m).await;
-                                    }
-                                    "ban" => {
-                                        self.ban_command(split.next(), true).await;
-                                    }
-                                    "unban" => {
-                                        self.ban_command(split.next(), false).await;
-                                    }
-                                    "whitelist" => {
-                                        self.whitelist_command(split.next(), true).await;
-                                    }
-                                    "unwhitelist" => {
-                                        self.whitelist_command(split.next(), false).await;
-                                    }
-                                    "set_whitelist" => {
-                                        let m = if let Some(arg) = split.next() {
-                                            match arg {
-                                                "on" | "true" => {
-                                                    self.channel_sender
-                                                        .send(ChannelCommand::SetWhitelist(true))
-                                                        .await
-                                                        .unwrap();
-                                                    "Whitelist on.".to_string()
-                                                }
-                                                "off" | "false" => {
-                                                    self.channel_sender
-                                                        .send(ChannelCommand::SetWhitelist(false))
-                                                        .await
-                                                        .unwrap();
-                                                    "Whitelist off.".to_string()
-                                                }
-                                                _ => {
-                                                    format!("Invalid argument: {}.\nExpected \"on\"/\"off\"", arg)
-                                                }
-                                            }
-                                        } else {
-                                            "No argument provided".to_string()
-                                        };
-                                        self.respond(m).await;
-                                    }
-                                    "set_allow_new_accounts" => {
-                                        let m = if let Some(arg) = split.next() {
-                                            match arg {
-                                                "on" | "true" => {
-                                                    self.channel_sender
-                                                        .send(ChannelCommand::SetAllowNewAccounts(
-                                                            true,
-                                                        ))
-                                                        .await
-                                                        .unwrap();
-                                                    "Allow new accounts on.".to_string()
-                                                }
-                                                "off" | "false" => {
-                                                    self.channel_sender
-                                                        .send(ChannelCommand::SetAllowNewAccounts(
-                                                            false,
-                                                        ))
-                                                        .await
-                                                        .unwrap();
-                                                    "Allow new accounts off.".to_string()
-                                                }
-                                                _ => {
-                                                    format!("Invalid argument: {}.\nExpected \"on\"/\"off\"", arg)
-                                                }
-                                            }
-                                        } else {
-                                            "No argument provided".to_string()
-                                        };
-                                        self.respond(m).await;
-                                    }
-                                    c => {
-                                        self.respond(format!("Unknown command: {}", c)).await;
-                                    }
+                            if let Err(e) = validate_command(&command) {
+                                log::info!("Invalid command from {:?}: {}", self.username, e);
+                                self.respond(format!("Invalid command: {}.", e)).await;
+                                return;
+                            }
+                            let context = match self.get_perms(self.username.clone().unwrap()).await
+                            {
+                                Ok(permissions) => CommandContext::User {
+                                    addr: self.addr,
+                                    permissions,
+                                },
+                                Err(e) => {
+                                    log::error!("Error while getting permissions: {}", e);
+                                    self.respond("Error.".to_string()).await;
+                                    return;
                                 }
+                            };
+                            match run_command(&command, &context, &self.channel_sender).await {
+                                CommandOutcome::Reply(m) => self.respond(m).await,
+                                CommandOutcome::Renamed(new_name) => self.username = Some(new_name),
+                                // Users can't exit the server, so `Exit` never happens here
+                                CommandOutcome::NoReply | CommandOutcome::Exit => (),
                             }
                         }
                         FetchMessages(o, n) => {
@@ -340,14 +340,50 @@ impl ConnectionReaderWrapper {
                                 .send(ChannelCommand::FetchMessages(o, n, otx))
                                 .await
                                 .unwrap();
-                            let mut messages = orx.await.unwrap();
-                            for m in messages.drain(..).rev() {
+                            self.write_fetched_messages(orx.await.unwrap()).await;
+                        }
+                        FetchMessagesAround { time, count } => {
+                            let (otx, orx) = oneshot::channel();
+                            self.channel_sender
+                                .send(ChannelCommand::FetchMessagesAround(time, count, otx))
+                                .await
+                                .unwrap();
+                            self.write_fetched_messages(orx.await.unwrap()).await;
+                        }
+                        FetchImage(hash) => {
+                            if accord::utils::verify_image_hash(&hash) {
+                                let (otx, orx) = oneshot::channel();
+                                self.channel_sender
+                                    .send(ChannelCommand::FetchImage(hash.clone(), otx))
+                                    .await
+                                    .unwrap();
+                                let p = ClientboundPacket::ImageData {
+                                    hash,
+                                    bytes: orx.await.unwrap(),
+                                };
                                 self.connection_sender
-                                    .send(ConnectionCommand::Write(m))
+                                    .send(ConnectionCommand::Write(p))
                                     .await
                                     .unwrap();
+                            } else {
+                                log::info!("Invalid image hash from {:?}: {}", self.username, hash);
                             }
                         }
+                        SetColor(color) => {
+                            let color = if color.is_empty() {
+                                None
+                            } else if accord::utils::hex_color_rgb(&color).is_some() {
+                                Some(color.to_lowercase())
+                            } else {
+                                self.respond("Invalid color, expected #rrggbb.".to_string())
+                                    .await;
+                                return;
+                            };
+                            self.channel_sender
+                                .send(ChannelCommand::SetColor(self.user_id.unwrap(), color))
+                                .await
+                                .unwrap();
+                        }
                         p => {
                             unreachable!("{:?} should have been handled!", p);
                         }
@@ -359,6 +395,16 @@ impl ConnectionReaderWrapper {
         };
     }
 
+    /// Writes fetched messages (ordered from newest) to user as one batch, oldest first.
+    async fn write_fetched_messages(&self, mut messages: Vec<ClientboundPacket>) {
+        messages.reverse();
+        let batch = ClientboundPacket::MessageBatch(messages);
+        self.connection_sender
+            .send(ConnectionCommand::Write(batch))
+            .await
+            .unwrap();
+    }
+
     /// Listens for incoming packets from user and handles them.
     async fn spawn_loop(mut self) {
         loop {
@@ -368,6 +414,7 @@ impl ConnectionReaderWrapper {
                 .await
             {
                 Ok(p) => {
+                    self.stats.set_bytes_received(self.reader.bytes_read());
                     match p {
                         Some(ServerboundPacket::ImageMessage(_)) => {
                             log::trace!("Got image packet");
@@ -384,12 +431,12 @@ impl ConnectionReaderWrapper {
                         .await
                         .unwrap();
                     self.connection_sender
-                        .send(ConnectionCommand::Close)
+                        .send(read_error_command(&e))
                         .await
                         .ok(); // it's ok if already closed
 
                     // This "error" is expected
-                    if e == "Connection reset by peer" {
+                    if let ConnectionError::Closed = e {
                         log::info!("{}", e);
                     } else {
                         log::error!("Err: {:?}", e);
@@ -413,64 +460,14 @@ impl ConnectionReaderWrapper {
         orx.await
     }
 
-    /// switch == true => ban
-    /// switch == false => unban
-    async fn ban_command(&mut self, target: Option<&str>, switch: bool) {
-        let m = if let Some(target) = target {
-            let perms = self.get_perms(self.username.to_owned().unwrap()).await;
-            if let Ok(perms) = perms {
-                if perms.operator {
-                    self.channel_sender
-                        .send(ChannelCommand::BanUser(target.to_owned(), switch))
-                        .await
-                        .unwrap();
-                    let prefix = if switch { "" } else { "un" };
-                    format!("{} {}banned.", target, prefix)
-                } else {
-                    "Not permitted.".to_owned()
-                }
-            } else {
-                "Error.".to_owned()
-            }
-        } else {
-            "No target provided".to_owned()
-        };
-        self.respond(m).await;
-    }
-
-    /// switch == true => add to whitelist
-    /// switch == false => remove form whitelist
-    async fn whitelist_command(&mut self, target: Option<&str>, switch: bool) {
-        let m = if let Some(target) = target {
-            let perms = self.get_perms(self.username.to_owned().unwrap()).await;
-            if let Ok(perms) = perms {
-                if perms.operator {
-                    self.channel_sender
-                        .send(ChannelCommand::WhitelistUser(target.to_owned(), switch))
-                        .await
-                        .unwrap();
-                    let prefix = if switch { "" } else { "un" };
-                    format!("{} {}whitelisted.", target, prefix)
-                } else {
-                    "Not permitted.".to_owned()
-                }
-            } else {
-                "Error.".to_owned()
-            }
-        } else {
-            "No target provided".to_owned()
-        };
-        self.respond(m).await;
-    }
-
     /// Sends `message` to the user of this channel as a reply from the server.
+    ///
+    /// Goes straight to this connection (not through the channel), so it's never broadcast or stored.
     async fn respond(&mut self, message: String) {
-        let p = ClientboundPacket::Message(accord::packets::Message {
-            sender_id: 0,
-            sender: "#SERVER#".to_string(),
-            text: message,
-            time: current_time_as_sec(),
-        });
+        let p = ClientboundPacket::CommandResponse(accord::packets::Message::from_server(
+            message,
+            current_time_as_millis(),
+        ));
         self.connection_sender
             .send(ConnectionCommand::Write(p))
             .await
@@ -483,51 +480,596 @@ pub struct ConnectionWriterWrapper {
     connection_receiver: Receiver<ConnectionCommand>,
     secret: Option<Vec<u8>>,
     nonce_generator: Option<ChaCha20Rng>,
+    stats: Arc<ConnectionStats>,
 }
 impl ConnectionWriterWrapper {
     fn new(
         writer: ConnectionWriter<ClientboundPacket>,
         connection_receiver: Receiver<ConnectionCommand>,
+        stats: Arc<ConnectionStats>,
     ) -> Self {
         Self {
             writer,
             connection_receiver,
             secret: None,
             nonce_generator: None,
+            stats,
         }
     }
 
     /// Listens for commands and sends packets to user.
+    ///
+    /// Written packets are flushed once no more commands are waiting,
+    /// so a burst of writes (e.g. broadcasts) costs a single flush.
     async fn spawn_loop(mut self) {
-        loop {
-            if let Some(com) = self.connection_receiver.recv().await {
-                use ConnectionCommand::*;
-                match com {
-                    Close => break,
-                    SetSecret(s) => {
-                        self.secret = s.clone();
-                        let mut seed = [0u8; accord::SECRET_LEN];
-                        seed.copy_from_slice(&s.unwrap());
-
-                        self.nonce_generator = Some(ChaCha20Rng::from_seed(seed));
-                    }
-                    Write(p) => self
-                        .writer
-                        .write_packet(p, &self.secret, self.nonce_generator.as_mut())
-                        .await
-                        .unwrap(),
+        while let Some(com) = self.connection_receiver.recv().await {
+            let mut next = Some(com);
+            while let Some(com) = next {
+                if !self.handle_command(com).await {
+                    self.writer.flush().await.ok();
+                    return;
                 }
+                next = self.connection_receiver.try_recv().ok();
             }
+            self.writer.flush().await.unwrap();
+            self.stats.set_bytes_sent(self.writer.bytes_written());
+        }
+    }
+
+    /// Queues packets to write (without flushing), returns `false` if connection should close.
+    async fn handle_command(&mut self, com: ConnectionCommand) -> bool {
+        use ConnectionCommand::*;
+        match com {
+            Close => return false,
+            Disconnect(reason) => {
+                // Flushed by the writer loop before closing
+                self.writer
+                    .queue_packet(
+                        ClientboundPacket::Disconnect(reason),
+                        &self.secret,
+                        self.nonce_generator.as_mut(),
+                    )
+                    .await
+                    .ok();
+                return false;
+            }
+            SetSecret(s) => {
+                self.secret = s.clone();
+                let mut seed = [0u8; accord::SECRET_LEN];
+                seed.copy_from_slice(&s.unwrap());
+
+                self.nonce_generator = Some(ChaCha20Rng::from_seed(seed));
+            }
+            Write(p) => self
+                .writer
+                .queue_packet(p, &self.secret, self.nonce_generator.as_mut())
+                .await
+                .unwrap(),
         }
+        true
     }
 }
 
-/// Current time since unix epoch in seconds
+/// Current time since unix epoch in milliseconds
 #[inline]
-fn current_time_as_sec() -> u64 {
+pub(crate) fn current_time_as_millis() -> i64 {
     use std::time::{SystemTime, UNIX_EPOCH};
     SystemTime::now()
         .duration_since(UNIX_EPOCH)
         .unwrap()
-        .as_secs()
+        .as_millis()
+        .try_into()
+        .expect("Time since epoch doesn't fit in i64!")
+}
+
+/// How to close connection after reading from it failed with `e`.
+///
+/// Client that went away isn't told anything, one that sent garbage gets
+/// [`DisconnectReason::ProtocolError`].
+fn read_error_command(e: &ConnectionError) -> ConnectionCommand {
+    match e {
+        ConnectionError::Closed | ConnectionError::Io(_) => ConnectionCommand::Close,
+        _ => ConnectionCommand::Disconnect(DisconnectReason::ProtocolError),
+    }
+}
+
+/// Builds packet broadcast to the channel when user sends a message or an image.
+///
+/// Sender is always the authenticated user (`user_id`, `username`).
+/// Serverbound packets carry no sender, so clients can't send as someone else.
+///
+/// For invalid messages and other packets returns [`ClientboundPacket::MessageRejected`]
+/// to send back to the user.
+fn sent_by_user(
+    user_id: i64,
+    username: &str,
+    time: i64,
+    packet: ServerboundPacket,
+) -> Result<ClientboundPacket, ClientboundPacket> {
+    let rejected = |reason: String| ClientboundPacket::MessageRejected { reason };
+    match packet {
+        ServerboundPacket::Message(m) => {
+            let m = trim_line_ending(&m);
+            validate_message(m)
+                .map(|()| {
+                    ClientboundPacket::Message(accord::packets::Message {
+                        sender_id: user_id,
+                        sender: username.to_string(),
+                        text: m.to_string(),
+                        time,
+                        // Filled in by the channel
+                        sender_color: None,
+                    })
+                })
+                .map_err(|e| rejected(format!("Message {}.", e)))
+        }
+        ServerboundPacket::ImageMessage(im) => Ok(ClientboundPacket::ImageMessage(
+            accord::packets::ImageMessage::new(user_id, username.to_string(), time, im),
+        )),
+        _ => Err(rejected("Not a message.".to_string())),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{current_time_as_millis, sent_by_user, ConnectionStats, ConnectionWrapper};
+    use crate::commands::{ChannelCommand, ConnectionCommand, UserPermissions};
+    use accord::packets::{ClientboundPacket, Packet, ServerboundPacket};
+
+    #[test]
+    fn current_time_is_in_millis() {
+        use std::time::{SystemTime, UNIX_EPOCH};
+        let secs = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+        let millis = current_time_as_millis();
+        assert!((secs..=secs + 1).contains(&(millis / 1000)));
+    }
+
+    #[test]
+    fn sender_is_authenticated_user() {
+        // Client tries to look like someone else
+        let crafted = [
+            ServerboundPacket::Message("admin (12:00): hi".to_string()),
+            ServerboundPacket::ImageMessage(b"admin".to_vec()),
+        ];
+        for packet in crafted {
+            // Goes through the wire like a real packet
+            let (packet, _) = ServerboundPacket::deserialized(&packet.serialized()).unwrap();
+            match sent_by_user(7, "mallory", 1000, packet) {
+                Ok(ClientboundPacket::Message(m)) => {
+                    assert_eq!((m.sender_id, m.sender.as_str()), (7, "mallory"));
+                    assert_eq!(m.time, 1000);
+                }
+                Ok(ClientboundPacket::ImageMessage(im)) => {
+                    assert_eq!((im.sender_id, im.sender.as_str()), (7, "mallory"));
+                    assert_eq!(im.time, 1000);
+                }
+                p => panic!("Unexpected packet: {:?}", p),
+            }
+        }
+
+        assert!(sent_by_user(7, "mallory", 0, ServerboundPacket::Message("\u{7}".into())).is_err());
+        assert!(sent_by_user(7, "mallory", 0, ServerboundPacket::Ping).is_err());
+    }
+
+    #[test]
+    fn rejected_message_is_reported() {
+        let (packet, _) = ServerboundPacket::deserialized(
+            &ServerboundPacket::Message("hi\u{7}".into()).serialized(),
+        )
+        .unwrap();
+        assert_eq!(
+            sent_by_user(7, "alice", 0, packet),
+            Err(ClientboundPacket::MessageRejected {
+                reason: "Message contains control characters.".to_string()
+            })
+        );
+        assert_eq!(
+            sent_by_user(7, "alice", 0, ServerboundPacket::Message(String::new())),
+            Err(ClientboundPacket::MessageRejected {
+                reason: "Message can't be empty.".to_string()
+            })
+        );
+        let overlong = "a".repeat(accord::utils::MAX_MESSAGE_LEN + 1);
+        assert_eq!(
+            sent_by_user(7, "alice", 0, ServerboundPacket::Message(overlong)),
+            Err(ClientboundPacket::MessageRejected {
+                reason: "Message too long (max 1023 bytes).".to_string()
+            })
+        );
+    }
+
+    /// Stands in for the channel loop and its database: does the encryption handshake,
+    /// accepts any login valid with `|` allowed in usernames, makes "admin" an operator
+    /// and keeps messages in memory.
+    async fn fake_channel(mut rx: tokio::sync::mpsc::Receiver<ChannelCommand>) {
+        use rsa::{pkcs8::ToPublicKey, PaddingScheme, RsaPrivateKey, RsaPublicKey};
+        let priv_key = RsaPrivateKey::new(&mut rand::rngs::OsRng, accord::RSA_BITS).unwrap();
+        let mut messages: Vec<ClientboundPacket> = vec![];
+        let mut users = vec![];
+        let policy = accord::utils::UsernamePolicy {
+            allowed_special: "|".to_string(),
+            ..Default::default()
+        };
+        while let Some(command) = rx.recv().await {
+            match command {
+                ChannelCommand::EncryptionRequest(tx, suite, otx) => {
+                    let pub_key = RsaPublicKey::from(&priv_key).to_public_key_der().unwrap();
+                    let token = vec![7; accord::ENC_TOK_LEN];
+                    let p = ClientboundPacket::EncryptionResponse(
+                        pub_key.as_ref().to_vec(),
+                        token.clone(),
+                        suite,
+                    );
+                    tx.send(ConnectionCommand::Write(p)).await.unwrap();
+                    otx.send(token).unwrap();
+                }
+                ChannelCommand::EncryptionConfirm(tx, otx, enc_s, enc_t, exp_t) => {
+                    let decrypt = |data: &[u8]| {
+                        let padding = PaddingScheme::new_pkcs1v15_encrypt();
+                        priv_key.decrypt(padding, data).unwrap()
+                    };
+                    assert_eq!(decrypt(&enc_t), exp_t);
+                    let s = decrypt(&enc_s);
+                    otx.send(Ok(s.clone())).unwrap();
+                    tx.send(ConnectionCommand::SetSecret(Some(s)))
+                        .await
+                        .unwrap();
+                    tx.send(ConnectionCommand::Write(ClientboundPacket::EncryptionAck))
+                        .await
+                        .unwrap();
+                }
+                ChannelCommand::LoginAttempt {
+                    username, otx, tx, ..
+                } => match policy.validate(&username) {
+                    Ok(()) => {
+                        users.push(tx);
+                        otx.send(Ok((users.len() as i64, username))).unwrap();
+                    }
+                    Err(e) => otx.send(Err(e.to_string())).unwrap(),
+                },
+                ChannelCommand::CheckPermissions(username, otx) => otx
+                    .send(UserPermissions {
+                        operator: username == "admin",
+                        ..Default::default()
+                    })
+                    .unwrap(),
+                ChannelCommand::Write(p) => {
+                    for tx in &users {
+                        tx.send(ConnectionCommand::Write(p.clone())).await.ok();
+                    }
+                    messages.push(p);
+                }
+                ChannelCommand::FetchMessages(offset, count, otx) => {
+                    // Newest first, like from the database
+                    let fetched = messages
+                        .iter()
+                        .rev()
+                        .skip(offset as usize)
+                        .take(count as usize)
+                        .cloned()
+                        .collect();
+                    otx.send(fetched).unwrap();
+                }
+                _ => (),
+            }
+        }
+    }
+
+    /// Binds a server backed by [`fake_channel`], accepting any number of connections.
+    async fn fake_server() -> std::net::SocketAddr {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+        let (ctx, crx) = tokio::sync::mpsc::channel(32);
+        tokio::spawn(fake_channel(crx));
+        tokio::spawn(async move {
+            while let Ok((socket, addr)) = listener.accept().await {
+                ConnectionWrapper::spawn(socket, addr, ctx.clone(), accord::MAX_FRAME_LEN).await;
+            }
+        });
+        server_addr
+    }
+
+    /// Client side of a connection, logged in and encrypted like the clients do it.
+    struct TestClient {
+        reader: accord::connection::ConnectionReader<ClientboundPacket>,
+        writer: accord::connection::ConnectionWriter<ServerboundPacket>,
+        secret: Option<Vec<u8>>,
+        read_nonces: rand_chacha::ChaCha20Rng,
+        write_nonces: rand_chacha::ChaCha20Rng,
+        /// From [`ClientboundPacket::LoginAck`]
+        user_id: i64,
+        /// From [`ClientboundPacket::YourPermissions`]
+        permissions: UserPermissions,
+    }
+
+    impl TestClient {
+        async fn login(server_addr: std::net::SocketAddr, username: &str) -> Self {
+            use accord::connection::Connection;
+            use rand::SeedableRng;
+            use rand_chacha::ChaCha20Rng;
+            use rsa::{PaddingScheme, PublicKey, RsaPublicKey};
+
+            let socket = tokio::net::TcpStream::connect(server_addr).await.unwrap();
+            let connection = Connection::<ClientboundPacket, ServerboundPacket>::new(socket);
+            let (mut reader, mut writer) = connection.split();
+
+            writer
+                .write_packet(
+                    ServerboundPacket::EncryptionRequest(accord::SUPPORTED_SUITES.to_vec()),
+                    &None,
+                    None,
+                )
+                .await
+                .unwrap();
+            let (pub_key, token) = match reader.read_packet(&None, None).await {
+                Ok(Some(ClientboundPacket::EncryptionResponse(pub_key, token, suite))) => {
+                    assert_eq!(suite, accord::CryptoSuite::V1);
+                    let pub_key: RsaPublicKey =
+                        rsa::pkcs8::FromPublicKey::from_public_key_der(&pub_key).unwrap();
+                    (pub_key, token)
+                }
+                p => panic!("Unexpected packet: {:?}", p),
+            };
+            let secret = [3u8; accord::SECRET_LEN];
+            let encrypt = |data: &[u8]| {
+                let padding = PaddingScheme::new_pkcs1v15_encrypt();
+                pub_key
+                    .encrypt(&mut rand::rngs::OsRng, padding, data)
+                    .unwrap()
+            };
+            writer
+                .write_packet(
+                    ServerboundPacket::EncryptionConfirm(encrypt(&secret), encrypt(&token)),
+                    &None,
+                    None,
+                )
+                .await
+                .unwrap();
+            let mut client = Self {
+                reader,
+                writer,
+                secret: Some(secret.to_vec()),
+                read_nonces: ChaCha20Rng::from_seed(secret),
+                write_nonces: ChaCha20Rng::from_seed(secret),
+                user_id: 0,
+                permissions: UserPermissions::default(),
+            };
+            assert_eq!(client.read().await, ClientboundPacket::EncryptionAck);
+
+            client
+                .write(ServerboundPacket::Login {
+                    username: username.to_string(),
+                    password: "password".to_string(),
+                })
+                .await;
+            client.user_id = match client.read().await {
+                ClientboundPacket::LoginAck {
+                    user_id,
+                    username: name,
+                } => {
+                    assert_eq!(name, username);
+                    user_id
+                }
+                p => panic!("Unexpected packet: {:?}", p),
+            };
+            client.permissions = match client.read().await {
+                ClientboundPacket::YourPermissions(permissions) => permissions,
+                p => panic!("Unexpected packet: {:?}", p),
+            };
+            client
+        }
+
+        async fn write(&mut self, p: ServerboundPacket) {
+            self.writer
+                .write_packet(p, &self.secret, Some(&mut self.write_nonces))
+                .await
+                .unwrap()
+        }
+
+        async fn read(&mut self) -> ClientboundPacket {
+            self.reader
+                .read_packet(&self.secret, Some(&mut self.read_nonces))
+                .await
+                .unwrap()
+                .unwrap()
+        }
+    }
+
+    #[test]
+    fn login_message_fetch_cycle() {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let mut alice = TestClient::login(fake_server().await, "alice").await;
+
+            alice
+                .write(ServerboundPacket::Message("hello there".to_string()))
+                .await;
+            let sent = match alice.read().await {
+                ClientboundPacket::Message(m) => m,
+                p => panic!("Unexpected packet: {:?}", p),
+            };
+            assert_eq!((sent.sender_id, sent.sender.as_str()), (1, "alice"));
+            // Login told us the id our messages have
+            assert_eq!(alice.user_id, sent.sender_id);
+            assert_eq!(sent.text, "hello there");
+
+            alice.write(ServerboundPacket::FetchMessages(0, 10)).await;
+            let sent = ClientboundPacket::Message(sent);
+            assert_eq!(
+                alice.read().await,
+                ClientboundPacket::MessageBatch(vec![sent.clone()])
+            );
+
+            // Whole history comes back in one packet, oldest first
+            let mut history = vec![sent];
+            for i in 1..20 {
+                alice.write(ServerboundPacket::Message(i.to_string())).await;
+                history.push(alice.read().await);
+            }
+            alice.write(ServerboundPacket::FetchMessages(0, 20)).await;
+            assert_eq!(alice.read().await, ClientboundPacket::MessageBatch(history));
+            alice.write(ServerboundPacket::Ping).await;
+            assert_eq!(alice.read().await, ClientboundPacket::Pong);
+        });
+    }
+
+    #[test]
+    fn username_with_separator_stays_whole() {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let server_addr = fake_server().await;
+            let admin = TestClient::login(server_addr, "admin").await;
+            assert!(admin.permissions.operator);
+            // Name is checked whole by login, so it isn't taken for "admin"
+            let mallory = TestClient::login(server_addr, "admin|x").await;
+            assert!(!mallory.permissions.operator);
+        });
+    }
+
+    #[test]
+    fn command_response_only_to_requester() {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let server_addr = fake_server().await;
+            let mut alice = TestClient::login(server_addr, "alice").await;
+            let mut bob = TestClient::login(server_addr, "bob").await;
+
+            // Alice isn't an operator
+            alice
+                .write(ServerboundPacket::Command("/kick bob".to_string()))
+                .await;
+            match alice.read().await {
+                ClientboundPacket::CommandResponse(m) => {
+                    assert!(m.is_from_server());
+                    assert_eq!(m.text, "Not permitted.");
+                }
+                p => panic!("Unexpected packet: {:?}", p),
+            }
+
+            // Next thing Bob gets is Alice's message, not the response
+            alice
+                .write(ServerboundPacket::Message("hi".to_string()))
+                .await;
+            let message = alice.read().await;
+            assert_eq!(bob.read().await, message);
+
+            // And it isn't part of the history
+            bob.write(ServerboundPacket::FetchMessages(0, 10)).await;
+            assert_eq!(
+                bob.read().await,
+                ClientboundPacket::MessageBatch(vec![message])
+            );
+        });
+    }
+
+    #[test]
+    fn protocol_errors_disconnect_with_reason() {
+        use accord::connection::{Connection, ConnectionError};
+        use accord::packets::DisconnectReason;
+        use rand::SeedableRng;
+
+        assert!(matches!(
+            super::read_error_command(&ConnectionError::Closed),
+            ConnectionCommand::Close
+        ));
+        assert!(matches!(
+            super::read_error_command(&ConnectionError::Decrypt),
+            ConnectionCommand::Disconnect(DisconnectReason::ProtocolError)
+        ));
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let server_addr = fake_server().await;
+
+            // Something else instead of EncryptionConfirm
+            let socket = tokio::net::TcpStream::connect(server_addr).await.unwrap();
+            let connection = Connection::<ClientboundPacket, ServerboundPacket>::new(socket);
+            let (mut reader, mut writer) = connection.split();
+            writer
+                .write_packet(
+                    ServerboundPacket::EncryptionRequest(accord::SUPPORTED_SUITES.to_vec()),
+                    &None,
+                    None,
+                )
+                .await
+                .unwrap();
+            assert!(matches!(
+                reader.read_packet(&None, None).await,
+                Ok(Some(ClientboundPacket::EncryptionResponse(..)))
+            ));
+            writer
+                .write_packet(ServerboundPacket::Ping, &None, None)
+                .await
+                .unwrap();
+            assert!(matches!(
+                reader.read_packet(&None, None).await,
+                Ok(Some(ClientboundPacket::Disconnect(
+                    DisconnectReason::ProtocolError
+                )))
+            ));
+            assert!(reader.read_packet(&None, None).await.is_err());
+
+            // No suite in common
+            let socket = tokio::net::TcpStream::connect(server_addr).await.unwrap();
+            let connection = Connection::<ClientboundPacket, ServerboundPacket>::new(socket);
+            let (mut reader, mut writer) = connection.split();
+            writer
+                .write_packet(
+                    ServerboundPacket::EncryptionRequest(vec![accord::CryptoSuite(999)]),
+                    &None,
+                    None,
+                )
+                .await
+                .unwrap();
+            assert!(matches!(
+                reader.read_packet(&None, None).await,
+                Ok(Some(ClientboundPacket::Disconnect(
+                    DisconnectReason::ProtocolError
+                )))
+            ));
+
+            // Frame that doesn't decrypt
+            let mut alice = TestClient::login(server_addr, "alice").await;
+            alice.write_nonces = rand_chacha::ChaCha20Rng::from_seed([9; accord::SECRET_LEN]);
+            alice.write(ServerboundPacket::Ping).await;
+            assert_eq!(
+                alice.read().await,
+                ClientboundPacket::Disconnect(DisconnectReason::ProtocolError)
+            );
+        });
+    }
+
+    #[test]
+    fn connection_stats_accumulate() {
+        let stats = ConnectionStats::new(0);
+        assert_eq!(
+            stats.report("alice", 0),
+            "alice: connected just now, 0 messages sent, 0 bytes received, 0 bytes sent."
+        );
+        for _ in 0..3 {
+            stats.message_sent();
+        }
+        // Byte counts are totals from the reader and writer
+        stats.set_bytes_received(100);
+        stats.set_bytes_received(250);
+        stats.set_bytes_sent(40);
+        assert_eq!(
+            stats.report("alice", 5 * 60 * 1000),
+            "alice: connected 5m ago, 3 messages sent, 250 bytes received, 40 bytes sent."
+        );
+    }
 }
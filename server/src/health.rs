@@ -0,0 +1,145 @@
+//! Lightweight HTTP health check (`GET /healthz`) for orchestration (e.g. Docker, Kubernetes).
+//!
+//! Healthy means the channel loop answers and the database is reachable.
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::sync::mpsc::Sender;
+
+use crate::commands::ChannelCommand;
+
+/// How long to wait for the channel loop to answer
+pub const HEALTH_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Result of a health check
+#[derive(Debug, PartialEq, Eq)]
+pub enum Health {
+    Healthy,
+    /// Query to the database failed with this error
+    DatabaseDown(String),
+    /// Channel loop didn't answer in time (or is gone)
+    ChannelUnresponsive,
+}
+
+impl Health {
+    /// HTTP response for this state, `200` if healthy and `503` otherwise
+    pub fn http_response(&self) -> String {
+        let (status, body) = match self {
+            Health::Healthy => ("200 OK", "ok".to_string()),
+            Health::DatabaseDown(e) => ("503 Service Unavailable", format!("database down: {}", e)),
+            Health::ChannelUnresponsive => (
+                "503 Service Unavailable",
+                "channel unresponsive".to_string(),
+            ),
+        };
+        format!(
+            "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status,
+            body.len(),
+            body
+        )
+    }
+}
+
+/// Asks channel loop to check the database, waiting at most `timeout`
+pub async fn check_health(channel_sender: &Sender<ChannelCommand>, timeout: Duration) -> Health {
+    let (otx, orx) = tokio::sync::oneshot::channel();
+    if channel_sender
+        .send(ChannelCommand::HealthCheck(otx))
+        .await
+        .is_err()
+    {
+        return Health::ChannelUnresponsive;
+    }
+    match tokio::time::timeout(timeout, orx).await {
+        Ok(Ok(Ok(()))) => Health::Healthy,
+        Ok(Ok(Err(e))) => Health::DatabaseDown(e),
+        Ok(Err(_)) | Err(_) => Health::ChannelUnresponsive,
+    }
+}
+
+/// Answers health check requests on `listener` until the server exits.
+pub async fn serve(listener: TcpListener, channel_sender: Sender<ChannelCommand>) {
+    loop {
+        let (mut socket, _) = match listener.accept().await {
+            Ok(s) => s,
+            Err(e) => {
+                log::error!("Health check accept error: {}", e);
+                continue;
+            }
+        };
+        let channel_sender = channel_sender.clone();
+        tokio::spawn(async move {
+            // Only the request line matters, which fits in one read
+            let mut buf = [0u8; 1024];
+            let n = socket.read(&mut buf).await.unwrap_or(0);
+            let request = String::from_utf8_lossy(&buf[..n]);
+            let response = match request.lines().next() {
+                Some(line) if line.starts_with("GET /healthz ") => {
+                    check_health(&channel_sender, HEALTH_TIMEOUT)
+                        .await
+                        .http_response()
+                }
+                _ => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                    .to_string(),
+            };
+            socket.write_all(response.as_bytes()).await.ok();
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{check_health, Health};
+    use crate::commands::ChannelCommand;
+    use std::time::Duration;
+    use tokio::sync::mpsc;
+
+    /// Runs health check against a fake channel loop answering with `reply`
+    /// (or not at all if `None`)
+    fn check_with(reply: Option<Result<(), String>>) -> Health {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let (tx, mut rx) = mpsc::channel(8);
+            let channel_loop = tokio::spawn(async move {
+                let mut pending = vec![];
+                while let Some(ChannelCommand::HealthCheck(otx)) = rx.recv().await {
+                    match &reply {
+                        Some(reply) => otx.send(reply.clone()).unwrap(),
+                        // Stuck, keep the sender so it doesn't look closed
+                        None => pending.push(otx),
+                    }
+                }
+            });
+            let health = check_health(&tx, Duration::from_millis(50)).await;
+            drop(tx);
+            channel_loop.await.unwrap();
+            health
+        })
+    }
+
+    #[test]
+    fn health_reflects_database_state() {
+        assert_eq!(check_with(Some(Ok(()))), Health::Healthy);
+        assert_eq!(
+            check_with(Some(Err("connection closed".to_string()))),
+            Health::DatabaseDown("connection closed".to_string())
+        );
+        assert_eq!(check_with(None), Health::ChannelUnresponsive);
+    }
+
+    #[test]
+    fn http_response_test() {
+        assert!(Health::Healthy
+            .http_response()
+            .starts_with("HTTP/1.1 200 OK\r\n"));
+        let response = Health::DatabaseDown("timeout".to_string()).http_response();
+        assert!(response.starts_with("HTTP/1.1 503 "));
+        assert!(response.ends_with("\r\n\r\ndatabase down: timeout"));
+        assert!(response.contains("Content-Length: 22\r\n"));
+    }
+}
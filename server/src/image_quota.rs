@@ -0,0 +1,80 @@
+//! Limiting how many images each user can send, so they can't fill up the database
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+/// Period the quota applies to
+pub const QUOTA_WINDOW: Duration = Duration::from_secs(60 * 60);
+
+/// Counts images sent by each user (by user id) in the last [`QUOTA_WINDOW`].
+#[derive(Debug, Default)]
+pub struct ImageQuota {
+    /// Images allowed per window, unlimited if `None`
+    limit: Option<u32>,
+    sent: HashMap<i64, VecDeque<Instant>>,
+}
+
+impl ImageQuota {
+    pub fn new(limit: Option<u32>) -> Self {
+        Self {
+            limit,
+            sent: HashMap::new(),
+        }
+    }
+
+    /// Records image from `user_id` sent at `now` if it fits in the quota.
+    ///
+    /// Otherwise returns how long until it does.
+    pub fn try_send(&mut self, user_id: i64, now: Instant) -> Result<(), Duration> {
+        let limit = match self.limit {
+            Some(limit) => limit as usize,
+            None => return Ok(()),
+        };
+        self.sent
+            .retain(|_, sent| sent.back().is_some_and(|t| now - *t < QUOTA_WINDOW));
+        let sent = self.sent.entry(user_id).or_default();
+        while sent.front().is_some_and(|t| now - *t >= QUOTA_WINDOW) {
+            sent.pop_front();
+        }
+        if sent.len() >= limit {
+            // Fits again when the oldest one leaves the window
+            return Err(sent
+                .front()
+                .map_or(QUOTA_WINDOW, |oldest| *oldest + QUOTA_WINDOW - now));
+        }
+        sent.push_back(now);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn quota_enforced_per_user() {
+        let mut quota = ImageQuota::new(Some(3));
+        let now = Instant::now();
+        let second = Duration::from_secs(1);
+
+        for i in 0..3 {
+            assert_eq!(quota.try_send(1, now + i * second), Ok(()));
+        }
+        // Fourth one has to wait until the first one is old enough
+        assert_eq!(
+            quota.try_send(1, now + 3 * second),
+            Err(QUOTA_WINDOW - 3 * second)
+        );
+        // Others aren't affected
+        assert_eq!(quota.try_send(2, now + 3 * second), Ok(()));
+
+        // Rejected image doesn't count
+        assert_eq!(quota.try_send(1, now + QUOTA_WINDOW), Ok(()));
+        assert!(quota.try_send(1, now + QUOTA_WINDOW).is_err());
+
+        assert!(ImageQuota::new(Some(0)).try_send(1, now).is_err());
+        let mut unlimited = ImageQuota::new(None);
+        for _ in 0..100 {
+            assert_eq!(unlimited.try_send(1, now), Ok(()));
+        }
+    }
+}
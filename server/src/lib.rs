@@ -1,4 +1,11 @@
 pub mod channel;
+pub mod chat_commands;
 pub mod commands;
 pub mod config;
 pub mod connection;
+pub mod health;
+pub mod image_quota;
+pub mod login_throttle;
+pub mod migrations;
+pub mod setup;
+pub mod shutdown;
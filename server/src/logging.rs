@@ -45,7 +45,7 @@ impl LogWriter for LogRouter {
         let s = record.into();
         self.logs_tx
             .try_send(s)
-            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string()))
+            .map_err(|e| std::io::Error::other(e.to_string()))
     }
 
     fn flush(&self) -> std::io::Result<()> {
@@ -0,0 +1,130 @@
+//! Slowing down password guessing by locking out addresses and usernames after failed logins
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+/// Failed logins allowed before a lockout
+pub const MAX_FAILURES: u32 = 5;
+/// Length of the first lockout, every next one is twice as long
+const BASE_LOCKOUT: Duration = Duration::from_secs(30);
+/// Longest possible lockout
+const MAX_LOCKOUT: Duration = Duration::from_secs(60 * 60);
+/// Failures are forgotten after this long without another one
+const FORGET_AFTER: Duration = Duration::from_secs(24 * 60 * 60);
+
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
+enum Key {
+    Ip(IpAddr),
+    Username(String),
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Failures {
+    count: u32,
+    last: Instant,
+    locked_until: Option<Instant>,
+}
+
+/// Counts failed logins per IP address and per username.
+///
+/// After [`MAX_FAILURES`] in a row, further attempts are refused for a while,
+/// with the lockout doubling on every next failure.
+#[derive(Debug, Default)]
+pub struct LoginThrottle {
+    failures: HashMap<Key, Failures>,
+}
+
+impl LoginThrottle {
+    /// Returns how long to wait if login from `ip` as `username` is locked out at `now`.
+    pub fn check(&self, ip: IpAddr, username: &str, now: Instant) -> Result<(), Duration> {
+        let wait = [Key::Ip(ip), Key::Username(username.to_string())]
+            .iter()
+            .filter_map(|key| self.failures.get(key)?.locked_until)
+            .map(|until| until.saturating_duration_since(now))
+            .max()
+            .unwrap_or_default();
+        if wait.is_zero() {
+            Ok(())
+        } else {
+            Err(wait)
+        }
+    }
+
+    /// Records failed login from `ip` as `username`.
+    pub fn failed(&mut self, ip: IpAddr, username: &str, now: Instant) {
+        self.failures
+            .retain(|_, f| now.saturating_duration_since(f.last) < FORGET_AFTER);
+        for key in [Key::Ip(ip), Key::Username(username.to_string())] {
+            let f = self.failures.entry(key).or_insert(Failures {
+                count: 0,
+                last: now,
+                locked_until: None,
+            });
+            f.count += 1;
+            f.last = now;
+            if f.count >= MAX_FAILURES {
+                let lockout = BASE_LOCKOUT
+                    .checked_mul(1 << (f.count - MAX_FAILURES).min(16))
+                    .unwrap_or(MAX_LOCKOUT)
+                    .min(MAX_LOCKOUT);
+                f.locked_until = Some(now + lockout);
+            }
+        }
+    }
+
+    /// Forgets failures of `ip` and `username` after they logged in.
+    pub fn succeeded(&mut self, ip: IpAddr, username: &str) {
+        self.failures.remove(&Key::Ip(ip));
+        self.failures.remove(&Key::Username(username.to_string()));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn lockout_triggers_and_resets() {
+        let mut throttle = LoginThrottle::default();
+        let ip: IpAddr = [10, 0, 0, 1].into();
+        let now = Instant::now();
+
+        for _ in 1..MAX_FAILURES {
+            throttle.failed(ip, "alice", now);
+            assert_eq!(throttle.check(ip, "alice", now), Ok(()));
+        }
+        throttle.failed(ip, "alice", now);
+        assert_eq!(throttle.check(ip, "alice", now), Err(BASE_LOCKOUT));
+        // Locked by address and by name
+        assert!(throttle.check(ip, "bob", now).is_err());
+        assert!(throttle.check([10, 0, 0, 2].into(), "alice", now).is_err());
+        assert_eq!(throttle.check([10, 0, 0, 2].into(), "bob", now), Ok(()));
+
+        // Lockout ends, next failure doubles it
+        let later = now + BASE_LOCKOUT;
+        assert_eq!(throttle.check(ip, "alice", later), Ok(()));
+        throttle.failed(ip, "alice", later);
+        assert_eq!(throttle.check(ip, "alice", later), Err(2 * BASE_LOCKOUT));
+
+        // Never longer than the max
+        for _ in 0..40 {
+            throttle.failed(ip, "alice", later);
+        }
+        assert_eq!(throttle.check(ip, "alice", later), Err(MAX_LOCKOUT));
+
+        // Successful login starts over
+        throttle.succeeded(ip, "alice");
+        assert_eq!(throttle.check(ip, "alice", later), Ok(()));
+        throttle.failed(ip, "alice", later);
+        assert_eq!(throttle.check(ip, "alice", later), Ok(()));
+
+        // And so does waiting long enough
+        for _ in 0..MAX_FAILURES {
+            throttle.failed(ip, "carol", later);
+        }
+        let much_later = later + FORGET_AFTER;
+        let other_ip: IpAddr = [10, 0, 0, 3].into();
+        throttle.failed(other_ip, "carol", much_later);
+        assert_eq!(throttle.check(other_ip, "carol", much_later), Ok(()));
+    }
+}
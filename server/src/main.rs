@@ -1,9 +1,12 @@
+use std::io::IsTerminal;
+use std::path::PathBuf;
 use tokio::net::TcpListener;
 
 use tokio::sync::mpsc;
 
 use accord_server::channel::AccordChannel;
 use accord_server::connection::ConnectionWrapper;
+use accord_server::shutdown;
 
 use clap::Parser;
 
@@ -23,6 +26,25 @@ struct Args {
     /// Log to file as well
     #[clap(short, long)]
     log_to_file: bool,
+
+    /// Check config and database connection, then exit without starting the server
+    #[clap(long)]
+    check_config: bool,
+
+    /// Path to the config file [env: ACCORD_CONFIG]
+    #[clap(short, long)]
+    config: Option<PathBuf>,
+}
+
+/// Why TUI can't run with given stdin and stdout, `None` if it can
+fn tui_unavailable(stdin_is_tty: bool, stdout_is_tty: bool) -> Option<&'static str> {
+    if !stdout_is_tty {
+        Some("Output is not a terminal")
+    } else if !stdin_is_tty {
+        Some("Input is not a terminal")
+    } else {
+        None
+    }
 }
 
 fn init_logger_tui(writer: Box<dyn LogWriter>, log_to_file: bool) {
@@ -56,9 +78,34 @@ fn init_logger_stdout(log_to_file: bool) {
 #[tokio::main]
 async fn main() {
     let args = Args::parse();
+    let config_path = accord_server::config::config_path(args.config);
+
+    if args.check_config {
+        let check = accord_server::config::check_config(&config_path).await;
+        println!("{}", check);
+        std::process::exit(if check.passed() { 0 } else { 1 });
+    }
+
+    // Before TUI takes over the terminal
+    accord_server::setup::first_run_setup(&config_path);
+    let config = match accord_server::config::load_config(&config_path) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{} ({:?})", e, config_path);
+            std::process::exit(-1)
+        }
+    };
 
     let (ctx, crx) = mpsc::channel(32);
-    let tui = !args.no_tui;
+    let no_tty = if args.no_tui {
+        None
+    } else {
+        tui_unavailable(
+            std::io::stdin().is_terminal(),
+            std::io::stdout().is_terminal(),
+        )
+    };
+    let tui = !args.no_tui && no_tty.is_none();
     let mut tui_handle = None;
     if tui {
         let (logs_tx, logs_rx) = mpsc::channel(128);
@@ -67,15 +114,23 @@ async fn main() {
         tui_handle = Some(tui::Tui::new(logs_rx, ctx.clone()).launch());
     } else {
         init_logger_stdout(args.log_to_file);
+        if let Some(reason) = no_tty {
+            log::warn!("{}, running without TUI (like with --no-tui).", reason);
+        }
     }
 
-    let config = accord_server::config::load_config();
+    log::info!("Loaded config from {:?}.", config_path);
+    if config.weak_db_password() {
+        log::warn!(
+            "!!! Database password is empty or easy to guess (allowed by `allow_insecure`) !!!"
+        );
+    }
 
-    let port = config.port.unwrap_or(accord::DEFAULT_PORT);
-    let listener = match TcpListener::bind(("0.0.0.0", port)).await {
+    let addr = config.chat_addr();
+    let listener = match TcpListener::bind(addr).await {
         Ok(listener) => listener,
         Err(e) => {
-            log::error!("Failed to bind to port {}. Error: {}", port, e);
+            log::error!("Failed to bind to {}. Error: {}", addr, e);
             if let Some(tui_handle) = tui_handle {
                 log::info!("Enter `exit` command to exit.");
                 if let Err(e) = tui_handle.await {
@@ -87,9 +142,11 @@ async fn main() {
         }
     };
 
-    log::info!("Listening on port {}.", port);
+    log::info!("Listening on {}.", addr);
 
-    let result = AccordChannel::spawn(crx, config).await;
+    let health_addr = config.health_addr();
+    let max_packet_size = config.max_packet_size;
+    let result = AccordChannel::spawn(crx, ctx.clone(), config, config_path).await;
     match result {
         Err(e) => {
             log::error!("Failed to start server. Error: {}", e);
@@ -102,33 +159,70 @@ async fn main() {
         }
         Ok(_) => {
             log::info!("Server ready!");
+            if let Some(health_addr) = health_addr {
+                match TcpListener::bind(health_addr).await {
+                    Ok(health_listener) => {
+                        log::info!("Health check on {}.", health_addr);
+                        tokio::spawn(accord_server::health::serve(health_listener, ctx.clone()));
+                    }
+                    Err(e) => log::error!(
+                        "Failed to bind health check to {}. Error: {}",
+                        health_addr,
+                        e
+                    ),
+                }
+            }
+            let signal = shutdown::signal();
+            tokio::pin!(signal);
             if let Some(mut tui_handle2) = tui_handle {
                 loop {
                     tokio::select! {
                         res = listener.accept() => {
                             let (socket, addr) = res.unwrap();
-                            ConnectionWrapper::spawn(socket, addr, ctx.clone()).await;
+                            ConnectionWrapper::spawn(socket, addr, ctx.clone(), max_packet_size)
+                                .await;
                         },
                         _ = &mut tui_handle2 => {
                             break;
                         }
+                        _ = &mut signal => {
+                            // Dropping TUI restores the terminal
+                            tui_handle2.abort();
+                            tui_handle2.await.ok();
+                            break;
+                        }
                     }
                 }
             } else {
-                #[cfg(unix)]
-                tokio::spawn(async move {
-                    tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
-                        .unwrap()
-                        .recv()
-                        .await;
-                    std::process::exit(0);
-                });
-
                 loop {
-                    let (socket, addr) = listener.accept().await.unwrap();
-                    ConnectionWrapper::spawn(socket, addr, ctx.clone()).await;
+                    tokio::select! {
+                        res = listener.accept() => {
+                            let (socket, addr) = res.unwrap();
+                            ConnectionWrapper::spawn(socket, addr, ctx.clone(), max_packet_size)
+                                .await;
+                        },
+                        _ = &mut signal => {
+                            break;
+                        }
+                    }
                 }
             };
+            if !shutdown::shutdown(&ctx, shutdown::SHUTDOWN_TIMEOUT).await {
+                log::error!("Server didn't shut down cleanly.");
+            }
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::tui_unavailable;
+
+    #[test]
+    fn tui_needs_terminal() {
+        assert_eq!(tui_unavailable(true, true), None);
+        assert!(tui_unavailable(true, false).is_some());
+        assert!(tui_unavailable(false, true).is_some());
+        assert!(tui_unavailable(false, false).is_some());
+    }
+}
@@ -0,0 +1,287 @@
+//! Versioned database schema, upgraded step by step at startup
+use anyhow::{bail, Context, Result};
+use tokio_postgres::Client as DBClient;
+
+/// Single step of upgrading the schema
+pub struct Migration {
+    /// What the step does, for logs and errors
+    pub description: &'static str,
+    /// SQL run in one transaction together with bumping the version
+    pub sql: &'static str,
+}
+
+/// Steps upgrading schema from version `i` to `i + 1`, in order. Only ever append to it.
+///
+/// Databases from before versioning are at version 0 with any of these applied already,
+/// so the first steps are written to be safe to run again.
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        description: "create tables",
+        sql: "CREATE TABLE IF NOT EXISTS accord.accounts (
+                user_id serial8 NOT null PRIMARY KEY,
+                username varchar(255) NOT NULL UNIQUE,
+                password varchar(44) NOT NULL,
+                salt varchar(88) NOT NULL,
+                banned bool NOT NULL DEFAULT false,
+                whitelisted bool NOT NULL DEFAULT false
+            );
+            CREATE TABLE IF NOT EXISTS accord.images (
+                image_hash varchar(64) PRIMARY KEY,
+                data BYTEA NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS accord.messages (
+                sender_id int8 NOT NULL,
+                sender varchar(255) NOT NULL DEFAULT '*deleted_user*',
+                content varchar(1023),
+                send_time bigint NOT NULL,
+                image_hash varchar(64) DEFAULT NULL,
+                CONSTRAINT fk_image_hash FOREIGN KEY(image_hash) REFERENCES accord.images(image_hash) ON DELETE SET DEFAULT ON UPDATE CASCADE,
+                CONSTRAINT fk_username FOREIGN KEY(sender) REFERENCES accord.accounts(username) ON DELETE SET DEFAULT ON UPDATE CASCADE
+            );",
+    },
+    Migration {
+        // Tiebreaker when ordering messages with the same send_time
+        description: "add message ids",
+        sql: "ALTER TABLE accord.messages ADD COLUMN IF NOT EXISTS message_id serial8;",
+    },
+    Migration {
+        description: "add name colors",
+        sql: "ALTER TABLE accord.accounts ADD COLUMN IF NOT EXISTS color varchar(7) DEFAULT NULL;",
+    },
+    Migration {
+        description: "store send_time in milliseconds",
+        sql: "UPDATE accord.messages SET send_time = send_time * 1000 WHERE send_time < 100000000000;",
+    },
+    Migration {
+        // Older versions keyed images on first 4 bytes of the hash
+        description: "key images on full hash",
+        sql: "DO $$ BEGIN
+            IF (SELECT data_type FROM information_schema.columns
+                WHERE table_schema = 'accord' AND table_name = 'images' AND column_name = 'image_hash') = 'integer'
+            THEN
+                ALTER TABLE accord.messages DROP CONSTRAINT fk_image_hash;
+                ALTER TABLE accord.messages ALTER COLUMN image_hash TYPE varchar(64) USING image_hash::text;
+                ALTER TABLE accord.images ADD COLUMN full_hash varchar(64);
+                UPDATE accord.images SET full_hash = encode(sha256(data), 'hex');
+                UPDATE accord.messages m SET image_hash = i.full_hash FROM accord.images i WHERE m.image_hash = i.image_hash::text;
+                ALTER TABLE accord.images DROP COLUMN image_hash;
+                ALTER TABLE accord.images RENAME COLUMN full_hash TO image_hash;
+                ALTER TABLE accord.images ADD PRIMARY KEY (image_hash);
+                ALTER TABLE accord.messages ADD CONSTRAINT fk_image_hash FOREIGN KEY(image_hash) REFERENCES accord.images(image_hash) ON DELETE SET DEFAULT ON UPDATE CASCADE;
+            END IF;
+        END $$;",
+    },
+    Migration {
+        // Argon2 PHC strings are longer than base64 of SHA-256
+        description: "widen password for Argon2 hashes",
+        sql: "ALTER TABLE accord.accounts ALTER COLUMN password TYPE varchar(255);",
+    },
+];
+
+/// Version the schema has after all [`MIGRATIONS`]
+pub fn latest_version() -> i32 {
+    MIGRATIONS.len() as i32
+}
+
+/// Migrations to run on schema at `version`, refuses schema newer than this server knows
+fn pending(version: i32) -> Result<&'static [Migration]> {
+    match usize::try_from(version) {
+        Ok(version) if version <= MIGRATIONS.len() => Ok(&MIGRATIONS[version..]),
+        _ => bail!(
+            "Database schema version {} is not supported by this server (latest is {}).",
+            version,
+            latest_version()
+        ),
+    }
+}
+
+/// Brings the schema up to [`latest_version`], returning the version it was at.
+pub async fn migrate(db_client: &mut DBClient) -> Result<i32> {
+    db_client
+        .batch_execute(
+            "CREATE SCHEMA IF NOT EXISTS accord;
+            CREATE TABLE IF NOT EXISTS accord.schema_version (version int NOT NULL);
+            INSERT INTO accord.schema_version SELECT 0 WHERE NOT EXISTS (SELECT 1 FROM accord.schema_version);",
+        )
+        .await
+        .with_context(|| "Failed to create table 'schema_version'.")?;
+
+    let initial: i32 = db_client
+        .query_one("SELECT version FROM accord.schema_version", &[])
+        .await
+        .with_context(|| "Failed to read schema version.")?
+        .get("version");
+    pending(initial)?;
+
+    loop {
+        let transaction = db_client.transaction().await?;
+        // Locked, so servers starting at the same time don't run the same step twice
+        let version: i32 = transaction
+            .query_one("SELECT version FROM accord.schema_version FOR UPDATE", &[])
+            .await?
+            .get("version");
+        let migration = match pending(version)?.first() {
+            Some(migration) => migration,
+            None => break,
+        };
+        log::info!(
+            "Migrating database to version {}: {}.",
+            version + 1,
+            migration.description
+        );
+        transaction
+            .batch_execute(migration.sql)
+            .await
+            .with_context(|| format!("Failed to {}.", migration.description))?;
+        transaction
+            .execute(
+                "UPDATE accord.schema_version SET version = $1",
+                &[&(version + 1)],
+            )
+            .await?;
+        transaction.commit().await?;
+    }
+    Ok(initial)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn pending_migrations() {
+        assert_eq!(pending(0).unwrap().len(), MIGRATIONS.len());
+        assert_eq!(pending(1).unwrap().len(), MIGRATIONS.len() - 1);
+        assert!(pending(latest_version()).unwrap().is_empty());
+        // Downgrades aren't supported
+        assert!(pending(latest_version() + 1).is_err());
+        assert!(pending(-1).is_err());
+    }
+
+    /// Runs `f` with a client connected to a new, empty database, which is dropped after.
+    ///
+    /// Needs a database user that can create databases, pointed to by `DATABASE_URL`.
+    fn with_empty_database<F>(f: impl FnOnce(DBClient) -> F)
+    where
+        F: std::future::Future<Output = ()>,
+    {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let url = std::env::var("DATABASE_URL").expect("DATABASE_URL not set");
+            let (admin, connection) = tokio_postgres::connect(&url, tokio_postgres::NoTls)
+                .await
+                .unwrap();
+            tokio::spawn(connection);
+            let name = format!("accord_migrations_{}", std::process::id());
+            // Separately, as neither can run in a transaction
+            admin
+                .batch_execute(&format!("DROP DATABASE IF EXISTS {}", name))
+                .await
+                .unwrap();
+            admin
+                .batch_execute(&format!("CREATE DATABASE {}", name))
+                .await
+                .unwrap();
+
+            let mut config: tokio_postgres::Config = url.parse().unwrap();
+            config.dbname(&name);
+            let (db_client, connection) = config.connect(tokio_postgres::NoTls).await.unwrap();
+            let connection = tokio::spawn(connection);
+            f(db_client).await;
+            connection.await.ok();
+
+            admin
+                .batch_execute(&format!("DROP DATABASE {}", name))
+                .await
+                .unwrap();
+        });
+    }
+
+    /// Needs `DATABASE_URL`, see [`with_empty_database`].
+    #[test]
+    #[ignore]
+    fn migrate_empty_database() {
+        with_empty_database(|mut db_client| async move {
+            assert_eq!(migrate(&mut db_client).await.unwrap(), 0);
+            let version: i32 = db_client
+                .query_one("SELECT version FROM accord.schema_version", &[])
+                .await
+                .unwrap()
+                .get("version");
+            assert_eq!(version, latest_version());
+            db_client
+                .execute(
+                    "INSERT INTO accord.accounts(username, password, salt, color) VALUES ('a', $1, '', '#ffffff')",
+                    &[&"$argon2id$".repeat(10)],
+                )
+                .await
+                .unwrap();
+
+            // Nothing to do the second time
+            assert_eq!(migrate(&mut db_client).await.unwrap(), latest_version());
+        });
+    }
+
+    /// Needs `DATABASE_URL`, see [`with_empty_database`].
+    #[test]
+    #[ignore]
+    fn migrate_database_from_before_versioning() {
+        with_empty_database(|mut db_client| async move {
+            // Schema and data of an old server
+            db_client
+                .batch_execute(
+                    "CREATE SCHEMA accord;
+                    CREATE TABLE accord.accounts (
+                        user_id serial8 NOT null PRIMARY KEY,
+                        username varchar(255) NOT NULL UNIQUE,
+                        password varchar(44) NOT NULL,
+                        salt varchar(88) NOT NULL,
+                        banned bool NOT NULL DEFAULT false,
+                        whitelisted bool NOT NULL DEFAULT false
+                    );
+                    CREATE TABLE accord.images (image_hash int4 PRIMARY KEY, data BYTEA NOT NULL);
+                    CREATE TABLE accord.messages (
+                        sender_id int8 NOT NULL,
+                        sender varchar(255) NOT NULL DEFAULT '*deleted_user*',
+                        content varchar(1023),
+                        send_time bigint NOT NULL,
+                        image_hash int4 DEFAULT NULL,
+                        CONSTRAINT fk_image_hash FOREIGN KEY(image_hash) REFERENCES accord.images(image_hash) ON DELETE SET DEFAULT ON UPDATE CASCADE,
+                        CONSTRAINT fk_username FOREIGN KEY(sender) REFERENCES accord.accounts(username) ON DELETE SET DEFAULT ON UPDATE CASCADE
+                    );
+                    INSERT INTO accord.accounts(username, password, salt) VALUES ('old', 'hash', 'salt');
+                    INSERT INTO accord.images VALUES (42, 'image');
+                    INSERT INTO accord.messages(sender_id, sender, send_time, image_hash) VALUES (1, 'old', 1651000000, 42);",
+                )
+                .await
+                .unwrap();
+
+            assert_eq!(migrate(&mut db_client).await.unwrap(), 0);
+            let row = db_client
+                .query_one(
+                    "SELECT send_time, message_id, m.image_hash, data FROM accord.messages m JOIN accord.images USING (image_hash)",
+                    &[],
+                )
+                .await
+                .unwrap();
+            assert_eq!(row.get::<_, i64>("send_time"), 1_651_000_000_000);
+            assert_eq!(row.get::<_, i64>("message_id"), 1);
+            assert_eq!(
+                row.get::<_, String>("image_hash"),
+                accord::utils::image_hash(b"image")
+            );
+            assert_eq!(row.get::<_, Vec<u8>>("data"), b"image");
+            // Room for Argon2 hashes
+            db_client
+                .execute(
+                    "UPDATE accord.accounts SET password = $1",
+                    &[&"$argon2id$".repeat(10)],
+                )
+                .await
+                .unwrap();
+        });
+    }
+}
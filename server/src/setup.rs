@@ -0,0 +1,141 @@
+//! Interactive first-run setup of the server config
+use std::io::{BufRead, IsTerminal, Write};
+use std::path::Path;
+
+use crate::config::{save_config, Config};
+
+/// Asks for database details and first operator if there is no config at `path` yet
+/// and we are running in a terminal, then saves the config there.
+///
+/// Without a terminal [`crate::config::load_config`] writes the default config instead.
+pub fn first_run_setup(path: &Path) {
+    if path.exists() || !std::io::stdin().is_terminal() {
+        return;
+    }
+    println!("No config found, let's create one. Press Enter to use the [default].");
+    let stdin = std::io::stdin();
+    match run_wizard(stdin.lock(), std::io::stdout()) {
+        Ok(config) => match save_config(path, &config) {
+            Ok(()) => println!("Config saved."),
+            Err(e) => eprintln!("Failed to save config: {}", e),
+        },
+        Err(e) => eprintln!("Setup failed: {}", e),
+    }
+}
+
+/// Prompts with `question` until `parse` accepts the answer (or the default if answer is empty)
+fn ask<R: BufRead, W: Write, T>(
+    input: &mut R,
+    output: &mut W,
+    question: &str,
+    default: &str,
+    parse: impl Fn(&str) -> Result<T, String>,
+) -> std::io::Result<T> {
+    loop {
+        if default.is_empty() {
+            write!(output, "{}: ", question)?;
+        } else {
+            write!(output, "{} [{}]: ", question, default)?;
+        }
+        output.flush()?;
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+        let answer = match line.trim() {
+            "" => default,
+            answer => answer,
+        };
+        match parse(answer) {
+            Ok(value) => return Ok(value),
+            Err(e) => writeln!(output, "{}", e)?,
+        }
+    }
+}
+
+/// Asks for config values, reading answers from `input`.
+fn run_wizard<R: BufRead, W: Write>(mut input: R, mut output: W) -> std::io::Result<Config> {
+    let (i, o) = (&mut input, &mut output);
+    let text = |s: &str| Ok(s.to_string());
+    let mut config = Config {
+        db_host: ask(i, o, "Database host", "localhost", text)?,
+        db_port: ask(i, o, "Database port", "5432", |s| match s.parse() {
+            Ok(0) | Err(_) => Err(format!("Invalid port: {}", s)),
+            Ok(port) => Ok(port),
+        })?,
+        db_user: ask(i, o, "Database user", "accord", text)?,
+        ..Default::default()
+    };
+    // Until password is strong or user accepts a weak one
+    loop {
+        config.db_pass = ask(i, o, "Database password", "", text)?;
+        // The password just typed in, even if `DATABASE_URL` is set
+        if !config.weak_db_password_from(None) {
+            break;
+        }
+        let question = "This password is easy to guess. Use it anyway? (y/n)";
+        let yes_no = |s: &str| match s {
+            "y" | "Y" => Ok(true),
+            "n" | "N" => Ok(false),
+            _ => Err("Answer y or n".to_string()),
+        };
+        if ask(i, o, question, "n", yes_no)? {
+            config.allow_insecure = true;
+            break;
+        }
+    }
+    config.db_dbname = ask(i, o, "Database name", "accord", text)?;
+    let policy = config.username_policy.clone();
+    let operator = ask(i, o, "First operator (username)", "", |s| {
+        if s.is_empty() {
+            Ok(None)
+        } else {
+            policy
+                .validate(s)
+                .map(|()| Some(s.to_string()))
+                .map_err(|e| format!("Invalid username: {}", e))
+        }
+    })?;
+    config.operators.extend(operator);
+    Ok(config)
+}
+
+#[cfg(test)]
+mod test {
+    use super::run_wizard;
+
+    fn wizard(input: &str) -> std::io::Result<crate::config::Config> {
+        run_wizard(input.as_bytes(), std::io::sink())
+    }
+
+    #[test]
+    fn wizard_test() {
+        let config = wizard("db.local\n5433\nchat\nhunter2hunter2\nchatdb\nadmin\n").unwrap();
+        assert_eq!(config.db_host, "db.local");
+        assert_eq!(config.db_port, 5433);
+        assert_eq!(config.db_user, "chat");
+        assert_eq!(config.db_pass, "hunter2hunter2");
+        assert_eq!(config.db_dbname, "chatdb");
+        assert!(config.operators.contains("admin"));
+        assert!(!config.allow_insecure);
+        assert!(config.validate_from(None).is_ok());
+
+        // Defaults, invalid port asked again, weak password allowed, no operator
+        let config = wizard("\nport\n0\n\n\npostgres\ny\n\n\n").unwrap();
+        assert_eq!(config.db_host, "localhost");
+        assert_eq!(config.db_port, 5432);
+        assert_eq!(config.db_user, "accord");
+        assert_eq!(config.db_dbname, "accord");
+        assert!(config.allow_insecure);
+        assert!(config.operators.is_empty());
+        assert!(config.validate_from(None).is_ok());
+
+        // Weak password rejected and asked again
+        let config = wizard("\n\n\n\nn\nl0ng-and-r4ndom\n\n\n").unwrap();
+        assert_eq!(config.db_pass, "l0ng-and-r4ndom");
+        assert!(!config.allow_insecure);
+
+        // Input ended early
+        assert!(wizard("localhost\n").is_err());
+    }
+}
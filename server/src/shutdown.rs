@@ -0,0 +1,93 @@
+//! Graceful shutdown on SIGINT (Ctrl+C) and SIGTERM
+use std::time::Duration;
+
+use tokio::sync::mpsc::Sender;
+
+use crate::commands::ChannelCommand;
+
+/// How long to wait for the channel loop to finish shutting down
+pub const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Resolves when the process receives SIGINT (Ctrl+C) or, on Unix, SIGTERM.
+pub async fn signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut terminate = signal(SignalKind::terminate()).unwrap();
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => (),
+            _ = terminate.recv() => (),
+        }
+    }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await.ok();
+}
+
+/// Asks channel loop to let users know, save config and stop.
+///
+/// Returns whether it finished within `timeout`.
+pub async fn shutdown(channel_sender: &Sender<ChannelCommand>, timeout: Duration) -> bool {
+    log::info!("Shutting down...");
+    let (otx, orx) = tokio::sync::oneshot::channel();
+    if channel_sender
+        .send(ChannelCommand::Close(otx))
+        .await
+        .is_err()
+    {
+        return false;
+    }
+    matches!(tokio::time::timeout(timeout, orx).await, Ok(Ok(())))
+}
+
+#[cfg(test)]
+mod test {
+    use super::shutdown;
+    use crate::commands::ChannelCommand;
+    use std::time::Duration;
+    use tokio::sync::mpsc;
+
+    /// Runs shutdown against a fake channel loop that confirms closing if `confirm`
+    fn shutdown_with(confirm: bool) -> (bool, usize) {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let (tx, mut rx) = mpsc::channel(8);
+            let channel_loop = tokio::spawn(async move {
+                let mut closes = 0;
+                let mut pending = vec![];
+                while let Some(command) = rx.recv().await {
+                    if let ChannelCommand::Close(otx) = command {
+                        closes += 1;
+                        if confirm {
+                            otx.send(()).unwrap();
+                            break;
+                        }
+                        pending.push(otx);
+                    }
+                }
+                closes
+            });
+            let finished = shutdown(&tx, Duration::from_millis(50)).await;
+            drop(tx);
+            (finished, channel_loop.await.unwrap())
+        })
+    }
+
+    #[test]
+    fn shutdown_waits_for_channel() {
+        assert_eq!(shutdown_with(true), (true, 1));
+        // Channel loop stuck
+        assert_eq!(shutdown_with(false), (false, 1));
+
+        // Channel loop already gone
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .build()
+            .unwrap();
+        let (tx, rx) = mpsc::channel(1);
+        drop(rx);
+        assert!(!rt.block_on(shutdown(&tx, Duration::from_millis(50))));
+    }
+}
@@ -1,3 +1,4 @@
+use accord_server::chat_commands::{run_command, CommandContext, CommandOutcome};
 use accord_server::commands::ChannelCommand;
 use futures::{FutureExt, StreamExt};
 use tokio::sync::mpsc;
@@ -14,7 +15,7 @@ use crossterm::{
 use std::io::{self, Stdout};
 use tui::{
     backend::CrosstermBackend,
-    layout::{Constraint, Direction, Layout},
+    layout::{Constraint, Direction, Layout, Rect},
     style::{Color, Modifier, Style},
     text::{Span, Spans},
     widgets::{Block, Borders, List, ListItem, Paragraph},
@@ -23,13 +24,18 @@ use tui::{
 
 use crate::logging::LogEntry;
 
+/// Minimal terminal width the TUI can be drawn in
+const MIN_WIDTH: u16 = 20;
+/// Minimal terminal height the TUI can be drawn in
+const MIN_HEIGHT: u16 = 5;
+
 /// Main TUI struct
 pub struct Tui {
     logs_rx: mpsc::Receiver<LogEntry>,
     logs: Vec<LogEntry>,
-    scroll: usize,
+    log_scroll: LogScroll,
     event_stream: EventStream,
-    commandline: String,
+    commandline: CommandLine,
     channel_sender: mpsc::Sender<ChannelCommand>,
     terminal: Option<Terminal<CrosstermBackend<Stdout>>>,
 }
@@ -58,9 +64,9 @@ impl Tui {
             logs_rx,
             channel_sender,
             logs: Vec::new(),
-            scroll: 0,
+            log_scroll: LogScroll::default(),
             event_stream: EventStream::new(),
-            commandline: String::new(),
+            commandline: CommandLine::default(),
             terminal: None,
         }
     }
@@ -101,6 +107,7 @@ impl Tui {
                 match maybe_log {
                     Some(log_entry) => {
                         self.logs.push(log_entry);
+                        self.log_scroll.log_added(self.logs.len());
                     }
                     None => panic!("Log writer dropped before TUI!"),
                 }
@@ -113,37 +120,51 @@ impl Tui {
                                 self.respond("Enter 'exit' command to exit.");
                                 return false;
                             }
-                            if let KeyEvent{code: KeyCode::Char(c), modifiers: _} = kevent {
-                                self.commandline.push(c);
+                            if kevent.modifiers == KeyModifiers::CONTROL {
+                                match kevent.code {
+                                    KeyCode::Char('u') => self.commandline.clear(),
+                                    KeyCode::Char('w') => self.commandline.delete_word(),
+                                    KeyCode::Char('a') => self.commandline.home(),
+                                    KeyCode::Char('e') => self.commandline.end(),
+                                    _ => (),
+                                }
+                            } else if let KeyEvent{code: KeyCode::Char(c), modifiers: _} = kevent {
+                                self.commandline.insert(c);
                             }
                             if kevent == KeyCode::Backspace.into() {
-                                self.commandline.pop();
+                                self.commandline.backspace();
+                            }
+                            if kevent == KeyCode::Delete.into() {
+                                self.commandline.delete();
+                            }
+                            if kevent == KeyCode::Left.into() {
+                                self.commandline.left();
+                            }
+                            if kevent == KeyCode::Right.into() {
+                                self.commandline.right();
                             }
                             if kevent == KeyCode::Enter.into() {
                                 return self.try_command().await;
                             }
+                            let logs_len = self.logs.len();
                             if kevent == KeyCode::Up.into() {
-                                self.scroll = self.scroll.saturating_sub(1);
+                                self.log_scroll.scroll_up(1, logs_len);
                             }
                             if kevent == KeyCode::Down.into() {
-                                self.scroll = self.scroll.saturating_add(1).min(self.logs.len()-1);
+                                self.log_scroll.scroll_down(1, logs_len);
                             }
                             if kevent == KeyCode::PageUp.into() {
-                                self.scroll = self.scroll.saturating_sub(10);
+                                self.log_scroll.scroll_up(10, logs_len);
                             }
                             if kevent == KeyCode::PageDown.into() {
-                                self.scroll = self.scroll.saturating_add(10).min(self.logs.len()-1);
+                                self.log_scroll.scroll_down(10, logs_len);
                             }
                             if kevent == KeyCode::Home.into() {
-                                self.scroll = 0;
+                                self.log_scroll.scroll_up(logs_len, logs_len);
                             }
                             if kevent == KeyCode::End.into() {
-                                self.scroll = self.logs.len().saturating_sub(1);
+                                self.log_scroll.scroll_down(logs_len, logs_len);
                             }
-                            if kevent == KeyCode::Up.into() {
-                                self.scroll = self.scroll.saturating_sub(1);
-                            }
-
                         }
                     }
                     Some(Err(e)) => log::error!("Error while getting event: {}", e),
@@ -162,22 +183,24 @@ impl Tui {
 
     /// Draws TUI
     fn draw(&mut self, frame: &mut Frame<CrosstermBackend<io::Stdout>>) {
-        let chunks = Layout::default()
-            .direction(Direction::Vertical)
-            .constraints(
-                [
-                    Constraint::Length(frame.size().height - 3),
-                    Constraint::Min(3),
-                ]
-                .as_ref(),
-            )
-            .split(frame.size());
+        let (log_area, commandline_area) = match layout(frame.size()) {
+            Some(areas) => areas,
+            None => {
+                let message = Paragraph::new("Terminal too small");
+                frame.render_widget(message, frame.size());
+                return;
+            }
+        };
+
+        // Top border takes one line
+        self.log_scroll
+            .resize(log_area.height.saturating_sub(1) as usize, self.logs.len());
 
         // Log items
         let items: Vec<ListItem> = self
             .logs
             .iter()
-            .skip(self.scroll)
+            .skip(self.log_scroll.first)
             .map(|l| {
                 let mut spans = vec![];
                 let style = style_from_level(l.level);
@@ -194,19 +217,22 @@ impl Tui {
                 ListItem::new(spans)
             })
             .collect();
+        let title = match self.log_scroll.unread {
+            0 => "Log".to_string(),
+            unread => format!("Log ({} new)", unread),
+        };
         let items = List::new(items).block(
             Block::default()
                 .borders(Borders::ALL.difference(Borders::BOTTOM))
-                .title("Log"),
+                .title(title),
         );
-        frame.render_widget(items, chunks[0]);
-        let input = Paragraph::new(self.commandline.as_str())
+        frame.render_widget(items, log_area);
+        let (x, scroll) = cursor_x(commandline_area, self.commandline.cursor_column());
+        let input = Paragraph::new(self.commandline.text.as_str())
+            .scroll((0, scroll))
             .block(Block::default().borders(Borders::ALL).title("Commandline"));
-        frame.set_cursor(
-            chunks[1].x + 1 + self.commandline.len() as u16,
-            chunks[1].y + 1,
-        );
-        frame.render_widget(input, chunks[1]);
+        frame.set_cursor(x, commandline_area.y + 1);
+        frame.render_widget(input, commandline_area);
     }
 
     /// Consumes the commandline input and tries to use it as a command.
@@ -216,155 +242,192 @@ impl Tui {
         if self.commandline.is_empty() {
             return false;
         }
-        let mut command = String::new();
-        std::mem::swap(&mut command, &mut self.commandline);
-        let command = command.trim_start_matches('/');
-        //TODO: abstract this code more
-        let mut split = command.split(' ');
-        if let Some(command) = split.next() {
-            match command {
-                "exit" => {
-                    log::info!("Exiting...");
-                    return true;
-                }
-                "list" => {
-                    let (otx, orx) = tokio::sync::oneshot::channel();
-
-                    self.channel_sender
-                        .send(ChannelCommand::UsersQueryTUI(otx))
-                        .await
-                        .unwrap();
-
-                    match orx.await {
-                        Ok(list) => log::info!("Connected users: {:?}", list),
-                        Err(e) => log::error!("Error while receiving user list in TUI: {}", e),
-                    }
-                }
-                "kick" => {
-                    let m = if let Some(target) = split.next() {
-                        self.channel_sender
-                            .send(ChannelCommand::KickUser(target.to_owned()))
-                            .await
-                            .unwrap();
-                        format!("Kicking {}.", target)
-                    } else {
-                        "No target provided".to_owned()
-                    };
-                    self.respond(m);
-                }
-                "ban" => {
-                    self.ban_command(split.next(), true).await;
-                }
-                "unban" => {
-                    self.ban_command(split.next(), false).await;
-                }
-                "whitelist" => {
-                    self.whitelist_command(split.next(), true).await;
-                }
-                "unwhitelist" => {
-                    self.whitelist_command(split.next(), false).await;
-                }
-                "set_whitelist" => {
-                    let m = if let Some(arg) = split.next() {
-                        match arg {
-                            "on" | "true" => {
-                                self.channel_sender
-                                    .send(ChannelCommand::SetWhitelist(true))
-                                    .await
-                                    .unwrap();
-                                "Whitelist on.".to_string()
-                            }
-                            "off" | "false" => {
-                                self.channel_sender
-                                    .send(ChannelCommand::SetWhitelist(false))
-                                    .await
-                                    .unwrap();
-                                "Whitelist off.".to_string()
-                            }
-                            _ => {
-                                format!("Invalid argument: {}.\nExpected \"on\"/\"off\"", arg)
-                            }
-                        }
-                    } else {
-                        "No argument provided".to_string()
-                    };
-                    self.respond(m);
-                }
-                "set_allow_new_accounts" => {
-                    let m = if let Some(arg) = split.next() {
-                        match arg {
-                            "on" | "true" => {
-                                self.channel_sender
-                                    .send(ChannelCommand::SetAllowNewAccounts(true))
-                                    .await
-                                    .unwrap();
-                                "Allow new accounts on.".to_string()
-                            }
-                            "off" | "false" => {
-                                self.channel_sender
-                                    .send(ChannelCommand::SetAllowNewAccounts(false))
-                                    .await
-                                    .unwrap();
-                                "Allow new accounts off.".to_string()
-                            }
-                            _ => {
-                                format!("Invalid argument: {}.\nExpected \"on\"/\"off\"", arg)
-                            }
-                        }
-                    } else {
-                        "No argument provided".to_string()
-                    };
-                    self.respond(m);
-                }
-                c => {
-                    self.respond(format!("Unknown command: {}", c));
-                }
+        let command = self.commandline.take();
+        match run_command(&command, &CommandContext::Local, &self.channel_sender).await {
+            CommandOutcome::Reply(m) => self.respond(m),
+            // Renaming is only for users
+            CommandOutcome::NoReply | CommandOutcome::Renamed(_) => (),
+            CommandOutcome::Exit => {
+                log::info!("Exiting...");
+                return true;
             }
-        };
+        }
         false
     }
 
-    /// switch == true => ban
-    /// switch == false => unban
-    async fn ban_command(&mut self, target: Option<&str>, switch: bool) {
-        let m = if let Some(target) = target {
-            self.channel_sender
-                .send(ChannelCommand::BanUser(target.to_owned(), switch))
-                .await
-                .unwrap();
-            if switch {
-                format!("Banning {}", target)
-            } else {
-                format!("Unbanning {}.", target)
-            }
+    // I don't remember why does this exist
+    fn respond<T: std::fmt::Display>(&mut self, s: T) {
+        log::info!("{}", s);
+    }
+}
+
+/// Splits terminal area into log and commandline areas.
+///
+/// Returns `None` if the terminal is too small.
+fn layout(area: Rect) -> Option<(Rect, Rect)> {
+    if area.width < MIN_WIDTH || area.height < MIN_HEIGHT {
+        return None;
+    }
+    let chunks = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([Constraint::Length(area.height - 3), Constraint::Min(3)].as_ref())
+        .split(area);
+    Some((chunks[0], chunks[1]))
+}
+
+/// Column of the cursor in commandline and horizontal scroll of the commandline,
+/// so that the cursor is kept inside the commandline box.
+fn cursor_x(commandline_area: Rect, cursor_column: usize) -> (u16, u16) {
+    let max_column = commandline_area.width.saturating_sub(3) as usize;
+    let scroll = cursor_column.saturating_sub(max_column);
+    let x = commandline_area.x + 1 + (cursor_column - scroll) as u16;
+    (x, scroll.min(u16::MAX as usize) as u16)
+}
+
+/// Scroll state of the log.
+///
+/// Follows newest log entries, unless user scrolled up.
+struct LogScroll {
+    /// Index of first visible log entry
+    first: usize,
+    /// Number of visible log entries
+    height: usize,
+    /// Whether view is at the bottom and should follow new log entries
+    follow: bool,
+    /// Log entries added since user scrolled up
+    unread: usize,
+}
+
+impl Default for LogScroll {
+    fn default() -> Self {
+        Self {
+            first: 0,
+            height: 0,
+            follow: true,
+            unread: 0,
+        }
+    }
+}
+
+impl LogScroll {
+    /// Index of first visible log entry when at the bottom
+    fn bottom(&self, logs_len: usize) -> usize {
+        logs_len.saturating_sub(self.height)
+    }
+
+    fn scroll_up(&mut self, n: usize, logs_len: usize) {
+        self.first = self.first.saturating_sub(n);
+        self.update_follow(logs_len);
+    }
+
+    fn scroll_down(&mut self, n: usize, logs_len: usize) {
+        self.first = self.first.saturating_add(n).min(self.bottom(logs_len));
+        self.update_follow(logs_len);
+    }
+
+    fn log_added(&mut self, logs_len: usize) {
+        if self.follow {
+            self.first = self.bottom(logs_len);
         } else {
-            "No target provided".to_owned()
-        };
-        self.respond(m);
-    }
-
-    /// switch == true => add to whitelist
-    /// switch == false => remove from whitelist
-    async fn whitelist_command(&mut self, target: Option<&str>, switch: bool) {
-        let m = if let Some(target) = target {
-            self.channel_sender
-                .send(ChannelCommand::WhitelistUser(target.to_owned(), switch))
-                .await
-                .unwrap();
-            if switch {
-                format!("Whitelisting {}.", target)
-            } else {
-                format!("Unwhitelisting {}.", target)
-            }
+            self.unread += 1;
+        }
+    }
+
+    fn resize(&mut self, height: usize, logs_len: usize) {
+        self.height = height;
+        if self.follow {
+            self.first = self.bottom(logs_len);
         } else {
-            "No target provided".to_owned()
-        };
-        self.respond(m);
+            self.first = self.first.min(self.bottom(logs_len));
+            self.update_follow(logs_len);
+        }
     }
 
-    // I don't remember why does this exist
-    fn respond<T: std::fmt::Display>(&mut self, s: T) {
-        log::info!("{}", s);
+    fn update_follow(&mut self, logs_len: usize) {
+        self.follow = self.first >= self.bottom(logs_len);
+        if self.follow {
+            self.unread = 0;
+        }
+    }
+}
+
+/// Editable line of text with a cursor
+#[derive(Default)]
+struct CommandLine {
+    text: String,
+    /// Byte index of the cursor in `text`, always on a char boundary
+    cursor: usize,
+}
+
+impl CommandLine {
+    fn insert(&mut self, c: char) {
+        self.text.insert(self.cursor, c);
+        self.cursor += c.len_utf8();
+    }
+
+    /// Deletes char before the cursor
+    fn backspace(&mut self) {
+        if let Some(c) = self.text[..self.cursor].chars().next_back() {
+            self.cursor -= c.len_utf8();
+            self.text.remove(self.cursor);
+        }
+    }
+
+    /// Deletes char under the cursor
+    fn delete(&mut self) {
+        if self.cursor < self.text.len() {
+            self.text.remove(self.cursor);
+        }
+    }
+
+    fn left(&mut self) {
+        if let Some(c) = self.text[..self.cursor].chars().next_back() {
+            self.cursor -= c.len_utf8();
+        }
+    }
+
+    fn right(&mut self) {
+        if let Some(c) = self.text[self.cursor..].chars().next() {
+            self.cursor += c.len_utf8();
+        }
+    }
+
+    fn home(&mut self) {
+        self.cursor = 0;
+    }
+
+    fn end(&mut self) {
+        self.cursor = self.text.len();
+    }
+
+    fn clear(&mut self) {
+        self.text.clear();
+        self.cursor = 0;
+    }
+
+    /// Deletes word (and whitespace after it) before the cursor
+    fn delete_word(&mut self) {
+        let trimmed = self.text[..self.cursor].trim_end();
+        let word_start = trimmed
+            .rfind(char::is_whitespace)
+            .map_or(0, |i| i + trimmed[i..].chars().next().unwrap().len_utf8());
+        self.text.replace_range(word_start..self.cursor, "");
+        self.cursor = word_start;
+    }
+
+    fn is_empty(&self) -> bool {
+        self.text.is_empty()
+    }
+
+    /// Takes the text out, leaving the line empty
+    fn take(&mut self) -> String {
+        self.cursor = 0;
+        std::mem::take(&mut self.text)
+    }
+
+    /// Column (in chars) the cursor is at
+    fn cursor_column(&self) -> usize {
+        self.text[..self.cursor].chars().count()
     }
 }
 
@@ -377,3 +440,166 @@ fn style_from_level(level: log::Level) -> Style {
         flexi_logger::Level::Trace => Style::default().fg(Color::Cyan),
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::{cursor_x, layout, CommandLine, LogScroll};
+    use tui::layout::Rect;
+
+    #[test]
+    fn layout_too_small() {
+        assert_eq!(layout(Rect::new(0, 0, 0, 0)), None);
+        assert_eq!(layout(Rect::new(0, 0, 3, 2)), None);
+        assert_eq!(layout(Rect::new(0, 0, 80, 4)), None);
+        assert_eq!(layout(Rect::new(0, 0, 19, 40)), None);
+    }
+
+    #[test]
+    fn layout_fits() {
+        let (log_area, commandline_area) = layout(Rect::new(0, 0, 20, 5)).unwrap();
+        assert_eq!(log_area, Rect::new(0, 0, 20, 2));
+        assert_eq!(commandline_area, Rect::new(0, 2, 20, 3));
+
+        let (log_area, commandline_area) = layout(Rect::new(0, 0, 80, 24)).unwrap();
+        assert_eq!(log_area.height + commandline_area.height, 24);
+        assert_eq!(commandline_area.height, 3);
+    }
+
+    #[test]
+    fn cursor_stays_in_commandline() {
+        let area = Rect::new(0, 21, 20, 3);
+        assert_eq!(cursor_x(area, 0), (1, 0));
+        assert_eq!(cursor_x(area, 5), (6, 0));
+        assert_eq!(cursor_x(area, 17), (18, 0));
+        assert_eq!(cursor_x(area, 18), (18, 1));
+        assert_eq!(cursor_x(area, 100), (18, 83));
+    }
+
+    fn line(text: &str) -> CommandLine {
+        let mut line = CommandLine::default();
+        text.chars().for_each(|c| line.insert(c));
+        line
+    }
+
+    #[test]
+    fn delete_word_test() {
+        let mut line = line("kick some user");
+        line.delete_word();
+        assert_eq!(line.text, "kick some ");
+        line.delete_word();
+        assert_eq!(line.text, "kick ");
+        line.delete_word();
+        assert_eq!(line.text, "");
+        line.delete_word();
+        assert_eq!(line.text, "");
+
+        let mut line = self::line("ban  żółw  ");
+        line.delete_word();
+        assert_eq!(line.text, "ban  ");
+
+        // Only text before the cursor is deleted
+        let mut line = self::line("ban user");
+        (0..4).for_each(|_| line.left());
+        line.delete_word();
+        assert_eq!(line.text, "user");
+        assert_eq!(line.cursor, 0);
+    }
+
+    #[test]
+    fn insert_at_cursor() {
+        let mut line = line("kik");
+        line.left();
+        line.insert('c');
+        assert_eq!(line.text, "kick");
+        line.home();
+        line.insert('/');
+        assert_eq!(line.text, "/kick");
+        line.end();
+        line.insert(' ');
+        assert_eq!(line.text, "/kick ");
+
+        let mut line = self::line("żółw");
+        line.left();
+        line.backspace();
+        assert_eq!(line.text, "żów");
+        assert_eq!(line.cursor_column(), 2);
+        line.delete();
+        assert_eq!(line.text, "żó");
+    }
+
+    #[test]
+    fn cursor_bounds() {
+        let mut line = line("ab");
+        line.right();
+        assert_eq!(line.cursor, 2);
+        line.delete();
+        assert_eq!(line.text, "ab");
+        (0..5).for_each(|_| line.left());
+        assert_eq!(line.cursor, 0);
+        line.backspace();
+        assert_eq!(line.text, "ab");
+
+        assert_eq!(line.take(), "ab");
+        assert_eq!(line.cursor, 0);
+        line.right();
+        assert_eq!(line.cursor, 0);
+    }
+
+    fn log_scroll(height: usize, logs_len: usize) -> LogScroll {
+        let mut log_scroll = LogScroll::default();
+        log_scroll.resize(height, 0);
+        for len in 1..=logs_len {
+            log_scroll.log_added(len);
+        }
+        log_scroll
+    }
+
+    #[test]
+    fn log_follows_at_bottom() {
+        let mut log_scroll = log_scroll(10, 5);
+        assert_eq!(log_scroll.first, 0);
+
+        log_scroll.log_added(15);
+        assert_eq!(log_scroll.first, 5);
+        assert!(log_scroll.follow);
+        assert_eq!(log_scroll.unread, 0);
+
+        // Scrolling back to the bottom resumes following
+        log_scroll.scroll_up(1, 15);
+        log_scroll.log_added(16);
+        log_scroll.scroll_down(100, 16);
+        assert_eq!(log_scroll.first, 6);
+        assert!(log_scroll.follow);
+        assert_eq!(log_scroll.unread, 0);
+        log_scroll.log_added(17);
+        assert_eq!(log_scroll.first, 7);
+    }
+
+    #[test]
+    fn log_keeps_position_when_scrolled_up() {
+        let mut log_scroll = log_scroll(10, 30);
+        assert_eq!(log_scroll.first, 20);
+
+        log_scroll.scroll_up(5, 30);
+        assert!(!log_scroll.follow);
+        log_scroll.log_added(31);
+        log_scroll.log_added(32);
+        assert_eq!(log_scroll.first, 15);
+        assert_eq!(log_scroll.unread, 2);
+
+        // Making the view taller can reach the bottom
+        log_scroll.resize(20, 32);
+        assert_eq!(log_scroll.first, 12);
+        assert!(log_scroll.follow);
+        assert_eq!(log_scroll.unread, 0);
+    }
+
+    #[test]
+    fn log_scroll_empty() {
+        let mut log_scroll = log_scroll(10, 0);
+        log_scroll.scroll_down(10, 0);
+        log_scroll.scroll_up(10, 0);
+        assert_eq!(log_scroll.first, 0);
+        assert!(log_scroll.follow);
+    }
+}
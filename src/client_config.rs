@@ -0,0 +1,336 @@
+//! Connection behavior shared by the clients
+use std::path::Path;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use tokio::net::{TcpStream, ToSocketAddrs};
+
+use rand::{rngs::OsRng, Rng, SeedableRng};
+use rand_chacha::ChaCha20Rng;
+use rsa::{PaddingScheme, PublicKey};
+
+use crate::connection::{ConnectionError, ConnectionReader, ConnectionWriter};
+use crate::packets::{ClientboundPacket, DisconnectReason, ServerboundPacket};
+use crate::{ENC_TOK_LEN, SECRET_LEN};
+
+/// How clients connect to the server and what they fetch after connecting.
+///
+/// Values missing from a config file are taken from [`ClientConfig::default`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ClientConfig {
+    /// Number of past messages fetched on connecting
+    pub history_count: i64,
+    /// Max number of past messages fetched at once, see [`history_chunks`](crate::utils::history_chunks)
+    pub history_chunk_size: i64,
+    /// Seconds to wait for the server to accept the connection
+    pub connect_timeout_secs: u64,
+    /// How many times to try connecting before giving up
+    pub connect_attempts: u32,
+    /// Seconds to wait between connection attempts
+    pub retry_delay_secs: u64,
+    /// Seconds between pings keeping the connection alive, `0` disables them
+    pub ping_interval_secs: u64,
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        Self {
+            history_count: 50,
+            history_chunk_size: 50,
+            connect_timeout_secs: 5,
+            connect_attempts: 3,
+            retry_delay_secs: 2,
+            ping_interval_secs: 60,
+        }
+    }
+}
+
+impl ClientConfig {
+    /// Loads config from a TOML file
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, String> {
+        let toml = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+        Self::from_toml(&toml)
+    }
+
+    fn from_toml(toml: &str) -> Result<Self, String> {
+        toml::from_str(toml).map_err(|e| e.to_string())
+    }
+
+    pub fn connect_timeout(&self) -> Duration {
+        Duration::from_secs(self.connect_timeout_secs)
+    }
+
+    pub fn retry_delay(&self) -> Duration {
+        Duration::from_secs(self.retry_delay_secs)
+    }
+
+    /// `None` if pinging is disabled
+    pub fn ping_interval(&self) -> Option<Duration> {
+        (self.ping_interval_secs > 0).then(|| Duration::from_secs(self.ping_interval_secs))
+    }
+
+    /// History fetches to send after connecting, see [`history_chunks`](crate::utils::history_chunks)
+    pub fn history_chunks(&self) -> Vec<(i64, i64)> {
+        crate::utils::history_chunks(self.history_count, self.history_chunk_size)
+    }
+
+    /// Connects to `addr`, retrying according to this config.
+    ///
+    /// Returns error of the last attempt if none succeeded.
+    pub async fn connect<A: ToSocketAddrs + Clone>(&self, addr: A) -> std::io::Result<TcpStream> {
+        let mut attempt = 1;
+        loop {
+            let error = match tokio::time::timeout(
+                self.connect_timeout(),
+                TcpStream::connect(addr.clone()),
+            )
+            .await
+            {
+                Ok(Ok(socket)) => return Ok(socket),
+                Ok(Err(e)) => e,
+                Err(_) => std::io::Error::new(std::io::ErrorKind::TimedOut, "Connection timed out"),
+            };
+            if attempt >= self.connect_attempts {
+                return Err(error);
+            }
+            attempt += 1;
+            tokio::time::sleep(self.retry_delay()).await;
+        }
+    }
+}
+
+/// Why connecting to the server failed before logging in
+#[derive(Debug)]
+pub enum InitError {
+    /// Server couldn't be reached, see [`ClientConfig::connect`]
+    ConnectFailed(std::io::Error),
+    /// Server answered the encryption handshake with something unexpected
+    HandshakeFailed(String),
+    /// Server and client have no crypto suite in common
+    ProtocolMismatch(String),
+    /// Connection broke during the handshake
+    Io(std::io::Error),
+}
+
+impl InitError {
+    /// Classifies packet the encryption handshake didn't expect,
+    /// including [`ClientboundPacket::EncryptionResponse`] with unsupported suite
+    pub fn unexpected(p: ClientboundPacket) -> Self {
+        match p {
+            ClientboundPacket::EncryptionResponse(_, _, suite) => {
+                InitError::ProtocolMismatch(format!("server chose crypto suite {}", suite.0))
+            }
+            ClientboundPacket::Disconnect(DisconnectReason::ProtocolError) => {
+                InitError::ProtocolMismatch("server supports none of our crypto suites".into())
+            }
+            p => InitError::HandshakeFailed(format!("server responded with {:?}", p)),
+        }
+    }
+
+    /// Whether trying again later could help
+    pub fn allows_reconnect(&self) -> bool {
+        matches!(self, InitError::ConnectFailed(_) | InitError::Io(_))
+    }
+}
+
+impl From<std::io::Error> for InitError {
+    fn from(e: std::io::Error) -> Self {
+        InitError::Io(e)
+    }
+}
+
+impl From<ConnectionError> for InitError {
+    fn from(e: ConnectionError) -> Self {
+        match e {
+            ConnectionError::Closed => {
+                InitError::Io(std::io::Error::from(std::io::ErrorKind::UnexpectedEof))
+            }
+            ConnectionError::Io(e) => InitError::Io(e),
+            e => InitError::HandshakeFailed(e.to_string()),
+        }
+    }
+}
+
+impl std::fmt::Display for InitError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InitError::ConnectFailed(e) => write!(f, "Failed to connect: {}", e),
+            InitError::HandshakeFailed(m) => write!(f, "Encryption failed: {}", m),
+            InitError::ProtocolMismatch(m) => write!(f, "Incompatible server: {}", m),
+            InitError::Io(e) => write!(f, "Connection lost: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for InitError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            InitError::ConnectFailed(e) | InitError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// Runs the encryption handshake, returning shared secret and nonce generators for writing and reading
+pub async fn establish_encryption(
+    reader: &mut ConnectionReader<ClientboundPacket>,
+    writer: &mut ConnectionWriter<ServerboundPacket>,
+) -> Result<(Vec<u8>, ChaCha20Rng, ChaCha20Rng), InitError> {
+    // Request encryption
+    writer
+        .write_packet(
+            ServerboundPacket::EncryptionRequest(crate::SUPPORTED_SUITES.to_vec()),
+            &None,
+            None,
+        )
+        .await?;
+
+    // Handle encryption response
+    let (pub_key, token) = match reader.read_packet(&None, None).await {
+        Ok(Some(ClientboundPacket::EncryptionResponse(pub_key_der, token, suite)))
+            if suite.is_supported() =>
+        {
+            let pub_key: rsa::RsaPublicKey =
+                rsa::pkcs8::FromPublicKey::from_public_key_der(&pub_key_der)
+                    .map_err(|e| InitError::HandshakeFailed(e.to_string()))?;
+            if token.len() != ENC_TOK_LEN {
+                return Err(InitError::HandshakeFailed("invalid token".to_string()));
+            }
+            (pub_key, token)
+        }
+        Ok(Some(p)) => return Err(InitError::unexpected(p)),
+        Ok(None) => return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into()),
+        Err(e) => return Err(e.into()),
+    };
+
+    // Generate secret
+    let mut secret = [0u8; SECRET_LEN];
+    OsRng.fill(&mut secret);
+
+    // Encrypt and send
+    let padding = PaddingScheme::new_pkcs1v15_encrypt();
+    let enc_secret = pub_key
+        .encrypt(&mut OsRng, padding, &secret[..])
+        .expect("failed to encrypt");
+    let padding = PaddingScheme::new_pkcs1v15_encrypt();
+    let enc_token = pub_key
+        .encrypt(&mut OsRng, padding, &token[..])
+        .expect("failed to encrypt");
+    writer
+        .write_packet(
+            ServerboundPacket::EncryptionConfirm(enc_secret, enc_token),
+            &None,
+            None,
+        )
+        .await?;
+
+    // From this point onward we assume everything is encrypted
+    let nonce_generator_write = ChaCha20Rng::from_seed(secret);
+    let mut nonce_generator_read = ChaCha20Rng::from_seed(secret);
+    let secret = secret.to_vec();
+
+    // Expect EncryptionAck (should be encrypted)
+    match reader
+        .read_packet(&Some(secret.clone()), Some(&mut nonce_generator_read))
+        .await
+    {
+        Ok(Some(ClientboundPacket::EncryptionAck)) => {}
+        Ok(Some(p)) => return Err(InitError::unexpected(p)),
+        Ok(None) => return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into()),
+        Err(e) => return Err(e.into()),
+    }
+    Ok((secret, nonce_generator_write, nonce_generator_read))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ClientConfig, ConnectionError, InitError};
+    use crate::packets::{ClientboundPacket, DisconnectReason};
+    use std::time::Duration;
+
+    #[test]
+    fn client_config_defaults_and_overrides() {
+        let default = ClientConfig::default();
+        assert_eq!(ClientConfig::from_toml("").unwrap(), default);
+        assert_eq!(default.history_chunks(), vec![(0, 50)]);
+
+        let config = ClientConfig::from_toml(
+            "history_count = 20\nhistory_chunk_size = 5\nping_interval_secs = 0",
+        )
+        .unwrap();
+        assert_eq!(config.history_count, 20);
+        assert_eq!(config.history_chunks().len(), 4);
+        assert_eq!(config.ping_interval(), None);
+        // Rest stays default
+        assert_eq!(config.connect_timeout(), Duration::from_secs(5));
+        assert_eq!(config.connect_attempts, default.connect_attempts);
+
+        assert!(ClientConfig::from_toml("connect_attempts = \"many\"").is_err());
+    }
+
+    #[test]
+    fn connect_gives_up_after_attempts() {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            // Free port nobody listens on
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            drop(listener);
+            let config = ClientConfig {
+                connect_attempts: 2,
+                retry_delay_secs: 0,
+                ..Default::default()
+            };
+            assert!(config.connect(addr).await.is_err());
+
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            assert!(config.connect(listener.local_addr().unwrap()).await.is_ok());
+        });
+    }
+
+    #[test]
+    fn init_error_variants() {
+        use std::io::{Error, ErrorKind};
+
+        let e = InitError::ConnectFailed(Error::new(ErrorKind::TimedOut, "Connection timed out"));
+        assert!(matches!(e, InitError::ConnectFailed(_)));
+        assert_eq!(e.to_string(), "Failed to connect: Connection timed out");
+        assert!(e.allows_reconnect());
+
+        let e = InitError::unexpected(ClientboundPacket::EncryptionAck);
+        assert!(matches!(e, InitError::HandshakeFailed(_)));
+        assert!(!e.allows_reconnect());
+
+        let e = InitError::unexpected(ClientboundPacket::EncryptionResponse(
+            vec![],
+            vec![],
+            crate::CryptoSuite(9),
+        ));
+        assert!(matches!(e, InitError::ProtocolMismatch(_)));
+        assert_eq!(
+            e.to_string(),
+            "Incompatible server: server chose crypto suite 9"
+        );
+        let e = InitError::unexpected(ClientboundPacket::Disconnect(
+            DisconnectReason::ProtocolError,
+        ));
+        assert!(matches!(e, InitError::ProtocolMismatch(_)));
+        assert!(!e.allows_reconnect());
+
+        let e: InitError = Error::from(ErrorKind::UnexpectedEof).into();
+        assert!(matches!(e, InitError::Io(_)));
+        assert!(e.allows_reconnect());
+        assert!(std::error::Error::source(&e).is_some());
+
+        // Server going away can be retried, garbage from it can't
+        let e: InitError = ConnectionError::Closed.into();
+        assert!(e.allows_reconnect());
+        let e: InitError = ConnectionError::Decrypt.into();
+        assert_eq!(e.to_string(), "Encryption failed: Decryption failed");
+        assert!(!e.allows_reconnect());
+    }
+}
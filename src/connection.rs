@@ -1,6 +1,6 @@
 use std::marker::PhantomData;
 
-use bytes::BytesMut;
+use bytes::{Buf, BytesMut};
 use tokio::io::{AsyncReadExt, AsyncWriteExt, BufWriter};
 use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
 use tokio::net::TcpStream;
@@ -12,6 +12,49 @@ use rand_chacha::ChaCha20Rng;
 
 use encryption::*;
 
+/// Why reading or writing a packet failed.
+#[derive(Debug)]
+pub enum ConnectionError {
+    /// Peer closed (or reset) the connection
+    Closed,
+    /// Connection broke
+    Io(std::io::Error),
+    /// Frame didn't decrypt, e.g. because of a wrong secret
+    Decrypt,
+    /// Frame decrypted but isn't a valid packet
+    Deserialize(rmp_serde::decode::Error),
+    /// Frame is over the max packet size, see [`ConnectionReader::set_max_packet_size`]
+    FrameTooLarge,
+}
+
+impl From<std::io::Error> for ConnectionError {
+    fn from(e: std::io::Error) -> Self {
+        ConnectionError::Io(e)
+    }
+}
+
+impl std::fmt::Display for ConnectionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConnectionError::Closed => write!(f, "Connection closed by peer"),
+            ConnectionError::Io(e) => write!(f, "Connection error: {}", e),
+            ConnectionError::Decrypt => write!(f, "Decryption failed"),
+            ConnectionError::Deserialize(e) => write!(f, "Invalid packet: {}", e),
+            ConnectionError::FrameTooLarge => write!(f, "Frame too big"),
+        }
+    }
+}
+
+impl std::error::Error for ConnectionError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ConnectionError::Io(e) => Some(e),
+            ConnectionError::Deserialize(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
 /// Connection that is later split into separate reader and writer.
 ///
 /// I = Incoming Packets
@@ -25,12 +68,20 @@ pub struct Connection<I, O> {
 pub struct ConnectionReader<P: Packet> {
     stream: OwnedReadHalf,
     buffer: BytesMut,
+    cipher: CachedCipher,
+    /// Total bytes of packets read so far
+    bytes_read: u64,
+    /// Frames over this many bytes are refused, [`MAX_FRAME_LEN`](crate::MAX_FRAME_LEN) by default
+    max_packet_size: usize,
     _marker: PhantomData<P>,
 }
 
 /// Writing half of the connection.
 pub struct ConnectionWriter<P: Packet> {
     stream: BufWriter<OwnedWriteHalf>,
+    cipher: CachedCipher,
+    /// Total bytes of packets written so far
+    bytes_written: u64,
     _marker: PhantomData<P>,
 }
 
@@ -53,10 +104,15 @@ where
         let read = ConnectionReader::<I> {
             stream: read,
             buffer: BytesMut::with_capacity(4096),
+            cipher: CachedCipher::default(),
+            bytes_read: 0,
+            max_packet_size: crate::MAX_FRAME_LEN,
             _marker: PhantomData,
         };
         let write = ConnectionWriter::<O> {
             stream: BufWriter::new(write),
+            cipher: CachedCipher::default(),
+            bytes_written: 0,
             _marker: PhantomData,
         };
         (read, write)
@@ -64,13 +120,23 @@ where
 }
 
 impl<P: Packet> ConnectionReader<P> {
+    /// Total bytes of packets read so far (encrypted size, if encrypted)
+    pub fn bytes_read(&self) -> u64 {
+        self.bytes_read
+    }
+
+    /// Sets max size of a frame in bytes, bigger ones make [`read_packet`](Self::read_packet) fail
+    pub fn set_max_packet_size(&mut self, max_packet_size: usize) {
+        self.max_packet_size = max_packet_size;
+    }
+
     /// Tries to read incoming packet on TCP stream
     /// and decrypts if secret and nonce_generator are `Some`
     pub async fn read_packet(
         &mut self,
         secret: &Option<Vec<u8>>,
         nonce_generator: Option<&mut ChaCha20Rng>,
-    ) -> Result<Option<P>, String> {
+    ) -> Result<Option<P>, ConnectionError> {
         let secret_and_nonce = if let Some(secret) = secret {
             let mut buf = [0u8; crate::SECRET_LEN];
             buf.copy_from_slice(&secret[..]);
@@ -83,34 +149,58 @@ impl<P: Packet> ConnectionReader<P> {
             None
         };
         loop {
+            // Frames that are already here go first, so only the pending one is size-checked
             if let Some((secret, nonce)) = secret_and_nonce {
-                if let Ok((p, b)) =
-                    decrypt_frame(&mut self.buffer.as_ref(), &secret, &nonce)
-                {
-                    self.buffer = BytesMut::from(b);
-                    if let Ok((p, _)) = P::deserialized(&p) {
-                        return Ok(Some(p));
-                    }
+                if full_frame_buffered(&self.buffer) {
+                    let cipher = self.cipher.get(&secret);
+                    let (p, rest) = decrypt_frame(
+                        &mut self.buffer.as_ref(),
+                        cipher,
+                        &nonce,
+                        self.max_packet_size,
+                    )?;
+                    let consumed = self.buffer.len() - rest.len();
+                    self.bytes_read += consumed as u64;
+                    self.buffer.advance(consumed);
+                    let (p, _) = P::deserialized(&p).map_err(ConnectionError::Deserialize)?;
+                    return Ok(Some(p));
+                }
+            } else if let Ok((p, rest)) = P::deserialized(&self.buffer) {
+                // Move buffer past what we already read, without reallocating
+                let consumed = self.buffer.len() - rest.len();
+                if frame_too_big(&self.buffer[..consumed], false, self.max_packet_size) {
+                    return Err(ConnectionError::FrameTooLarge);
                 }
-            } else if let Ok((p, b)) = P::deserialized(&self.buffer) {
-                // Effectively move buffer past what we already read
-                self.buffer = BytesMut::from(b);
+                self.bytes_read += consumed as u64;
+                self.buffer.advance(consumed);
                 return Ok(Some(p));
             }
+            if frame_too_big(
+                &self.buffer,
+                secret_and_nonce.is_some(),
+                self.max_packet_size,
+            ) {
+                return Err(ConnectionError::FrameTooLarge);
+            }
 
-            if 0 == self
-                .stream
-                .read_buf(&mut self.buffer)
-                .await
-                .map_err(|e| e.to_string())?
-            {
-                return Err("Connection reset by peer".into());
+            match self.stream.read_buf(&mut self.buffer).await {
+                Ok(0) => return Err(ConnectionError::Closed),
+                Ok(_) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::ConnectionReset => {
+                    return Err(ConnectionError::Closed)
+                }
+                Err(e) => return Err(e.into()),
             }
         }
     }
 }
 
 impl<P: Packet> ConnectionWriter<P> {
+    /// Total bytes of packets written so far (encrypted size, if encrypted)
+    pub fn bytes_written(&self) -> u64 {
+        self.bytes_written
+    }
+
     /// Tries to write the packet to TCP stream
     /// and encrypts it if secret and nonce_generator are `Some`
     pub async fn write_packet(
@@ -118,7 +208,35 @@ impl<P: Packet> ConnectionWriter<P> {
         packet: P,
         secret: &Option<Vec<u8>>,
         nonce_generator: Option<&mut ChaCha20Rng>,
-    ) -> std::io::Result<()> {
+    ) -> Result<(), ConnectionError> {
+        self.queue_packet(packet, secret, nonce_generator).await?;
+        Ok(self.flush().await?)
+    }
+
+    /// Like [`write_packet`](Self::write_packet) for multiple packets, but flushes only once
+    pub async fn write_packets(
+        &mut self,
+        packets: impl IntoIterator<Item = P>,
+        secret: &Option<Vec<u8>>,
+        mut nonce_generator: Option<&mut ChaCha20Rng>,
+    ) -> Result<(), ConnectionError> {
+        for packet in packets {
+            self.queue_packet(packet, secret, nonce_generator.as_deref_mut())
+                .await?;
+        }
+        Ok(self.flush().await?)
+    }
+
+    /// Writes the packet to the buffer, without flushing it to TCP stream.
+    ///
+    /// Packets are sent in order they were queued, once [`flush`](Self::flush) is called
+    /// (or the buffer fills up).
+    pub async fn queue_packet(
+        &mut self,
+        packet: P,
+        secret: &Option<Vec<u8>>,
+        nonce_generator: Option<&mut ChaCha20Rng>,
+    ) -> Result<(), ConnectionError> {
         let secret_and_nonce = if let Some(secret) = secret {
             let mut buf = [0u8; crate::SECRET_LEN];
             buf.copy_from_slice(&secret[..]);
@@ -132,9 +250,15 @@ impl<P: Packet> ConnectionWriter<P> {
         };
         let mut p = packet.serialized();
         if let Some((secret, nonce)) = secret_and_nonce {
-            p = encrypt_frame(&p, &secret, &nonce);
+            p = encrypt_frame(&p, self.cipher.get(&secret), &nonce);
         }
         self.stream.write_all(&p).await?;
+        self.bytes_written += p.len() as u64;
+        Ok(())
+    }
+
+    /// Flushes queued packets to TCP stream
+    pub async fn flush(&mut self) -> std::io::Result<()> {
         self.stream.flush().await
     }
 }
@@ -145,18 +269,33 @@ mod encryption {
         XChaCha20Poly1305,
     };
 
+    use super::ConnectionError;
     use crate::{NONCE_LEN, SECRET_LEN};
 
+    /// Cipher kept for the whole connection, so it isn't reconstructed for every frame.
+    #[derive(Default)]
+    pub struct CachedCipher {
+        cipher: Option<([u8; SECRET_LEN], XChaCha20Poly1305)>,
+    }
+
+    impl CachedCipher {
+        /// Returns cipher for given key, only constructing it when the key changed
+        pub fn get(&mut self, key: &[u8; SECRET_LEN]) -> &XChaCha20Poly1305 {
+            if !matches!(&self.cipher, Some((k, _)) if k == key) {
+                self.cipher = Some((*key, XChaCha20Poly1305::new(key.into())));
+            }
+            &self.cipher.as_ref().unwrap().1
+        }
+    }
+
     /// Encrypts the packet using [`XChaCha20Poly1305`].
     ///
     /// [u8; n] -> [u8;n+4] (1st 4 bytes is len)
     pub fn encrypt_frame(
         packet_bytes: &[u8],
-        key: &[u8; SECRET_LEN],
+        cipher: &XChaCha20Poly1305,
         nonce: &[u8; NONCE_LEN],
     ) -> Vec<u8> {
-        // This maybe could use some unsafe pointer magic to be more optimal?
-        let cipher = XChaCha20Poly1305::new(key.into());
         let len: u32 = packet_bytes.len().try_into().expect("Packet too big!");
         let mut buf = vec![0; len as usize + 4];
         buf[0..4].copy_from_slice(&len.to_be_bytes());
@@ -171,29 +310,46 @@ mod encryption {
 
     /// Decrypts the packet using [`XChaCha20Poly1305`].
     ///
-    /// [u8; n] -> [u8;n+4] (1st 4 bytes is len)
+    /// [u8; n] -> [u8;n+4] (1st 4 bytes is len), frames longer than `max_len` are refused.
+    /// Expects the whole frame, see [`full_frame_buffered`](super::full_frame_buffered).
     pub fn decrypt_frame<'a>(
         encrypted_bytes: &mut &'a [u8],
-        key: &[u8; SECRET_LEN],
+        cipher: &XChaCha20Poly1305,
         nonce: &[u8; NONCE_LEN],
-    ) -> Result<(Vec<u8>, &'a [u8]), String> {
-        if encrypted_bytes.len() < 4 {
-            return Err("Too short".to_string());
-        }
-
+        max_len: usize,
+    ) -> Result<(Vec<u8>, &'a [u8]), ConnectionError> {
+        debug_assert!(super::full_frame_buffered(encrypted_bytes));
         let data_len: u32 = super::read_be_u32(encrypted_bytes);
-        if data_len as usize > encrypted_bytes.len() {
-            return Err("Not full frame".to_string());
+        if data_len as usize > max_len {
+            return Err(ConnectionError::FrameTooLarge);
         }
 
-        // This maybe could use some unsafe pointer magic to be more optimal?
-        let cipher = XChaCha20Poly1305::new(key.into());
         let (packet_bytes, rest) = encrypted_bytes.split_at(data_len as usize);
-        let ret = cipher.decrypt(nonce.into(), packet_bytes).unwrap();
+        let ret = cipher
+            .decrypt(nonce.into(), packet_bytes)
+            .map_err(|_| ConnectionError::Decrypt)?;
         Ok((ret, rest))
     }
 }
 
+/// Checks whether the frame at the start of `buffer` is too big to be valid
+/// with at most `max_len` bytes, either because the frame header says so (for encrypted frames)
+/// or because too much data was buffered without a complete packet.
+///
+/// Complete frames should be taken out first, so they don't count towards a plaintext one.
+fn frame_too_big(buffer: &[u8], encrypted: bool, max_len: usize) -> bool {
+    if encrypted {
+        buffer.len() >= 4 && read_be_u32(&mut &buffer[..]) as usize > max_len
+    } else {
+        buffer.len() > max_len.saturating_add(4)
+    }
+}
+
+/// Checks whether the buffer holds a whole encrypted frame (header and data)
+fn full_frame_buffered(buffer: &[u8]) -> bool {
+    buffer.len() >= 4 && read_be_u32(&mut &buffer[..]) as usize <= buffer.len() - 4
+}
+
 /// Reads big endian u32 from bytes, advancing input head by the size of u32
 fn read_be_u32(input: &mut &[u8]) -> u32 {
     let (int_bytes, rest) = input.split_at(std::mem::size_of::<u32>());
@@ -204,8 +360,15 @@ fn read_be_u32(input: &mut &[u8]) -> u32 {
 #[cfg(test)]
 mod test {
     use super::encryption::*;
+    use super::{frame_too_big, full_frame_buffered};
     use crate::packets::*;
-    use crate::{NONCE_LEN, SECRET_LEN};
+    use crate::{MAX_FRAME_LEN, NONCE_LEN, SECRET_LEN};
+    use chacha20poly1305::{aead::NewAead, XChaCha20Poly1305};
+
+    fn cipher(key: &[u8; SECRET_LEN]) -> XChaCha20Poly1305 {
+        XChaCha20Poly1305::new(key.into())
+    }
+
     #[test]
     fn encrypt_packet_test() {
         let key = [0u8; SECRET_LEN];
@@ -213,7 +376,7 @@ mod test {
 
         let packet = ServerboundPacket::Message("test".to_string());
         let packet_data = packet.serialized();
-        let encrypted = encrypt_frame(&packet_data, &key, &nonce);
+        let encrypted = encrypt_frame(&packet_data, &cipher(&key), &nonce);
         let exp_encrypted = [
             0, 0, 0, 30, 249, 57, 219, 236, 150, 83, 236, 24, 188, 69, 135, 160, 198, 64, 126, 155,
             247, 135, 6, 132, 161, 45, 1, 86, 75, 207, 109, 177, 135, 228,
@@ -231,7 +394,7 @@ mod test {
             247, 135, 6, 132, 161, 45, 1, 86, 75, 207, 109, 177, 135, 228,
         ];
 
-        let decrypted = decrypt_frame(&mut &encrypted[..], &key, &nonce);
+        let decrypted = decrypt_frame(&mut &encrypted[..], &cipher(&key), &nonce, MAX_FRAME_LEN);
         assert_eq!(
             ServerboundPacket::Message("test".to_string()),
             ServerboundPacket::deserialized(&decrypted.unwrap().0)
@@ -248,9 +411,9 @@ mod test {
         let packet = ServerboundPacket::Message("test".to_string());
 
         let packet_data = packet.serialized();
-        let encrypted = encrypt_frame(&packet_data, &key, &nonce);
+        let encrypted = encrypt_frame(&packet_data, &cipher(&key), &nonce);
 
-        let decrypted = decrypt_frame(&mut &encrypted[..], &key, &nonce);
+        let decrypted = decrypt_frame(&mut &encrypted[..], &cipher(&key), &nonce, MAX_FRAME_LEN);
         assert_eq!(
             packet,
             ServerboundPacket::deserialized(&decrypted.unwrap().0)
@@ -258,4 +421,238 @@ mod test {
                 .0
         );
     }
+
+    #[test]
+    fn frame_too_big_test() {
+        let key = [0u8; SECRET_LEN];
+        let nonce = [0u8; NONCE_LEN];
+        let packet = ServerboundPacket::Message("test".to_string()).serialized();
+        let encrypted = encrypt_frame(&packet, &cipher(&key), &nonce);
+        assert!(!frame_too_big(&encrypted, true, MAX_FRAME_LEN));
+        assert!(!frame_too_big(&packet, false, MAX_FRAME_LEN));
+        assert!(!frame_too_big(&[], true, MAX_FRAME_LEN));
+
+        // Header announcing frame over the limit
+        let header = (MAX_FRAME_LEN as u32 + 1).to_be_bytes();
+        assert!(frame_too_big(&header, true, MAX_FRAME_LEN));
+        assert!(frame_too_big(&encrypted, true, 4));
+
+        // Too much buffered data
+        let command = ServerboundPacket::Command("a".repeat(MAX_FRAME_LEN)).serialized();
+        assert!(frame_too_big(&command, false, MAX_FRAME_LEN));
+        assert!(frame_too_big(&packet, false, 4));
+    }
+
+    #[test]
+    fn decrypt_garbage_test() {
+        let key = [0u8; SECRET_LEN];
+        let nonce = [0u8; NONCE_LEN];
+        let mut encrypted =
+            encrypt_frame(&ServerboundPacket::Ping.serialized(), &cipher(&key), &nonce);
+        assert!(full_frame_buffered(&encrypted));
+        assert!(!full_frame_buffered(&encrypted[..encrypted.len() - 1]));
+        assert!(!full_frame_buffered(&encrypted[..3]));
+
+        // Corrupted frame is an error, not a panic
+        let last = encrypted.len() - 1;
+        encrypted[last] ^= 1;
+        assert!(decrypt_frame(&mut &encrypted[..], &cipher(&key), &nonce, MAX_FRAME_LEN).is_err());
+        assert!(decrypt_frame(
+            &mut &[0, 0, 0, 2, 1, 2][..],
+            &cipher(&key),
+            &nonce,
+            MAX_FRAME_LEN
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn cached_cipher_test() {
+        let nonce = [0u8; NONCE_LEN];
+        let packet = ServerboundPacket::Message("test".to_string()).serialized();
+        let mut cached = CachedCipher::default();
+        let first = encrypt_frame(&packet, cached.get(&[0u8; SECRET_LEN]), &nonce);
+        assert_eq!(
+            first,
+            encrypt_frame(&packet, &cipher(&[0u8; SECRET_LEN]), &nonce)
+        );
+        // New secret gets a new cipher
+        let second = encrypt_frame(&packet, cached.get(&[1u8; SECRET_LEN]), &nonce);
+        assert_eq!(
+            second,
+            encrypt_frame(&packet, &cipher(&[1u8; SECRET_LEN]), &nonce)
+        );
+        assert_ne!(first, second);
+    }
+
+    #[test]
+    fn write_packets_keeps_order_test() {
+        use super::Connection;
+        use rand::SeedableRng;
+        use rand_chacha::ChaCha20Rng;
+
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let client = tokio::net::TcpStream::connect(addr).await.unwrap();
+            let (server, _) = listener.accept().await.unwrap();
+            let (_, mut writer) =
+                Connection::<ServerboundPacket, ServerboundPacket>::new(client).split();
+            let (mut reader, _) =
+                Connection::<ServerboundPacket, ServerboundPacket>::new(server).split();
+
+            let secret = Some(vec![5u8; SECRET_LEN]);
+            let mut write_nonces = ChaCha20Rng::from_seed([5u8; SECRET_LEN]);
+            let mut read_nonces = ChaCha20Rng::from_seed([5u8; SECRET_LEN]);
+            let packets: Vec<_> = (0..50)
+                .map(|i| ServerboundPacket::Message(i.to_string()))
+                .collect();
+            writer
+                .write_packets(packets.clone(), &secret, Some(&mut write_nonces))
+                .await
+                .unwrap();
+            writer
+                .write_packet(ServerboundPacket::Ping, &secret, Some(&mut write_nonces))
+                .await
+                .unwrap();
+            for p in packets.into_iter().chain([ServerboundPacket::Ping]) {
+                let read = reader.read_packet(&secret, Some(&mut read_nonces)).await;
+                assert!(matches!(read, Ok(Some(r)) if r == p));
+            }
+            assert_eq!(reader.bytes_read(), writer.bytes_written());
+        });
+    }
+
+    #[test]
+    fn oversized_frame_is_refused_test() {
+        use super::{Connection, ConnectionError};
+        use rand::SeedableRng;
+        use rand_chacha::ChaCha20Rng;
+        use tokio::io::AsyncWriteExt;
+
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let mut client = tokio::net::TcpStream::connect(addr).await.unwrap();
+            let (server, _) = listener.accept().await.unwrap();
+            let (mut reader, _) =
+                Connection::<ServerboundPacket, ServerboundPacket>::new(server).split();
+
+            // Header claiming 4 GiB frame, without any data
+            client.write_all(&[0xFF; 4]).await.unwrap();
+            let secret = Some(vec![5u8; SECRET_LEN]);
+            let mut nonces = ChaCha20Rng::from_seed([5u8; SECRET_LEN]);
+            let read = reader.read_packet(&secret, Some(&mut nonces)).await;
+            assert!(matches!(read, Err(ConnectionError::FrameTooLarge)));
+
+            // Plaintext over the configured limit
+            let client = tokio::net::TcpStream::connect(addr).await.unwrap();
+            let (server, _) = listener.accept().await.unwrap();
+            let (_, mut writer) =
+                Connection::<ServerboundPacket, ServerboundPacket>::new(client).split();
+            let (mut reader, _) =
+                Connection::<ServerboundPacket, ServerboundPacket>::new(server).split();
+            reader.set_max_packet_size(64);
+            writer
+                .write_packet(ServerboundPacket::Message("a".repeat(100)), &None, None)
+                .await
+                .unwrap();
+            let read = reader.read_packet(&None, None).await;
+            assert!(matches!(read, Err(ConnectionError::FrameTooLarge)));
+        });
+    }
+
+    #[test]
+    fn frames_together_over_limit_test() {
+        use super::Connection;
+        use rand::SeedableRng;
+        use rand_chacha::ChaCha20Rng;
+
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            for secret in [None, Some(vec![5u8; SECRET_LEN])] {
+                let client = tokio::net::TcpStream::connect(addr).await.unwrap();
+                let (server, _) = listener.accept().await.unwrap();
+                let (_, mut writer) =
+                    Connection::<ServerboundPacket, ServerboundPacket>::new(client).split();
+                let (mut reader, _) =
+                    Connection::<ServerboundPacket, ServerboundPacket>::new(server).split();
+                reader.set_max_packet_size(128);
+                let mut write_nonces = ChaCha20Rng::from_seed([5u8; SECRET_LEN]);
+                let mut read_nonces = ChaCha20Rng::from_seed([5u8; SECRET_LEN]);
+
+                // Each fits, both don't, and they arrive together
+                let packets = vec![ServerboundPacket::Message("a".repeat(80)); 2];
+                writer
+                    .write_packets(packets.clone(), &secret, Some(&mut write_nonces))
+                    .await
+                    .unwrap();
+                for p in packets {
+                    let read = reader.read_packet(&secret, Some(&mut read_nonces)).await;
+                    assert!(matches!(read, Ok(Some(r)) if r == p));
+                }
+            }
+        });
+    }
+
+    #[test]
+    fn read_errors_test() {
+        use super::{Connection, ConnectionError};
+        use rand::SeedableRng;
+        use rand_chacha::ChaCha20Rng;
+        use tokio::io::AsyncWriteExt;
+
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let mut client = tokio::net::TcpStream::connect(addr).await.unwrap();
+            let (server, _) = listener.accept().await.unwrap();
+            let (mut reader, _) =
+                Connection::<ServerboundPacket, ServerboundPacket>::new(server).split();
+            let key = [5u8; SECRET_LEN];
+            let secret = Some(key.to_vec());
+            let mut nonces = ChaCha20Rng::from_seed(key);
+            let mut write_nonces = ChaCha20Rng::from_seed(key);
+
+            // Decrypts fine, but isn't a packet
+            let mut nonce = [0u8; NONCE_LEN];
+            rand::RngCore::fill_bytes(&mut write_nonces, &mut nonce);
+            let frame = encrypt_frame(&[0xC1], &cipher(&key), &nonce);
+            client.write_all(&frame).await.unwrap();
+            let read = reader.read_packet(&secret, Some(&mut nonces)).await;
+            assert!(matches!(read, Err(ConnectionError::Deserialize(_))));
+
+            // Wrong nonce
+            let frame = encrypt_frame(&ServerboundPacket::Ping.serialized(), &cipher(&key), &nonce);
+            client.write_all(&frame).await.unwrap();
+            let read = reader.read_packet(&secret, Some(&mut nonces)).await;
+            assert!(matches!(read, Err(ConnectionError::Decrypt)));
+
+            // Fresh connection, as the broken frame stays buffered
+            let client = tokio::net::TcpStream::connect(addr).await.unwrap();
+            let (server, _) = listener.accept().await.unwrap();
+            let (mut reader, _) =
+                Connection::<ServerboundPacket, ServerboundPacket>::new(server).split();
+            drop(client);
+            let read = reader.read_packet(&None, None).await;
+            assert!(matches!(read, Err(ConnectionError::Closed)));
+        });
+    }
 }
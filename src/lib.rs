@@ -1,7 +1,11 @@
+pub mod client_config;
 pub mod connection;
+pub mod markdown;
 pub mod packets;
 pub mod utils;
 
+use serde::{Deserialize, Serialize};
+
 pub const DEFAULT_PORT: u16 = 13723;
 
 pub const RSA_BITS: usize = 1024;
@@ -9,3 +13,60 @@ pub const RSA_BITS: usize = 1024;
 pub const ENC_TOK_LEN: usize = 32;
 pub const SECRET_LEN: usize = 32;
 pub const NONCE_LEN: usize = 24;
+
+/// Algorithms and parameters of encryption, agreed on in the encryption handshake.
+///
+/// Changing any of them needs a new suite, so older peers can still connect
+/// (or at least get refused clearly).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct CryptoSuite(pub u16);
+
+impl CryptoSuite {
+    /// RSA ([`RSA_BITS`], PKCS#1 v1.5) key exchange with [`ENC_TOK_LEN`] token,
+    /// XChaCha20-Poly1305 frames with [`SECRET_LEN`] key and [`NONCE_LEN`] nonces from ChaCha20 RNG
+    pub const V1: CryptoSuite = CryptoSuite(1);
+
+    pub fn is_supported(&self) -> bool {
+        SUPPORTED_SUITES.contains(self)
+    }
+
+    /// Picks suite to use from ones `offered` by the peer, preferring ours in order
+    pub fn negotiate(offered: &[CryptoSuite]) -> Option<CryptoSuite> {
+        SUPPORTED_SUITES
+            .iter()
+            .find(|suite| offered.contains(suite))
+            .copied()
+    }
+}
+
+/// Suites this version can use, most preferred first
+pub const SUPPORTED_SUITES: &[CryptoSuite] = &[CryptoSuite::V1];
+
+/// Max size of a single frame (packet) in bytes.
+///
+/// Big enough for images, guards against clients making us buffer endless data.
+pub const MAX_FRAME_LEN: usize = 32 * 1024 * 1024;
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn crypto_suite_negotiation() {
+        let unknown = CryptoSuite(999);
+        assert!(CryptoSuite::V1.is_supported());
+        assert!(!unknown.is_supported());
+
+        assert_eq!(
+            CryptoSuite::negotiate(&[CryptoSuite::V1]),
+            Some(CryptoSuite::V1)
+        );
+        // Unknown ones are skipped
+        assert_eq!(
+            CryptoSuite::negotiate(&[unknown, CryptoSuite::V1]),
+            Some(CryptoSuite::V1)
+        );
+        assert_eq!(CryptoSuite::negotiate(&[unknown]), None);
+        assert_eq!(CryptoSuite::negotiate(&[]), None);
+    }
+}
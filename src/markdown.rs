@@ -0,0 +1,350 @@
+//! Minimal markdown used in messages: `**bold**`, `*italic*`, `` `code` ``
+//! and fenced code blocks.
+//!
+//! Only styles text, there is no HTML or anything else that could be abused.
+//! Markers that don't pair up are left as they are.
+
+/// Run of text with the same style
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Span<'a> {
+    pub text: &'a str,
+    pub bold: bool,
+    pub italic: bool,
+    pub code: bool,
+}
+
+impl<'a> Span<'a> {
+    /// Unstyled text
+    pub fn plain(text: &'a str) -> Self {
+        Self {
+            text,
+            bold: false,
+            italic: false,
+            code: false,
+        }
+    }
+}
+
+/// Part of a message, either text or a fenced code block
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Block<'a> {
+    /// Text with inline markdown (see [`parse_inline`])
+    Text(&'a str),
+    /// Code between "```" fences, shown as is
+    Code {
+        /// Language hint after the opening fence (e.g. "rust" in "```rust")
+        language: Option<&'a str>,
+        code: &'a str,
+    },
+}
+
+/// Splits text into text and fenced code blocks (```` ```lang\ncode\n``` ````).
+///
+/// Fence without a closing one is left as text.
+pub fn split_code_blocks(text: &str) -> Vec<Block<'_>> {
+    const FENCE: &str = "```";
+    let mut blocks = vec![];
+    let mut rest = text;
+    while let Some(open) = rest.find(FENCE) {
+        let after_open = &rest[open + FENCE.len()..];
+        let close = match after_open.find(FENCE) {
+            Some(close) => close,
+            None => break,
+        };
+        let inside = &after_open[..close];
+        // Language hint is the rest of the opening line, if code starts on the next one
+        let (language, code) = match inside.split_once('\n') {
+            Some((first_line, code)) => {
+                let language = first_line.trim();
+                (
+                    (!language.is_empty()).then_some(language),
+                    code.strip_suffix('\n').unwrap_or(code),
+                )
+            }
+            None => (None, inside),
+        };
+        if code.is_empty() {
+            break;
+        }
+        if open > 0 {
+            blocks.push(Block::Text(&rest[..open]));
+        }
+        blocks.push(Block::Code { language, code });
+        rest = &after_open[close + FENCE.len()..];
+    }
+    if !rest.is_empty() {
+        blocks.push(Block::Text(rest));
+    }
+    blocks
+}
+
+/// Style of text between byte offsets `start` and `end` of parsed text
+#[derive(Debug, Clone, Copy)]
+struct Run {
+    start: usize,
+    end: usize,
+    bold: bool,
+    italic: bool,
+    code: bool,
+}
+
+/// Splits text into styled spans, without the markers.
+pub fn parse_inline(text: &str) -> Vec<Span<'_>> {
+    let mut runs: Vec<Run> = vec![];
+    parse_runs(text, 0, text.len(), false, false, &mut runs);
+    runs.into_iter()
+        .map(|r| Span {
+            text: &text[r.start..r.end],
+            bold: r.bold,
+            italic: r.italic,
+            code: r.code,
+        })
+        .collect()
+}
+
+/// Parses `text[start..end]` (already inside bold/italic if `bold`/`italic`) into `runs`.
+fn parse_runs(text: &str, start: usize, end: usize, bold: bool, italic: bool, runs: &mut Vec<Run>) {
+    let push = |runs: &mut Vec<Run>, start: usize, end: usize, code: bool| {
+        if start == end {
+            return;
+        }
+        match runs.last_mut() {
+            // Continues previous run, e.g. after a marker that didn't pair up
+            Some(last)
+                if last.end == start
+                    && (last.bold, last.italic, last.code) == (bold, italic, code) =>
+            {
+                last.end = end
+            }
+            _ => runs.push(Run {
+                start,
+                end,
+                bold,
+                italic,
+                code,
+            }),
+        }
+    };
+    let mut plain_start = start;
+    // Markers without a closing one until `end`, not looked for again
+    let mut unclosed: Vec<&str> = vec![];
+    let mut i = start;
+    while i < end {
+        let rest = &text[i..end];
+        let marker = if rest.starts_with('`') {
+            "`"
+        } else if rest.starts_with("**") && !bold {
+            "**"
+        } else if rest.starts_with('*') && !italic && !rest.starts_with("**") {
+            "*"
+        } else {
+            i += rest.chars().next().map_or(1, char::len_utf8);
+            continue;
+        };
+        let content_start = i + marker.len();
+        if unclosed.contains(&marker) {
+            i = content_start;
+            continue;
+        }
+        match find_closing(&text[content_start..end], marker) {
+            Closing::Found(len) => {
+                push(runs, plain_start, i, false);
+                let content_end = content_start + len;
+                match marker {
+                    "`" => push(runs, content_start, content_end, true),
+                    "**" => parse_runs(text, content_start, content_end, true, italic, runs),
+                    _ => parse_runs(text, content_start, content_end, bold, true, runs),
+                }
+                i = content_end + marker.len();
+                plain_start = i;
+            }
+            Closing::Rejected => i = content_start,
+            Closing::Missing => {
+                unclosed.push(marker);
+                i = content_start;
+            }
+        }
+    }
+    push(runs, plain_start, end, false);
+}
+
+/// Result of [`find_closing`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Closing {
+    /// Length of the content it closes
+    Found(usize),
+    /// Content can't start here, later marker of the same kind may still be closed
+    Rejected,
+    /// No closing marker until the end, so later markers of the same kind aren't closed either
+    Missing,
+}
+
+/// Finds length of content closed by `marker` at the start of `text`.
+///
+/// Content can't be empty, and bold/italic content can't start or end with whitespace
+/// (so "2 * 3 * 4" isn't italic).
+fn find_closing(text: &str, marker: &str) -> Closing {
+    if marker != "`" && text.starts_with(char::is_whitespace) {
+        return Closing::Rejected;
+    }
+    let mut from = 0;
+    while let Some(mut pos) = text[from..].find(marker).map(|p| from + p) {
+        if marker == "**" {
+            // In "***" closing "**" are the last two, so "**a *b***" closes both
+            while text[pos + 2..].starts_with('*') {
+                pos += 1;
+            }
+        }
+        let content = &text[..pos];
+        let after = &text[pos + marker.len()..];
+        // Single "*" followed by another one is part of "**"
+        let part_of_double = marker == "*" && after.starts_with('*');
+        if part_of_double {
+            // Skip the whole "**"
+            from = pos + 2;
+            continue;
+        }
+        if content.is_empty() {
+            return Closing::Rejected;
+        }
+        if marker != "`" && content.ends_with(char::is_whitespace) {
+            from = pos + marker.len();
+            continue;
+        }
+        return Closing::Found(pos);
+    }
+    Closing::Missing
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse_inline, split_code_blocks, Block, Span};
+
+    fn bold(text: &str) -> Span<'_> {
+        Span {
+            bold: true,
+            ..Span::plain(text)
+        }
+    }
+
+    fn italic(text: &str) -> Span<'_> {
+        Span {
+            italic: true,
+            ..Span::plain(text)
+        }
+    }
+
+    fn code(text: &str) -> Span<'_> {
+        Span {
+            code: true,
+            ..Span::plain(text)
+        }
+    }
+
+    #[test]
+    fn parse_inline_test() {
+        assert_eq!(parse_inline("plain text"), [Span::plain("plain text")]);
+        assert!(parse_inline("").is_empty());
+        assert_eq!(
+            parse_inline("a **b** *c* `d`"),
+            [
+                Span::plain("a "),
+                bold("b"),
+                Span::plain(" "),
+                italic("c"),
+                Span::plain(" "),
+                code("d"),
+            ]
+        );
+        // Nesting
+        assert_eq!(
+            parse_inline("**bold *both***"),
+            [
+                bold("bold "),
+                Span {
+                    bold: true,
+                    italic: true,
+                    ..Span::plain("both")
+                },
+            ]
+        );
+        assert_eq!(
+            parse_inline("*it **both** it*"),
+            [
+                italic("it "),
+                Span {
+                    bold: true,
+                    italic: true,
+                    ..Span::plain("both")
+                },
+                italic(" it"),
+            ]
+        );
+        // No formatting inside code
+        assert_eq!(parse_inline("`**x**`"), [code("**x**")]);
+    }
+
+    #[test]
+    fn unmatched_markers_are_literal() {
+        assert_eq!(parse_inline("**not bold"), [Span::plain("**not bold")]);
+        assert_eq!(parse_inline("a * b"), [Span::plain("a * b")]);
+        assert_eq!(parse_inline("2 * 3 * 4"), [Span::plain("2 * 3 * 4")]);
+        assert_eq!(parse_inline("``"), [Span::plain("``")]);
+        assert_eq!(parse_inline("****"), [Span::plain("****")]);
+        assert_eq!(parse_inline("`a"), [Span::plain("`a")]);
+        assert_eq!(parse_inline("*a **b*"), [italic("a **b")]);
+        assert_eq!(parse_inline("x*y*"), [Span::plain("x"), italic("y")]);
+        // Isn't rescanned for every marker
+        let long = "*a ".repeat(100_000);
+        assert_eq!(parse_inline(&long), [Span::plain(&long)]);
+    }
+
+    #[test]
+    fn split_code_blocks_test() {
+        assert_eq!(split_code_blocks("no code"), [Block::Text("no code")]);
+        assert!(split_code_blocks("").is_empty());
+        assert_eq!(
+            split_code_blocks("look:\n```rust\nfn main() {\n    x();\n}\n```\nnice"),
+            [
+                Block::Text("look:\n"),
+                Block::Code {
+                    language: Some("rust"),
+                    code: "fn main() {\n    x();\n}",
+                },
+                Block::Text("\nnice"),
+            ]
+        );
+        // No language hint
+        assert_eq!(
+            split_code_blocks("```\n  indented\n```"),
+            [Block::Code {
+                language: None,
+                code: "  indented",
+            }]
+        );
+        // Single line
+        assert_eq!(
+            split_code_blocks("```a  b```"),
+            [Block::Code {
+                language: None,
+                code: "a  b",
+            }]
+        );
+        // Unclosed or empty fences are text
+        assert_eq!(
+            split_code_blocks("```rust\nfn"),
+            [Block::Text("```rust\nfn")]
+        );
+        assert_eq!(split_code_blocks("``````"), [Block::Text("``````")]);
+        assert_eq!(
+            split_code_blocks("```a``` and ```"),
+            [
+                Block::Code {
+                    language: None,
+                    code: "a",
+                },
+                Block::Text(" and ```"),
+            ]
+        );
+    }
+}
@@ -1,13 +1,42 @@
 use rmp_serde::{Deserializer, Serializer};
 use serde::{Deserialize, Serialize};
 
+/// Sender name of messages from the server itself (i.e. replies to commands).
+///
+/// No user can register it, see [`UsernamePolicy::validate`](crate::utils::UsernamePolicy::validate).
+pub const SERVER_SENDER: &str = "#SERVER#";
+/// Sender id of messages from the server itself, no account has it
+pub const SERVER_SENDER_ID: i64 = 0;
+
 /// A text message
 #[derive(Debug, PartialEq, Eq, Clone, Deserialize, Serialize)]
 pub struct Message {
     pub sender_id: i64,
     pub sender: String,
     pub text: String,
-    pub time: u64,
+    /// Milliseconds since unix epoch
+    pub time: i64,
+    /// Name color chosen by the sender, see [`ServerboundPacket::SetColor`]
+    #[serde(default)]
+    pub sender_color: Option<String>,
+}
+
+impl Message {
+    /// Message from the server itself, with [`SERVER_SENDER`] as sender
+    pub fn from_server(text: String, time: i64) -> Self {
+        Self {
+            sender_id: SERVER_SENDER_ID,
+            sender: SERVER_SENDER.to_string(),
+            text,
+            time,
+            sender_color: None,
+        }
+    }
+
+    /// Whether message comes from the server itself rather than a user
+    pub fn is_from_server(&self) -> bool {
+        self.sender_id == SERVER_SENDER_ID
+    }
 }
 
 /// A message with an image
@@ -15,10 +44,87 @@ pub struct Message {
 pub struct ImageMessage {
     pub sender_id: i64,
     pub sender: String,
-    pub time: u64,
-    pub image_bytes: Vec<u8>,
+    /// Milliseconds since unix epoch
+    pub time: i64,
+    /// `None` in fetched history, clients get it with [`ServerboundPacket::FetchImage`]
+    pub image_bytes: Option<Vec<u8>>,
+    /// Hash of `image_bytes` (see [`image_hash`](crate::utils::image_hash))
+    pub hash: String,
+    /// Name color chosen by the sender, see [`ServerboundPacket::SetColor`]
+    #[serde(default)]
+    pub sender_color: Option<String>,
+}
+
+impl ImageMessage {
+    pub fn new(sender_id: i64, sender: String, time: i64, image_bytes: Vec<u8>) -> Self {
+        let hash = crate::utils::image_hash(&image_bytes);
+        Self {
+            sender_id,
+            sender,
+            time,
+            image_bytes: Some(image_bytes),
+            hash,
+            sender_color: None,
+        }
+    }
+}
+
+/// Permissions of a user, sent to them after login
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UserPermissions {
+    pub operator: bool,
+    pub whitelisted: bool,
+    pub banned: bool,
+}
+
+/// Why the server closed the connection, see [`ClientboundPacket::Disconnect`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DisconnectReason {
+    /// Kicked by an operator
+    Kicked,
+    /// Banned by an operator, logging in again will fail
+    Banned,
+    ServerShutdown,
+    /// Client sent something the server couldn't make sense of
+    ProtocolError,
+    /// Client didn't respond in time
+    Timeout,
+    /// Same account logged in from somewhere else
+    DuplicateLogin,
+}
+
+impl DisconnectReason {
+    /// Whether reconnecting right away makes sense
+    pub fn allows_reconnect(&self) -> bool {
+        !matches!(self, DisconnectReason::Banned | DisconnectReason::Kicked)
+    }
+}
+
+impl std::fmt::Display for DisconnectReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DisconnectReason::Kicked => write!(f, "Kicked from the server."),
+            DisconnectReason::Banned => write!(f, "Banned from the server."),
+            DisconnectReason::ServerShutdown => write!(f, "Server is shutting down."),
+            DisconnectReason::ProtocolError => write!(f, "Protocol error."),
+            DisconnectReason::Timeout => write!(f, "Connection timed out."),
+            DisconnectReason::DuplicateLogin => write!(f, "Logged in from somewhere else."),
+        }
+    }
 }
 
+/// Chat commands that only operators can use
+pub const OPERATOR_COMMANDS: &[&str] = &[
+    "kick",
+    "ban",
+    "unban",
+    "whitelist",
+    "unwhitelist",
+    "set_whitelist",
+    "set_allow_new_accounts",
+    "userinfo",
+];
+
 pub trait Packet {
     fn serialized(&self) -> Vec<u8>;
     fn deserialized(buf: &[u8]) -> Result<(Self, &[u8]), rmp_serde::decode::Error>
@@ -30,13 +136,27 @@ pub trait Packet {
 #[derive(Debug, PartialEq, Eq, Serialize, Deserialize, Clone)]
 pub enum ServerboundPacket {
     Ping,
-    EncryptionRequest,
+    /// Suites supported by the client, see [`CryptoSuite::negotiate`](crate::CryptoSuite::negotiate)
+    EncryptionRequest(Vec<crate::CryptoSuite>),
     EncryptionConfirm(Vec<u8>, Vec<u8>), // encrypted secret and token
-    Login { username: String, password: String },
+    Login {
+        username: String,
+        password: String,
+    },
     Message(String),
     ImageMessage(Vec<u8>),
     Command(String),
     FetchMessages(i64, i64),
+    /// Fetch `count` messages closest to `time` (milliseconds since unix epoch)
+    FetchMessagesAround {
+        time: i64,
+        count: i64,
+    },
+    /// Fetch image with given hash (see [`image_hash`](crate::utils::image_hash))
+    FetchImage(String),
+    /// Sets color of user's name (`#rrggbb`, see [`hex_color_rgb`](crate::utils::hex_color_rgb)),
+    /// empty to go back to default
+    SetColor(String),
 }
 
 impl Packet for ServerboundPacket {
@@ -56,15 +176,49 @@ impl Packet for ServerboundPacket {
 #[derive(Debug, PartialEq, Eq, Serialize, Deserialize, Clone)]
 pub enum ClientboundPacket {
     Pong,
-    EncryptionResponse(Vec<u8>, Vec<u8>), // channel's public key and token
+    EncryptionResponse(Vec<u8>, Vec<u8>, crate::CryptoSuite), // channel's public key, token and chosen suite
     EncryptionAck,
-    LoginAck,
+    /// Login succeeded, `user_id` is the `sender_id` of our messages
+    /// and `username` is the name as stored by the server
+    LoginAck {
+        user_id: i64,
+        username: String,
+    },
     LoginFailed(String),
     UserJoined(String),
     UserLeft(String),
+    /// User changed their username from `old` to `new`
+    UserRenamed {
+        old: String,
+        new: String,
+    },
     UsersOnline(Vec<String>),
     Message(Message),
     ImageMessage(ImageMessage),
+    ServerInfo {
+        name: String,
+        description: String,
+    },
+    /// Response to [`ServerboundPacket::FetchImage`], `bytes` are `None` if there is no such image
+    ImageData {
+        hash: String,
+        bytes: Option<Vec<u8>>,
+    },
+    /// Permissions of the logged in user, sent after [`ClientboundPacket::LoginAck`]
+    YourPermissions(UserPermissions),
+    /// Message sent by this client was not accepted (and not delivered to anyone)
+    MessageRejected {
+        reason: String,
+    },
+    /// Fetched history in one packet, oldest first
+    /// (contains [`ClientboundPacket::Message`]s and [`ClientboundPacket::ImageMessage`]s)
+    MessageBatch(Vec<ClientboundPacket>),
+    /// Reply to [`ServerboundPacket::Command`] sent by this client, from [`SERVER_SENDER`].
+    ///
+    /// Only the requester gets it and it's never stored in message history.
+    CommandResponse(Message),
+    /// Last packet before the server closes the connection
+    Disconnect(DisconnectReason),
 }
 
 impl Packet for ClientboundPacket {
@@ -79,3 +233,160 @@ impl Packet for ClientboundPacket {
         Self::deserialize(&mut d).map(|p| (p, d.into_inner()))
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn server_info_roundtrip_test() {
+        let packet = ClientboundPacket::ServerInfo {
+            name: "Test server".to_string(),
+            description: "Just testing".to_string(),
+        };
+        let buf = packet.serialized();
+        let (deserialized, rest) = ClientboundPacket::deserialized(&buf).unwrap();
+        assert_eq!(packet, deserialized);
+        assert!(rest.is_empty());
+    }
+
+    #[test]
+    fn permissions_roundtrip_test() {
+        let permissions = UserPermissions {
+            operator: true,
+            whitelisted: false,
+            banned: true,
+        };
+        let mut buf = Vec::new();
+        permissions
+            .serialize(&mut Serializer::new(&mut buf))
+            .unwrap();
+        let deserialized = UserPermissions::deserialize(&mut Deserializer::new(&buf[..])).unwrap();
+        assert_eq!(permissions, deserialized);
+
+        let packet = ClientboundPacket::YourPermissions(UserPermissions::default());
+        let buf = packet.serialized();
+        let (deserialized, rest) = ClientboundPacket::deserialized(&buf).unwrap();
+        assert_eq!(packet, deserialized);
+        assert!(rest.is_empty());
+    }
+
+    #[test]
+    fn disconnect_roundtrip_test() {
+        use DisconnectReason::*;
+        for reason in [
+            Kicked,
+            Banned,
+            ServerShutdown,
+            ProtocolError,
+            Timeout,
+            DuplicateLogin,
+        ] {
+            let packet = ClientboundPacket::Disconnect(reason);
+            let buf = packet.serialized();
+            let (deserialized, rest) = ClientboundPacket::deserialized(&buf).unwrap();
+            assert_eq!(packet, deserialized);
+            assert!(rest.is_empty());
+            assert_eq!(
+                reason.allows_reconnect(),
+                !matches!(reason, Kicked | Banned)
+            );
+        }
+    }
+
+    #[test]
+    fn image_data_roundtrip_test() {
+        for bytes in [Some(vec![1, 2, 3]), None] {
+            let packet = ClientboundPacket::ImageData {
+                hash: crate::utils::image_hash([1, 2, 3]),
+                bytes,
+            };
+            let buf = packet.serialized();
+            let (deserialized, rest) = ClientboundPacket::deserialized(&buf).unwrap();
+            assert_eq!(packet, deserialized);
+            assert!(rest.is_empty());
+        }
+    }
+
+    #[test]
+    fn image_message_hash_roundtrip_test() {
+        let image_bytes = b"not really an image".to_vec();
+        let packet = ClientboundPacket::ImageMessage(ImageMessage::new(
+            1,
+            "user".to_string(),
+            0,
+            image_bytes.clone(),
+        ));
+        let buf = packet.serialized();
+        let (deserialized, _) = ClientboundPacket::deserialized(&buf).unwrap();
+        match deserialized {
+            ClientboundPacket::ImageMessage(im) => {
+                assert_eq!(im.hash, crate::utils::image_hash(&image_bytes));
+                assert_eq!(im.image_bytes, Some(image_bytes));
+            }
+            p => panic!("Unexpected packet: {:?}", p),
+        }
+    }
+
+    /// Valid packets used as a starting point for mutated inputs
+    fn seed_corpus() -> Vec<Vec<u8>> {
+        let message = Message {
+            sender_id: 1,
+            sender: "user".to_string(),
+            text: "hello".to_string(),
+            time: 1_600_000_000_000,
+            sender_color: Some("#ff8800".to_string()),
+        };
+        vec![
+            ServerboundPacket::Ping.serialized(),
+            ServerboundPacket::EncryptionConfirm(vec![1; 16], vec![2; 16]).serialized(),
+            ServerboundPacket::Login {
+                username: "user".to_string(),
+                password: "password".to_string(),
+            }
+            .serialized(),
+            ServerboundPacket::Message("hello".to_string()).serialized(),
+            ServerboundPacket::FetchMessagesAround { time: 5, count: 10 }.serialized(),
+            ClientboundPacket::Message(message).serialized(),
+            ClientboundPacket::ImageMessage(ImageMessage::new(
+                1,
+                "user".to_string(),
+                0,
+                vec![3; 8],
+            ))
+            .serialized(),
+            ClientboundPacket::UsersOnline(vec!["a".to_string(), "b".to_string()]).serialized(),
+            ClientboundPacket::YourPermissions(UserPermissions::default()).serialized(),
+        ]
+    }
+
+    #[test]
+    fn deserialize_garbage_test() {
+        use rand::{Rng, SeedableRng};
+        // Fixed seed, so failures are reproducible
+        let mut rng = rand_chacha::ChaCha20Rng::seed_from_u64(0);
+        let corpus = seed_corpus();
+        for i in 0..20_000 {
+            let mut input = if i % 4 == 0 {
+                let len = rng.gen_range(0..64);
+                (0..len).map(|_| rng.gen()).collect()
+            } else {
+                corpus[rng.gen_range(0..corpus.len())].clone()
+            };
+            for _ in 0..rng.gen_range(1..4) {
+                if input.is_empty() {
+                    break;
+                }
+                let at = rng.gen_range(0..input.len());
+                match rng.gen_range(0..3) {
+                    0 => input[at] = rng.gen(),
+                    1 => input.truncate(at),
+                    _ => input.insert(at, rng.gen()),
+                }
+            }
+            // Only checks that nothing panics, both outcomes are fine
+            let _ = ServerboundPacket::deserialized(&input);
+            let _ = ClientboundPacket::deserialized(&input);
+        }
+    }
+}
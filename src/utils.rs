@@ -1,13 +1,799 @@
-/// Checks for incorrect characters (i.e. control characters)
+use serde::{Deserialize, Serialize};
+
+/// Default max length of username in bytes
+pub const MAX_USERNAME_LEN: usize = 18;
+
+/// Reason why message or username is invalid
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ValidationError {
+    Empty,
+    /// Shorter than given min length
+    TooShort(usize),
+    /// Longer than given max length
+    TooLong(usize),
+    ControlCharacter,
+    /// Contains characters other than letters, digits and allowed special characters
+    NotAlphanumeric,
+    /// Reserved for the server (see [`SERVER_SENDER`](crate::packets::SERVER_SENDER))
+    Reserved,
+}
+
+impl std::fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ValidationError::Empty => write!(f, "can't be empty"),
+            ValidationError::TooShort(min) => write!(f, "too short (min {} bytes)", min),
+            ValidationError::TooLong(max) => write!(f, "too long (max {} bytes)", max),
+            ValidationError::ControlCharacter => write!(f, "contains control characters"),
+            ValidationError::NotAlphanumeric => {
+                write!(f, "contains characters that aren't allowed")
+            }
+            ValidationError::Reserved => write!(f, "is reserved"),
+        }
+    }
+}
+
+impl std::error::Error for ValidationError {}
+
+/// Max length of message in bytes, fits `content` column of server's database
+pub const MAX_MESSAGE_LEN: usize = 1023;
+
+/// Checks length and incorrect characters (i.e. control characters)
 #[inline]
 pub fn verify_message<T: AsRef<str>>(m: T) -> bool {
+    validate_message(m).is_ok()
+}
+
+/// Like [`verify_message`], but returns the reason why message is invalid
+///
+/// Line breaks and tabs are allowed (e.g. for code blocks).
+pub fn validate_message<T: AsRef<str>>(m: T) -> Result<(), ValidationError> {
     let m = m.as_ref();
-    !m.chars().any(|c| c.is_control()) && !m.is_empty()
+    if m.is_empty() {
+        Err(ValidationError::Empty)
+    } else if m.len() > MAX_MESSAGE_LEN {
+        Err(ValidationError::TooLong(MAX_MESSAGE_LEN))
+    } else if m
+        .chars()
+        .any(|c| c.is_control() && !matches!(c, '\n' | '\t'))
+    {
+        Err(ValidationError::ControlCharacter)
+    } else {
+        Ok(())
+    }
+}
+
+/// Max length of command in bytes
+pub const MAX_COMMAND_LEN: usize = 256;
+
+/// Parses color in `#rrggbb` form, as users pick for their names
+pub fn hex_color_rgb(color: &str) -> Option<(u8, u8, u8)> {
+    let hex = color.strip_prefix('#')?;
+    if hex.len() != 6 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).ok();
+    Some((channel(0)?, channel(2)?, channel(4)?))
+}
+
+/// Checks command (without the leading "/") for length and incorrect characters
+pub fn validate_command<T: AsRef<str>>(command: T) -> Result<(), ValidationError> {
+    let command = command.as_ref();
+    if command.len() > MAX_COMMAND_LEN {
+        Err(ValidationError::TooLong(MAX_COMMAND_LEN))
+    } else if command.is_empty() {
+        Err(ValidationError::Empty)
+    } else if command.chars().any(|c| c.is_control()) {
+        Err(ValidationError::ControlCharacter)
+    } else {
+        Ok(())
+    }
 }
 
-/// Checks length and characters
+/// Strips line ending (`\n`, or `\r\n` sent as Enter by Windows terminals)
+/// from the end of the message.
+#[inline]
+pub fn trim_line_ending(m: &str) -> &str {
+    m.trim_end_matches(['\r', '\n'])
+}
+
+/// Checks length and characters, using default [`UsernamePolicy`]
 #[inline]
 pub fn verify_username<T: AsRef<str>>(u: T) -> bool {
-    let u = u.as_ref();
-    !((u.len() > 18) || u.is_empty() || u.chars().any(|c| !c.is_alphanumeric()))
+    validate_username(u).is_ok()
+}
+
+/// Like [`verify_username`], but returns the reason why username is invalid
+pub fn validate_username<T: AsRef<str>>(u: T) -> Result<(), ValidationError> {
+    UsernamePolicy::default().validate(u)
+}
+
+/// Rules for valid usernames.
+///
+/// Servers can configure it, clients check the default one.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct UsernamePolicy {
+    /// Min length in bytes
+    pub min_len: usize,
+    /// Max length in bytes
+    pub max_len: usize,
+    /// Characters allowed besides letters and digits (e.g. `"_-"`)
+    pub allowed_special: String,
+}
+
+impl Default for UsernamePolicy {
+    fn default() -> Self {
+        Self {
+            min_len: 1,
+            max_len: MAX_USERNAME_LEN,
+            allowed_special: String::new(),
+        }
+    }
+}
+
+impl UsernamePolicy {
+    pub fn validate<T: AsRef<str>>(&self, u: T) -> Result<(), ValidationError> {
+        let u = u.as_ref();
+        if u.is_empty() {
+            Err(ValidationError::Empty)
+        } else if u.eq_ignore_ascii_case(crate::packets::SERVER_SENDER) {
+            // Even if policy allows "#", so nobody can pose as the server
+            Err(ValidationError::Reserved)
+        } else if u.len() < self.min_len {
+            Err(ValidationError::TooShort(self.min_len))
+        } else if u.len() > self.max_len {
+            Err(ValidationError::TooLong(self.max_len))
+        } else if u
+            .chars()
+            .any(|c| !c.is_alphanumeric() && !self.allowed_special.contains(c))
+        {
+            Err(ValidationError::NotAlphanumeric)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Full SHA-256 of the image as hex.
+///
+/// Identifies images on the server and in clients' caches.
+pub fn image_hash<T: AsRef<[u8]>>(image_bytes: T) -> String {
+    use sha2::{Digest, Sha256};
+    format!("{:x}", Sha256::digest(image_bytes.as_ref()))
+}
+
+/// Checks whether string could be an [`image_hash`]
+#[inline]
+pub fn verify_image_hash<T: AsRef<str>>(hash: T) -> bool {
+    let hash = hash.as_ref();
+    hash.len() == 64
+        && hash
+            .chars()
+            .all(|c| c.is_ascii_digit() || ('a'..='f').contains(&c))
+}
+
+/// Formats message time relative to `now` (e.g. "5m ago").
+///
+/// Both times are in milliseconds since unix epoch.
+pub fn format_relative(time: i64, now: i64) -> String {
+    const MINUTE: i64 = 60 * 1000;
+    const HOUR: i64 = 60 * MINUTE;
+    const DAY: i64 = 24 * HOUR;
+    // Times slightly in the future (clock differences) count as now
+    let ago = now.saturating_sub(time).max(0);
+    if ago < MINUTE {
+        "just now".to_string()
+    } else if ago < HOUR {
+        format!("{}m ago", ago / MINUTE)
+    } else if ago < DAY {
+        format!("{}h ago", ago / HOUR)
+    } else if ago < 2 * DAY {
+        "yesterday".to_string()
+    } else {
+        format!("{}d ago", ago / DAY)
+    }
+}
+
+/// How far ahead (in milliseconds) of client's clock message time can be
+/// before server's clock is considered wrong
+pub const MAX_CLOCK_SKEW: i64 = 60 * 1000;
+
+/// Checks if message `time` is further ahead of `now` than [`MAX_CLOCK_SKEW`]
+/// (both in milliseconds since unix epoch), i.e. server's or client's clock is off.
+pub fn is_from_future(time: i64, now: i64) -> bool {
+    time.saturating_sub(now) > MAX_CLOCK_SKEW
+}
+
+/// Max time (in milliseconds) between messages from the same sender
+/// for them to be shown as one group
+pub const MESSAGE_GROUP_GAP: i64 = 5 * 60 * 1000;
+
+/// Checks whether message continues the group of previous message,
+/// i.e. it's from the same sender and was sent shortly after.
+///
+/// Grouped messages are shown without repeated sender and time.
+/// Messages from the server are never grouped.
+#[inline]
+pub fn continues_group(prev_sender_id: i64, prev_time: i64, sender_id: i64, time: i64) -> bool {
+    sender_id != crate::packets::SERVER_SENDER_ID
+        && prev_sender_id == sender_id
+        && (0..=MESSAGE_GROUP_GAP).contains(&(time - prev_time))
+}
+
+/// Checks whether message from `sender_id` was sent by the logged in user
+/// (with id from [`ClientboundPacket::LoginAck`](crate::packets::ClientboundPacket::LoginAck)).
+///
+/// Messages from the server are never own.
+#[inline]
+pub fn is_own_message(sender_id: i64, own_id: Option<i64>) -> bool {
+    sender_id != crate::packets::SERVER_SENDER_ID && own_id == Some(sender_id)
+}
+
+/// Names mentioned in text with "@name" (without trailing punctuation, e.g. "@bob," is "bob").
+pub fn mentioned_names(text: &str) -> Vec<&str> {
+    text.split_whitespace()
+        .filter_map(|word| word.strip_prefix('@'))
+        .map(|name| name.trim_end_matches(|c: char| c.is_ascii_punctuation()))
+        .filter(|name| !name.is_empty())
+        .collect()
+}
+
+/// Finds http(s) links in text.
+///
+/// Returns byte offset of each link together with the link.
+pub fn extract_urls(text: &str) -> Vec<(usize, &str)> {
+    let mut urls = vec![];
+    let mut offset = 0;
+    for word in text.split_inclusive(char::is_whitespace) {
+        let token = word.trim_end();
+        let trimmed = token.trim_start_matches(['(', '<', '"', '\'']);
+        let lead = token.len() - trimmed.len();
+        let url = trimmed.trim_end_matches(|c| {
+            matches!(
+                c,
+                '.' | ',' | ':' | ';' | '!' | '?' | ')' | '>' | '"' | '\''
+            )
+        });
+        let is_url = ["https://", "http://"]
+            .iter()
+            .any(|scheme| url.len() > scheme.len() && url.starts_with(scheme));
+        if is_url {
+            urls.push((offset + lead, url));
+        }
+        offset += word.len();
+    }
+    urls
+}
+
+/// Emoji shortcodes (without colons) supported by [`expand_shortcodes`]
+pub const EMOJI_SHORTCODES: &[(&str, &str)] = &[
+    ("smile", "😄"),
+    ("grin", "😁"),
+    ("joy", "😂"),
+    ("laughing", "😆"),
+    ("wink", "😉"),
+    ("blush", "😊"),
+    ("slight_smile", "🙂"),
+    ("upside_down", "🙃"),
+    ("heart_eyes", "😍"),
+    ("kissing_heart", "😘"),
+    ("thinking", "🤔"),
+    ("neutral_face", "😐"),
+    ("unamused", "😒"),
+    ("roll_eyes", "🙄"),
+    ("sweat_smile", "😅"),
+    ("cry", "😢"),
+    ("sob", "😭"),
+    ("angry", "😠"),
+    ("rage", "😡"),
+    ("scream", "😱"),
+    ("sunglasses", "😎"),
+    ("sleeping", "😴"),
+    ("skull", "💀"),
+    ("thumbsup", "👍"),
+    ("+1", "👍"),
+    ("thumbsdown", "👎"),
+    ("-1", "👎"),
+    ("clap", "👏"),
+    ("wave", "👋"),
+    ("pray", "🙏"),
+    ("ok_hand", "👌"),
+    ("muscle", "💪"),
+    ("eyes", "👀"),
+    ("heart", "❤️"),
+    ("broken_heart", "💔"),
+    ("fire", "🔥"),
+    ("star", "⭐"),
+    ("sparkles", "✨"),
+    ("tada", "🎉"),
+    ("100", "💯"),
+    ("check", "✅"),
+    ("x", "❌"),
+    ("warning", "⚠️"),
+    ("rocket", "🚀"),
+    ("coffee", "☕"),
+    ("pizza", "🍕"),
+    ("beer", "🍺"),
+    ("cat", "🐱"),
+    ("dog", "🐶"),
+    ("crab", "🦀"),
+];
+
+/// Replaces emoji shortcodes (e.g. `:smile:`) with emoji, leaving unknown ones as they are.
+pub fn expand_shortcodes(text: &str) -> String {
+    let mut expanded = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find(':') {
+        expanded.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        let emoji = after.find(':').and_then(|end| {
+            let code = &after[..end];
+            EMOJI_SHORTCODES
+                .iter()
+                .find(|(c, _)| *c == code)
+                .map(|(_, emoji)| (*emoji, end))
+        });
+        match emoji {
+            Some((emoji, end)) => {
+                expanded.push_str(emoji);
+                rest = &after[end + 1..];
+            }
+            None => {
+                // Closing colon might start the next shortcode
+                expanded.push(':');
+                rest = after;
+            }
+        }
+    }
+    expanded.push_str(rest);
+    expanded
+}
+
+/// Splits text into parts that are either plain or spoilers (`||hidden||`),
+/// returned as `(part, is_spoiler)` without the markers.
+///
+/// Markers pair up from the left, so spoilers can't be nested.
+/// Unpaired marker and empty spoiler (`||||`) are left as plain text.
+pub fn split_spoilers(text: &str) -> Vec<(&str, bool)> {
+    const MARKER: &str = "||";
+    let mut parts = vec![];
+    // Start of current plain part
+    let mut plain_start = 0;
+    let mut rest_start = 0;
+    while let Some(open) = text[rest_start..].find(MARKER).map(|i| rest_start + i) {
+        let content_start = open + MARKER.len();
+        let close = match text[content_start..].find(MARKER) {
+            Some(i) => content_start + i,
+            None => break,
+        };
+        if close == content_start {
+            // Empty, keep as text
+            rest_start = close + MARKER.len();
+            continue;
+        }
+        if open > plain_start {
+            parts.push((&text[plain_start..open], false));
+        }
+        parts.push((&text[content_start..close], true));
+        plain_start = close + MARKER.len();
+        rest_start = plain_start;
+    }
+    if plain_start < text.len() {
+        parts.push((&text[plain_start..], false));
+    }
+    parts
+}
+
+/// Max number of messages server sends for a single fetch
+pub const MAX_FETCH_COUNT: i64 = 64;
+
+/// Splits fetching last `count` messages into `(offset, count)` fetches of at most
+/// `chunk_size` messages (and [`MAX_FETCH_COUNT`]), ordered from the oldest chunk.
+///
+/// Server sends each chunk from the oldest message, so they can be shown as they arrive.
+pub fn history_chunks(count: i64, chunk_size: i64) -> Vec<(i64, i64)> {
+    let chunk_size = chunk_size.clamp(1, MAX_FETCH_COUNT);
+    let mut chunks = vec![];
+    // Offset counts from the newest message
+    let mut offset = 0;
+    while offset < count {
+        let size = (count - offset).min(chunk_size);
+        chunks.push((offset, size));
+        offset += size;
+    }
+    chunks.reverse();
+    chunks
+}
+
+/// Removes `flag` with its value (`--flag value` or `--flag=value`) from `args`,
+/// returning the value if it was there
+pub fn take_flag(args: &mut Vec<String>, flag: &str) -> Option<String> {
+    let i = args
+        .iter()
+        .position(|a| a == flag || a.strip_prefix(flag).is_some_and(|r| r.starts_with('=')))?;
+    let arg = args.remove(i);
+    if arg == flag {
+        (i < args.len()).then(|| args.remove(i))
+    } else {
+        Some(arg[flag.len() + 1..].to_string())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        continues_group, expand_shortcodes, extract_urls, format_relative, hex_color_rgb,
+        history_chunks, image_hash, is_from_future, is_own_message, mentioned_names,
+        split_spoilers, take_flag, trim_line_ending, validate_command, validate_message,
+        validate_username, verify_image_hash, verify_message, verify_username, UsernamePolicy,
+        ValidationError, MAX_CLOCK_SKEW, MAX_COMMAND_LEN, MAX_MESSAGE_LEN, MAX_USERNAME_LEN,
+        MESSAGE_GROUP_GAP,
+    };
+
+    #[test]
+    fn image_hash_test() {
+        // These collide on first 4 bytes of the hash
+        let a = b"image 9808";
+        let b = b"image 45671";
+        assert_eq!(image_hash(a)[..8], image_hash(b)[..8]);
+        assert_ne!(image_hash(a), image_hash(b));
+
+        assert_eq!(image_hash(a).len(), 64);
+        assert_eq!(
+            image_hash(b""),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+    }
+
+    #[test]
+    fn validate_message_test() {
+        assert_eq!(validate_message("hello"), Ok(()));
+        assert_eq!(validate_message(""), Err(ValidationError::Empty));
+        assert_eq!(
+            validate_message("hel\u{7}lo"),
+            Err(ValidationError::ControlCharacter)
+        );
+        // Multi-line messages (e.g. code blocks) are fine
+        assert_eq!(validate_message("line\nbreak"), Ok(()));
+        assert_eq!(validate_message("\tindented"), Ok(()));
+        assert_eq!(
+            validate_message("line\r\nbreak"),
+            Err(ValidationError::ControlCharacter)
+        );
+        assert_eq!(validate_message("a".repeat(MAX_MESSAGE_LEN)), Ok(()));
+        assert_eq!(
+            validate_message("a".repeat(MAX_MESSAGE_LEN + 1)),
+            Err(ValidationError::TooLong(MAX_MESSAGE_LEN))
+        );
+        assert!(verify_message("hello"));
+        assert!(!verify_message(""));
+    }
+
+    #[test]
+    fn hex_color_test() {
+        assert_eq!(hex_color_rgb("#ff8800"), Some((255, 136, 0)));
+        assert_eq!(hex_color_rgb("#A0b1C2"), Some((160, 177, 194)));
+        for invalid in [
+            "",
+            "#",
+            "ff8800",
+            "#ff880",
+            "#ff88000",
+            "#gg8800",
+            "#ff88\u{e9}",
+            "red",
+        ] {
+            assert_eq!(hex_color_rgb(invalid), None, "{:?}", invalid);
+        }
+    }
+
+    #[test]
+    fn validate_command_test() {
+        assert_eq!(validate_command("kick someone"), Ok(()));
+        assert_eq!(validate_command("a".repeat(MAX_COMMAND_LEN)), Ok(()));
+        assert_eq!(
+            validate_command("a".repeat(MAX_COMMAND_LEN + 1)),
+            Err(ValidationError::TooLong(MAX_COMMAND_LEN))
+        );
+        // Way too long
+        assert_eq!(
+            validate_command("kick ".repeat(1_000_000)),
+            Err(ValidationError::TooLong(MAX_COMMAND_LEN))
+        );
+        assert_eq!(validate_command(""), Err(ValidationError::Empty));
+        assert_eq!(
+            validate_command("list\u{0}"),
+            Err(ValidationError::ControlCharacter)
+        );
+        assert_eq!(
+            validate_command("kick\nsomeone"),
+            Err(ValidationError::ControlCharacter)
+        );
+    }
+
+    #[test]
+    fn validate_username_test() {
+        assert_eq!(validate_username("user1"), Ok(()));
+        assert_eq!(validate_username("ż".repeat(9)), Ok(()));
+        assert_eq!(validate_username(""), Err(ValidationError::Empty));
+        assert_eq!(
+            validate_username("a".repeat(MAX_USERNAME_LEN + 1)),
+            Err(ValidationError::TooLong(MAX_USERNAME_LEN))
+        );
+        // Length is in bytes
+        assert_eq!(
+            validate_username("ż".repeat(10)),
+            Err(ValidationError::TooLong(MAX_USERNAME_LEN))
+        );
+        assert_eq!(
+            validate_username("user name"),
+            Err(ValidationError::NotAlphanumeric)
+        );
+        assert_eq!(
+            validate_username("user\n"),
+            Err(ValidationError::NotAlphanumeric)
+        );
+        assert!(verify_username("user1"));
+        assert!(!verify_username("user!"));
+    }
+
+    #[test]
+    fn server_name_is_reserved() {
+        let hashes = UsernamePolicy {
+            allowed_special: "#".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(hashes.validate("#user#"), Ok(()));
+        assert_eq!(hashes.validate("#SERVER#"), Err(ValidationError::Reserved));
+        assert_eq!(hashes.validate("#server#"), Err(ValidationError::Reserved));
+        assert_eq!(
+            validate_username("#SERVER#"),
+            Err(ValidationError::Reserved)
+        );
+    }
+
+    #[test]
+    fn username_policy_test() {
+        let underscores = UsernamePolicy {
+            allowed_special: "_".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(underscores.validate("snake_case"), Ok(()));
+        assert_eq!(underscores.validate("_"), Ok(()));
+        assert_eq!(
+            underscores.validate("kebab-case"),
+            Err(ValidationError::NotAlphanumeric)
+        );
+        assert_eq!(
+            UsernamePolicy::default().validate("snake_case"),
+            Err(ValidationError::NotAlphanumeric)
+        );
+
+        let spaces = UsernamePolicy {
+            min_len: 3,
+            max_len: 10,
+            allowed_special: " -".to_string(),
+        };
+        assert_eq!(spaces.validate("Jo Ann-Lee"), Ok(()));
+        assert_eq!(spaces.validate("Jo"), Err(ValidationError::TooShort(3)));
+        assert_eq!(
+            spaces.validate("Jo Ann-Leee"),
+            Err(ValidationError::TooLong(10))
+        );
+        assert_eq!(spaces.validate(""), Err(ValidationError::Empty));
+        assert_eq!(
+            spaces.validate("Jo\tAnn"),
+            Err(ValidationError::NotAlphanumeric)
+        );
+    }
+
+    #[test]
+    fn trim_line_ending_test() {
+        assert_eq!(trim_line_ending("hello\r\n"), "hello");
+        assert_eq!(trim_line_ending("hello\r"), "hello");
+        assert_eq!(trim_line_ending("hello\n"), "hello");
+        assert_eq!(trim_line_ending("hello"), "hello");
+        assert_eq!(trim_line_ending("\r\n"), "");
+        // Only the end is trimmed
+        assert_eq!(trim_line_ending("he\rllo\r\n"), "he\rllo");
+
+        assert!(!verify_message("hello\r"));
+        assert!(verify_message(trim_line_ending("hello\r")));
+        assert!(!verify_message(trim_line_ending("\r\n")));
+    }
+
+    #[test]
+    fn verify_image_hash_test() {
+        assert!(verify_image_hash(image_hash(b"image")));
+        assert!(!verify_image_hash(""));
+        // Too short
+        assert!(!verify_image_hash(&image_hash(b"image")[..63]));
+        // Uppercase
+        assert!(!verify_image_hash(image_hash(b"image").to_uppercase()));
+        // Not hex
+        assert!(!verify_image_hash("g".repeat(64)));
+        assert!(!verify_image_hash("'; DROP TABLE accord.images; --"));
+    }
+
+    #[test]
+    fn format_relative_test() {
+        let now = 1_651_000_000_000;
+        let s = 1000;
+        let m = 60 * s;
+        let h = 60 * m;
+        let d = 24 * h;
+        assert_eq!(format_relative(now, now), "just now");
+        assert_eq!(format_relative(now - 59 * s, now), "just now");
+        assert_eq!(format_relative(now + 5 * s, now), "just now");
+        assert_eq!(format_relative(now - m, now), "1m ago");
+        assert_eq!(format_relative(now - 59 * m - 59 * s, now), "59m ago");
+        assert_eq!(format_relative(now - h, now), "1h ago");
+        assert_eq!(format_relative(now - 23 * h - 59 * m, now), "23h ago");
+        assert_eq!(format_relative(now - d, now), "yesterday");
+        assert_eq!(format_relative(now - 2 * d + 1, now), "yesterday");
+        assert_eq!(format_relative(now - 2 * d, now), "2d ago");
+        assert_eq!(format_relative(now - 400 * d, now), "400d ago");
+        assert_eq!(
+            format_relative(i64::MIN, now),
+            format!("{}d ago", i64::MAX / d)
+        );
+    }
+
+    #[test]
+    fn future_time_test() {
+        let now = 1_651_000_000_000;
+        assert!(!is_from_future(now - 1000, now));
+        assert!(!is_from_future(now, now));
+        // Small differences between clocks are fine
+        assert!(!is_from_future(now + MAX_CLOCK_SKEW, now));
+        assert!(is_from_future(now + MAX_CLOCK_SKEW + 1, now));
+        assert!(is_from_future(now + 24 * 60 * 60 * 1000, now));
+        assert!(is_from_future(i64::MAX, i64::MIN));
+        assert!(!is_from_future(i64::MIN, i64::MAX));
+    }
+
+    #[test]
+    fn continues_group_test() {
+        // (sender_id, time, expected grouping)
+        let messages = [
+            (1, 0, false),
+            (1, 1000, true),
+            (1, 1000 + MESSAGE_GROUP_GAP, true),
+            // Too long since previous message
+            (1, 2001 + 2 * MESSAGE_GROUP_GAP, false),
+            // Different sender
+            (2, 3000 + 2 * MESSAGE_GROUP_GAP, false),
+            (1, 4000 + 2 * MESSAGE_GROUP_GAP, false),
+            // Older than previous (e.g. history mixed with new messages)
+            (1, 0, false),
+            // Server replies stand on their own
+            (0, 5000 + 2 * MESSAGE_GROUP_GAP, false),
+            (0, 5001 + 2 * MESSAGE_GROUP_GAP, false),
+        ];
+        let mut prev: Option<(i64, i64)> = None;
+        for (sender_id, time, expected) in messages {
+            let grouped = prev.is_some_and(|(prev_sender_id, prev_time)| {
+                continues_group(prev_sender_id, prev_time, sender_id, time)
+            });
+            assert_eq!(grouped, expected, "message at {}", time);
+            prev = Some((sender_id, time));
+        }
+    }
+
+    #[test]
+    fn own_message_test() {
+        assert!(is_own_message(3, Some(3)));
+        assert!(!is_own_message(4, Some(3)));
+        assert!(!is_own_message(3, None));
+        let server = crate::packets::SERVER_SENDER_ID;
+        assert!(!is_own_message(server, Some(server)));
+    }
+
+    #[test]
+    fn mentioned_names_test() {
+        assert_eq!(mentioned_names("@bob, look"), ["bob"]);
+        assert_eq!(mentioned_names("hi @alice and @bob!"), ["alice", "bob"]);
+        assert!(mentioned_names("mail me at a@b.c or @ or @!").is_empty());
+    }
+
+    #[test]
+    fn extract_urls_test() {
+        let text = "see https://example.com/a, and (http://x.org/b?c=d). ftp://no https:// ok";
+        let urls = extract_urls(text);
+        assert_eq!(
+            urls,
+            vec![(4, "https://example.com/a"), (32, "http://x.org/b?c=d")]
+        );
+        for (start, url) in urls {
+            assert_eq!(&text[start..start + url.len()], url);
+        }
+
+        assert!(extract_urls("").is_empty());
+        assert!(extract_urls("no links here").is_empty());
+        // Offsets are in bytes
+        assert_eq!(
+            extract_urls("zażółć https://ąę.pl"),
+            vec![(11, "https://ąę.pl")]
+        );
+    }
+
+    #[test]
+    fn expand_shortcodes_test() {
+        assert_eq!(expand_shortcodes("hi :smile:"), "hi 😄");
+        assert_eq!(expand_shortcodes(":+1::fire:"), "👍🔥");
+        // Unknown codes are left intact
+        assert_eq!(expand_shortcodes(":nope: :smile"), ":nope: :smile");
+        assert_eq!(expand_shortcodes("at 10:30 :wave:"), "at 10:30 👋");
+        assert_eq!(expand_shortcodes("a:b:smile:"), "a:b😄");
+        assert_eq!(expand_shortcodes("::"), "::");
+        assert_eq!(expand_shortcodes(""), "");
+        // Emoji are valid message text
+        for (code, emoji) in super::EMOJI_SHORTCODES {
+            let expanded = expand_shortcodes(&format!(":{}:", code));
+            assert_eq!(expanded, *emoji);
+            assert_eq!(validate_message(&expanded), Ok(()), "{}", code);
+        }
+    }
+
+    #[test]
+    fn split_spoilers_test() {
+        assert_eq!(split_spoilers("no spoilers"), [("no spoilers", false)]);
+        assert_eq!(
+            split_spoilers("he dies ||in the end|| sadly"),
+            [("he dies ", false), ("in the end", true), (" sadly", false)]
+        );
+        assert_eq!(split_spoilers("||a||||b||"), [("a", true), ("b", true)]);
+        // Nested markers pair up from the left
+        assert_eq!(
+            split_spoilers("||a ||b|| c||"),
+            [("a ", true), ("b", false), (" c", true)]
+        );
+        // Unbalanced marker stays as text
+        assert_eq!(
+            split_spoilers("||a|| and || b"),
+            [("a", true), (" and || b", false)]
+        );
+        assert_eq!(split_spoilers("a || b"), [("a || b", false)]);
+        // Empty spoiler stays as text
+        assert_eq!(split_spoilers("||||"), [("||||", false)]);
+        assert_eq!(
+            split_spoilers("x |||| ||y||"),
+            [("x |||| ", false), ("y", true)]
+        );
+        assert!(split_spoilers("").is_empty());
+    }
+
+    #[test]
+    fn history_chunks_test() {
+        // Oldest chunk (with the biggest offset) first
+        assert_eq!(history_chunks(20, 8), [(16, 4), (8, 8), (0, 8)]);
+        assert_eq!(history_chunks(20, 20), [(0, 20)]);
+        assert_eq!(history_chunks(20, 100), [(0, 20)]);
+        assert_eq!(history_chunks(3, 1), [(2, 1), (1, 1), (0, 1)]);
+        // Server doesn't send more than MAX_FETCH_COUNT at once
+        assert_eq!(history_chunks(100, 100), [(64, 36), (0, 64)]);
+        // Nonsense values
+        assert!(history_chunks(0, 8).is_empty());
+        assert!(history_chunks(-5, 8).is_empty());
+        assert_eq!(history_chunks(2, 0), [(1, 1), (0, 1)]);
+    }
+
+    #[test]
+    fn take_flag_test() {
+        let args = |a: &[&str]| a.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+
+        let mut a = args(&["client", "--config", "a.toml", "10.0.0.1"]);
+        assert_eq!(take_flag(&mut a, "--config"), Some("a.toml".to_string()));
+        assert_eq!(a, args(&["client", "10.0.0.1"]));
+
+        let mut a = args(&["client", "10.0.0.1", "--config=b.toml"]);
+        assert_eq!(take_flag(&mut a, "--config"), Some("b.toml".to_string()));
+        assert_eq!(a, args(&["client", "10.0.0.1"]));
+
+        let mut a = args(&["client", "--configure", "--config"]);
+        assert_eq!(take_flag(&mut a, "--config"), None);
+        assert_eq!(a, args(&["client", "--configure"]));
+        assert_eq!(take_flag(&mut a, "--config"), None);
+    }
 }